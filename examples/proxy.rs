@@ -19,6 +19,9 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
 extern crate gj;
 use gj::io::{AsyncRead, AsyncWrite};
 
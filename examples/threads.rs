@@ -29,6 +29,9 @@
 //! `std::thread::sleep_ms()` as a stand-in for a blocking computation.
 
 
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
 extern crate gj;
 use gj::io::{AsyncRead, AsyncWrite};
 
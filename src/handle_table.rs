@@ -19,32 +19,74 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+
 use std::collections::binary_heap::BinaryHeap;
 use std::ops::{Index, IndexMut};
 
-#[derive(PartialEq, Eq, Copy, Clone, Hash)]
-pub struct Handle { pub val : usize }
+/// Refers to an entry of a `HandleTable`. Slots are reused once their entry has been removed, so
+/// a handle also records the generation of the slot that it was issued for; using a handle
+/// whose entry has since been removed panics, rather than silently reaching the slot's new entry.
+#[derive(PartialEq, Eq, Copy, Clone, Hash, Debug)]
+pub struct Handle { pub val : usize, pub generation : u32 }
+
+// The number of low bits of a token that hold the slot index. The rest hold the generation.
+fn token_index_bits() -> usize { ::std::mem::size_of::<usize>() * 4 }
+
+// The largest generation that fits in a token. Slot generations wrap around after it, so that a
+// handle always survives the trip through `to_token()` and `from_token()`.
+fn max_generation() -> u32 {
+    let max = (1usize << (token_index_bits() - 1)) - 1;
+    if max > u32::max_value() as usize { u32::max_value() } else { max as u32 }
+}
+
+fn next_generation(generation: u32) -> u32 {
+    if generation >= max_generation() { 0 } else { generation + 1 }
+}
+
+impl Handle {
+    /// Packs the handle into a single `usize`, e.g. for a mio token. The token's top bit is
+    /// always clear, so a token never collides with the ones that mio reserves for itself.
+    pub fn to_token(&self) -> usize {
+        let bits = token_index_bits();
+        assert!(self.val < (1 << bits), "too many handles to encode as tokens");
+        debug_assert!(self.generation <= max_generation());
+        ((self.generation as usize) << bits) | self.val
+    }
 
-// Reverse ordering.
-impl ::std::cmp::Ord for Handle {
-    fn cmp(&self, other : &Handle) -> ::std::cmp::Ordering {
-        if self.val > other.val { ::std::cmp::Ordering::Less }
-        else if self.val < other.val { ::std::cmp::Ordering::Greater }
-        else { ::std::cmp::Ordering::Equal }
+    /// The inverse of `to_token()`.
+    pub fn from_token(token: usize) -> Handle {
+        let bits = token_index_bits();
+        Handle { val: token & ((1 << bits) - 1), generation: (token >> bits) as u32 }
     }
 }
 
-impl ::std::cmp::PartialOrd for Handle {
-    fn partial_cmp(&self, other : &Handle) -> Option<::std::cmp::Ordering> {
+// A free slot index. Reverse ordering, so that the heap hands out low indices first.
+#[derive(PartialEq, Eq)]
+struct FreeId(usize);
+
+impl ::std::cmp::Ord for FreeId {
+    fn cmp(&self, other : &FreeId) -> ::std::cmp::Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+impl ::std::cmp::PartialOrd for FreeId {
+    fn partial_cmp(&self, other : &FreeId) -> Option<::std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
+struct Slot<T> {
+    // Advanced by `next_generation()` each time the slot's entry is removed.
+    generation : u32,
+    value : Option<T>,
+}
+
 pub struct HandleTable<T> {
-    slots : Vec<Option<T>>,
+    slots : Vec<Slot<T>>,
 
     // prioritize lower values
-    free_ids : BinaryHeap<Handle>,
+    free_ids : BinaryHeap<FreeId>,
 }
 
 impl <T> HandleTable<T> {
@@ -53,24 +95,115 @@ impl <T> HandleTable<T> {
                       free_ids : BinaryHeap::new() }
     }
 
+    /// Removes the entry for `handle`. Returns `None` if it was already removed, even if its slot
+    /// has since been reused.
     pub fn remove(&mut self, handle: Handle) -> Option<T> {
-        let result = ::std::mem::replace(&mut self.slots[handle.val], None);
+        let slot = &mut self.slots[handle.val];
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let result = ::std::mem::replace(&mut slot.value, None);
         if !result.is_none() {
-            self.free_ids.push(handle);
+            slot.generation = next_generation(slot.generation);
+            self.free_ids.push(FreeId(handle.val));
         }
         return result;
     }
 
+    /// Returns the entry for `handle`, or `None` if it has been removed.
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        match self.slots.get(handle.val) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the entry for `handle`, or `None` if it has been removed.
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        match self.slots.get_mut(handle.val) {
+            Some(slot) => {
+                if slot.generation == handle.generation { slot.value.as_mut() } else { None }
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the number of occupied slots.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free_ids.len()
+    }
+
+    /// Makes room for at least `additional` more entries than there are now.
+    pub fn reserve(&mut self, additional: usize) {
+        self.slots.reserve(additional);
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { slots: self.slots.iter() }
+    }
+
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, T> {
+        IterMut { slots: self.slots.iter_mut() }
+    }
+
     pub fn push(&mut self, val : T) -> Handle {
         match self.free_ids.pop() {
-            Some(Handle { val: id }) => {
-                assert!(self.slots[id as usize].is_none());
-                self.slots[id as usize] = Some(val);
-                Handle { val: id }
+            Some(FreeId(id)) => {
+                let slot = &mut self.slots[id];
+                assert!(slot.value.is_none());
+                slot.value = Some(val);
+                Handle { val: id, generation: slot.generation }
             }
             None => {
-                self.slots.push(Some(val));
-                Handle { val: self.slots.len() - 1 }
+                self.slots.push(Slot { generation: 0, value: Some(val) });
+                Handle { val: self.slots.len() - 1, generation: 0 }
+            }
+        }
+    }
+
+    /// Panics with a message that says what is wrong with `handle`.
+    fn invalid(&self, handle: Handle) -> ! {
+        match self.slots.get(handle.val) {
+            None => panic!("invalid handle idx: {}", handle.val),
+            Some(slot) if slot.generation != handle.generation =>
+                panic!("stale handle idx: {}; its entry was removed (handle generation {}, \
+                        slot generation {})", handle.val, handle.generation, slot.generation),
+            Some(_) => panic!("invalid handle idx: {}", handle.val),
+        }
+    }
+}
+
+pub struct Iter<'a, T> where T: 'a {
+    slots: ::std::slice::Iter<'a, Slot<T>>,
+}
+
+impl <'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            match self.slots.next() {
+                None => return None,
+                Some(&Slot { value: Some(ref v), .. }) => return Some(v),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+pub struct IterMut<'a, T> where T: 'a {
+    slots: ::std::slice::IterMut<'a, Slot<T>>,
+}
+
+impl <'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        loop {
+            match self.slots.next() {
+                None => return None,
+                Some(&mut Slot { value: Some(ref mut v), .. }) => return Some(v),
+                Some(_) => {}
             }
         }
     }
@@ -80,18 +213,62 @@ impl<T> Index<Handle> for HandleTable<T> {
     type Output = T;
 
     fn index<'a>(&'a self, idx: Handle) -> &'a T {
-        match &self.slots[idx.val] {
-            &Some(ref v) => return v,
-            &None => panic!("invalid handle idx: {}", idx.val),
+        match self.get(idx) {
+            Some(v) => return v,
+            None => self.invalid(idx),
         }
     }
 }
 
 impl<T> IndexMut<Handle> for HandleTable<T> {
     fn index_mut<'a>(&'a mut self, idx: Handle) -> &'a mut T {
-        match &mut self.slots[idx.val] {
-            &mut Some(ref mut v) => return v,
-            &mut None => panic!("invalid handle idx: {}", idx.val),
+        if self.get(idx).is_none() {
+            self.invalid(idx);
         }
+        return self.get_mut(idx).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handle, HandleTable, max_generation, next_generation};
+
+    #[test]
+    fn token_round_trip() {
+        let handles = [Handle { val: 0, generation: 0 },
+                       Handle { val: 12345, generation: 7 },
+                       Handle { val: 3, generation: max_generation() }];
+        for &handle in handles.iter() {
+            assert_eq!(Handle::from_token(handle.to_token()), handle);
+        }
+    }
+
+    #[test]
+    fn generation_wraps_at_token_width() {
+        assert_eq!(next_generation(max_generation() - 1), max_generation());
+        assert_eq!(next_generation(max_generation()), 0);
+    }
+
+    #[test]
+    fn reused_slot_gets_new_generation() {
+        let mut table = HandleTable::new();
+        let first = table.push("first");
+        assert_eq!(table.remove(first), Some("first"));
+        let second = table.push("second");
+        assert_eq!(second.val, first.val);
+        assert!(second.generation != first.generation);
+        assert_eq!(table.get(first), None);
+        assert_eq!(table.remove(first), None);
+        assert_eq!(table[second], "second");
+    }
+
+    #[test]
+    #[should_panic(expected = "stale handle")]
+    fn stale_handle_panics() {
+        let mut table = HandleTable::new();
+        let first = table.push(1);
+        table.remove(first);
+        table.push(2);
+        let _ = table[first];
     }
 }
@@ -0,0 +1,299 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Buffering for async streams.
+
+use std::ops::{Deref, DerefMut};
+use io::{AsyncRead, AsyncWrite, Tail};
+use stream::{spawn_stream, PromiseStream, StreamSender};
+use Promise;
+
+/// The buffer size that `BufferedReader::new()` and `BufferedWriter::new()` use.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Wraps an `AsyncRead` and reads from it in big chunks, so that reading a protocol a few bytes
+/// at a time doesn't cost a system call and a promise per read.
+///
+/// Like the streams it wraps, a `BufferedReader` is passed by value through each operation.
+/// If the inner stream is also an `AsyncWrite`, so is the reader, which lets one value carry a
+/// connection through a request and its response.
+pub struct BufferedReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+
+    // The bytes of `buf` in `pos..end` have been read from `inner` but not yet consumed.
+    pos: usize,
+    end: usize,
+}
+
+impl <R> BufferedReader<R> where R: AsyncRead {
+    pub fn new(inner: R) -> BufferedReader<R> {
+        BufferedReader::with_capacity(DEFAULT_BUFFER_SIZE, inner)
+    }
+
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(capacity: usize, inner: R) -> BufferedReader<R> {
+        assert!(capacity > 0, "the buffer capacity must be positive");
+        BufferedReader { inner: inner, buf: vec![0; capacity], pos: 0, end: 0 }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the inner stream. Any data still in the buffer is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the data that has been read but not yet consumed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.end]
+    }
+
+    /// Marks the first `amount` bytes of `buffer()` as used, so that they are not returned again.
+    pub fn consume(&mut self, amount: usize) {
+        assert!(amount <= self.end - self.pos, "consumed more than was buffered");
+        self.pos += amount;
+    }
+
+    /// Resolves once `buffer()` has data in it, reading from the inner stream if it is empty. If
+    /// `buffer()` is still empty afterwards, the inner stream is at EOF.
+    pub fn fill_buf(self) -> Promise<BufferedReader<R>> {
+        if self.pos < self.end {
+            return Promise::fulfilled(self);
+        }
+        let BufferedReader { inner, buf, .. } = self;
+        return inner.try_read(buf, 1).map(|(inner, buf, n)| {
+            return Ok(BufferedReader { inner: inner, buf: buf, pos: 0, end: n });
+        });
+    }
+
+    /// Reads up to and including the next `delimiter`, appending the bytes to `buf`. At EOF,
+    /// resolves with whatever came before it, so if `buf` doesn't end with `delimiter`, the
+    /// stream has ended.
+    pub fn read_until(self, delimiter: u8, buf: Vec<u8>) -> Promise<(BufferedReader<R>, Vec<u8>)> {
+        return read_until_internal(self, delimiter, buf);
+    }
+
+    /// Reads the next line, without its `\n` or `\r\n`. Resolves to `None` at EOF. The last line
+    /// doesn't need a line ending. Fails if the line isn't UTF-8.
+    pub fn read_line(self) -> Promise<(BufferedReader<R>, Option<String>)> {
+        return self.read_until(b'\n', Vec::new()).map(|(reader, mut line)| {
+            if line.is_empty() {
+                return Ok((reader, None));
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            }
+            match String::from_utf8(line) {
+                Ok(line) => Ok((reader, Some(line))),
+                Err(_) => Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
+                                                             "line is not valid UTF-8"))),
+            }
+        });
+    }
+
+    /// Turns the reader into a stream of its lines, as `read_line()` reads them. Each line is
+    /// only read once the one before it has been taken from the stream.
+    pub fn lines(self) -> PromiseStream<String> {
+        return spawn_stream(move |sender| lines_loop(self, sender));
+    }
+}
+
+fn read_until_internal<R>(mut reader: BufferedReader<R>, delimiter: u8,
+                          mut buf: Vec<u8>) -> Promise<(BufferedReader<R>, Vec<u8>)>
+    where R: AsyncRead
+{
+    let (n, found) = {
+        let available = reader.buffer();
+        let (n, found) = match available.iter().position(|&byte| byte == delimiter) {
+            Some(index) => (index + 1, true),
+            None => (available.len(), false),
+        };
+        buf.extend_from_slice(&available[..n]);
+        (n, found)
+    };
+    reader.consume(n);
+    if found {
+        return Promise::fulfilled((reader, buf));
+    }
+
+    return reader.fill_buf().then(move |reader| {
+        if reader.buffer().is_empty() {
+            // EOF
+            return Ok(Promise::fulfilled((reader, buf)));
+        }
+        return Ok(read_until_internal(reader, delimiter, buf));
+    });
+}
+
+fn lines_loop<R>(reader: BufferedReader<R>, mut sender: StreamSender<String>) -> Promise<()>
+    where R: AsyncRead
+{
+    return reader.read_line().then(move |(reader, line)| {
+        match line {
+            Some(line) => {
+                sender.send(line);
+                return Ok(sender.when_drained().then(move |()| Ok(lines_loop(reader, sender))));
+            }
+            None => return Ok(Promise::fulfilled(())),
+        }
+    });
+}
+
+fn read_internal<R, T>(mut reader: BufferedReader<R>, mut buf: T, mut already_read: usize,
+                       min_bytes: usize) -> Promise<(BufferedReader<R>, T, usize)>
+    where R: AsyncRead, T: DerefMut<Target=[u8]>
+{
+    let n = ::std::cmp::min(reader.end - reader.pos, buf.len() - already_read);
+    buf[already_read..already_read + n].copy_from_slice(&reader.buf[reader.pos..reader.pos + n]);
+    reader.pos += n;
+    already_read += n;
+    if already_read >= min_bytes || already_read == buf.len() {
+        return Promise::fulfilled((reader, buf, already_read));
+    }
+
+    if buf.len() - already_read >= reader.buf.len() {
+        // The buffer is empty, and the rest of the read is bigger than it, so copying through
+        // it would gain nothing.
+        let BufferedReader { inner, buf: own_buf, .. } = reader;
+        let tail = Tail { buf: buf, start: already_read };
+        return inner.try_read(tail, min_bytes - already_read).map(move |(inner, tail, n)| {
+            let reader = BufferedReader { inner: inner, buf: own_buf, pos: 0, end: 0 };
+            return Ok((reader, tail.buf, already_read + n));
+        });
+    }
+
+    return reader.fill_buf().then(move |reader| {
+        if reader.pos == reader.end {
+            // EOF
+            return Ok(Promise::fulfilled((reader, buf, already_read)));
+        }
+        return Ok(read_internal(reader, buf, already_read, min_bytes));
+    });
+}
+
+impl <R> AsyncRead for BufferedReader<R> where R: AsyncRead {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return read_internal(self, buf, 0, min_bytes);
+    }
+}
+
+impl <R> AsyncWrite for BufferedReader<R> where R: AsyncRead + AsyncWrite {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        let BufferedReader { inner, buf: own_buf, pos, end } = self;
+        return inner.write(buf).map(move |(inner, buf)| {
+            return Ok((BufferedReader { inner: inner, buf: own_buf, pos: pos, end: end }, buf));
+        });
+    }
+}
+
+/// Wraps an `AsyncWrite` and gathers small writes into a buffer, which is written out when it
+/// fills up or when `flush()` is called. A write resolves as soon as its bytes are in the
+/// buffer, so a protocol that sends many small frames makes one system call per bufferful
+/// instead of one per frame.
+///
+/// Data that is still buffered when the writer is dropped is lost; call `flush()` or
+/// `into_inner()` first.
+pub struct BufferedWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl <W> BufferedWriter<W> where W: AsyncWrite {
+    pub fn new(inner: W) -> BufferedWriter<W> {
+        BufferedWriter::with_capacity(DEFAULT_BUFFER_SIZE, inner)
+    }
+
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(capacity: usize, inner: W) -> BufferedWriter<W> {
+        assert!(capacity > 0, "the buffer capacity must be positive");
+        BufferedWriter { inner: inner, buf: Vec::with_capacity(capacity), capacity: capacity }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the data that has been written but not yet flushed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Writes out everything in the buffer.
+    pub fn flush(self) -> Promise<BufferedWriter<W>> {
+        if self.buf.is_empty() {
+            return Promise::fulfilled(self);
+        }
+        let BufferedWriter { inner, buf, capacity } = self;
+        return inner.write(buf).map(move |(inner, mut buf)| {
+            buf.clear();
+            return Ok(BufferedWriter { inner: inner, buf: buf, capacity: capacity });
+        });
+    }
+
+    /// Flushes the buffer and then returns the inner stream.
+    pub fn into_inner(self) -> Promise<W> {
+        return self.flush().map(|writer| Ok(writer.inner));
+    }
+}
+
+impl <W> AsyncWrite for BufferedWriter<W> where W: AsyncWrite {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        if self.buf.len() + buf.len() > self.capacity && !self.buf.is_empty() {
+            return self.flush().then(move |writer| Ok(writer.write(buf)));
+        }
+        if buf.len() >= self.capacity {
+            // Too big to be worth copying. The buffer is empty, so the order is kept.
+            let BufferedWriter { inner, buf: own_buf, capacity } = self;
+            return inner.write(buf).map(move |(inner, buf)| {
+                return Ok((BufferedWriter { inner: inner, buf: own_buf, capacity: capacity }, buf));
+            });
+        }
+        let mut writer = self;
+        writer.buf.extend_from_slice(&buf);
+        return Promise::fulfilled((writer, buf));
+    }
+}
+
+impl <W> AsyncRead for BufferedWriter<W> where W: AsyncRead + AsyncWrite {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        let BufferedWriter { inner, buf: own_buf, capacity } = self;
+        return inner.try_read(buf, min_bytes).map(move |(inner, buf, n)| {
+            return Ok((BufferedWriter { inner: inner, buf: own_buf, capacity: capacity }, buf, n));
+        });
+    }
+}
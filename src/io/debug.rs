@@ -0,0 +1,296 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A debug server that lets an operator inspect a running event loop.
+//!
+//! `serve()` listens on a Unix socket and answers line-based text commands, on the same thread
+//! and event loop as the rest of the program. Try it with `socat - UNIX-CONNECT:<path>`.
+//!
+//! Commands:
+//!
+//! * `stats`: counts of queued events, live fulfillers, fd observers, and task sets.
+//! * `tasks`: the outcome counts of each live `TaskSet`.
+//! * `traces`: live and created promise nodes of each kind. Needs the `node-counters` feature.
+//! * `log [level]`: shows or sets the level that gj logs at. See `EventLoop::set_log_level()`.
+//!   Needs the `log` feature.
+//! * `help`: lists the commands.
+//! * `quit`: closes the connection.
+//!
+//! Lines longer than 4096 bytes make the server close the connection.
+
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use handle_table::Handle;
+use io::{AsyncRead, AsyncWrite, HasHandle, Timer, register_handle, register_new_handle,
+         try_read_internal, write_internal};
+use private::with_current_event_loop;
+use {Error, ErrorHandler, Promise, Result, TaskSet};
+
+/// Starts a debug server listening on the Unix socket at `path`, which must not already exist.
+/// The socket file is removed again when the server stops. The returned promise never resolves
+/// on its own; dropping it stops the server and closes its connections.
+pub fn serve<P>(path: P) -> Result<Promise<()>> where P: AsRef<Path> {
+    let listener = try!(Listener::bind(path.as_ref()));
+    return Ok(accept_loop(listener, TaskSet::new(Box::new(IgnoreErrors))));
+}
+
+struct IgnoreErrors;
+
+impl ErrorHandler for IgnoreErrors {
+    fn task_failed(&mut self, _error: Error) {}
+}
+
+// How long to wait before accepting again after a failure, such as running out of file
+// descriptors. The listener is edge-triggered, so a connection left in the backlog by the failure
+// would not wake it up again.
+const ACCEPT_RETRY_DELAY_MS: u64 = 100;
+
+fn accept_loop(listener: Listener, mut connections: TaskSet) -> Promise<()> {
+    return listener.accept().then(move |(listener, connection)| {
+        match connection {
+            Ok(connection) => {
+                connections.add(handle_connection(connection, Vec::new()));
+                return Ok(accept_loop(listener, connections));
+            }
+            Err(_error) => {
+                return Ok(Timer.after_delay_ms(ACCEPT_RETRY_DELAY_MS).then(move |()| {
+                    return Ok(accept_loop(listener, connections));
+                }));
+            }
+        }
+    });
+}
+
+// Longer lines are refused, so that a client can't make the server buffer without bound.
+const MAX_LINE_LENGTH: usize = 4096;
+
+fn handle_connection(connection: Connection, mut pending: Vec<u8>) -> Promise<()> {
+    return connection.try_read(vec![0u8; 1024], 1).then(move |(connection, buf, n)| {
+        if n == 0 {
+            return Ok(Promise::fulfilled(()));
+        }
+        pending.extend(buf[..n].iter().cloned());
+
+        let mut output = String::new();
+        let mut quit = false;
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..end + 1).collect();
+            let command = String::from_utf8_lossy(&line).trim().to_string();
+            if command == "quit" {
+                quit = true;
+                break;
+            }
+            output.push_str(&respond(&command));
+        }
+        if !quit && pending.len() > MAX_LINE_LENGTH {
+            output.push_str("line too long\n");
+            quit = true;
+        }
+
+        return Ok(connection.write(output.into_bytes()).then(move |(connection, _)| {
+            if quit {
+                return Ok(Promise::fulfilled(()));
+            } else {
+                return Ok(handle_connection(connection, pending));
+            }
+        }));
+    });
+}
+
+fn respond(command: &str) -> String {
+    match command {
+        "" => String::new(),
+        "help" => "commands: stats, tasks, traces, log [off|error|warn|info|debug|trace], help, \
+                   quit\n".to_string(),
+        "stats" => stats(),
+        "tasks" => tasks(),
+        "traces" => traces(),
+        "log" => log_level(None),
+        _ if command.starts_with("log ") => log_level(Some(command[4..].trim())),
+        _ => format!("unknown command: {}\n", command),
+    }
+}
+
+fn stats() -> String {
+    with_current_event_loop(|event_loop| {
+        let mut result = String::new();
+        result.push_str(&format!("queued events: {}\n", event_loop.queued_event_count()));
+        result.push_str(&format!("live fulfillers: {}\n", event_loop.live_fulfillers.get()));
+        result.push_str(&format!("fd observers: {}\n",
+                                 event_loop.event_port.borrow().observer_count()));
+        let task_sets = event_loop.task_sets.borrow().iter()
+            .filter(|task_set| task_set.upgrade().is_some()).count();
+        result.push_str(&format!("task sets: {}\n", task_sets));
+        return result;
+    })
+}
+
+fn tasks() -> String {
+    with_current_event_loop(|event_loop| {
+        let mut result = String::new();
+        for (index, task_set) in event_loop.task_sets.borrow().iter().enumerate() {
+            if let Some(task_set) = task_set.upgrade() {
+                let stats = task_set.borrow().stats();
+                result.push_str(&format!(
+                    "task set {}: outstanding={} added={} fulfilled={} rejected={} cancelled={}\n",
+                    index, stats.outstanding(), stats.added, stats.fulfilled, stats.rejected,
+                    stats.cancelled));
+            }
+        }
+        return result;
+    })
+}
+
+#[cfg(feature = "node-counters")]
+fn traces() -> String {
+    let counts = ::EventLoop::node_counts();
+    let mut result = String::new();
+    for &(kind, count) in &[("transform", counts.transform), ("chain", counts.chain),
+                            ("join", counts.join), ("immediate", counts.immediate)] {
+        result.push_str(&format!("{} nodes: live={} created={}\n", kind, count.live,
+                                 count.created));
+    }
+    return result;
+}
+
+#[cfg(not(feature = "node-counters"))]
+fn traces() -> String {
+    "promise traces need the node-counters feature\n".to_string()
+}
+
+#[cfg(feature = "log")]
+fn log_level(level: Option<&str>) -> String {
+    if let Some(level) = level {
+        match level.parse() {
+            Ok(level) => ::EventLoop::set_log_level(level),
+            Err(_) => return format!("unknown log level: {}\n", level),
+        }
+    }
+    return format!("log level: {}\n", ::EventLoop::log_level());
+}
+
+#[cfg(not(feature = "log"))]
+fn log_level(_level: Option<&str>) -> String {
+    "log levels need the log feature\n".to_string()
+}
+
+struct Listener {
+    listener: ::mio::deprecated::UnixListener,
+    handle: Handle,
+    path: PathBuf,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.listener);
+        });
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+}
+
+impl Listener {
+    fn bind(path: &Path) -> Result<Listener> {
+        let listener = try!(::mio::deprecated::UnixListener::bind(path));
+        let handle = match register_handle(&listener, ::mio::Ready::readable(), "debug::Listener") {
+            Ok(handle) => handle,
+            Err(e) => {
+                let _ = ::std::fs::remove_file(path);
+                return Err(e);
+            }
+        };
+        return Ok(Listener { listener: listener, handle: handle, path: path.to_path_buf() });
+    }
+
+    fn accept_internal(self) -> Promise<(Listener, Result<Connection>)> {
+        let stream = match ::mio::deprecated::TryAccept::accept(&self.listener) {
+            Ok(Some(stream)) => stream,
+            Ok(None) => {
+                return with_current_event_loop(move |event_loop| {
+                    let promise =
+                        event_loop.event_port.borrow_mut().handler.observers[self.handle].when_becomes_readable();
+                    return promise.then(move |()| {
+                        return Ok(self.accept_internal());
+                    });
+                });
+            }
+            Err(e) => return Promise::fulfilled((self, Err(Box::new(e)))),
+        };
+        let connection = register_new_handle(&stream, "debug::Connection").map(|handle| {
+            Connection { stream: stream, handle: handle }
+        });
+        return Promise::fulfilled((self, connection));
+    }
+
+    /// Waits for the next connection. A failure to accept one is handed back along with the
+    /// listener, so that the caller can keep listening.
+    fn accept(self) -> Promise<(Listener, Result<Connection>)> {
+        return Promise::fulfilled(()).then(move |()| { return Ok(self.accept_internal()); });
+    }
+}
+
+struct Connection {
+    stream: ::mio::deprecated::UnixStream,
+    handle: Handle,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.stream);
+        });
+    }
+}
+
+impl ::mio::deprecated::TryRead for Connection {
+    fn try_read(&mut self, buf: &mut [u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_read(buf)
+    }
+}
+
+impl ::mio::deprecated::TryWrite for Connection {
+    fn try_write(&mut self, buf: &[u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_write(buf)
+    }
+}
+
+impl HasHandle for Connection {
+    fn get_handle(&self) -> Handle { self.handle }
+}
+
+impl AsyncRead for Connection {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return Promise::fulfilled(()).then(move |()| {
+            return try_read_internal(self, buf, 0, min_bytes);
+        });
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return Promise::fulfilled(()).then(move |()| {
+            return write_internal(self, buf, 0);
+        });
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! File system operations that don't block the event loop.
+//!
+//! Regular files are always "ready" as far as the reactor is concerned, so reading one in the
+//! usual way would stall the whole loop on the disk. Instead, the functions here run the
+//! blocking calls on the event loop's small pool of worker threads, which it shares with
+//! `NetworkAddress::resolve()`. Dropping a returned promise doesn't stop a call that has already
+//! started.
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use io::worker_pool;
+use Promise;
+
+/// Opens the file at `path` for reading.
+pub fn open<P: AsRef<Path>>(path: P) -> Promise<File> {
+    let path = path.as_ref().to_path_buf();
+    return run_blocking(move || File::open(path));
+}
+
+/// Opens the file at `path` for writing, creating it if needed and truncating it otherwise.
+pub fn create<P: AsRef<Path>>(path: P) -> Promise<File> {
+    let path = path.as_ref().to_path_buf();
+    return run_blocking(move || File::create(path));
+}
+
+/// Opens the file at `path` with `options`.
+pub fn open_with<P: AsRef<Path>>(path: P, options: &OpenOptions) -> Promise<File> {
+    let path = path.as_ref().to_path_buf();
+    let options = options.clone();
+    return run_blocking(move || options.open(path));
+}
+
+/// Reads up to `buf.len()` bytes from `file`, at its current offset. Resolves to the file, the
+/// buffer, and the number of bytes read, which is zero at the end of the file.
+pub fn read(file: File, buf: Vec<u8>) -> Promise<(File, Vec<u8>, usize)> {
+    return run_blocking(move || {
+        let (mut file, mut buf) = (file, buf);
+        let n = try!(file.read(&mut buf));
+        Ok((file, buf, n))
+    });
+}
+
+/// Writes all of `buf` to `file`, at its current offset.
+pub fn write(file: File, buf: Vec<u8>) -> Promise<(File, Vec<u8>)> {
+    return run_blocking(move || {
+        let mut file = file;
+        try!(file.write_all(&buf));
+        Ok((file, buf))
+    });
+}
+
+/// Returns the metadata of the file at `path`, following symbolic links.
+pub fn metadata<P: AsRef<Path>>(path: P) -> Promise<Metadata> {
+    let path = path.as_ref().to_path_buf();
+    return run_blocking(move || ::std::fs::metadata(path));
+}
+
+/// Reads the whole file at `path`.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Promise<Vec<u8>> {
+    let path = path.as_ref().to_path_buf();
+    return run_blocking(move || {
+        let mut contents = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut contents));
+        Ok(contents)
+    });
+}
+
+/// Replaces the contents of the file at `path` with `contents`, creating it if needed.
+pub fn write_file<P: AsRef<Path>>(path: P, contents: Vec<u8>) -> Promise<()> {
+    let path = path.as_ref().to_path_buf();
+    return run_blocking(move || try!(File::create(path)).write_all(&contents));
+}
+
+/// Runs `func` on the current event loop's worker pool, for blocking calls that the functions
+/// in this module don't cover. Panics if the current thread has no event loop.
+pub fn run_blocking<F, R>(func: F) -> Promise<R>
+    where F: FnOnce() -> ::std::io::Result<R> + Send + 'static, R: Send + 'static
+{
+    return worker_pool::run(func);
+}
@@ -0,0 +1,2028 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Asynchronous input and output.
+
+use std::ops::{DerefMut, Deref};
+use std::os::unix::io::AsRawFd;
+use handle_table::{HandleTable, Handle};
+use {EventLoop, EventPort, Promise, PromiseFulfiller, Result, TaskSet, WaitScope,
+     new_promise_and_fulfiller, current_deadline};
+use private::{with_current_event_loop};
+use stream::{spawn_stream, PromiseStream, StreamSender};
+
+mod buffered;
+#[cfg(feature = "debug-server")] pub mod debug;
+pub mod fs;
+mod pipe;
+pub mod post_mortem;
+mod precise_timer;
+mod process;
+mod proxy;
+mod pump;
+pub mod router;
+pub mod rpc;
+mod sockopt;
+mod throttled;
+mod timer_wheel;
+#[cfg(feature = "tls")] pub mod tls;
+mod transfer;
+mod tty;
+mod udp;
+mod unix;
+mod vectored;
+mod watcher;
+pub mod wire;
+mod worker_pool;
+mod write_queue;
+
+pub use self::buffered::{BufferedReader, BufferedWriter, DEFAULT_BUFFER_SIZE};
+pub use self::pipe::{pipe, stdin, stdout, stderr, PipeReader, PipeWriter};
+pub use self::process::{Child, Command};
+pub use self::proxy::{ProxyConnector, ProxyKind};
+pub use self::pump::{pump, pump_limited};
+pub use self::sockopt::{Keepalive, SocketOptions};
+pub use self::throttled::Throttled;
+pub use self::transfer::transfer;
+pub use self::tty::{Key, Tty};
+pub use self::udp::UdpSocket;
+pub use self::unix::{UnixAddress, UnixConnectionReceiver, UnixDatagram, UnixStream, MAX_RECEIVED_FDS};
+pub use self::watcher::{Change, ChangeKind, Watcher};
+pub use self::write_queue::WriteQueue;
+
+
+/// A nonblocking input bytestream.
+pub trait AsyncRead: Sized + 'static {
+
+    /// Attempts to read `buf.len()` bytes from the stream, writing them into `buf`.
+    /// Returns `self`, the modified `buf`, and the number of bytes actually read.
+    /// Returns as soon as `min_bytes` are read or EOF is encountered.
+    fn try_read<T>(self, buf: T, min_bytes: usize) -> Promise<(Self, T, usize)>
+        where T: DerefMut<Target=[u8]>;
+
+    /// Like `try_read()`, but returns an error if EOF is encountered before `min_bytes`
+    /// can be read.
+    fn read<T>(self, buf: T, min_bytes: usize) -> Promise<(Self, T, usize)>
+        where T: DerefMut<Target=[u8]>, Self: Sized
+    {
+        return self.try_read(buf, min_bytes).map(move |(s, buf, n)| {
+            if n < min_bytes {
+                return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "Premature EOF")))
+            } else {
+                return Ok((s, buf, n));
+            }
+        });
+    }
+
+    /// Fills all of `buf`, or returns an error if EOF comes first.
+    fn read_exact<T>(self, buf: T) -> Promise<(Self, T)>
+        where T: DerefMut<Target=[u8]>, Self: Sized
+    {
+        let len = buf.len();
+        return self.read(buf, len).map(|(s, buf, _)| Ok((s, buf)));
+    }
+
+    /// Reads until EOF, appending everything to `buf`, which grows as needed.
+    fn read_to_end(self, buf: Vec<u8>) -> Promise<(Self, Vec<u8>)> where Self: Sized {
+        return read_to_end_internal(self, buf);
+    }
+
+    /// Like `try_read()`, but fills each of `bufs` in turn, as if they were one buffer. Returns
+    /// the total number of bytes read. Streams backed by a file descriptor do this with a single
+    /// `readv()` where they can.
+    fn try_read_vectored<T>(self, bufs: Vec<T>, min_bytes: usize) -> Promise<(Self, Vec<T>, usize)>
+        where T: DerefMut<Target=[u8]>, Self: Sized
+    {
+        return read_each(self, bufs.into_iter(), Vec::new(), 0, min_bytes);
+    }
+}
+
+/// The least room that `read_to_end()` makes at the end of its buffer before each read.
+const READ_TO_END_MIN_GROWTH: usize = 4096;
+
+fn read_to_end_internal<R>(reader: R, mut buf: Vec<u8>) -> Promise<(R, Vec<u8>)>
+    where R: AsyncRead
+{
+    let len = buf.len();
+    // The buffer doubles each time it fills up, so that each byte is zeroed and moved only a
+    // constant number of times on average. Only a short read, meaning EOF, ends the loop.
+    let growth = ::std::cmp::max(len, READ_TO_END_MIN_GROWTH);
+    buf.resize(len + growth, 0);
+    return reader.try_read(Tail { buf: buf, start: len }, growth).then(move |(reader, tail, n)| {
+        let mut buf = tail.buf;
+        buf.truncate(len + n);
+        if n < growth {
+            return Ok(Promise::fulfilled((reader, buf)));
+        }
+        return Ok(read_to_end_internal(reader, buf));
+    });
+}
+
+fn read_each<R, T>(reader: R, mut rest: ::std::vec::IntoIter<T>, mut done: Vec<T>,
+                   already_read: usize, min_bytes: usize) -> Promise<(R, Vec<T>, usize)>
+    where R: AsyncRead, T: DerefMut<Target=[u8]>
+{
+    if already_read >= min_bytes {
+        done.extend(rest);
+        return Promise::fulfilled((reader, done, already_read));
+    }
+    let buf = match rest.next() {
+        Some(buf) => buf,
+        None => return Promise::fulfilled((reader, done, already_read)),
+    };
+    let wanted = ::std::cmp::min(buf.len(), min_bytes - already_read);
+    return reader.try_read(buf, wanted).then(move |(reader, buf, n)| {
+        done.push(buf);
+        if n < wanted {
+            // EOF
+            done.extend(rest);
+            return Ok(Promise::fulfilled((reader, done, already_read + n)));
+        }
+        return Ok(read_each(reader, rest, done, already_read + n, min_bytes));
+    });
+}
+
+/// A nonblocking output bytestream.
+pub trait AsyncWrite: Sized + 'static {
+    /// Attempts to write all `buf.len()` bytes from `buf` into the stream. Returns `self` and `buf`
+    /// once all of the bytes have been written.
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]>;
+
+    /// Writes all of `bufs`, in order, as if they were one buffer, so that a header and a body
+    /// don't need to be copied together first. Streams backed by a file descriptor do this with
+    /// `writev()`.
+    fn write_vectored<T>(self, bufs: Vec<T>) -> Promise<(Self, Vec<T>)>
+        where T: Deref<Target=[u8]>, Self: Sized
+    {
+        return write_each(self, bufs.into_iter(), Vec::new());
+    }
+}
+
+fn write_each<W, T>(writer: W, mut rest: ::std::vec::IntoIter<T>,
+                    mut done: Vec<T>) -> Promise<(W, Vec<T>)>
+    where W: AsyncWrite, T: Deref<Target=[u8]>
+{
+    match rest.next() {
+        None => return Promise::fulfilled((writer, done)),
+        Some(buf) => {
+            return writer.write(buf).then(move |(writer, buf)| {
+                done.push(buf);
+                return Ok(write_each(writer, rest, done));
+            });
+        }
+    }
+}
+
+pub struct Slice<T> where T: Deref<Target=[u8]> {
+    pub buf: T,
+    pub end: usize,
+}
+
+impl <T> Slice<T> where T: Deref<Target=[u8]> {
+    pub fn new(buf: T, end: usize) -> Slice<T> {
+        Slice { buf: buf, end: end }
+    }
+}
+
+impl <T> Deref for Slice<T> where T: Deref<Target=[u8]> {
+    type Target=[u8];
+    fn deref<'a>(&'a self) -> &'a [u8] {
+        &self.buf[0..self.end]
+    }
+}
+
+impl <T> DerefMut for Slice<T> where T: DerefMut<Target=[u8]> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[0..self.end]
+    }
+}
+
+/// The part of `buf` from `start` on, for reading into the rest of a partly filled buffer.
+struct Tail<T> {
+    buf: T,
+    start: usize,
+}
+
+impl <T> Deref for Tail<T> where T: Deref<Target=[u8]> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+}
+
+impl <T> DerefMut for Tail<T> where T: DerefMut<Target=[u8]> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..]
+    }
+}
+
+/// Takes ownership of `fd`, which can then be registered with the reactor.
+fn io_from_raw_fd(fd: ::std::os::unix::io::RawFd) -> ::mio::deprecated::unix::Io {
+    unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd) }
+}
+
+fn register_new_handle<E>(evented: &E, kind: &'static str) -> Result<Handle>
+    where E: ::mio::event::Evented + ::std::os::unix::io::AsRawFd
+{
+    let interest = ::mio::Ready::writable() | ::mio::Ready::readable();
+    return register_handle(evented, interest, kind);
+}
+
+/// Registers `evented` with the reactor under a new fd observer.
+fn register_handle<E>(evented: &E, interest: ::mio::Ready, kind: &'static str) -> Result<Handle>
+    where E: ::mio::event::Evented + ::std::os::unix::io::AsRawFd
+{
+    let handle = FdObserver::new(interest, evented.as_raw_fd(), kind);
+    let token = ::mio::Token(handle.to_token());
+    return with_current_event_loop(move |event_loop| -> Result<Handle> {
+        let event_port = &mut *event_loop.event_port.borrow_mut();
+        if let Err(e) = event_port.reactor.register(evented, token, interest, ::mio::PollOpt::edge()) {
+            event_port.handler.observers.remove(handle);
+            return Err(Box::new(e));
+        }
+        return Ok(handle);
+    });
+}
+
+/// Reregisters `evented` with the reactor under a new interest set, and records the new interest
+/// in the observer for `handle`.
+fn set_interest_internal<E>(evented: &E, handle: Handle, interest: ::mio::Ready) -> Result<()>
+    where E: ::mio::event::Evented
+{
+    return with_current_event_loop(move |event_loop| {
+        let event_port = &mut *event_loop.event_port.borrow_mut();
+        try!(event_port.reactor.reregister(evented, ::mio::Token(handle.to_token()), interest,
+                                           ::mio::PollOpt::edge()));
+        event_port.handler.observers[handle].interest = interest;
+        return Ok(());
+    });
+}
+
+fn get_interest_internal(handle: Handle) -> ::mio::Ready {
+    return with_current_event_loop(move |event_loop| {
+        return event_loop.event_port.borrow().handler.observers[handle].interest;
+    });
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkAddress {
+    // All of the addresses that the name stood for, in the order the resolver returned them.
+    // Empty only if `host` is set.
+    addresses: Vec<::std::net::SocketAddr>,
+
+    // The name that `connect()` looks up before connecting. See `from_host()`.
+    host: Option<String>,
+
+    // How long it took to resolve `addresses`, if they had to be resolved.
+    resolution: Option<::std::time::Duration>,
+
+    // Whether a listener on an IPv6 address should refuse IPv4 connections. `None` leaves it up
+    // to the system.
+    v6_only: Option<bool>,
+
+    // Set on each socket that `listen()` or `connect()` creates.
+    options: SocketOptions,
+}
+
+/// How long it took to establish a connection. See `TcpStream::connect_timings()`.
+#[derive(Copy, Clone, Debug)]
+pub struct ConnectTimings {
+    /// The time spent resolving the address that was connected to, when it was created by
+    /// `NetworkAddress::new()` or `NetworkAddress::resolve_all()`.
+    pub resolution: Option<::std::time::Duration>,
+
+    /// The time from the start of the connection attempt until the socket was connected.
+    pub handshake: ::std::time::Duration,
+}
+
+impl NetworkAddress {
+    /// Resolves `address` and keeps all of the addresses it stands for, which `socket_addrs()`
+    /// returns. `connect()` falls back through them in order, as `connect_any()` does, and
+    /// `listen()` binds the first. Fails if `address` resolves to nothing.
+    ///
+    /// Resolving a host name blocks the calling thread until the resolver answers, which can take
+    /// seconds. From a running event loop, use `resolve()` or `connect_host()` instead.
+    pub fn new<T : ::std::net::ToSocketAddrs>(address: T) -> Result<NetworkAddress> {
+        let start = ::std::time::Instant::now();
+        let addrs: Vec<::std::net::SocketAddr> = try!(address.to_socket_addrs()).collect();
+        if addrs.is_empty() {
+            return Err(no_addresses_error());
+        }
+        return Ok(NetworkAddress { addresses: addrs, host: None, resolution: Some(start.elapsed()),
+                                   v6_only: None, options: SocketOptions::new() });
+    }
+
+    /// Makes an address for `address`, such as `"example.com:80"`, without looking it up.
+    /// Instead, each `connect()` looks it up with `resolve()`, on the event loop's worker pool,
+    /// and then tries the addresses it stands for as `connect_any()` does. This is the way to
+    /// keep a name in a configuration without blocking anything on the lookup. An address made
+    /// this way can't `listen()`, and isn't one of the resolved addresses that `connect_any()`
+    /// takes.
+    pub fn from_host(address: &str) -> NetworkAddress {
+        NetworkAddress { addresses: Vec::new(), host: Some(address.to_string()), resolution: None,
+                         v6_only: None, options: SocketOptions::new() }
+    }
+
+    /// Resolves `address` to all of the addresses that it stands for, in the order the resolver
+    /// returned them. Each of them records the time the resolution took, which is reported in the
+    /// `ConnectTimings` of the streams connected to it.
+    pub fn resolve_all<T : ::std::net::ToSocketAddrs>(address: T) -> Result<Vec<NetworkAddress>> {
+        let start = ::std::time::Instant::now();
+        let addrs: Vec<::std::net::SocketAddr> = try!(address.to_socket_addrs()).collect();
+        let resolution = start.elapsed();
+        return Ok(addrs.into_iter().map(|addr| {
+            NetworkAddress { addresses: vec![addr], host: None, resolution: Some(resolution),
+                             v6_only: None, options: SocketOptions::new() }
+        }).collect());
+    }
+
+    /// Like `resolve_all()`, but does the lookup on the event loop's pool of worker threads, so
+    /// that a slow name server holds up only the returned promise and not the whole event loop.
+    /// The pool is shared with `fs` and has a fixed number of threads, so a burst of lookups
+    /// waits its turn rather than starting a thread apiece.
+    pub fn resolve<T>(address: T) -> Promise<Vec<NetworkAddress>>
+        where T: ::std::net::ToSocketAddrs + Send + 'static
+    {
+        return worker_pool::run(move || {
+            let start = ::std::time::Instant::now();
+            let addrs: Vec<::std::net::SocketAddr> = try!(address.to_socket_addrs()).collect();
+            Ok((addrs, start.elapsed()))
+        }).map(|(addrs, resolution)| {
+            return Ok(addrs.into_iter().map(|addr| {
+                NetworkAddress { addresses: vec![addr], host: None, resolution: Some(resolution),
+                             v6_only: None, options: SocketOptions::new() }
+            }).collect());
+        });
+    }
+
+    /// Connects to `address`, which may be a host name such as `"example.com:80"`. The name is
+    /// looked up with `resolve()`, off the event loop thread, and each of the addresses it stands
+    /// for is tried with `connect_any()`. This is the way to connect by name from a running loop;
+    /// `new()` would block the loop during the lookup.
+    pub fn connect_host<T>(address: T) -> Promise<TcpStream>
+        where T: ::std::net::ToSocketAddrs + Send + 'static
+    {
+        return NetworkAddress::resolve(address).then(|addrs| Ok(NetworkAddress::connect_any(addrs)));
+    }
+
+    /// Connects to the first of `addresses` that accepts, trying them in order. Each attempt gets
+    /// a head start of `CONNECT_ANY_STAGGER_MS` before the next one begins alongside it, as in
+    /// Happy Eyeballs (RFC 8305), and a failed attempt starts the next one right away. Once one
+    /// attempt succeeds, the others are cancelled. Fails if every attempt fails.
+    pub fn connect_any(addresses: Vec<NetworkAddress>) -> Promise<TcpStream> {
+        NetworkAddress::connect_any_staggered(
+            addresses, ::std::time::Duration::from_millis(CONNECT_ANY_STAGGER_MS))
+    }
+
+    /// Like `connect_any()`, with the given head start for each attempt.
+    pub fn connect_any_staggered(addresses: Vec<NetworkAddress>,
+                                 stagger: ::std::time::Duration) -> Promise<TcpStream> {
+        // An address that stands for several is tried as that many.
+        let singles: Vec<NetworkAddress> = addresses.iter().flat_map(|address| address.singles()).collect();
+        if singles.is_empty() {
+            return Promise::rejected(no_addresses_error());
+        }
+        return connect_staggered(singles.into_iter(), stagger);
+    }
+
+    /// Splits this address into one for each of the addresses that it stands for, with the same
+    /// options.
+    fn singles(&self) -> Vec<NetworkAddress> {
+        return self.addresses.iter().map(|&addr| {
+            NetworkAddress { addresses: vec![addr], host: None, resolution: self.resolution,
+                             v6_only: self.v6_only, options: self.options }
+        }).collect();
+    }
+
+    /// How long it took to resolve this address.
+    pub fn resolution_time(&self) -> Option<::std::time::Duration> {
+        self.resolution
+    }
+
+    /// The first of the addresses, which is the one that `listen()` binds to and the first that
+    /// `connect()` tries. Panics if the address was made by `from_host()`.
+    pub fn socket_addr(&self) -> ::std::net::SocketAddr {
+        match self.addresses.first() {
+            Some(&addr) => addr,
+            None => panic!("{} has not been resolved", self),
+        }
+    }
+
+    /// All of the addresses that this one stands for, in the order that `connect()` tries them.
+    /// Empty if the address was made by `from_host()`.
+    pub fn socket_addrs(&self) -> &[::std::net::SocketAddr] {
+        &self.addresses
+    }
+
+    /// Sets whether `listen()` on an IPv6 address accepts only IPv6 connections (`true`), or
+    /// IPv4 connections too, which show up as IPv4-mapped addresses (`false`). Without this, the
+    /// system default applies, which on Linux is `net.ipv6.bindv6only`. Has no effect on IPv4
+    /// addresses or on `connect()`.
+    pub fn with_v6_only(mut self, v6_only: bool) -> NetworkAddress {
+        self.v6_only = Some(v6_only);
+        self
+    }
+
+    /// Sets the options to give the sockets that `connect()` creates, and the listening socket
+    /// that `listen()` creates along with each connection that it accepts.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> NetworkAddress {
+        self.options = options;
+        self
+    }
+
+    /// Listens on the first of the addresses. Fails if the address was made by `from_host()`.
+    pub fn listen(&self) -> Result<ConnectionReceiver> {
+        if self.addresses.is_empty() {
+            return Err(Box::new(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                format!("cannot listen on {}, which has not been resolved", self))));
+        }
+        let address = self.socket_addr();
+        let socket = try!(new_tcp_socket(&address));
+        try!(socket.reuse_address(true));
+        if let (::std::net::SocketAddr::V6(_), Some(v6_only)) = (address, self.v6_only) {
+            try!(sockopt::set_v6_only(socket.as_raw_fd(), v6_only));
+        }
+        // Buffer sizes need to be set before `listen()` to apply to the window that is offered
+        // during the handshake.
+        try!(self.options.apply(socket.as_raw_fd()));
+        try!(socket.bind(&address));
+        let listener = try!(::mio::tcp::TcpListener::from_std(try!(socket.listen(256))));
+        let handle = try!(register_handle(&listener, ::mio::Ready::readable(),
+                                          "ConnectionReceiver"));
+        return Ok(ConnectionReceiver { listener: listener,
+                                       handle: handle,
+                                       options: self.options });
+    }
+
+    /// Like `connect()`, but fails with an error of kind `TimedOut` if the connection isn't
+    /// established within `timeout`.
+    pub fn connect_with_timeout(&self, timeout: ::std::time::Duration) -> Promise<TcpStream> {
+        let address = self.to_string();
+        return self.connect().exclusive_join(Timer.after_delay(timeout).map(move |()| {
+            return Err(Box::new(::std::io::Error::new(
+                ::std::io::ErrorKind::TimedOut,
+                format!("connecting to {} timed out", address))));
+        }));
+    }
+
+    /// Connects to this address. If it stands for several, they are tried in order, as by
+    /// `connect_any()`. If it was made by `from_host()`, the name is looked up first, off the
+    /// event loop thread.
+    pub fn connect(&self) -> Promise<TcpStream> {
+        if let (true, Some(host)) = (self.addresses.is_empty(), self.host.clone()) {
+            let options = self.options;
+            return under_deadline(NetworkAddress::resolve(host).then(move |addrs| {
+                let addrs = addrs.into_iter().map(|addr| addr.with_socket_options(options)).collect();
+                return Ok(NetworkAddress::connect_any(addrs));
+            }));
+        }
+        if self.addresses.len() > 1 {
+            return NetworkAddress::connect_any(vec![self.clone()]);
+        }
+        let address = self.socket_addr();
+        let resolution = self.resolution;
+        let options = self.options;
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            let start = ::std::time::Instant::now();
+            let timings = move || {
+                Some(ConnectTimings { resolution: resolution, handshake: start.elapsed() })
+            };
+            let socket = try!(new_tcp_socket(&address));
+            try!(options.apply(socket.as_raw_fd()));
+            let stream = match socket.to_tcp_stream().and_then(|stream| {
+                ::mio::tcp::TcpStream::connect_stream(stream, &address)
+            }) {
+                Ok(stream) => stream,
+                Err(error) => return Err(connect_error(address, error)),
+            };
+
+            // TODO: maybe only register writable interest until the connection is established,
+            // and then reregister with read/write interest.
+
+            let handle = try!(register_new_handle(&stream, "TcpStream"));
+
+            // The connect is nonblocking, so even a loopback connection may still be in progress.
+            return with_current_event_loop(move |event_loop| {
+                let promise =
+                    event_loop.event_port.borrow_mut().handler.observers[handle].when_becomes_writable();
+
+                return Ok(promise.map(move |()| {
+                    let mut stream = TcpStream::new(stream, handle);
+                    // Writability only means that the attempt is over. Whether it worked
+                    // is in SO_ERROR.
+                    if let Some(error) = try!(sockopt::take_error(stream.as_raw_fd())) {
+                        return Err(connect_error(address, error));
+                    }
+                    stream.timings = timings();
+                    return Ok(stream);
+                }));
+            });
+        }));
+    }
+}
+
+impl ::std::fmt::Display for NetworkAddress {
+    /// Shows the name given to `from_host()`, or else the first of the addresses.
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match (&self.host, self.addresses.first()) {
+            (&Some(ref host), None) => write!(fmt, "{}", host),
+            (_, Some(addr)) => write!(fmt, "{}", addr),
+            (&None, None) => write!(fmt, "(no address)"),
+        }
+    }
+}
+
+/// How many milliseconds each of `NetworkAddress::connect_any()`'s attempts gets before the next
+/// one starts. RFC 8305 recommends 250.
+pub const CONNECT_ANY_STAGGER_MS: u64 = 250;
+
+fn connect_staggered(mut rest: ::std::vec::IntoIter<NetworkAddress>,
+                     stagger: ::std::time::Duration) -> Promise<TcpStream> {
+    let address = rest.next().expect("no addresses left to try");
+    if rest.len() == 0 {
+        return address.connect();
+    }
+
+    let failed = ::Latch::new();
+    let on_failure = failed.clone();
+    let attempt = address.connect().map_else(|stream| Ok(stream), move |error| {
+        on_failure.trigger();
+        Err(error)
+    });
+    let next = Timer.after_delay(stagger).exclusive_join(failed.when_triggered()).then(move |()| {
+        return Ok(connect_staggered(rest, stagger));
+    });
+    return ::race_ok(vec![attempt, next]);
+}
+
+fn no_addresses_error() -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                   "address resolved to nothing"))
+}
+
+/// Wraps an error from connecting to `address` so that it names the address. The kind, such as
+/// `ConnectionRefused`, is kept.
+fn connect_error(address: ::std::net::SocketAddr, error: ::std::io::Error) -> ::Error {
+    Box::new(::std::io::Error::new(error.kind(),
+                                   format!("failed to connect to {}: {}", address, error)))
+}
+
+/// Creates a socket of the same family as `address`.
+fn new_tcp_socket(address: &::std::net::SocketAddr) -> ::std::io::Result<::net2::TcpBuilder> {
+    match *address {
+        ::std::net::SocketAddr::V4(_) => ::net2::TcpBuilder::new_v4(),
+        ::std::net::SocketAddr::V6(_) => ::net2::TcpBuilder::new_v6(),
+    }
+}
+
+pub struct ConnectionReceiver {
+    listener: ::mio::tcp::TcpListener,
+    handle: Handle,
+    options: SocketOptions,
+}
+
+impl Drop for ConnectionReceiver {
+    fn drop(&mut self) {
+        // deregister the token
+    }
+}
+
+impl ConnectionReceiver {
+    /// Replaces the options that are set on each accepted connection.
+    pub fn set_socket_options(&mut self, options: SocketOptions) {
+        self.options = options;
+    }
+
+    fn accept_internal(self) -> Result<Promise<(ConnectionReceiver, TcpStream)>> {
+        let accept_result = match self.listener.accept() {
+            Ok((stream, _)) => Some(stream),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => None,
+            Err(e) => return Err(Box::new(e)),
+        };
+        match accept_result {
+            Some(stream) => {
+                try!(self.options.apply(stream.as_raw_fd()));
+                let handle = try!(register_new_handle(&stream, "TcpStream"));
+                return Ok(Promise::fulfilled((self, TcpStream::new(stream, handle))));
+            }
+            None => {
+                return with_current_event_loop(move |event_loop| {
+                    let promise =
+                        event_loop.event_port.borrow_mut().handler.observers[self.handle].when_becomes_readable();
+                    return Ok(promise.then(move |()| {
+                        return self.accept_internal();
+                    }));
+                });
+            }
+        }
+    }
+
+
+    pub fn accept(self) -> Promise<(ConnectionReceiver, TcpStream)> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {return self.accept_internal(); }));
+    }
+
+    /// Accepts connections until the returned promise is dropped, adding `handler(stream)` to
+    /// `tasks` for each one. Errors from the handlers go to the error handler of `tasks`; an
+    /// error from accepting ends the loop and rejects the returned promise. Dropping the promise
+    /// also drops `tasks`, which cancels the connections that are still being handled.
+    pub fn serve<F>(self, tasks: TaskSet, handler: F) -> Promise<()>
+        where F: FnMut(TcpStream) -> Promise<()> + 'static
+    {
+        return serve_loop(self, tasks, handler);
+    }
+}
+
+fn serve_loop<F>(receiver: ConnectionReceiver, mut tasks: TaskSet, mut handler: F) -> Promise<()>
+    where F: FnMut(TcpStream) -> Promise<()> + 'static
+{
+    return receiver.accept().then(move |(receiver, stream)| {
+        tasks.add(handler(stream));
+        return Ok(serve_loop(receiver, tasks, handler));
+    });
+}
+
+pub struct TcpStream {
+    stream: ::mio::tcp::TcpStream,
+    handle: Handle,
+    timings: Option<ConnectTimings>,
+    max_read_bytes_per_turn: usize,
+}
+
+impl ::mio::deprecated::TryRead for TcpStream {
+    fn try_read(&mut self, buf: &mut [u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_read(buf)
+    }
+}
+
+impl ::mio::deprecated::TryWrite for TcpStream {
+    fn try_write(&mut self, buf: &[u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_write(buf)
+    }
+}
+
+trait HasHandle {
+    fn get_handle(&self) -> Handle;
+
+    /// The most bytes that one read may take from this stream before letting other events run.
+    fn max_read_bytes_per_turn(&self) -> usize { usize::max_value() }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl HasHandle for TcpStream {
+    fn get_handle(&self) -> Handle { self.handle }
+    fn max_read_bytes_per_turn(&self) -> usize { self.max_read_bytes_per_turn }
+}
+
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        return with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.stream);
+        });
+    }
+}
+
+impl TcpStream {
+    fn new(stream: ::mio::tcp::TcpStream, handle: Handle) -> TcpStream {
+        TcpStream { stream: stream, handle: handle, timings: None,
+                    max_read_bytes_per_turn: DEFAULT_MAX_READ_BYTES_PER_TURN }
+    }
+
+    pub fn try_clone(&self) -> Result<TcpStream> {
+        let stream = try!(self.stream.try_clone());
+        let handle = try!(register_new_handle(&stream, "TcpStream"));
+        let mut clone = TcpStream::new(stream, handle);
+        clone.timings = self.timings;
+        clone.max_read_bytes_per_turn = self.max_read_bytes_per_turn;
+        return Ok(clone);
+    }
+
+    /// Limits how many bytes a single read may take from this stream before it lets the event
+    /// loop run other events, so that a peer that sends faster than it can be processed can't
+    /// starve other connections. The read carries on once the events that were queued have had
+    /// their turn. Each time the limit is hit, `IoStats::read_cap_hits` goes up. Defaults to
+    /// `DEFAULT_MAX_READ_BYTES_PER_TURN`. Panics if `bytes` is zero.
+    pub fn set_max_read_bytes_per_turn(&mut self, bytes: usize) {
+        assert!(bytes > 0, "the read limit must be positive");
+        self.max_read_bytes_per_turn = bytes;
+    }
+
+    /// Returns how long it took to establish the connection, for streams that were created by
+    /// `NetworkAddress::connect()`. Accepted streams have no timings.
+    pub fn connect_timings(&self) -> Option<ConnectTimings> {
+        self.timings
+    }
+
+    /// Sets `TCP_NODELAY`, which turns off Nagle's algorithm so that small writes go out right
+    /// away instead of waiting to be combined.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        Ok(try!(sockopt::set_nodelay(self.as_raw_fd(), nodelay)))
+    }
+
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(try!(sockopt::nodelay(self.as_raw_fd())))
+    }
+
+    /// Turns on `SO_KEEPALIVE` with the given timing, or turns it off if `keepalive` is `None`.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> Result<()> {
+        Ok(try!(sockopt::set_keepalive(self.as_raw_fd(), keepalive)))
+    }
+
+    pub fn keepalive(&self) -> Result<Option<Keepalive>> {
+        Ok(try!(sockopt::keepalive(self.as_raw_fd())))
+    }
+
+    /// Sets `SO_LINGER`. With `Some(Duration::from_secs(0))`, dropping the stream resets the
+    /// connection and throws away unsent data rather than closing it gracefully.
+    pub fn set_linger(&self, linger: Option<::std::time::Duration>) -> Result<()> {
+        Ok(try!(sockopt::set_linger(self.as_raw_fd(), linger)))
+    }
+
+    pub fn linger(&self) -> Result<Option<::std::time::Duration>> {
+        Ok(try!(sockopt::linger(self.as_raw_fd())))
+    }
+
+    /// Sets `SO_SNDBUF`. The kernel may round the size, and Linux doubles it to leave room for
+    /// bookkeeping, which `send_buffer_size()` then reports.
+    pub fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        Ok(try!(sockopt::set_send_buffer_size(self.as_raw_fd(), size)))
+    }
+
+    pub fn send_buffer_size(&self) -> Result<usize> {
+        Ok(try!(sockopt::send_buffer_size(self.as_raw_fd())))
+    }
+
+    /// Sets `SO_RCVBUF`. See `set_send_buffer_size()`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        Ok(try!(sockopt::set_recv_buffer_size(self.as_raw_fd(), size)))
+    }
+
+    pub fn recv_buffer_size(&self) -> Result<usize> {
+        Ok(try!(sockopt::recv_buffer_size(self.as_raw_fd())))
+    }
+
+    /// Waits for data to arrive and copies as much of it as fits into `buf`, without consuming
+    /// it, so that the next read sees the same bytes. Resolves with the number of bytes copied,
+    /// which is zero at EOF. Useful for sniffing a protocol, such as telling TLS from plaintext
+    /// on one port, before handing the stream to the code that speaks it.
+    pub fn peek<T>(self, buf: T) -> Promise<(TcpStream, T, usize)> where T: DerefMut<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return peek_internal(self, buf);
+        }));
+    }
+
+    /// Shuts down the reading side, the writing side, or both. After `Shutdown::Write` the peer
+    /// sees EOF, and the stream can still be read until the peer closes its side. A write only
+    /// resolves once all of its bytes have been handed to the kernel, so nothing that was written
+    /// before is lost; the kernel still delivers whatever it has buffered before the FIN.
+    pub fn shutdown(self, how: ::std::net::Shutdown) -> Promise<TcpStream> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            try!(sockopt::shutdown(self.as_raw_fd(), how));
+            return Ok(Promise::fulfilled(self));
+        }));
+    }
+
+    /// Returns the set of readiness events that this stream is currently registered for.
+    pub fn interest(&self) -> ::mio::Ready {
+        get_interest_internal(self.handle)
+    }
+
+    /// Reregisters the stream with the reactor under a new interest set. Removing readable
+    /// interest stops the kernel from reporting incoming data, which lets a proxy stop reading
+    /// from a fast peer while the other side drains. A pending read does not complete until
+    /// readable interest is restored.
+    pub fn set_interest(&mut self, interest: ::mio::Ready) -> Result<()> {
+        set_interest_internal(&self.stream, self.handle, interest)
+    }
+}
+
+
+fn peek_internal<T>(stream: TcpStream, mut buf: T) -> Result<Promise<(TcpStream, T, usize)>>
+    where T: DerefMut<Target=[u8]>
+{
+    loop {
+        match sockopt::peek(stream.as_raw_fd(), &mut buf) {
+            Ok(n) => return Ok(Promise::fulfilled((stream, buf, n))),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => (),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    return with_current_event_loop(move |event_loop| {
+        let promise =
+            event_loop.event_port.borrow_mut()
+            .handler.observers[stream.handle].when_becomes_readable();
+        return Ok(promise.then(move |()| {
+            return peek_internal(stream, buf);
+        }));
+    });
+}
+
+/// Calls `op` until it stops reporting `WouldBlock`, waiting for `socket` to become readable
+/// (or writable, if `readable` is false) in between. For operations, like those on datagram
+/// sockets, that don't fit `TryRead` and `TryWrite`.
+fn retry_internal<S, T, R, F>(socket: S, mut buf: T, readable: bool,
+                              mut op: F) -> Result<Promise<(S, T, R)>>
+    where F: FnMut(&S, &mut T) -> ::std::io::Result<R> + 'static,
+          S: HasHandle + 'static, T: 'static, R: 'static
+{
+    match op(&socket, &mut buf) {
+        Ok(r) => return Ok(Promise::fulfilled((socket, buf, r))),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => (),
+        Err(e) => return Err(Box::new(e)),
+    }
+
+    let handle = socket.get_handle();
+    return with_current_event_loop(move |event_loop| {
+        let promise = {
+            let observers = &mut event_loop.event_port.borrow_mut().handler.observers;
+            if readable {
+                observers[handle].when_becomes_readable()
+            } else {
+                observers[handle].when_becomes_writable()
+            }
+        };
+        return Ok(promise.then(move |()| {
+            return retry_internal(socket, buf, readable, op);
+        }));
+    });
+}
+
+fn try_read_internal<R, T>(mut reader: R,
+                           mut buf: T,
+                           mut already_read: usize,
+                           min_bytes: usize) -> Result<Promise<(R, T, usize)>>
+    where T: DerefMut<Target=[u8]>, R: ::mio::deprecated::TryRead + HasHandle
+{
+    let max_bytes = reader.max_read_bytes_per_turn();
+    let mut read_this_turn = 0;
+    while already_read < min_bytes {
+        if read_this_turn >= max_bytes {
+            // Let other events run before reading more, as in `write_internal()`.
+            with_current_event_loop(|event_loop| {
+                event_loop.event_port.borrow_mut().stats.read_cap_hits += 1;
+            });
+            return Ok(Promise::fulfilled(()).then(move |()| {
+                return try_read_internal(reader, buf, already_read, min_bytes);
+            }));
+        }
+        let end = ::std::cmp::min(buf.len(),
+                                  already_read.saturating_add(max_bytes - read_this_turn));
+        let read_result = try!(reader.try_read(&mut buf[already_read..end]));
+        match read_result {
+            Some(0) => {
+                // EOF
+                return Ok(Promise::fulfilled((reader, buf, already_read)));
+            }
+            Some(n) => {
+                already_read += n;
+                read_this_turn += n;
+            }
+            None => { // would block
+                return with_current_event_loop(move |event_loop| {
+                    let promise =
+                        event_loop.event_port.borrow_mut()
+                        .handler.observers[reader.get_handle()].when_becomes_readable();
+                    return Ok(promise.then(move |()| {
+                        return try_read_internal(reader, buf, already_read, min_bytes);
+                    }));
+                });
+            }
+        }
+    }
+
+    return Ok(Promise::fulfilled((reader, buf, already_read)));
+}
+
+fn write_internal<W, T>(mut writer: W,
+                        buf: T,
+                        mut already_written: usize) -> Result<Promise<(W, T)>>
+    where T: Deref<Target=[u8]>, W: ::mio::deprecated::TryWrite + HasHandle
+{
+    let max_bytes = with_current_event_loop(|event_loop| {
+        event_loop.event_port.borrow().max_write_bytes_per_turn
+    });
+    let mut written_this_turn = 0;
+    while already_written < buf.len() {
+        if written_this_turn >= max_bytes {
+            // Let other events run before writing more. An immediate promise's event goes to the
+            // back of the queue.
+            with_current_event_loop(|event_loop| {
+                event_loop.event_port.borrow_mut().stats.write_cap_hits += 1;
+            });
+            return Ok(Promise::fulfilled(()).then(move |()| {
+                return write_internal(writer, buf, already_written);
+            }));
+        }
+        let end = ::std::cmp::min(buf.len(),
+                                  already_written.saturating_add(max_bytes - written_this_turn));
+        let write_result = try!(writer.try_write(&buf[already_written..end]));
+        match write_result {
+            Some(n) => {
+                already_written += n;
+                written_this_turn += n;
+            }
+            None => { // would block
+                return with_current_event_loop(move |event_loop| {
+                    let promise =
+                        event_loop.event_port.borrow_mut()
+                        .handler.observers[writer.get_handle()].when_becomes_writable();
+                    return Ok(promise.then(move |()| {
+                        return write_internal(writer, buf, already_written);
+                    }));
+                });
+            }
+        }
+    }
+
+    return Ok(Promise::fulfilled((writer, buf)));
+}
+
+
+impl AsyncRead for TcpStream {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return try_read_internal(self, buf, 0, min_bytes);
+        }));
+    }
+
+    fn try_read_vectored<T>(self, bufs: Vec<T>,
+                            min_bytes: usize) -> Promise<(Self, Vec<T>, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::try_read_internal(self, bufs, 0, min_bytes);
+        }));
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return write_internal(self, buf, 0);
+        }));
+    }
+
+    fn write_vectored<T>(self, bufs: Vec<T>) -> Promise<(Self, Vec<T>)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::write_internal(self, bufs, 0);
+        }));
+    }
+}
+
+
+struct FdObserver {
+    read_fulfiller: Option<Box<PromiseFulfiller<()>>>,
+    write_fulfiller: Option<Box<PromiseFulfiller<()>>>,
+    interest: ::mio::Ready,
+
+    // Only used for post-mortem dumps.
+    fd: ::std::os::unix::io::RawFd,
+    kind: &'static str,
+}
+
+impl FdObserver {
+    pub fn new(interest: ::mio::Ready, fd: ::std::os::unix::io::RawFd, kind: &'static str) -> Handle {
+        with_current_event_loop(move |event_loop| {
+
+            let observer = FdObserver { read_fulfiller: None, write_fulfiller: None,
+                                        interest: interest, fd: fd, kind: kind };
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            event_port.generation += 1;
+            return event_port.handler.observers.push(observer);
+        })
+    }
+
+    pub fn when_becomes_readable(&mut self) -> Promise<()> {
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        self.read_fulfiller = Some(fulfiller);
+        return promise;
+    }
+
+    pub fn when_becomes_writable(&mut self) -> Promise<()> {
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        self.write_fulfiller = Some(fulfiller);
+        return promise;
+    }
+}
+
+/// The default for `TcpStream::set_max_read_bytes_per_turn()`: 4 MiB.
+pub const DEFAULT_MAX_READ_BYTES_PER_TURN: usize = 4 << 20;
+
+/// Counts of notable I/O events on an event loop. See `stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    /// The number of times a read stopped to let other events run, having hit its stream's
+    /// `max_read_bytes_per_turn`.
+    pub read_cap_hits: u64,
+
+    /// The number of times a write stopped to let other events run, having hit the limit set by
+    /// `set_max_write_bytes_per_turn()`.
+    pub write_cap_hits: u64,
+}
+
+/// Returns the I/O counts of the current thread's event loop. Panics if there is none.
+pub fn stats() -> IoStats {
+    with_current_event_loop(|event_loop| event_loop.event_port.borrow().stats)
+}
+
+/// The default for `set_max_write_bytes_per_turn()`: 4 MiB.
+pub const DEFAULT_MAX_WRITE_BYTES_PER_TURN: usize = 4 << 20;
+
+/// Limits how many bytes a single write may hand to the kernel before it lets the event loop run
+/// other events, so that a huge write to a fast peer can't keep other connections waiting. The
+/// write carries on where it left off once the events that were queued have had their turn.
+/// Applies to writes on the current thread's event loop. Panics if `bytes` is zero.
+pub fn set_max_write_bytes_per_turn(bytes: usize) {
+    assert!(bytes > 0, "the write limit must be positive");
+    with_current_event_loop(|event_loop| {
+        event_loop.event_port.borrow_mut().max_write_bytes_per_turn = bytes;
+    });
+}
+
+pub struct MioEventPort {
+    handler: Handler,
+    reactor: ::mio::deprecated::EventLoop<Handler>,
+
+    // The number of fd observers that have been registered since the loop started.
+    generation: u64,
+
+    // See `set_max_write_bytes_per_turn()`.
+    max_write_bytes_per_turn: usize,
+
+    stats: IoStats,
+    post_mortem: post_mortem::PostMortem,
+}
+
+struct Handler {
+    observers: HandleTable<FdObserver>,
+
+    // Set when `wake()` is called, and cleared when `wait()` or `poll()` reports it.
+    woken: bool,
+
+    // Set along with `woken`, and cleared when the loop runs its `EventLoop::on_wake()`
+    // callbacks, which may happen after a custom port has already reported `woken`.
+    wakeup_pending: bool,
+
+    // The timers that have been set and have neither fired nor been cleared. The reactor only
+    // knows about the next time that the wheel needs advancing.
+    timers: timer_wheel::TimerWheel<Box<PromiseFulfiller<()>>>,
+
+    // Created when the first precise timer is set. See `Timer::at_precise()`.
+    precise: Option<precise_timer::PreciseTimers>,
+
+    // Set if creating `precise` failed, in which case precise timers use `timers` instead.
+    precise_unsupported: bool,
+
+    // Set by `Timer::set_clock()`. `None` means real time.
+    clock: Option<Box<Clock>>,
+
+    // The clock's time when the port last checked for expired timers. See `Timer::now()`.
+    now: ::std::time::Instant,
+
+    // The reactor timeout that wakes the port up to advance `timers`, and the time for which it
+    // is set. Cleared when it fires.
+    wakeup: Option<(::mio::timer::Timeout, ::std::time::Instant)>,
+
+    // Readiness that arrived after `max_events` had already been delivered in the current wait
+    // or poll, oldest first. See `EventLoopBuilder::max_events_per_poll()`.
+    pending: ::std::collections::VecDeque<(::mio::Token, ::mio::Ready)>,
+    max_events: usize,
+
+    // The number of readiness events delivered so far in the current wait or poll.
+    delivered: usize,
+}
+
+impl MioEventPort {
+    pub fn new() -> Result<MioEventPort> {
+        Ok(MioEventPort {
+            handler: Handler {
+                observers: HandleTable::new(),
+                woken: false,
+                wakeup_pending: false,
+                timers: timer_wheel::TimerWheel::new(::std::time::Instant::now()),
+                precise: None,
+                precise_unsupported: false,
+                clock: None,
+                now: ::std::time::Instant::now(),
+                wakeup: None,
+                pending: ::std::collections::VecDeque::new(),
+                max_events: usize::max_value(),
+                delivered: 0,
+            },
+            reactor: try!(::mio::deprecated::EventLoop::new()),
+            generation: 0,
+            max_write_bytes_per_turn: DEFAULT_MAX_WRITE_BYTES_PER_TURN,
+            stats: IoStats::default(),
+            post_mortem: post_mortem::PostMortem::Disabled,
+        })
+    }
+}
+
+impl MioEventPort {
+    /// Drops all fd observers, along with any fulfillers that are waiting on them. Called when the
+    /// event loop is torn down; not part of the public API.
+    #[doc(hidden)]
+    pub fn clear_observers(&mut self) {
+        self.handler.observers = HandleTable::new();
+    }
+
+    /// Checks the current thread's built-in port for I/O and expired timers, without sleeping.
+    /// Meant for custom ports installed by `EventLoop::top_level_with_port()`, which need to do
+    /// this for the sockets and timers in `io` to make progress.
+    pub fn poll_current() {
+        with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().poll();
+        });
+    }
+
+    /// Returns whether a `LoopWaker` has woken the port since the last call. Used by the event
+    /// loop to decide whether to run its `EventLoop::on_wake()` callbacks.
+    #[doc(hidden)]
+    pub fn take_wakeup(&mut self) -> bool {
+        ::std::mem::replace(&mut self.handler.wakeup_pending, false)
+    }
+
+    /// Makes room for `additional` more fd observers. See `EventLoopBuilder::observer_capacity()`.
+    #[doc(hidden)]
+    pub fn reserve_observers(&mut self, additional: usize) {
+        self.handler.observers.reserve(additional);
+    }
+
+    /// See `EventLoopBuilder::max_events_per_poll()`.
+    #[doc(hidden)]
+    pub fn set_max_events_per_poll(&mut self, events: usize) {
+        self.handler.max_events = events;
+    }
+
+    /// Runs the reactor once, sleeping at most `timeout`, and then fires the expired timers.
+    /// Readiness left over from last time goes first, and if any is left over, the reactor does
+    /// not sleep, since there is already something to do.
+    fn run_once(&mut self, timeout: Option<::std::time::Duration>) {
+        self.handler.delivered = 0;
+        while self.handler.delivered < self.handler.max_events {
+            match self.handler.pending.pop_front() {
+                Some((token, events)) => self.handler.deliver(token, events),
+                None => break,
+            }
+        }
+        let timeout = if self.handler.delivered > 0 { Some(::std::time::Duration::new(0, 0)) } else { timeout };
+        self.reactor.run_once(&mut self.handler, timeout).unwrap();
+        self.fire_timers();
+    }
+
+    /// Returns the number of fd observers that are currently registered.
+    pub fn observer_count(&self) -> usize {
+        self.handler.observers.len()
+    }
+
+    /// Returns the number of timers that are waiting to fire.
+    pub fn pending_timer_count(&self) -> usize {
+        self.handler.timers.len() + self.handler.precise.as_ref().map_or(0, |precise| precise.len())
+    }
+
+    /// Returns true if `Timer::at_precise()` can use the precise timers, setting them up if
+    /// necessary.
+    fn precise_timers_supported(&mut self) -> bool {
+        !self.has_custom_clock() && self.init_precise_timers()
+    }
+
+    /// Sets up the precise timers, unless that has already been done. Returns false if they are
+    /// not supported.
+    fn init_precise_timers(&mut self) -> bool {
+        if self.handler.precise.is_some() {
+            return true;
+        }
+        if self.handler.precise_unsupported {
+            return false;
+        }
+        let precise = match precise_timer::PreciseTimers::new() {
+            Ok(precise) => precise,
+            Err(_) => {
+                self.handler.precise_unsupported = true;
+                return false;
+            }
+        };
+        let registered = self.reactor.register(precise.timerfd(),
+                                                   ::mio::Token(precise_timer::TOKEN),
+                                                   ::mio::Ready::readable(),
+                                                   ::mio::PollOpt::edge());
+        if registered.is_err() {
+            self.handler.precise_unsupported = true;
+            return false;
+        }
+        self.handler.precise = Some(precise);
+        return true;
+    }
+
+    /// Returns true if the timers run on a clock installed by `Timer::set_clock()`, in which case
+    /// they cannot wake the port up.
+    pub fn has_custom_clock(&self) -> bool {
+        self.handler.clock.is_some()
+    }
+
+    /// The current time of the clock that the timers run on.
+    fn clock_now(&self) -> ::std::time::Instant {
+        match self.handler.clock {
+            Some(ref clock) => clock.now(),
+            None => ::std::time::Instant::now(),
+        }
+    }
+
+    /// Fulfills the timers that have expired, then makes sure that the reactor will wake up when
+    /// the next one is due.
+    fn fire_timers(&mut self) {
+        let mut expired = Vec::new();
+        let now = self.clock_now();
+        self.handler.now = now;
+        self.handler.timers.advance(now, &mut expired);
+        if let Some(ref mut precise) = self.handler.precise {
+            precise.expire(::std::time::Instant::now(), &mut expired);
+        }
+        if !expired.is_empty() {
+            // Timer callbacks jump ahead of queued I/O continuations, so that they fire on time.
+            ::EventLoop::with_priority(::Priority::High, move || {
+                for fulfiller in expired.into_iter() {
+                    fulfiller.fulfill(());
+                }
+            });
+        }
+        self.schedule_wakeup();
+    }
+
+    /// Sets the reactor timeout for the next time the timer wheel needs advancing, if it is not
+    /// already set for then. With a custom clock, whose time the reactor knows nothing about, no
+    /// timeout is set.
+    fn schedule_wakeup(&mut self) {
+        let next = if self.has_custom_clock() { None } else { self.handler.timers.next_wakeup() };
+        if next == self.handler.wakeup.as_ref().map(|&(_, time)| time) {
+            return;
+        }
+        if let Some((timeout, _)) = self.handler.wakeup.take() {
+            self.reactor.clear_timeout(&timeout);
+        }
+        if let Some(time) = next {
+            let now = ::std::time::Instant::now();
+            let delay = if time > now { time - now } else { ::std::time::Duration::new(0, 0) };
+            let timeout = self.reactor.timeout(Timeout { wakeup: true }, delay).unwrap();
+            self.handler.wakeup = Some((timeout, time));
+        }
+    }
+}
+
+/// A handle that other threads can use to wake up an event loop that is sleeping in the built-in
+/// event port. Cheap to clone. See `LoopWaker::current()`.
+///
+/// Waking the loop delivers no event by itself; the loop runs its `EventLoop::on_wake()`
+/// callbacks and then goes back to sleep. A thread that hands work to the loop would typically put
+/// it in a shared queue, call `wake()`, and have an `on_wake()` callback on the loop check the
+/// queue.
+#[derive(Clone)]
+pub struct LoopWaker {
+    sender: ::mio::deprecated::Sender<()>,
+}
+
+impl LoopWaker {
+    /// Returns a waker for the current thread's event loop. Panics if the current thread has no
+    /// event loop. If the loop has a custom event port, it is that port's `wake()` that needs
+    /// calling, not this.
+    pub fn current() -> LoopWaker {
+        with_current_event_loop(|event_loop| {
+            LoopWaker { sender: event_loop.event_port.borrow().reactor.channel() }
+        })
+    }
+
+    /// Wakes the loop, or makes its next sleep return right away if it is not sleeping. Returns
+    /// an error if the loop is gone or its notification queue is full.
+    pub fn wake(&self) -> Result<()> {
+        match self.sender.send(()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                         "failed to wake event loop"))),
+        }
+    }
+}
+
+impl ::mio::deprecated::Handler for Handler {
+    type Timeout = Timeout;
+    type Message = ();
+    fn ready(&mut self, _event_loop: &mut ::mio::deprecated::EventLoop<Handler>,
+             token: ::mio::Token, events: ::mio::Ready) {
+        if token.0 == precise_timer::TOKEN {
+            // The precise timers are fired by `MioEventPort::fire_timers()`.
+            return;
+        }
+        if self.delivered >= self.max_events {
+            self.pending.push_back((token, events));
+            return;
+        }
+        self.deliver(token, events);
+    }
+    fn notify(&mut self, _event_loop: &mut ::mio::deprecated::EventLoop<Handler>, _msg: ()) {
+        self.woken = true;
+        self.wakeup_pending = true;
+    }
+    fn timeout(&mut self, _event_loop: &mut ::mio::deprecated::EventLoop<Handler>, timeout: Timeout) {
+        // The timers themselves are fired by `MioEventPort::fire_timers()` once `run_once()`
+        // returns.
+        if timeout.wakeup {
+            self.wakeup = None;
+        }
+    }
+}
+
+impl Handler {
+    fn deliver(&mut self, token: ::mio::Token, events: ::mio::Ready) {
+        self.delivered += 1;
+        // A hangup or an error is reported to both directions, so that whoever is waiting finds
+        // out about it on their next read or write.
+        let unix_events = ::mio::unix::UnixReady::from(events);
+        let broken = unix_events.is_hup() || unix_events.is_error();
+        // Readiness for an observer that has since been dropped is ignored.
+        let (read_fulfiller, write_fulfiller) = match self.observers.get_mut(Handle::from_token(token.0)) {
+            Some(observer) => {
+                let read = if events.is_readable() || broken { observer.read_fulfiller.take() } else { None };
+                let write = if events.is_writable() || broken { observer.write_fulfiller.take() } else { None };
+                (read, write)
+            }
+            None => (None, None),
+        };
+        if let Some(fulfiller) = read_fulfiller {
+            fulfiller.fulfill(());
+        }
+        if let Some(fulfiller) = write_fulfiller {
+            fulfiller.fulfill(());
+        }
+    }
+}
+
+impl EventPort for MioEventPort {
+    fn wait(&mut self) -> bool {
+        self.run_once(None);
+        return ::std::mem::replace(&mut self.handler.woken, false);
+    }
+
+    fn poll(&mut self) -> bool {
+        self.run_once(Some(::std::time::Duration::new(0, 0)));
+        return ::std::mem::replace(&mut self.handler.woken, false);
+    }
+
+    /// Interrupts `wait()`, which then returns true. Uses the reactor's notification channel, so
+    /// it works even if the notification is queued before `wait()` is called.
+    fn wake(&mut self) {
+        let _ = self.reactor.channel().send(());
+    }
+}
+
+fn deadline_exceeded() -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::TimedOut, "deadline exceeded"))
+}
+
+/// Makes `promise` fail if it is still pending when the current deadline passes.
+/// See `::with_deadline()`.
+fn under_deadline<T>(promise: Promise<T>) -> Promise<T> {
+    let deadline = match current_deadline() {
+        Some(deadline) => deadline,
+        None => return promise,
+    };
+    if deadline <= Timer.now() {
+        return Promise::rejected(deadline_exceeded());
+    }
+    return promise.exclusive_join(Timer.at(deadline).map(|()| Err(deadline_exceeded())));
+}
+
+/// Converts `duration` to the whole milliseconds that the reactor's timers work in, rounding up
+/// so that a timer never fires early. In particular, a nonzero duration of less than a
+/// millisecond becomes one millisecond rather than zero.
+fn duration_to_ms(duration: ::std::time::Duration) -> u64 {
+    duration.as_secs() * 1000 + ((duration.subsec_nanos() + 999_999) / 1_000_000) as u64
+}
+
+/// A source of monotonic time for timers. See `Timer::set_clock()`.
+pub trait Clock {
+    fn now(&self) -> ::std::time::Instant;
+}
+
+/// The timers of the current thread's event loop, which live in the loop's built-in port. Being
+/// a unit struct, `Timer` can be named anywhere, but it can only be used on a thread that has an
+/// event loop.
+pub struct Timer;
+
+impl Timer {
+    /// Returns a promise that resolves once `delay` has passed. The delay is rounded up to a
+    /// whole number of milliseconds.
+    pub fn after_delay(&self, delay: ::std::time::Duration) -> Promise<()> {
+        self.at(self.now() + delay)
+    }
+
+    /// Like `after_delay()`, but with a precision of better than a millisecond where the
+    /// platform allows, for latency-sensitive work such as pacing packets. See `at_precise()`.
+    /// Unless a clock has been installed, the delay counts from the current time rather than
+    /// from `now()`, which can be most of a turn behind at this precision.
+    pub fn after_delay_precise(&self, delay: ::std::time::Duration) -> Promise<()> {
+        let custom_clock = with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow().has_custom_clock()
+        });
+        let start = if custom_clock { self.now() } else { ::std::time::Instant::now() };
+        self.at_precise(start + delay)
+    }
+
+    /// Like `at()`, but with a precision of better than a millisecond where the platform allows.
+    /// On Linux, precise timers are backed by a timerfd and fire within about
+    /// `precise_resolution()` of `time`, plus scheduling latency. Elsewhere, and with a clock
+    /// installed by `set_clock()`, they fall back to the ordinary millisecond timers. Each
+    /// precise timer costs a heap operation, so the ordinary timers are better for large numbers
+    /// of coarse timeouts.
+    pub fn at_precise(&self, time: ::std::time::Instant) -> Promise<()> {
+        let supported = with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().precise_timers_supported()
+        });
+        if !supported {
+            return self.at(time);
+        }
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        return with_current_event_loop(move |event_loop| {
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            let precise = event_port.handler.precise.as_mut().expect("no precise timers?");
+            let handle = precise.insert(time, fulfiller);
+            return
+                Promise {
+                    node: Box::new(
+                        ::private::promise_node::Wrapper::new(promise.node,
+                                                              PreciseTimeoutDropper { handle: handle })) };
+        });
+    }
+
+    /// Returns the precision of the timers set by `at_precise()` and `after_delay_precise()`:
+    /// the resolution of the system's monotonic clock if precise timers are supported, or else
+    /// the one millisecond of the ordinary timers. Panics if the current thread has no event
+    /// loop.
+    pub fn precise_resolution(&self) -> ::std::time::Duration {
+        let supported = with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().precise_timers_supported()
+        });
+        match precise_timer::resolution() {
+            Some(resolution) if supported => resolution,
+            _ => ::std::time::Duration::from_millis(1),
+        }
+    }
+
+    /// Like `after_delay()`, but also returns a handle through which the timer can be cancelled,
+    /// reset, or inspected without holding on to the promise.
+    pub fn after_delay_with_handle(&self, delay: ::std::time::Duration) -> (Promise<()>, TimerHandle) {
+        let state = ::std::rc::Rc::new(::std::cell::RefCell::new(TimerState {
+            deadline: Some(self.now() + delay),
+            changed: None,
+            fired: false,
+        }));
+        (wait_for_timer(state.clone()), TimerHandle { state: state })
+    }
+
+    /// Returns the time at which the event loop last checked for expired timers, as measured by
+    /// the clock that timers run on: the system's monotonic clock unless `set_clock()` has
+    /// installed another one. This is the time against which timers expire, so durations
+    /// computed from it agree with when timers fire; a timer set for `now() + delay` fires once
+    /// the loop's clock has moved on by `delay`. The time stays the same while the loop runs
+    /// events, and only advances when the loop checks for I/O. On a thread with no loop, returns
+    /// the system's monotonic time.
+    pub fn now(&self) -> ::std::time::Instant {
+        let mut now = None;
+        ::private::if_current_event_loop(|event_loop| {
+            now = Some(event_loop.event_port.borrow().handler.now);
+        });
+        now.unwrap_or_else(::std::time::Instant::now)
+    }
+
+    /// Makes the current thread's timers run on `clock` instead of the system's monotonic clock,
+    /// for example a `gj::test::TestClock` that tests advance by hand. Timers then fire only when
+    /// the built-in port is polled after the clock has reached their deadlines, and they no
+    /// longer count as something that could wake a waiting loop. Should be called before any
+    /// timers are set. Panics if the current thread has no event loop.
+    pub fn set_clock<C>(&self, clock: C) where C: Clock + 'static {
+        with_current_event_loop(move |event_loop| {
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            event_port.handler.clock = Some(Box::new(clock));
+            event_port.handler.now = event_port.clock_now();
+            if event_port.handler.timers.len() == 0 {
+                // Count the wheel's ticks from the new clock's time, so that a deadline a whole
+                // number of milliseconds away expires exactly when the clock gets there.
+                event_port.handler.timers = timer_wheel::TimerWheel::new(event_port.handler.now);
+            }
+            event_port.schedule_wakeup();
+        });
+    }
+
+    /// Returns a promise that resolves once `Timer::now()` has reached `time`, or on a later turn
+    /// if it already has. Scheduling periodic work for `start + n * period`, rather than for a
+    /// delay after the previous run, keeps the time that callbacks take from accumulating as
+    /// drift.
+    pub fn at(&self, time: ::std::time::Instant) -> Promise<()> {
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        return with_current_event_loop(move |event_loop| {
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            let id = event_port.handler.timers.insert(time, fulfiller);
+            event_port.schedule_wakeup();
+            return
+                Promise {
+                    node: Box::new(
+                        ::private::promise_node::Wrapper::new(promise.node,
+                                                              TimeoutDropper { id: id })) };
+        });
+    }
+
+    /// Returns a stream that yields a tick every `interval`, starting one interval from now. Each
+    /// tick is the time for which it was scheduled, and ticks are scheduled for
+    /// `start + n * interval`, so the time that consumers take does not make them drift. A tick
+    /// is not scheduled until the consumer has taken the previous one; ticks missed by a slow
+    /// consumer are skipped rather than delivered in a burst. Dropping the stream stops the
+    /// timer. Panics if `interval` is zero.
+    pub fn every(&self, interval: ::std::time::Duration) -> PromiseStream<::std::time::Instant> {
+        assert!(interval > ::std::time::Duration::new(0, 0), "interval must be nonzero");
+        let first = self.now() + interval;
+        spawn_stream(move |sender| tick_loop(sender, first, interval))
+    }
+
+    /// Like `every()`, with the interval in milliseconds.
+    pub fn every_ms(&self, interval: u64) -> PromiseStream<::std::time::Instant> {
+        self.every(::std::time::Duration::from_millis(interval))
+    }
+
+    /// Like `after_delay()`, with the delay in milliseconds.
+    pub fn after_delay_ms(&self, delay: u64) -> Promise<()> {
+        self.after_delay(::std::time::Duration::from_millis(delay))
+    }
+
+    /// Makes `promise` fail if it is still pending once `delay` has passed.
+    pub fn timeout_after<T>(&self, delay: ::std::time::Duration, promise: Promise<T>) -> Promise<T> {
+        promise.exclusive_join(self.after_delay(delay).map(|()| {
+            return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "operation timed out")))
+        }))
+    }
+
+    /// Like `timeout_after()`, but instead of cancelling `promise` when `delay` passes, hands it
+    /// back, still running, in `TimeoutResult::TimedOut`, so that the caller can wait on it
+    /// again or shut down whatever it is reading from cleanly. If `promise` fails before the
+    /// delay passes, so does the returned promise.
+    pub fn timeout_after_keep<T>(&self, delay: ::std::time::Duration,
+                                 promise: Promise<T>) -> Promise<TimeoutResult<T>> {
+        let (result, result_fulfiller) = new_promise_and_fulfiller();
+        let (later, later_fulfiller) = new_promise_and_fulfiller();
+        let state = ::std::rc::Rc::new(::std::cell::RefCell::new(KeepState {
+            result: Some(result_fulfiller),
+            later: Some(later_fulfiller),
+            later_promise: None,
+        }));
+
+        // The tasks report their outcomes through the fulfillers, so they never fail.
+        let mut tasks = TaskSet::new_with(|_error| {});
+        let state1 = state.clone();
+        tasks.add(promise.map_else(|value| Ok(Ok(value)), |error| Ok(Err(error))).map(move |outcome| {
+            let (result, later) = {
+                let state = &mut *state1.borrow_mut();
+                (state.result.take(), state.later.take())
+            };
+            match (result, later) {
+                (Some(result), _) => match outcome {
+                    Ok(value) => result.fulfill(TimeoutResult::Completed(value)),
+                    Err(error) => result.reject(error),
+                },
+                (None, Some(later)) => match outcome {
+                    Ok(value) => later.fulfill(value),
+                    Err(error) => later.reject(error),
+                },
+                (None, None) => {}
+            }
+            Ok(())
+        }));
+        let state2 = state.clone();
+        tasks.add(self.after_delay(delay).map(move |()| {
+            let (result, later) = {
+                let state = &mut *state2.borrow_mut();
+                (state.result.take(), state.later_promise.take())
+            };
+            if let (Some(result), Some(later)) = (result, later) {
+                result.fulfill(TimeoutResult::TimedOut(later));
+            }
+            Ok(())
+        }));
+
+        // Whoever ends up with `later` keeps the tasks running.
+        let tasks = ::std::rc::Rc::new(tasks);
+        let tasks1 = tasks.clone();
+        state.borrow_mut().later_promise = Some(later.map(move |value| {
+            drop(tasks1);
+            Ok(value)
+        }));
+        let keeper = KeepGuard { state: state, _tasks: tasks };
+        return result.map(move |result| {
+            drop(keeper);
+            Ok(result)
+        });
+    }
+
+    /// Like `timeout_after_keep()`, with the delay in milliseconds.
+    pub fn timeout_after_keep_ms<T>(&self, delay: u64, promise: Promise<T>) -> Promise<TimeoutResult<T>> {
+        self.timeout_after_keep(::std::time::Duration::from_millis(delay), promise)
+    }
+
+    /// Like `timeout_after()`, with the delay in milliseconds.
+    pub fn timeout_after_ms<T>(&self, delay: u64, promise: Promise<T>) -> Promise<T> {
+        self.timeout_after(::std::time::Duration::from_millis(delay), promise)
+    }
+}
+
+/// The outcome of `Timer::timeout_after_keep()`.
+pub enum TimeoutResult<T> where T: 'static {
+    /// The promise resolved to this value before the delay passed.
+    Completed(T),
+
+    /// The delay passed first. The promise is still running, and can be waited on again.
+    TimedOut(Promise<T>),
+}
+
+struct KeepState<T> where T: 'static {
+    // Set until the promise has resolved or the delay has passed, whichever happens first.
+    result: Option<Box<PromiseFulfiller<TimeoutResult<T>>>>,
+
+    // Delivers the promise's outcome if it resolves after the delay has passed.
+    later: Option<Box<PromiseFulfiller<T>>>,
+
+    // The promise that `TimeoutResult::TimedOut` carries, until the delay passes.
+    later_promise: Option<Promise<T>>,
+}
+
+/// Keeps the tasks of `Timer::timeout_after_keep()` running until its result has been delivered.
+/// Once it has, or if the result is dropped, the tasks are cancelled unless the caller has taken
+/// over the still-running promise.
+struct KeepGuard<T> where T: 'static {
+    state: ::std::rc::Rc<::std::cell::RefCell<KeepState<T>>>,
+    _tasks: ::std::rc::Rc<TaskSet>,
+}
+
+impl <T> Drop for KeepGuard<T> {
+    fn drop(&mut self) {
+        // `later_promise` holds a reference to the tasks, which hold this state.
+        let later_promise = self.state.borrow_mut().later_promise.take();
+        drop(later_promise);
+    }
+}
+
+struct TimerState {
+    // None once the timer has been cancelled.
+    deadline: Option<::std::time::Instant>,
+
+    // Wakes up `wait_for_timer()` when the deadline changes.
+    changed: Option<Box<PromiseFulfiller<()>>>,
+
+    fired: bool,
+}
+
+fn wait_for_timer(state: ::std::rc::Rc<::std::cell::RefCell<TimerState>>) -> Promise<()> {
+    let deadline = match state.borrow().deadline {
+        Some(deadline) => deadline,
+        None => {
+            return Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                                    "timer cancelled")));
+        }
+    };
+    let (changed, fulfiller) = new_promise_and_fulfiller();
+    state.borrow_mut().changed = Some(fulfiller);
+    let expired = Timer.at(deadline).map(|()| Ok(true));
+    return expired.exclusive_join(changed.map(|()| Ok(false))).then(move |expired| {
+        if expired {
+            let state = &mut *state.borrow_mut();
+            state.fired = true;
+            state.changed = None;
+            return Ok(Promise::fulfilled(()));
+        }
+        return Ok(wait_for_timer(state));
+    });
+}
+
+/// Controls a timer created by `Timer::after_delay_with_handle()`.
+pub struct TimerHandle {
+    state: ::std::rc::Rc<::std::cell::RefCell<TimerState>>,
+}
+
+impl TimerHandle {
+    /// Stops the timer, rejecting its promise. Returns false if the timer had already fired or
+    /// been cancelled, in which case this has no effect.
+    pub fn cancel(&self) -> bool {
+        let changed = {
+            let state = &mut *self.state.borrow_mut();
+            if state.fired || state.deadline.is_none() {
+                return false;
+            }
+            state.deadline = None;
+            state.changed.take()
+        };
+        if let Some(changed) = changed {
+            changed.fulfill(());
+        }
+        return true;
+    }
+
+    /// Makes the timer fire once `delay` has passed from now instead of at its original
+    /// deadline, e.g. to push back an idle timeout whenever a connection sees traffic. Returns
+    /// false if the timer had already fired or been cancelled, in which case this has no effect.
+    pub fn reset(&self, delay: ::std::time::Duration) -> bool {
+        let changed = {
+            let state = &mut *self.state.borrow_mut();
+            if state.fired || state.deadline.is_none() {
+                return false;
+            }
+            state.deadline = Some(Timer.now() + delay);
+            state.changed.take()
+        };
+        if let Some(changed) = changed {
+            changed.fulfill(());
+        }
+        return true;
+    }
+
+    /// Returns how long is left until the timer fires, which is zero if it is due, or `None`
+    /// if it has already fired or been cancelled.
+    pub fn remaining(&self) -> Option<::std::time::Duration> {
+        let state = self.state.borrow();
+        if state.fired {
+            return None;
+        }
+        state.deadline.map(|deadline| {
+            let now = Timer.now();
+            if deadline > now { deadline - now } else { ::std::time::Duration::new(0, 0) }
+        })
+    }
+}
+
+fn tick_loop(mut sender: StreamSender<::std::time::Instant>, tick: ::std::time::Instant,
+             interval: ::std::time::Duration) -> Promise<()> {
+    return Timer.at(tick).then(move |()| {
+        sender.send(tick);
+        return Ok(sender.when_drained().then(move |()| {
+            let now = Timer.now();
+            let mut next = tick + interval;
+            while next <= now {
+                next = next + interval;
+            }
+            return Ok(tick_loop(sender, next, interval));
+        }));
+    });
+}
+
+struct TimeoutDropper {
+    id: timer_wheel::TimerId,
+}
+
+impl Drop for TimeoutDropper {
+    fn drop(&mut self) {
+        let fulfiller = with_current_event_loop(move |event_loop| {
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            // The reactor timeout is left alone; if it was for this timer, the port wakes up
+            // once for nothing.
+            event_port.handler.timers.remove(self.id)
+        });
+        drop(fulfiller);
+    }
+}
+
+struct PreciseTimeoutDropper {
+    handle: Handle,
+}
+
+impl Drop for PreciseTimeoutDropper {
+    fn drop(&mut self) {
+        let fulfiller = with_current_event_loop(move |event_loop| {
+            let event_port = &mut *event_loop.event_port.borrow_mut();
+            event_port.handler.precise.as_mut().and_then(|precise| precise.remove(self.handle))
+        });
+        drop(fulfiller);
+    }
+}
+
+struct Timeout {
+    // False for the timeouts that `MioEventPort::poll()` uses to avoid blocking, true for the
+    // ones that advance the timer wheel.
+    wakeup: bool,
+}
+
+pub struct SocketStream {
+    stream: ::mio::deprecated::unix::Io,
+    handle: Handle,
+    max_read_bytes_per_turn: usize,
+}
+
+impl ::mio::deprecated::TryRead for SocketStream {
+    fn try_read(&mut self, buf: &mut [u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_read(buf)
+    }
+}
+
+impl ::mio::deprecated::TryWrite for SocketStream {
+    fn try_write(&mut self, buf: &[u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_write(buf)
+    }
+}
+
+impl Drop for SocketStream {
+    fn drop(&mut self) {
+        return with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.stream);
+        });
+    }
+}
+
+impl AsRawFd for SocketStream {
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl HasHandle for SocketStream {
+    fn get_handle(&self) -> Handle { self.handle }
+    fn max_read_bytes_per_turn(&self) -> usize { self.max_read_bytes_per_turn }
+}
+
+impl SocketStream {
+    /// Returns a new handle to the same socket, so that one handle can be read from while the
+    /// other is written to.
+    pub fn try_clone(&self) -> Result<SocketStream> {
+        let fd = match ::nix::unistd::dup(self.stream.as_raw_fd()) {
+            Ok(fd) => fd,
+            Err(_) => {
+                return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                          "failed to duplicate socket")))
+            }
+        };
+        let io = io_from_raw_fd(fd);
+        let handle = try!(register_new_handle(&io, "SocketStream"));
+        let mut clone = SocketStream::new(io, handle);
+        clone.max_read_bytes_per_turn = self.max_read_bytes_per_turn;
+        return Ok(clone);
+    }
+
+    fn new(stream: ::mio::deprecated::unix::Io, handle: Handle) -> SocketStream {
+        SocketStream { stream: stream, handle: handle,
+                       max_read_bytes_per_turn: DEFAULT_MAX_READ_BYTES_PER_TURN }
+    }
+
+    /// See `TcpStream::set_max_read_bytes_per_turn()`.
+    pub fn set_max_read_bytes_per_turn(&mut self, bytes: usize) {
+        assert!(bytes > 0, "the read limit must be positive");
+        self.max_read_bytes_per_turn = bytes;
+    }
+
+    /// See `TcpStream::shutdown()`.
+    pub fn shutdown(self, how: ::std::net::Shutdown) -> Promise<SocketStream> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            try!(sockopt::shutdown(self.as_raw_fd(), how));
+            return Ok(Promise::fulfilled(self));
+        }));
+    }
+
+    /// Returns the set of readiness events that this stream is currently registered for.
+    pub fn interest(&self) -> ::mio::Ready {
+        get_interest_internal(self.handle)
+    }
+
+    /// Reregisters the stream with the reactor under a new interest set.
+    /// See `TcpStream::set_interest()`.
+    pub fn set_interest(&mut self, interest: ::mio::Ready) -> Result<()> {
+        set_interest_internal(&self.stream, self.handle, interest)
+    }
+}
+
+impl AsyncRead for SocketStream {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return try_read_internal(self, buf, 0, min_bytes);
+        }));
+    }
+
+    fn try_read_vectored<T>(self, bufs: Vec<T>,
+                            min_bytes: usize) -> Promise<(Self, Vec<T>, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::try_read_internal(self, bufs, 0, min_bytes);
+        }));
+    }
+}
+
+impl AsyncWrite for SocketStream {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return write_internal(self, buf, 0);
+        }));
+    }
+
+    fn write_vectored<T>(self, bufs: Vec<T>) -> Promise<(Self, Vec<T>)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::write_internal(self, bufs, 0);
+        }));
+    }
+}
+
+/// Creates a new thread and sets up a socket pair that can be used to communicate with it.
+/// Passes one of the sockets to the thread's start function and returns the other socket.
+/// The new thread will already have an active event loop when `start_func` is called.
+pub fn spawn<F>(start_func: F) -> Result<(::std::thread::JoinHandle<()>, SocketStream)>
+    where F: FnOnce(SocketStream, &WaitScope) -> Result<()>,
+          F: Send + 'static
+{
+    use nix::sys::socket::{socketpair, AddressFamily, SockType, SOCK_CLOEXEC, SOCK_NONBLOCK};
+
+    let (fd0, fd1) =
+        // eh... the trait `std::error::Error` is not implemented for the type `nix::Error`
+        match socketpair(AddressFamily::Unix, SockType::Stream, 0, SOCK_NONBLOCK | SOCK_CLOEXEC) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                          "failed to create socketpair")))
+            }
+        };
+
+    let io = io_from_raw_fd(fd0);
+    let handle = try!(register_new_handle(&io, "SocketStream"));
+    let socket_stream = SocketStream::new(io, handle);
+
+    let join_handle = ::std::thread::spawn(move || {
+        let _result = EventLoop::top_level(move |wait_scope| {
+            let io = io_from_raw_fd(fd1);
+            let handle = try!(register_new_handle(&io, "SocketStream"));
+            let socket_stream = SocketStream::new(io, handle);
+            start_func(socket_stream, &wait_scope)
+        });
+    });
+
+    return Ok((join_handle, socket_stream));
+}
+
+/// Wraps a file descriptor that was opened some other way, such as a socket inherited from a
+/// parent process, a pty, or a character device, as an async stream. Makes `fd` nonblocking and
+/// registers it with the reactor. The stream owns `fd` from then on and closes it when dropped,
+/// even if this fails.
+///
+/// This is unsafe because nothing checks that `fd` is open and owned by no one else; if some
+/// other object also closes it, a descriptor that was opened in the meantime could be closed
+/// instead.
+pub unsafe fn stream_from_raw_fd(fd: ::std::os::unix::io::RawFd) -> Result<SocketStream> {
+    let (io, handle) = try!(pipe::register_fd(fd, "SocketStream"));
+    return Ok(SocketStream::new(io, handle));
+}
+
+/// Starts `shards` threads, each with its own event loop and its own listener on `address`, and
+/// calls `serve` on each thread with that thread's listener. The listeners are created with
+/// `SO_REUSEPORT`, on top of whatever socket options `address` has, so the kernel spreads the
+/// incoming connections across the threads.
+///
+/// Each thread ends when the promise that `serve` returns does. Its join handle yields the
+/// message of the error that ended it, if any, since errors can't cross threads.
+pub fn listen_sharded<F>(address: NetworkAddress, shards: usize, serve: F)
+                         -> Vec<::std::thread::JoinHandle<::std::result::Result<(), String>>>
+    where F: Fn(ConnectionReceiver) -> Promise<()>,
+          F: Send + Sync + 'static
+{
+    let options = address.options.reuse_port(true);
+    let address = address.with_socket_options(options);
+    let serve = ::std::sync::Arc::new(serve);
+    return (0..shards).map(|_| {
+        let serve = serve.clone();
+        let address = address.clone();
+        ::std::thread::spawn(move || {
+            let result = EventLoop::top_level(move |wait_scope| {
+                let receiver = try!(address.listen());
+                serve(receiver).wait(wait_scope)
+            });
+            result.map_err(|e| format!("{}", e))
+        })
+    }).collect();
+}
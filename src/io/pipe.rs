@@ -0,0 +1,212 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Pipes and the standard streams.
+
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use nix::fcntl::{fcntl, FcntlArg, OFlag, O_CLOEXEC, O_NONBLOCK};
+use handle_table::Handle;
+use io::{AsyncRead, AsyncWrite, HasHandle, io_from_raw_fd, register_new_handle, try_read_internal,
+         under_deadline, vectored, write_internal, DEFAULT_MAX_READ_BYTES_PER_TURN};
+use private::with_current_event_loop;
+use {Promise, Result};
+
+fn set_nonblocking(fd: RawFd) -> ::nix::Result<()> {
+    let flags = OFlag::from_bits_truncate(try!(fcntl(fd, FcntlArg::F_GETFL)));
+    try!(fcntl(fd, FcntlArg::F_SETFL(flags | O_NONBLOCK)));
+    return Ok(());
+}
+
+/// Takes ownership of `fd`, makes it nonblocking, and registers it with the reactor.
+pub fn register_fd(fd: RawFd, kind: &'static str) -> Result<(::mio::deprecated::unix::Io, Handle)> {
+    let io = io_from_raw_fd(fd);
+    try!(set_nonblocking(fd));
+    let handle = try!(register_new_handle(&io, kind));
+    return Ok((io, handle));
+}
+
+/// Registers a duplicate of one of the standard streams, so that dropping the result leaves the
+/// original open.
+fn dup_standard_stream(fd: RawFd, kind: &'static str) -> Result<(::mio::deprecated::unix::Io, Handle)> {
+    let duplicate = try!(::nix::unistd::dup(fd));
+    return register_fd(duplicate, kind);
+}
+
+/// Creates an OS pipe. Whatever is written to the `PipeWriter` can be read from the `PipeReader`.
+///
+/// Both ends are close-on-exec, so child processes don't inherit them. (nix sets the flag with
+/// `fcntl()` right after creating the pipe, so a `fork()` on another thread in between could
+/// still catch them.)
+pub fn pipe() -> Result<(PipeReader, PipeWriter)> {
+    let (read_fd, write_fd) = try!(::nix::unistd::pipe2(O_CLOEXEC | O_NONBLOCK));
+    // Both ends are owned from here on, so if one fails to register, the other is closed too.
+    let read_io = io_from_raw_fd(read_fd);
+    let write_io = io_from_raw_fd(write_fd);
+    let read_handle = try!(register_new_handle(&read_io, "PipeReader"));
+    let reader = PipeReader::new(read_io, read_handle);
+    let write_handle = try!(register_new_handle(&write_io, "PipeWriter"));
+    return Ok((reader, PipeWriter { io: write_io, handle: write_handle }));
+}
+
+/// Takes ownership of `fd`, the reading end of a pipe, and makes it an async stream.
+pub fn reader_from_fd(fd: RawFd) -> Result<PipeReader> {
+    let (io, handle) = try!(register_fd(fd, "PipeReader"));
+    return Ok(PipeReader::new(io, handle));
+}
+
+/// Takes ownership of `fd`, the writing end of a pipe, and makes it an async stream.
+pub fn writer_from_fd(fd: RawFd) -> Result<PipeWriter> {
+    let (io, handle) = try!(register_fd(fd, "PipeWriter"));
+    return Ok(PipeWriter { io: io, handle: handle });
+}
+
+/// Returns the process's standard input as an async stream.
+///
+/// The standard streams are made nonblocking, and because the flag belongs to the open file
+/// rather than the descriptor, other processes that share it (such as the parent shell) see
+/// the change too. Fails if standard input is a regular file, which the reactor can't watch.
+pub fn stdin() -> Result<PipeReader> {
+    let (io, handle) = try!(dup_standard_stream(0, "PipeReader"));
+    return Ok(PipeReader::new(io, handle));
+}
+
+/// Returns the process's standard output as an async stream. See `stdin()` for caveats.
+pub fn stdout() -> Result<PipeWriter> {
+    let (io, handle) = try!(dup_standard_stream(1, "PipeWriter"));
+    return Ok(PipeWriter { io: io, handle: handle });
+}
+
+/// Returns the process's standard error as an async stream. See `stdin()` for caveats.
+pub fn stderr() -> Result<PipeWriter> {
+    let (io, handle) = try!(dup_standard_stream(2, "PipeWriter"));
+    return Ok(PipeWriter { io: io, handle: handle });
+}
+
+/// The reading end of a pipe, or standard input.
+pub struct PipeReader {
+    io: ::mio::deprecated::unix::Io,
+    handle: Handle,
+    max_read_bytes_per_turn: usize,
+}
+
+impl PipeReader {
+    fn new(io: ::mio::deprecated::unix::Io, handle: Handle) -> PipeReader {
+        PipeReader { io: io, handle: handle,
+                     max_read_bytes_per_turn: DEFAULT_MAX_READ_BYTES_PER_TURN }
+    }
+
+    /// See `TcpStream::set_max_read_bytes_per_turn()`.
+    pub fn set_max_read_bytes_per_turn(&mut self, bytes: usize) {
+        assert!(bytes > 0, "the read limit must be positive");
+        self.max_read_bytes_per_turn = bytes;
+    }
+}
+
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.io);
+        });
+    }
+}
+
+impl ::mio::deprecated::TryRead for PipeReader {
+    fn try_read(&mut self, buf: &mut [u8]) -> ::std::io::Result<Option<usize>> {
+        self.io.try_read(buf)
+    }
+}
+
+impl HasHandle for PipeReader {
+    fn get_handle(&self) -> Handle { self.handle }
+    fn max_read_bytes_per_turn(&self) -> usize { self.max_read_bytes_per_turn }
+}
+
+impl AsyncRead for PipeReader {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return try_read_internal(self, buf, 0, min_bytes);
+        }));
+    }
+
+    fn try_read_vectored<T>(self, bufs: Vec<T>,
+                            min_bytes: usize) -> Promise<(Self, Vec<T>, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::try_read_internal(self, bufs, 0, min_bytes);
+        }));
+    }
+}
+
+/// The writing end of a pipe, or standard output or standard error. Dropping the writing end
+/// of a pipe makes the reading end see EOF.
+pub struct PipeWriter {
+    io: ::mio::deprecated::unix::Io,
+    handle: Handle,
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.io);
+        });
+    }
+}
+
+impl ::mio::deprecated::TryWrite for PipeWriter {
+    fn try_write(&mut self, buf: &[u8]) -> ::std::io::Result<Option<usize>> {
+        self.io.try_write(buf)
+    }
+}
+
+impl HasHandle for PipeWriter {
+    fn get_handle(&self) -> Handle { self.handle }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return write_internal(self, buf, 0);
+        }));
+    }
+
+    fn write_vectored<T>(self, bufs: Vec<T>) -> Promise<(Self, Vec<T>)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::write_internal(self, bufs, 0);
+        }));
+    }
+}
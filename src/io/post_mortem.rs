@@ -0,0 +1,141 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Snapshots of the fds that the event loop is watching, for reconstructing what a crashed
+//! process was waiting on.
+//!
+//! Call `set_post_mortem()` to have a snapshot written automatically when the event loop is
+//! torn down because of a panic, or because the closure passed to `EventLoop::top_level()`
+//! returned an error.
+
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use private::{if_current_event_loop, with_current_event_loop};
+
+/// Where post-mortem dumps are written.
+#[derive(Clone, Debug)]
+pub enum PostMortem {
+    /// Don't write dumps. The default.
+    Disabled,
+
+    /// Write dumps to standard error.
+    Stderr,
+
+    /// Append dumps to the file at this path, creating it if necessary.
+    File(PathBuf),
+}
+
+/// The state of one fd observer at the time of a snapshot.
+#[derive(Clone, Debug)]
+pub struct ObserverInfo {
+    pub fd: RawFd,
+
+    /// The type of the object that owns the fd, e.g. `"TcpStream"`.
+    pub kind: &'static str,
+
+    pub readable_interest: bool,
+    pub writable_interest: bool,
+
+    /// Whether a promise is waiting for the fd to become readable.
+    pub waiting_to_read: bool,
+
+    /// Whether a promise is waiting for the fd to become writable.
+    pub waiting_to_write: bool,
+}
+
+/// The fd observers of the current event loop at some point in time.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// The number of observers that had been registered when the snapshot was taken. Comparing
+    /// the generations of two snapshots tells you whether observers came and went in between.
+    pub generation: u64,
+
+    pub observers: Vec<ObserverInfo>,
+}
+
+impl Snapshot {
+    /// Writes the snapshot in a human-readable form, one line per observer.
+    pub fn write_to<W>(&self, out: &mut W) -> ::std::io::Result<()> where W: Write {
+        try!(writeln!(out, "{} fd observers, generation {}", self.observers.len(), self.generation));
+        for observer in &self.observers {
+            let mut interest = Vec::new();
+            if observer.readable_interest { interest.push("readable") }
+            if observer.writable_interest { interest.push("writable") }
+            let mut waiting = Vec::new();
+            if observer.waiting_to_read { waiting.push("read") }
+            if observer.waiting_to_write { waiting.push("write") }
+            try!(writeln!(out, "  fd {} {} interest={} waiting={}", observer.fd, observer.kind,
+                          interest.join("|"), waiting.join(",")));
+        }
+        return Ok(());
+    }
+}
+
+/// Takes a snapshot of the current event loop's fd observers. Panics if the current thread has
+/// no event loop.
+pub fn snapshot() -> Snapshot {
+    with_current_event_loop(|event_loop| {
+        let event_port = event_loop.event_port.borrow();
+        Snapshot {
+            generation: event_port.generation,
+            observers: event_port.handler.observers.iter().map(|observer| {
+                ObserverInfo {
+                    fd: observer.fd,
+                    kind: observer.kind,
+                    readable_interest: observer.interest.is_readable(),
+                    writable_interest: observer.interest.is_writable(),
+                    waiting_to_read: observer.read_fulfiller.is_some(),
+                    waiting_to_write: observer.write_fulfiller.is_some(),
+                }
+            }).collect(),
+        }
+    })
+}
+
+/// Sets where the current event loop writes its post-mortem dump. Panics if the current thread
+/// has no event loop.
+pub fn set_post_mortem(target: PostMortem) {
+    with_current_event_loop(move |event_loop| {
+        event_loop.event_port.borrow_mut().post_mortem = target;
+    });
+}
+
+/// Writes a snapshot to the target chosen with `set_post_mortem()`, headed by `reason`. Does
+/// nothing if dumps are disabled or the current thread has no event loop. Called automatically
+/// when the loop is torn down after a failure, but may also be called directly, e.g. before
+/// exiting on a fatal error.
+pub fn dump(reason: &str) -> ::std::io::Result<()> {
+    let mut target = PostMortem::Disabled;
+    if_current_event_loop(|event_loop| {
+        target = event_loop.event_port.borrow().post_mortem.clone();
+    });
+
+    let mut out: Box<Write> = match target {
+        PostMortem::Disabled => return Ok(()),
+        PostMortem::Stderr => Box::new(::std::io::stderr()),
+        PostMortem::File(path) => {
+            Box::new(try!(::std::fs::OpenOptions::new().create(true).append(true).open(path)))
+        }
+    };
+    try!(writeln!(out, "gj post-mortem: {}", reason));
+    return snapshot().write_to(&mut out);
+}
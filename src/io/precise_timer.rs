@@ -0,0 +1,226 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Timers with sub-millisecond precision, for `Timer::after_delay_precise()`.
+//!
+//! On Linux these are backed by a timerfd, which the built-in port registers with its reactor
+//! and keeps armed for the earliest deadline, so that the reactor wakes up on time. Elsewhere,
+//! or if the timerfd cannot be created, `PreciseTimers::new()` fails and the caller falls back
+//! to the millisecond timer wheel.
+
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+use handle_table::{Handle, HandleTable};
+use PromiseFulfiller;
+
+/// The reactor token of the timerfd. `Handle::to_token()` never sets the top bit, so this cannot
+/// collide with an fd observer's token.
+pub const TOKEN: usize = !(::std::usize::MAX >> 1);
+
+// An entry in the deadline heap. Reverse ordering, so that the heap yields the earliest first.
+#[derive(PartialEq, Eq)]
+struct Deadline {
+    time: Instant,
+    handle: Handle,
+}
+
+impl ::std::cmp::Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> ::std::cmp::Ordering {
+        match other.time.cmp(&self.time) {
+            ::std::cmp::Ordering::Equal => other.handle.val.cmp(&self.handle.val),
+            ordering => ordering,
+        }
+    }
+}
+
+impl ::std::cmp::PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<::std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct PreciseTimers {
+    // May include entries for timers that have since been removed from `fulfillers`; those are
+    // skipped when they reach the top.
+    deadlines: BinaryHeap<Deadline>,
+    fulfillers: HandleTable<Box<PromiseFulfiller<()>>>,
+    timerfd: ::mio::deprecated::unix::Io,
+
+    // The deadline for which the timerfd is set.
+    armed_for: Option<Instant>,
+}
+
+impl PreciseTimers {
+    /// Creates the timerfd. The caller registers it with the reactor under `TOKEN`.
+    pub fn new() -> ::std::io::Result<PreciseTimers> {
+        let timerfd = try!(sys::create());
+        Ok(PreciseTimers {
+            deadlines: BinaryHeap::new(),
+            fulfillers: HandleTable::new(),
+            timerfd: timerfd,
+            armed_for: None,
+        })
+    }
+
+    pub fn timerfd(&self) -> &::mio::deprecated::unix::Io {
+        &self.timerfd
+    }
+
+    /// The number of timers that have neither fired nor been removed.
+    pub fn len(&self) -> usize {
+        self.fulfillers.len()
+    }
+
+    pub fn insert(&mut self, time: Instant, fulfiller: Box<PromiseFulfiller<()>>) -> Handle {
+        let handle = self.fulfillers.push(fulfiller);
+        self.deadlines.push(Deadline { time: time, handle: handle });
+        self.rearm();
+        return handle;
+    }
+
+    /// Removes a timer, returning its fulfiller, or `None` if it has already fired or been
+    /// removed. The timerfd stays armed; if it was armed for this timer, it goes off for nothing.
+    pub fn remove(&mut self, handle: Handle) -> Option<Box<PromiseFulfiller<()>>> {
+        self.fulfillers.remove(handle)
+    }
+
+    /// Appends the fulfillers of the timers that are due by `now` to `expired`, earliest first,
+    /// and rearms the timerfd for the next one.
+    pub fn expire(&mut self, now: Instant, expired: &mut Vec<Box<PromiseFulfiller<()>>>) {
+        // Drain whatever the timerfd has counted, so that it can signal again.
+        let mut buf = [0u8; 8];
+        let _ = ::mio::deprecated::TryRead::try_read(&mut self.timerfd, &mut buf);
+
+        while self.deadlines.peek().map_or(false, |deadline| deadline.time <= now) {
+            let deadline = self.deadlines.pop().unwrap();
+            if let Some(fulfiller) = self.fulfillers.remove(deadline.handle) {
+                expired.push(fulfiller);
+            }
+        }
+        self.armed_for = None;
+        self.rearm();
+    }
+
+    /// Sets the timerfd for the earliest live deadline, if it is not already set for it.
+    fn rearm(&mut self) {
+        while let Some(handle) = self.deadlines.peek().map(|deadline| deadline.handle) {
+            if self.fulfillers.get(handle).is_some() {
+                break;
+            }
+            self.deadlines.pop();
+        }
+        let next = self.deadlines.peek().map(|deadline| deadline.time);
+        if next == self.armed_for {
+            return;
+        }
+        let delay = next.map(|time| {
+            let now = Instant::now();
+            // A zero delay would disarm the timerfd.
+            if time > now { time - now } else { Duration::new(0, 1) }
+        });
+        let _ = sys::set(&self.timerfd, delay);
+        self.armed_for = next;
+    }
+}
+
+/// The precision with which precise timers fire, as reported by the system for the monotonic
+/// clock, or `None` if precise timers are not supported.
+pub fn resolution() -> Option<Duration> {
+    sys::resolution()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::{c_int, c_long};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    const CLOCK_MONOTONIC: c_int = 1;
+    const TFD_NONBLOCK: c_int = 0o4000;
+    const TFD_CLOEXEC: c_int = 0o2000000;
+
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    #[repr(C)]
+    struct Itimerspec {
+        it_interval: Timespec,
+        it_value: Timespec,
+    }
+
+    extern "C" {
+        fn timerfd_create(clockid: c_int, flags: c_int) -> c_int;
+        fn timerfd_settime(fd: c_int, flags: c_int, new_value: *const Itimerspec,
+                           old_value: *mut Itimerspec) -> c_int;
+        fn clock_getres(clockid: c_int, res: *mut Timespec) -> c_int;
+    }
+
+    pub fn create() -> ::std::io::Result<::mio::deprecated::unix::Io> {
+        let fd = unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(::std::io::Error::last_os_error());
+        }
+        Ok(::io::io_from_raw_fd(fd))
+    }
+
+    /// Arms the timerfd to go off once after `delay`, or disarms it if `delay` is `None`.
+    pub fn set(timerfd: &::mio::deprecated::unix::Io, delay: Option<Duration>) -> ::std::io::Result<()> {
+        let value = match delay {
+            Some(delay) => Timespec { tv_sec: delay.as_secs() as c_long,
+                                      tv_nsec: delay.subsec_nanos() as c_long },
+            None => Timespec { tv_sec: 0, tv_nsec: 0 },
+        };
+        let spec = Itimerspec { it_interval: Timespec { tv_sec: 0, tv_nsec: 0 }, it_value: value };
+        let result = unsafe {
+            timerfd_settime(timerfd.as_raw_fd(), 0, &spec, ::std::ptr::null_mut())
+        };
+        if result < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    pub fn resolution() -> Option<Duration> {
+        let mut res = Timespec { tv_sec: 0, tv_nsec: 0 };
+        if unsafe { clock_getres(CLOCK_MONOTONIC, &mut res) } < 0 {
+            return None;
+        }
+        Some(Duration::new(res.tv_sec as u64, res.tv_nsec as u32))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::time::Duration;
+
+    pub fn create() -> ::std::io::Result<::mio::deprecated::unix::Io> {
+        Err(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                  "precise timers are not supported on this platform"))
+    }
+
+    pub fn set(_timerfd: &::mio::deprecated::unix::Io, _delay: Option<Duration>) -> ::std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn resolution() -> Option<Duration> {
+        None
+    }
+}
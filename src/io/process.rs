@@ -0,0 +1,330 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Child processes.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::rc::Rc;
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use io::{LoopWaker, PipeReader, PipeWriter};
+use io::pipe::{reader_from_fd, writer_from_fd};
+use {EventLoop, Promise, PromiseFulfiller, Result, new_promise_and_fulfiller};
+
+/// Builds and spawns a child process, like `std::process::Command`, but with async pipes to the
+/// child and a promise for its exit.
+///
+/// Children are reaped by a single thread for the whole process. The first `spawn()` installs a
+/// `SIGCHLD` handler, replacing any that was there, which does nothing but write a byte to a pipe
+/// that the thread reads. On each byte, the thread checks each of the children that it was given
+/// with a nonblocking `waitpid()` on that child's ID, so exits of processes that other code
+/// started are left for that code to collect. The thread hands each exit back to the event loop
+/// that spawned the child, and wakes it with a `LoopWaker`.
+pub struct Command {
+    inner: ::std::process::Command,
+}
+
+impl Command {
+    /// Starts describing a process that runs `program`. By default, its standard streams are
+    /// inherited from this process.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        Command { inner: ::std::process::Command::new(program) }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Command {
+        self.inner.args(args);
+        self
+    }
+
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut Command
+        where K: AsRef<OsStr>, V: AsRef<OsStr>
+    {
+        self.inner.env(key, value);
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    /// Sets up the child's standard input. With `Stdio::piped()`, `Child::stdin` is an async
+    /// stream that writes to it.
+    pub fn stdin(&mut self, stdin: Stdio) -> &mut Command {
+        self.inner.stdin(stdin);
+        self
+    }
+
+    /// Sets up the child's standard output. With `Stdio::piped()`, `Child::stdout` is an async
+    /// stream that reads from it.
+    pub fn stdout(&mut self, stdout: Stdio) -> &mut Command {
+        self.inner.stdout(stdout);
+        self
+    }
+
+    /// Sets up the child's standard error. With `Stdio::piped()`, `Child::stderr` is an async
+    /// stream that reads from it.
+    pub fn stderr(&mut self, stderr: Stdio) -> &mut Command {
+        self.inner.stderr(stderr);
+        self
+    }
+
+    /// Starts the child. Must be called on a thread with an event loop.
+    pub fn spawn(&mut self) -> Result<Child> {
+        let mut child = try!(self.inner.spawn());
+        let stdin = match child.stdin.take() {
+            Some(stdin) => Some(try!(writer_from_fd(stdin.into_raw_fd()))),
+            None => None,
+        };
+        let stdout = match child.stdout.take() {
+            Some(stdout) => Some(try!(reader_from_fd(stdout.into_raw_fd()))),
+            None => None,
+        };
+        let stderr = match child.stderr.take() {
+            Some(stderr) => Some(try!(reader_from_fd(stderr.into_raw_fd()))),
+            None => None,
+        };
+        let id = child.id();
+        let exit = try!(reap(child));
+        return Ok(Child { stdin: stdin, stdout: stdout, stderr: stderr, id: id, exit: exit });
+    }
+}
+
+/// A running child process, as started by `Command::spawn()`.
+///
+/// The child is reaped once it exits, whether or not anyone waits for it, so dropping a `Child`
+/// doesn't leave a zombie behind. It doesn't kill the child either.
+pub struct Child {
+    /// The child's standard input, if it was set to `Stdio::piped()`.
+    pub stdin: Option<PipeWriter>,
+
+    /// The child's standard output, if it was set to `Stdio::piped()`.
+    pub stdout: Option<PipeReader>,
+
+    /// The child's standard error, if it was set to `Stdio::piped()`.
+    pub stderr: Option<PipeReader>,
+
+    id: u32,
+    exit: Promise<ExitStatus>,
+}
+
+impl Child {
+    /// Returns the child's process ID.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Resolves once the child has exited. Any streams still in the `Child` are closed first, so
+    /// a child that reads its input to the end isn't left waiting for more; take the ones you
+    /// need out of it before calling this.
+    pub fn wait(self) -> Promise<ExitStatus> {
+        return self.exit;
+    }
+}
+
+/// Hands `child` to the reaper thread, and returns a promise for its status.
+fn reap(child: ::std::process::Child) -> Result<Promise<ExitStatus>> {
+    let sigchld_pipe = try!(start_reaper());
+    let (promise, fulfiller) = new_promise_and_fulfiller();
+    let (id, exits, waker) = with_exits(move |exits| {
+        let id = exits.next_id;
+        exits.next_id += 1;
+        exits.waiting.insert(id, fulfiller);
+        (id, exits.sender.clone(), exits.waker.clone())
+    });
+    REAPING.lock().unwrap().push(Reaping { child: child, id: id, exits: exits, waker: waker });
+
+    // The child may have exited before it was on the list, in which case its `SIGCHLD` has come
+    // and gone. Pretend it came now.
+    let _ = ::nix::unistd::write(sigchld_pipe, &[0u8]);
+    return Ok(promise);
+}
+
+/// A child that the reaper thread is waiting for, and where to send its status.
+struct Reaping {
+    child: ::std::process::Child,
+    id: u64,
+    exits: Sender<Exit>,
+    waker: LoopWaker,
+}
+
+/// The children of every event loop in the process that have yet to exit.
+static REAPING: Mutex<Vec<Reaping>> = Mutex::new(Vec::new());
+
+static START_REAPER: Once = Once::new();
+
+/// The writing end of the pipe that the `SIGCHLD` handler writes to, or -1 if the reaper thread
+/// failed to start.
+static SIGCHLD_PIPE: AtomicIsize = AtomicIsize::new(-1);
+
+/// Starts the reaper thread and installs the `SIGCHLD` handler, the first time it's called.
+/// Returns the writing end of the pipe.
+fn start_reaper() -> Result<RawFd> {
+    START_REAPER.call_once(|| {
+        let _ = try_start_reaper();
+    });
+    match SIGCHLD_PIPE.load(Ordering::SeqCst) {
+        -1 => Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                 "failed to start the child reaper"))),
+        fd => Ok(fd as RawFd),
+    }
+}
+
+fn try_start_reaper() -> ::nix::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, O_CLOEXEC, O_NONBLOCK};
+    use nix::sys::signal::{sigaction, SigAction, SigHandler, SigSet, SA_NOCLDSTOP, SA_RESTART,
+                           SIGCHLD};
+
+    // The handler must never block, so the writing end is nonblocking. If the pipe is full, the
+    // reaper has a wakeup coming anyway.
+    let (read_fd, write_fd) = try!(::nix::unistd::pipe2(O_CLOEXEC));
+    try!(fcntl(write_fd, FcntlArg::F_SETFL(O_NONBLOCK)));
+
+    let started = ::std::thread::Builder::new().name("gj-reaper".to_string()).spawn(move || {
+        reaper_loop(read_fd)
+    });
+    if started.is_err() {
+        let _ = ::nix::unistd::close(read_fd);
+        let _ = ::nix::unistd::close(write_fd);
+        return Err(::nix::Error::Sys(::nix::Errno::EAGAIN));
+    }
+
+    // `SA_RESTART` keeps the signal from interrupting blocking calls on other threads.
+    SIGCHLD_PIPE.store(write_fd as isize, Ordering::SeqCst);
+    let action = SigAction::new(SigHandler::Handler(on_sigchld), SA_NOCLDSTOP | SA_RESTART,
+                                SigSet::empty());
+    if let Err(error) = unsafe { sigaction(SIGCHLD, &action) } {
+        SIGCHLD_PIPE.store(-1, Ordering::SeqCst);
+        return Err(error);
+    }
+    return Ok(());
+}
+
+extern "C" fn on_sigchld(_: ::libc::c_int) {
+    let fd = SIGCHLD_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let _ = ::nix::unistd::write(fd as RawFd, &[0u8]);
+    }
+}
+
+/// Waits for `SIGCHLD`s on the pipe, and checks on the children each time one arrives.
+fn reaper_loop(read_fd: RawFd) {
+    let mut buf = [0u8; 64];
+    loop {
+        match ::nix::unistd::read(read_fd, &mut buf) {
+            Ok(0) => return,
+            Ok(_) | Err(::nix::Error::Sys(::nix::Errno::EINTR)) => {}
+            Err(_) => return,
+        }
+        let mut reaping = REAPING.lock().unwrap();
+        let mut i = 0;
+        while i < reaping.len() {
+            let status = match reaping[i].child.try_wait() {
+                Ok(None) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Some(status)) => Ok(status),
+                Err(error) => Err(error),
+            };
+            // The loop may be gone, in which case nobody is waiting for the status.
+            let exited = reaping.swap_remove(i);
+            let _ = exited.exits.send((exited.id, status));
+            let _ = exited.waker.wake();
+        }
+    }
+}
+
+type Exit = (u64, ::std::io::Result<ExitStatus>);
+
+/// The children of the current thread's event loop that have yet to exit, and the queue through
+/// which the reaper thread hands back their statuses.
+struct Exits {
+    sender: Sender<Exit>,
+    receiver: Receiver<Exit>,
+    waker: LoopWaker,
+    next_id: u64,
+    waiting: HashMap<u64, Box<PromiseFulfiller<ExitStatus>>>,
+
+    // Whether there is an `EventLoop::on_wake()` callback registered, which there is exactly
+    // while there are children in `waiting`. See `io::worker_pool`.
+    listening: bool,
+}
+
+thread_local!(static EXITS: RefCell<Option<Rc<RefCell<Exits>>>> = const { RefCell::new(None) });
+
+fn with_exits<F, R>(func: F) -> R where F: FnOnce(&mut Exits) -> R {
+    let exits = EXITS.with(|exits| {
+        if let Some(ref exits) = *exits.borrow() {
+            return exits.clone();
+        }
+        let (sender, receiver) = channel();
+        let new_exits = Rc::new(RefCell::new(Exits {
+            sender: sender, receiver: receiver, waker: LoopWaker::current(), next_id: 0,
+            waiting: HashMap::new(), listening: false,
+        }));
+        *exits.borrow_mut() = Some(new_exits.clone());
+        EventLoop::on_teardown(|| EXITS.with(|exits| *exits.borrow_mut() = None));
+        new_exits
+    });
+    let result = func(&mut *exits.borrow_mut());
+
+    let listen = {
+        let mut exits = exits.borrow_mut();
+        let listen = !exits.listening && !exits.waiting.is_empty();
+        exits.listening = exits.listening || listen;
+        listen
+    };
+    if listen {
+        EventLoop::on_wake(move || {
+            let mut exits = exits.borrow_mut();
+            exits.deliver();
+            exits.listening = !exits.waiting.is_empty();
+            exits.listening
+        });
+    }
+    return result;
+}
+
+impl Exits {
+    /// Fulfills the promises of the children that have exited.
+    fn deliver(&mut self) {
+        while let Ok((id, status)) = self.receiver.try_recv() {
+            if let Some(fulfiller) = self.waiting.remove(&id) {
+                match status {
+                    Ok(status) => fulfiller.fulfill(status),
+                    Err(error) => fulfiller.reject(Box::new(error)),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Connecting through SOCKS5 and HTTP proxies.
+
+use io::{AsyncRead, AsyncWrite, NetworkAddress, TcpStream};
+use Promise;
+
+/// The protocol that a `ProxyConnector` speaks to its proxy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS version 5, as in RFC 1928.
+    Socks5,
+
+    /// An HTTP proxy's `CONNECT` method.
+    HttpConnect,
+}
+
+/// Opens connections through a proxy. The proxy resolves the target's host name, so it works
+/// for names that only the proxy can resolve.
+#[derive(Clone, Debug)]
+pub struct ProxyConnector {
+    proxy: NetworkAddress,
+    kind: ProxyKind,
+    credentials: Option<(String, String)>,
+}
+
+/// The longest HTTP response head that `ProxyKind::HttpConnect` accepts from the proxy.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+impl ProxyConnector {
+    pub fn new(proxy: NetworkAddress, kind: ProxyKind) -> ProxyConnector {
+        ProxyConnector { proxy: proxy, kind: kind, credentials: None }
+    }
+
+    /// Authenticates to the proxy with `username` and `password`: with the username/password
+    /// method of RFC 1929 for SOCKS5, and with a `Proxy-Authorization: Basic` header for HTTP.
+    pub fn credentials(mut self, username: &str, password: &str) -> ProxyConnector {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Connects to the proxy and asks it for a connection to `host` on `port`. Resolves to a
+    /// stream that is ready to talk to the target once the proxy has agreed.
+    pub fn connect(&self, host: &str, port: u16) -> Promise<TcpStream> {
+        let kind = self.kind;
+        let credentials = self.credentials.clone();
+        let host = host.to_string();
+        return self.proxy.connect().then(move |stream| {
+            return Ok(match kind {
+                ProxyKind::Socks5 => socks5_handshake(stream, host, port, credentials),
+                ProxyKind::HttpConnect => http_connect_handshake(stream, host, port, credentials),
+            });
+        });
+    }
+}
+
+fn proxy_error(message: String) -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, message))
+}
+
+fn socks5_handshake(stream: TcpStream, host: String, port: u16,
+                    credentials: Option<(String, String)>) -> Promise<TcpStream> {
+    let greeting = if credentials.is_some() { vec![5, 2, 0, 2] } else { vec![5, 1, 0] };
+    return stream.write(greeting).then(move |(stream, _)| {
+        return Ok(stream.read_exact(vec![0u8; 2]));
+    }).then(move |(stream, reply)| {
+        if reply[0] != 5 {
+            return Err(proxy_error(format!("not a SOCKS5 proxy (version {})", reply[0])));
+        }
+        return Ok(match (reply[1], credentials) {
+            (0, _) => Promise::fulfilled(stream),
+            (2, Some((username, password))) => socks5_authenticate(stream, username, password),
+            (_, _) => Promise::rejected(proxy_error(
+                "the SOCKS5 proxy accepted none of the offered authentication methods".to_string())),
+        });
+    }).then(move |stream| {
+        let mut request = vec![5, 1, 0];
+        match host.parse::<::std::net::IpAddr>() {
+            Ok(::std::net::IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend(ip.octets().iter().cloned());
+            }
+            Ok(::std::net::IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend(ip.octets().iter().cloned());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(proxy_error(format!("host name too long for SOCKS5: {}", host)));
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend(host.bytes());
+            }
+        }
+        request.push((port >> 8) as u8);
+        request.push(port as u8);
+        return Ok(stream.write(request).then(|(stream, _)| {
+            return Ok(stream.read_exact(vec![0u8; 5]));
+        }));
+    }).then(|(stream, reply)| {
+        if reply[1] != 0 {
+            return Err(proxy_error(format!("the SOCKS5 proxy refused the connection: {}",
+                                           socks5_reply_message(reply[1]))));
+        }
+        // The reply ends with the address that the proxy bound, which we don't need. `reply`
+        // already holds its first byte.
+        let remaining = match reply[3] {
+            1 => 4 - 1 + 2,
+            3 => reply[4] as usize + 2,
+            4 => 16 - 1 + 2,
+            kind => return Err(proxy_error(format!("unknown SOCKS5 address type {}", kind))),
+        };
+        return Ok(stream.read_exact(vec![0u8; remaining]).map(|(stream, _)| Ok(stream)));
+    });
+}
+
+fn socks5_authenticate(stream: TcpStream, username: String,
+                       password: String) -> Promise<TcpStream> {
+    if username.len() > 255 || password.len() > 255 {
+        return Promise::rejected(proxy_error(
+            "SOCKS5 usernames and passwords are limited to 255 bytes".to_string()));
+    }
+    let mut request = vec![1, username.len() as u8];
+    request.extend(username.bytes());
+    request.push(password.len() as u8);
+    request.extend(password.bytes());
+    return stream.write(request).then(|(stream, _)| {
+        return Ok(stream.read_exact(vec![0u8; 2]));
+    }).map(|(stream, reply)| {
+        if reply[1] != 0 {
+            return Err(proxy_error("the SOCKS5 proxy rejected the credentials".to_string()));
+        }
+        return Ok(stream);
+    });
+}
+
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn http_connect_handshake(stream: TcpStream, host: String, port: u16,
+                          credentials: Option<(String, String)>) -> Promise<TcpStream> {
+    let authority = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((username, password)) = credentials {
+        let token = base64(format!("{}:{}", username, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    return stream.write(request.into_bytes()).then(|(stream, _)| {
+        return Ok(read_response_head(stream, Vec::new()));
+    }).map(|(stream, head)| {
+        let head = String::from_utf8_lossy(&head).into_owned();
+        let status_line = head.lines().next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        let status = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(proxy_error(format!("not an HTTP response: {}", status_line)));
+        }
+        if !status.starts_with('2') {
+            return Err(proxy_error(format!("the HTTP proxy refused the connection: {}",
+                                           status_line)));
+        }
+        return Ok(stream);
+    });
+}
+
+/// Reads the proxy's response up to the blank line that ends its head. This reads a byte at a
+/// time so that nothing the target sends right after is taken from the stream.
+fn read_response_head(stream: TcpStream, mut head: Vec<u8>) -> Promise<(TcpStream, Vec<u8>)> {
+    return stream.read_exact(vec![0u8; 1]).then(move |(stream, byte)| {
+        head.push(byte[0]);
+        if head.ends_with(b"\r\n\r\n") {
+            return Ok(Promise::fulfilled((stream, head)));
+        }
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(proxy_error("the HTTP proxy's response head is too long".to_string()));
+        }
+        return Ok(read_response_head(stream, head));
+    });
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &'static [u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i)) & 63] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    return output;
+}
@@ -0,0 +1,71 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Copying from one stream to another.
+
+use io::{AsyncRead, AsyncWrite, Slice};
+use Promise;
+
+/// The size of the buffer that `pump()` copies through.
+const PUMP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copies everything from `reader` to `writer`, until `reader` reaches EOF. Resolves to both
+/// streams and the number of bytes copied. `writer` is not shut down at the end, so a proxy can
+/// decide for itself whether to half-close the connection.
+///
+/// Each chunk is fully written before the next one is read, so a slow writer slows down the
+/// reads rather than letting data pile up. Dropping the returned promise stops the copy and drops
+/// both streams; a chunk that was read but not yet written is lost.
+pub fn pump<R, W>(reader: R, writer: W) -> Promise<(R, W, u64)>
+    where R: AsyncRead, W: AsyncWrite
+{
+    return pump_internal(reader, writer, vec![0; PUMP_BUFFER_SIZE], None, 0);
+}
+
+/// Like `pump()`, but stops after `limit` bytes. Resolves to fewer than `limit` bytes only if
+/// `reader` reached EOF first. Nothing past the limit is read from `reader`.
+pub fn pump_limited<R, W>(reader: R, writer: W, limit: u64) -> Promise<(R, W, u64)>
+    where R: AsyncRead, W: AsyncWrite
+{
+    let size = ::std::cmp::min(limit, PUMP_BUFFER_SIZE as u64) as usize;
+    return pump_internal(reader, writer, vec![0; size], Some(limit), 0);
+}
+
+fn pump_internal<R, W>(reader: R, writer: W, buf: Vec<u8>, limit: Option<u64>,
+                       copied: u64) -> Promise<(R, W, u64)>
+    where R: AsyncRead, W: AsyncWrite
+{
+    let len = match limit {
+        Some(limit) => ::std::cmp::min(limit - copied, buf.len() as u64) as usize,
+        None => buf.len(),
+    };
+    if len == 0 {
+        return Promise::fulfilled((reader, writer, copied));
+    }
+    return reader.try_read(Slice::new(buf, len), 1).then(move |(reader, slice, n)| {
+        if n == 0 {
+            return Ok(Promise::fulfilled((reader, writer, copied)));
+        }
+        return Ok(writer.write(Slice::new(slice.buf, n)).then(move |(writer, slice)| {
+            return Ok(pump_internal(reader, writer, slice.buf, limit, copied + n as u64));
+        }));
+    });
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Serving several listeners from one place.
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use io::{NetworkAddress, ConnectionReceiver, TcpStream};
+use {Error, ErrorHandler, Latch, Promise, Result, TaskInfo, TaskSet};
+
+/// Owns a set of listeners and hands each connection they accept to the handler that was
+/// registered for that listener. All connections run as tasks in a single `TaskSet`, named after
+/// the address they were accepted on, and `shutdown()` stops every listener at once.
+pub struct Router {
+    listeners: TaskSet,
+    connections: Rc<RefCell<TaskSet>>,
+    stopping: Latch,
+}
+
+impl Router {
+    /// Creates a router with no routes. `error_handler` is told about connections whose handlers
+    /// fail and about listeners that fail to accept.
+    pub fn new(error_handler: Box<ErrorHandler>) -> Router {
+        let error_handler = Rc::new(RefCell::new(error_handler));
+        Router {
+            listeners: TaskSet::new(Box::new(SharedErrorHandler { inner: error_handler.clone() })),
+            connections: Rc::new(RefCell::new(
+                TaskSet::new(Box::new(SharedErrorHandler { inner: error_handler })))),
+            stopping: Latch::new(),
+        }
+    }
+
+    /// Starts listening on `address`, passing each accepted connection to `handler`. The promise
+    /// returned by `handler` runs as a task until the connection is done with.
+    pub fn route<F>(&mut self, address: NetworkAddress, handler: F) -> Result<()>
+        where F: 'static,
+              F: FnMut(TcpStream) -> Promise<()>
+    {
+        if self.stopping.is_triggered() {
+            return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                      "router is shutting down")));
+        }
+        let receiver = try!(address.listen());
+        let name = format!("{}", address.socket_addr());
+        let accepting = accept_loop(receiver, Box::new(handler), Rc::downgrade(&self.connections),
+                                    name.clone());
+        self.listeners.add_named(accepting.exclusive_join(self.stopping.when_triggered()),
+                                 &format!("listener {}", name));
+        return Ok(());
+    }
+
+    /// The number of connections whose handlers have not yet finished.
+    pub fn connection_count(&self) -> usize {
+        self.connections.borrow().len()
+    }
+
+    /// Stops accepting connections on every route. The returned promise resolves once the
+    /// handlers of the connections already accepted have all finished. Dropping the router
+    /// instead cancels them.
+    pub fn shutdown(&mut self) -> Promise<()> {
+        self.stopping.trigger();
+        self.connections.borrow_mut().on_empty()
+    }
+}
+
+fn accept_loop(receiver: ConnectionReceiver,
+               mut handler: Box<FnMut(TcpStream) -> Promise<()>>,
+               connections: Weak<RefCell<TaskSet>>,
+               name: String) -> Promise<()> {
+    return receiver.accept().then(move |(receiver, stream)| {
+        match connections.upgrade() {
+            Some(connections) => { connections.borrow_mut().add_named(handler(stream), &name); }
+            None => return Ok(Promise::fulfilled(())),
+        }
+        return Ok(accept_loop(receiver, handler, connections, name));
+    });
+}
+
+struct SharedErrorHandler {
+    inner: Rc<RefCell<Box<ErrorHandler>>>,
+}
+
+impl ErrorHandler for SharedErrorHandler {
+    fn task_failed(&mut self, error: Error) {
+        self.inner.borrow_mut().task_failed(error);
+    }
+
+    fn task_failed_with_info(&mut self, task: &TaskInfo, error: Error) {
+        self.inner.borrow_mut().task_failed_with_info(task, error);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Request/response calls between event loops that are connected by a `SocketStream`, such as
+//! the pair set up by `io::spawn()`.
+//!
+//! Requests and responses are encoded with their `Wire` implementations. Each message is sent as
+//! a frame: an 8-byte call id, a 1-byte status, and a 4-byte payload
+//! length, all big-endian, followed by the payload. The ids let a server answer calls in any
+//! order.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use io::{AsyncRead, AsyncWrite, SocketStream};
+use io::wire::Wire;
+use {new_promise_and_fulfiller, Error, Promise, PromiseFulfiller, TaskSet};
+
+const HEADER_LEN: usize = 13;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+struct Frame {
+    id: u64,
+    status: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn encode(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        for shift in (0..8).rev() {
+            bytes.push((self.id >> (shift * 8)) as u8);
+        }
+        bytes.push(self.status);
+        let len = self.payload.len() as u32;
+        for shift in (0..4).rev() {
+            bytes.push((len >> (shift * 8)) as u8);
+        }
+        bytes.extend(self.payload);
+        return bytes;
+    }
+}
+
+/// Reads the next frame, or `None` if the stream ends cleanly before one starts.
+fn read_frame(stream: SocketStream) -> Promise<(SocketStream, Option<Frame>)> {
+    return stream.try_read(vec![0u8; HEADER_LEN], HEADER_LEN).then(move |(stream, header, n)| {
+        if n == 0 {
+            return Ok(Promise::fulfilled((stream, None)));
+        } else if n < HEADER_LEN {
+            return Err(rpc_error("premature EOF in frame header"));
+        }
+        let id = header[..8].iter().fold(0u64, |id, &b| (id << 8) | b as u64);
+        let status = header[8];
+        let len = header[9..].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        return Ok(stream.read(vec![0u8; len], len).map(move |(stream, payload, _)| {
+            return Ok((stream, Some(Frame { id: id, status: status, payload: payload })));
+        }));
+    });
+}
+
+fn rpc_error(message: &str) -> Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, message))
+}
+
+/// Writes frames one at a time, in the order in which they were sent.
+struct FrameWriter {
+    stream: Rc<RefCell<Option<SocketStream>>>,
+    queue: TaskSet,
+}
+
+impl FrameWriter {
+    fn new(stream: SocketStream) -> FrameWriter {
+        FrameWriter {
+            stream: Rc::new(RefCell::new(Some(stream))),
+            queue: TaskSet::with_limit(Box::new(|error: Error| {
+                panic!("unexpected frame writer failure: {}", error);
+            }), 1),
+        }
+    }
+
+    /// Queues `frame` to be written. The returned promise resolves once it has been. After a
+    /// failed write, all later frames fail too.
+    fn send(&mut self, frame: Frame) -> Promise<()> {
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        let stream = self.stream.clone();
+        self.queue.add_factory(move || {
+            let writer = match stream.borrow_mut().take() {
+                Some(writer) => writer,
+                None => {
+                    fulfiller.reject(rpc_error("rpc stream failed"));
+                    return Promise::fulfilled(());
+                }
+            };
+            return writer.write(frame.encode()).map_else(|(writer, _)| Ok(Ok(writer)),
+                                                         |error| Ok(Err(error))).map(move |result| {
+                match result {
+                    Ok(writer) => {
+                        *stream.borrow_mut() = Some(writer);
+                        fulfiller.fulfill(());
+                    }
+                    Err(error) => fulfiller.reject(error),
+                }
+                return Ok(());
+            });
+        });
+        return promise;
+    }
+}
+
+struct ChannelState {
+    writer: FrameWriter,
+    next_id: u64,
+    pending: HashMap<u64, Box<PromiseFulfiller<Vec<u8>>>>,
+
+    // Set once the channel can no longer be used.
+    failure: Option<String>,
+}
+
+impl ChannelState {
+    fn fail(&mut self, message: String) {
+        for (_, fulfiller) in self.pending.drain() {
+            fulfiller.reject(rpc_error(&message));
+        }
+        self.failure = Some(message);
+    }
+}
+
+/// The calling end of an RPC connection. Calls may be outstanding concurrently. Dropping the
+/// channel closes the connection, which stops the server's `serve()` loop.
+pub struct RpcChannel {
+    state: Rc<RefCell<ChannelState>>,
+    _reader: TaskSet,
+}
+
+impl RpcChannel {
+    pub fn new(stream: SocketStream) -> ::Result<RpcChannel> {
+        let reader = try!(stream.try_clone());
+        let state = Rc::new(RefCell::new(ChannelState {
+            writer: FrameWriter::new(stream),
+            next_id: 0,
+            pending: HashMap::new(),
+            failure: None,
+        }));
+        let mut tasks = TaskSet::new(Box::new(|error: Error| {
+            panic!("unexpected rpc reader failure: {}", error);
+        }));
+        tasks.add(response_loop(reader, Rc::downgrade(&state)));
+        return Ok(RpcChannel { state: state, _reader: tasks });
+    }
+
+    /// Sends `request` to the server and returns a promise for its response. If the server's
+    /// handler fails, the promise is rejected with an error carrying the handler's message.
+    pub fn call<Req, Resp>(&self, request: &Req) -> Promise<Resp> where Req: Wire, Resp: Wire + 'static {
+        let mut bytes = Vec::new();
+        request.encode(&mut bytes);
+        return self.call_bytes(bytes).map(|response| Resp::decode(&response));
+    }
+
+    fn call_bytes(&self, request: Vec<u8>) -> Promise<Vec<u8>> {
+        let state = &mut *self.state.borrow_mut();
+        if let Some(ref message) = state.failure {
+            return Promise::rejected(rpc_error(message));
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        state.pending.insert(id, fulfiller);
+        let weak_state = Rc::downgrade(&self.state);
+        return state.writer.send(Frame { id: id, status: STATUS_OK, payload: request }).then_else(
+            move |()| Ok(promise),
+            move |error| {
+                if let Some(state) = weak_state.upgrade() {
+                    state.borrow_mut().pending.remove(&id);
+                }
+                Err(error)
+            });
+    }
+}
+
+/// Sends `request` over `channel` and returns a promise for the response.
+pub fn remote_call<Req, Resp>(channel: &RpcChannel, request: &Req) -> Promise<Resp>
+    where Req: Wire, Resp: Wire + 'static
+{
+    channel.call(request)
+}
+
+fn response_loop(reader: SocketStream, state: Weak<RefCell<ChannelState>>) -> Promise<()> {
+    let state1 = state.clone();
+    return read_frame(reader).then_else(move |(reader, frame)| {
+        let state_rc = match state.upgrade() {
+            Some(state) => state,
+            None => return Ok(Promise::fulfilled(())),
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => {
+                state_rc.borrow_mut().fail("rpc channel closed".to_string());
+                return Ok(Promise::fulfilled(()));
+            }
+        };
+        let fulfiller = state_rc.borrow_mut().pending.remove(&frame.id);
+        match fulfiller {
+            Some(fulfiller) => {
+                if frame.status == STATUS_OK {
+                    fulfiller.fulfill(frame.payload);
+                } else {
+                    fulfiller.reject(rpc_error(&String::from_utf8_lossy(&frame.payload)));
+                }
+            }
+            None => {} // The caller's id was abandoned after a failed send.
+        }
+        return Ok(response_loop(reader, state));
+    }, move |error| {
+        if let Some(state) = state1.upgrade() {
+            state.borrow_mut().fail(format!("{}", error));
+        }
+        return Ok(Promise::fulfilled(()));
+    });
+}
+
+/// Answers calls arriving on `stream` with `handler`, which may answer several at once. The
+/// returned promise resolves once the other end has closed the connection and the calls already
+/// received have been answered. Dropping it stops the server.
+pub fn serve<Req, Resp, F>(stream: SocketStream, mut handler: F) -> ::Result<Promise<()>>
+    where Req: Wire + 'static, Resp: Wire + 'static,
+          F: 'static,
+          F: FnMut(Req) -> Promise<Resp>
+{
+    let handler = move |request: Vec<u8>| {
+        match Req::decode(&request) {
+            Ok(request) => handler(request).map(|response| {
+                let mut bytes = Vec::new();
+                response.encode(&mut bytes);
+                return Ok(bytes);
+            }),
+            Err(error) => Promise::rejected(error),
+        }
+    };
+    let reader = try!(stream.try_clone());
+    let writer = Rc::new(RefCell::new(FrameWriter::new(stream)));
+    // A response that can't be written has nowhere to go.
+    let calls = TaskSet::new(Box::new(|_error: Error| {}));
+    return Ok(serve_loop(reader, Box::new(handler), writer, calls));
+}
+
+fn serve_loop(reader: SocketStream,
+              mut handler: Box<FnMut(Vec<u8>) -> Promise<Vec<u8>>>,
+              writer: Rc<RefCell<FrameWriter>>,
+              mut calls: TaskSet) -> Promise<()> {
+    return read_frame(reader).then(move |(reader, frame)| {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => {
+                return Ok(calls.on_empty().map(move |()| {
+                    drop(calls);
+                    return Ok(());
+                }));
+            }
+        };
+        let id = frame.id;
+        let response_writer = writer.clone();
+        calls.add(handler(frame.payload).map_else(
+            |response| Ok((STATUS_OK, response)),
+            |error| Ok((STATUS_ERROR, format!("{}", error).into_bytes()))).then(move |(status, payload)| {
+                return Ok(response_writer.borrow_mut().send(Frame { id: id, status: status,
+                                                                    payload: payload }));
+            }));
+        return Ok(serve_loop(reader, handler, writer, calls));
+    });
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Socket options and calls that neither mio nor the standard library give us.
+
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+const IPPROTO_TCP: c_int = 6;
+const IPPROTO_IPV6: c_int = 41;
+const TCP_NODELAY: c_int = 1;
+const MSG_PEEK: c_int = 2;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod consts {
+    use std::os::raw::c_int;
+    pub const SOL_SOCKET: c_int = 1;
+    pub const SO_ERROR: c_int = 4;
+    pub const SO_REUSEPORT: c_int = 15;
+    pub const SO_SNDBUF: c_int = 7;
+    pub const SO_RCVBUF: c_int = 8;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const SO_LINGER: c_int = 13;
+    pub const TCP_KEEPIDLE: c_int = 4;
+    pub const TCP_KEEPINTVL: c_int = 5;
+    pub const TCP_KEEPCNT: c_int = 6;
+    pub const IPV6_V6ONLY: c_int = 26;
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod consts {
+    use std::os::raw::c_int;
+    pub const SOL_SOCKET: c_int = 0xffff;
+    pub const SO_ERROR: c_int = 0x1007;
+    pub const SO_REUSEPORT: c_int = 0x200;
+    pub const SO_SNDBUF: c_int = 0x1001;
+    pub const SO_RCVBUF: c_int = 0x1002;
+    pub const SO_KEEPALIVE: c_int = 0x8;
+    // SO_LINGER_SEC; plain SO_LINGER counts in clock ticks on these systems.
+    pub const SO_LINGER: c_int = 0x1080;
+    pub const TCP_KEEPIDLE: c_int = 0x10;
+    pub const TCP_KEEPINTVL: c_int = 0x101;
+    pub const TCP_KEEPCNT: c_int = 0x102;
+    pub const IPV6_V6ONLY: c_int = 27;
+}
+
+use self::consts::*;
+
+#[repr(C)]
+#[derive(Default)]
+struct Linger {
+    onoff: c_int,
+    linger: c_int,
+}
+
+extern "C" {
+    fn setsockopt(socket: c_int, level: c_int, name: c_int, value: *const c_void,
+                  len: u32) -> c_int;
+    fn getsockopt(socket: c_int, level: c_int, name: c_int, value: *mut c_void,
+                  len: *mut u32) -> c_int;
+    #[link_name = "shutdown"]
+    fn os_shutdown(socket: c_int, how: c_int) -> c_int;
+    fn recv(socket: c_int, buf: *mut c_void, len: usize, flags: c_int) -> isize;
+}
+
+fn set<T>(fd: RawFd, level: c_int, name: c_int, value: T) -> ::std::io::Result<()> {
+    let result = unsafe {
+        setsockopt(fd, level, name, &value as *const T as *const c_void,
+                   ::std::mem::size_of::<T>() as u32)
+    };
+    if result < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(()) }
+}
+
+fn get<T: Default>(fd: RawFd, level: c_int, name: c_int) -> ::std::io::Result<T> {
+    let mut value = T::default();
+    let mut len = ::std::mem::size_of::<T>() as u32;
+    let result = unsafe {
+        getsockopt(fd, level, name, &mut value as *mut T as *mut c_void, &mut len)
+    };
+    if result < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(value) }
+}
+
+/// How a connection checks that an idle peer is still there.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the connection has to be idle before the first probe. Rounded down to seconds.
+    pub idle: Duration,
+
+    /// The time between probes. Rounded down to seconds.
+    pub interval: Duration,
+
+    /// How many unanswered probes it takes to drop the connection.
+    pub retries: u32,
+}
+
+/// Options to set on a socket as it is created. Options left as `None` keep the system default.
+/// See `NetworkAddress::with_socket_options()`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Option<Keepalive>>,
+    linger: Option<Option<Duration>>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    reuse_port: Option<bool>,
+}
+
+impl SocketOptions {
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// See `TcpStream::set_nodelay()`.
+    pub fn nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// See `TcpStream::set_keepalive()`.
+    pub fn keepalive(mut self, keepalive: Option<Keepalive>) -> SocketOptions {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// See `TcpStream::set_linger()`.
+    pub fn linger(mut self, linger: Option<Duration>) -> SocketOptions {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// See `TcpStream::set_send_buffer_size()`.
+    pub fn send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// See `TcpStream::set_recv_buffer_size()`.
+    pub fn recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, which lets several listening sockets bind the same port as long as
+    /// all of them set it. The kernel spreads incoming connections across them, so each of
+    /// several event loops can have its own listener. See `io::listen_sharded()`.
+    pub fn reuse_port(mut self, reuse_port: bool) -> SocketOptions {
+        self.reuse_port = Some(reuse_port);
+        self
+    }
+
+    /// Sets the options that were asked for on `fd`.
+    pub fn apply(&self, fd: RawFd) -> ::std::io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            try!(set_nodelay(fd, nodelay));
+        }
+        if let Some(keepalive) = self.keepalive {
+            try!(set_keepalive(fd, keepalive));
+        }
+        if let Some(linger) = self.linger {
+            try!(set_linger(fd, linger));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(set_send_buffer_size(fd, size));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(set_recv_buffer_size(fd, size));
+        }
+        if let Some(reuse_port) = self.reuse_port {
+            try!(set(fd, SOL_SOCKET, SO_REUSEPORT, reuse_port as c_int));
+        }
+        Ok(())
+    }
+}
+
+/// Sets whether an IPv6 socket accepts only IPv6 traffic, rather than also accepting IPv4
+/// traffic through mapped addresses. Has to be set before the socket is bound.
+pub fn set_v6_only(fd: RawFd, v6_only: bool) -> ::std::io::Result<()> {
+    set(fd, IPPROTO_IPV6, IPV6_V6ONLY, v6_only as c_int)
+}
+
+pub fn set_nodelay(fd: RawFd, nodelay: bool) -> ::std::io::Result<()> {
+    set(fd, IPPROTO_TCP, TCP_NODELAY, nodelay as c_int)
+}
+
+pub fn nodelay(fd: RawFd) -> ::std::io::Result<bool> {
+    Ok(try!(get::<c_int>(fd, IPPROTO_TCP, TCP_NODELAY)) != 0)
+}
+
+pub fn set_keepalive(fd: RawFd, keepalive: Option<Keepalive>) -> ::std::io::Result<()> {
+    match keepalive {
+        None => set(fd, SOL_SOCKET, SO_KEEPALIVE, 0 as c_int),
+        Some(keepalive) => {
+            try!(set(fd, IPPROTO_TCP, TCP_KEEPIDLE, to_secs(keepalive.idle)));
+            try!(set(fd, IPPROTO_TCP, TCP_KEEPINTVL, to_secs(keepalive.interval)));
+            try!(set(fd, IPPROTO_TCP, TCP_KEEPCNT, keepalive.retries as c_int));
+            set(fd, SOL_SOCKET, SO_KEEPALIVE, 1 as c_int)
+        }
+    }
+}
+
+pub fn keepalive(fd: RawFd) -> ::std::io::Result<Option<Keepalive>> {
+    if try!(get::<c_int>(fd, SOL_SOCKET, SO_KEEPALIVE)) == 0 {
+        return Ok(None);
+    }
+    let idle = try!(get::<c_int>(fd, IPPROTO_TCP, TCP_KEEPIDLE));
+    let interval = try!(get::<c_int>(fd, IPPROTO_TCP, TCP_KEEPINTVL));
+    let retries = try!(get::<c_int>(fd, IPPROTO_TCP, TCP_KEEPCNT));
+    Ok(Some(Keepalive { idle: Duration::from_secs(idle as u64),
+                        interval: Duration::from_secs(interval as u64),
+                        retries: retries as u32 }))
+}
+
+pub fn set_linger(fd: RawFd, linger: Option<Duration>) -> ::std::io::Result<()> {
+    let value = match linger {
+        None => Linger { onoff: 0, linger: 0 },
+        Some(duration) => Linger { onoff: 1, linger: to_secs(duration) },
+    };
+    set(fd, SOL_SOCKET, SO_LINGER, value)
+}
+
+pub fn linger(fd: RawFd) -> ::std::io::Result<Option<Duration>> {
+    let value = try!(get::<Linger>(fd, SOL_SOCKET, SO_LINGER));
+    if value.onoff == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_secs(value.linger as u64)))
+    }
+}
+
+pub fn set_send_buffer_size(fd: RawFd, size: usize) -> ::std::io::Result<()> {
+    set(fd, SOL_SOCKET, SO_SNDBUF, to_c_int(size))
+}
+
+pub fn send_buffer_size(fd: RawFd) -> ::std::io::Result<usize> {
+    Ok(try!(get::<c_int>(fd, SOL_SOCKET, SO_SNDBUF)) as usize)
+}
+
+pub fn set_recv_buffer_size(fd: RawFd, size: usize) -> ::std::io::Result<()> {
+    set(fd, SOL_SOCKET, SO_RCVBUF, to_c_int(size))
+}
+
+pub fn recv_buffer_size(fd: RawFd) -> ::std::io::Result<usize> {
+    Ok(try!(get::<c_int>(fd, SOL_SOCKET, SO_RCVBUF)) as usize)
+}
+
+fn to_secs(duration: Duration) -> c_int {
+    to_c_int(duration.as_secs() as usize)
+}
+
+fn to_c_int(value: usize) -> c_int {
+    ::std::cmp::min(value, c_int::max_value() as usize) as c_int
+}
+
+/// Shuts down one or both directions of a connected socket.
+pub fn shutdown(fd: RawFd, how: ::std::net::Shutdown) -> ::std::io::Result<()> {
+    let how = match how {
+        ::std::net::Shutdown::Read => 0,
+        ::std::net::Shutdown::Write => 1,
+        ::std::net::Shutdown::Both => 2,
+    };
+    if unsafe { os_shutdown(fd, how) } < 0 {
+        Err(::std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns and clears the socket's pending error, which is how a nonblocking `connect()`
+/// reports failure.
+pub fn take_error(fd: RawFd) -> ::std::io::Result<Option<::std::io::Error>> {
+    match try!(get::<c_int>(fd, SOL_SOCKET, SO_ERROR)) {
+        0 => Ok(None),
+        errno => Ok(Some(::std::io::Error::from_raw_os_error(errno))),
+    }
+}
+
+/// Copies the data waiting on the socket into `buf` without taking it off the socket.
+pub fn peek(fd: RawFd, buf: &mut [u8]) -> ::std::io::Result<usize> {
+    let result = unsafe { recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), MSG_PEEK) };
+    if result < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(result as usize) }
+}
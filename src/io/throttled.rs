@@ -0,0 +1,213 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Bandwidth limits for async streams.
+
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+use io::{AsyncRead, AsyncWrite, Timer};
+use Promise;
+
+/// Wraps a stream and limits how many bytes per second pass through it in each direction, for
+/// simulating slow links in tests or for staying polite to a server. The limits are enforced with
+/// the event loop's `Timer`, so a clock installed by `Timer::set_clock()` drives them too.
+///
+/// Data moves in chunks of about a tenth of a second's worth, so a stream that is limited to a
+/// few bytes per second still makes steady progress.
+pub struct Throttled<S> {
+    inner: S,
+    read: Option<Pacer>,
+    write: Option<Pacer>,
+}
+
+impl <S> Throttled<S> {
+    /// Wraps `inner` without any limits. Use `read_limit()` and `write_limit()` to add them.
+    pub fn new(inner: S) -> Throttled<S> {
+        Throttled { inner: inner, read: None, write: None }
+    }
+
+    /// Limits reads to `bytes_per_second`. Panics if it is zero.
+    pub fn read_limit(mut self, bytes_per_second: u64) -> Throttled<S> {
+        self.read = Some(Pacer::new(bytes_per_second));
+        self
+    }
+
+    /// Limits writes to `bytes_per_second`. Panics if it is zero.
+    pub fn write_limit(mut self, bytes_per_second: u64) -> Throttled<S> {
+        self.write = Some(Pacer::new(bytes_per_second));
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Spaces out the chunks of one direction of a `Throttled` stream.
+#[derive(Copy, Clone)]
+struct Pacer {
+    bytes_per_second: u64,
+
+    // When the next chunk may go. `None` until the first chunk.
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    fn new(bytes_per_second: u64) -> Pacer {
+        assert!(bytes_per_second > 0, "the rate limit must be positive");
+        Pacer { bytes_per_second: bytes_per_second, next: None }
+    }
+
+    fn chunk_size(&self) -> usize {
+        ::std::cmp::max(1, self.bytes_per_second / 10) as usize
+    }
+
+    /// Resolves when the next chunk may go.
+    fn ready(&self) -> Promise<()> {
+        match self.next {
+            Some(next) if next > Timer.now() => Timer.at(next),
+            _ => Promise::fulfilled(()),
+        }
+    }
+
+    /// Records that `bytes` just went, which pushes back the next chunk by the time they take at
+    /// the limit.
+    fn record(&mut self, bytes: usize) {
+        let now = Timer.now();
+        let start = match self.next {
+            Some(next) if next > now => next,
+            _ => now,
+        };
+        let bytes = bytes as u64;
+        let rate = self.bytes_per_second;
+        let nanos = (bytes % rate) * 1_000_000_000 / rate;
+        self.next = Some(start + Duration::new(bytes / rate, nanos as u32));
+    }
+}
+
+/// The part of `buf` in `start..end`.
+struct Window<T> {
+    buf: T,
+    start: usize,
+    end: usize,
+}
+
+impl <T> Deref for Window<T> where T: Deref<Target=[u8]> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+impl <T> DerefMut for Window<T> where T: DerefMut<Target=[u8]> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.end]
+    }
+}
+
+fn read_internal<S, T>(stream: Throttled<S>, buf: T, already_read: usize,
+                       min_bytes: usize) -> Promise<(Throttled<S>, T, usize)>
+    where S: AsyncRead, T: DerefMut<Target=[u8]>
+{
+    let pacer = match stream.read {
+        None => {
+            let Throttled { inner, read, write } = stream;
+            return inner.try_read(buf, min_bytes).map(move |(inner, buf, n)| {
+                Ok((Throttled { inner: inner, read: read, write: write }, buf, n))
+            });
+        }
+        Some(pacer) => pacer,
+    };
+    if already_read >= buf.len() || (already_read > 0 && already_read >= min_bytes) {
+        return Promise::fulfilled((stream, buf, already_read));
+    }
+
+    return pacer.ready().then(move |()| {
+        let Throttled { inner, read, write } = stream;
+        let end = ::std::cmp::min(buf.len(), already_read + pacer.chunk_size());
+        let window = Window { buf: buf, start: already_read, end: end };
+        return Ok(inner.try_read(window, 1).then(move |(inner, window, n)| {
+            let mut read = read;
+            if let Some(ref mut pacer) = read {
+                pacer.record(n);
+            }
+            let stream = Throttled { inner: inner, read: read, write: write };
+            if n == 0 {
+                return Ok(Promise::fulfilled((stream, window.buf, already_read)));
+            }
+            return Ok(read_internal(stream, window.buf, already_read + n, min_bytes));
+        }));
+    });
+}
+
+fn write_internal<S, T>(stream: Throttled<S>, buf: T,
+                        already_written: usize) -> Promise<(Throttled<S>, T)>
+    where S: AsyncWrite, T: Deref<Target=[u8]>
+{
+    let pacer = match stream.write {
+        None => {
+            let Throttled { inner, read, write } = stream;
+            return inner.write(buf).map(move |(inner, buf)| {
+                Ok((Throttled { inner: inner, read: read, write: write }, buf))
+            });
+        }
+        Some(pacer) => pacer,
+    };
+    if already_written >= buf.len() {
+        return Promise::fulfilled((stream, buf));
+    }
+
+    return pacer.ready().then(move |()| {
+        let Throttled { inner, read, write } = stream;
+        let end = ::std::cmp::min(buf.len(), already_written + pacer.chunk_size());
+        let window = Window { buf: buf, start: already_written, end: end };
+        return Ok(inner.write(window).then(move |(inner, window)| {
+            let mut write = write;
+            if let Some(ref mut pacer) = write {
+                pacer.record(end - already_written);
+            }
+            let stream = Throttled { inner: inner, read: read, write: write };
+            return Ok(write_internal(stream, window.buf, end));
+        }));
+    });
+}
+
+impl <S> AsyncRead for Throttled<S> where S: AsyncRead {
+    fn try_read<T>(self, buf: T, min_bytes: usize) -> Promise<(Self, T, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        return read_internal(self, buf, 0, min_bytes);
+    }
+}
+
+impl <S> AsyncWrite for Throttled<S> where S: AsyncWrite {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return write_internal(self, buf, 0);
+    }
+}
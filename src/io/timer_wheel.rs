@@ -0,0 +1,260 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A hierarchical timer wheel, which keeps the built-in event port's timers so that the reactor
+//! only ever has to know about the nearest deadline.
+//!
+//! Time is measured in ticks of one millisecond since the wheel was created. Level `l` of the
+//! wheel has `SLOTS` slots, each covering `SLOTS^l` ticks; a timer goes in the lowest level
+//! whose span reaches its deadline, and moves down a level each time the wheel reaches the
+//! start of its slot, until it expires from level 0. Each slot is a doubly-linked list of
+//! entries kept in a slab, so inserting and removing a timer are O(1), as is advancing the
+//! wheel by one tick.
+
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = (SLOTS as u64) - 1;
+
+// With six bits per level, this spans 2^36 ticks, a bit over two years. Timers beyond that wait
+// in the top level and are placed again each time their slot comes around.
+const LEVELS: usize = 6;
+
+/// Identifies a timer in a `TimerWheel`. Stays invalid once the timer has expired or been
+/// removed, even if its slot in the slab is reused.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TimerId {
+    index: usize,
+    generation: u32,
+}
+
+struct Entry<T> {
+    value: Option<T>,
+    generation: u32,
+    deadline: u64,
+    level: usize,
+    slot: usize,
+    next: Option<usize>,
+    prev: Option<usize>,
+}
+
+pub struct TimerWheel<T> {
+    start: Instant,
+
+    // Every tick up to and including this one has been processed.
+    now: u64,
+
+    entries: Vec<Entry<T>>,
+    free: Vec<usize>,
+    len: usize,
+
+    // The first entry of each slot of each level.
+    heads: Vec<[Option<usize>; SLOTS]>,
+}
+
+impl <T> TimerWheel<T> {
+    pub fn new(start: Instant) -> TimerWheel<T> {
+        TimerWheel {
+            start: start,
+            now: 0,
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            heads: (0..LEVELS).map(|_| [None; SLOTS]).collect(),
+        }
+    }
+
+    /// The number of timers that have neither expired nor been removed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Adds a timer that expires once `deadline` has passed.
+    pub fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let deadline = self.ticks_until(deadline);
+        let index = match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.value = Some(value);
+                entry.deadline = deadline;
+                index
+            }
+            None => {
+                self.entries.push(Entry { value: Some(value), generation: 0, deadline: deadline,
+                                          level: 0, slot: 0, next: None, prev: None });
+                self.entries.len() - 1
+            }
+        };
+        self.len += 1;
+        self.link(index);
+        TimerId { index: index, generation: self.entries[index].generation }
+    }
+
+    /// Removes a timer, returning its value, or `None` if it has already expired or been removed.
+    pub fn remove(&mut self, id: TimerId) -> Option<T> {
+        match self.entries.get(id.index) {
+            Some(entry) if entry.generation == id.generation && entry.value.is_some() => {}
+            _ => return None,
+        }
+        self.unlink(id.index);
+        return self.release(id.index);
+    }
+
+    /// Processes the ticks up to `now`, appending the values of the timers that expire to
+    /// `expired` in deadline order. Timers that expire on the same tick are mostly, but not
+    /// always, in the order in which they were inserted.
+    pub fn advance(&mut self, now: Instant, expired: &mut Vec<T>) {
+        let target = self.ticks_since_start(now);
+        while self.now < target {
+            if self.len == 0 {
+                self.now = target;
+                break;
+            }
+            self.now += 1;
+            self.cascade();
+            let slot = (self.now & SLOT_MASK) as usize;
+            let first = expired.len();
+            while let Some(index) = self.heads[0][slot] {
+                self.unlink(index);
+                if let Some(value) = self.release(index) {
+                    expired.push(value);
+                }
+            }
+            // Slots are built newest first.
+            expired[first..].reverse();
+        }
+    }
+
+    /// Returns when the wheel next needs to be advanced: the deadline of the next timer to
+    /// expire if it is in level 0, or else the time at which the nearest occupied slot of a
+    /// higher level moves down. `None` if there are no timers.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut best: Option<u64> = None;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            for k in 1..(SLOTS as u64 + 1) {
+                let tick = if level == 0 {
+                    self.now + k
+                } else {
+                    ((self.now >> shift) + k) << shift
+                };
+                if best.map_or(false, |best| tick >= best) {
+                    break;
+                }
+                if self.heads[level][((tick >> shift) & SLOT_MASK) as usize].is_some() {
+                    best = Some(tick);
+                    break;
+                }
+            }
+        }
+        best.map(|tick| self.start + Duration::from_millis(tick))
+    }
+
+    /// Moves the timers in the higher-level slots that start at the current tick down.
+    fn cascade(&mut self) {
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.now & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            let slot = ((self.now >> shift) & SLOT_MASK) as usize;
+            let mut next = self.heads[level][slot].take();
+            while let Some(index) = next {
+                next = self.entries[index].next;
+                self.entries[index].next = None;
+                self.entries[index].prev = None;
+                self.link(index);
+            }
+        }
+    }
+
+    /// Puts an entry in the slot for its deadline.
+    fn link(&mut self, index: usize) {
+        // A deadline that has already been reached expires on the next tick.
+        let deadline = ::std::cmp::max(self.entries[index].deadline, self.now + 1);
+        let delta = deadline - self.now;
+        let mut level = 0;
+        while level + 1 < LEVELS && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot = ((deadline >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+
+        let head = self.heads[level][slot];
+        {
+            let entry = &mut self.entries[index];
+            entry.deadline = deadline;
+            entry.level = level;
+            entry.slot = slot;
+            entry.prev = None;
+            entry.next = head;
+        }
+        if let Some(head) = head {
+            self.entries[head].prev = Some(index);
+        }
+        self.heads[level][slot] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (level, slot, next, prev) = {
+            let entry = &self.entries[index];
+            (entry.level, entry.slot, entry.next, entry.prev)
+        };
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.heads[level][slot] = next,
+        }
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+        self.entries[index].next = None;
+        self.entries[index].prev = None;
+    }
+
+    /// Frees an unlinked entry's slab slot and returns its value.
+    fn release(&mut self, index: usize) -> Option<T> {
+        let entry = &mut self.entries[index];
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(index);
+        self.len -= 1;
+        entry.value.take()
+    }
+
+    /// The number of whole ticks from the start of the wheel to `time`.
+    fn ticks_since_start(&self, time: Instant) -> u64 {
+        if time <= self.start {
+            return 0;
+        }
+        let elapsed = time - self.start;
+        elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+    }
+
+    /// The first tick at or after `time`.
+    fn ticks_until(&self, time: Instant) -> u64 {
+        if time <= self.start {
+            return 0;
+        }
+        super::duration_to_ms(time - self.start)
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! TLS over any stream that implements `AsyncRead` and `AsyncWrite`, using rustls. Requires the
+//! `tls` feature.
+//!
+//! rustls does no I/O of its own: it hands over the ciphertext it wants sent and takes the
+//! ciphertext that arrives. `TlsStream` shuttles that ciphertext through the underlying stream,
+//! so the handshake and the records that follow it never block the event loop.
+
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use io::{AsyncRead, AsyncWrite};
+use {Promise, Result};
+
+/// How many bytes of ciphertext to ask the underlying stream for at a time. Enough for one
+/// maximum-size record.
+const READ_CHUNK_SIZE: usize = 18 * 1024;
+
+/// Starts client sessions.
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<::rustls::ClientConfig>,
+}
+
+impl TlsConnector {
+    pub fn new(config: Arc<::rustls::ClientConfig>) -> TlsConnector {
+        TlsConnector { config: config }
+    }
+
+    /// Runs a client handshake over `stream`, checking the server's certificate against `host`.
+    /// Resolves once the handshake is done. Fails straight away if `host` is not a valid DNS
+    /// name.
+    pub fn connect<S>(&self, host: &str, stream: S) -> Promise<TlsStream<S>>
+        where S: AsyncRead + AsyncWrite
+    {
+        let name = match ::webpki::DNSNameRef::try_from_ascii_str(host) {
+            Ok(name) => name,
+            Err(_) => return Promise::rejected(Box::new(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput, format!("invalid TLS host name: {}", host)))),
+        };
+        let session = ::rustls::ClientSession::new(&self.config, name);
+        return TlsStream::new(stream, Box::new(session)).handshake();
+    }
+}
+
+/// Starts server sessions.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<::rustls::ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: Arc<::rustls::ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor { config: config }
+    }
+
+    /// Runs a server handshake over `stream`, which is typically a freshly accepted connection.
+    /// Resolves once the handshake is done.
+    pub fn accept<S>(&self, stream: S) -> Promise<TlsStream<S>>
+        where S: AsyncRead + AsyncWrite
+    {
+        let session = ::rustls::ServerSession::new(&self.config);
+        return TlsStream::new(stream, Box::new(session)).handshake();
+    }
+}
+
+/// An encrypted stream. Reads and writes plaintext, and reads and writes ciphertext on the
+/// stream underneath.
+pub struct TlsStream<S> {
+    stream: S,
+    session: Box<::rustls::Session>,
+
+    // Whether the underlying stream has reached EOF.
+    eof: bool,
+}
+
+impl <S> TlsStream<S> where S: AsyncRead + AsyncWrite {
+    fn new(stream: S, session: Box<::rustls::Session>) -> TlsStream<S> {
+        TlsStream { stream: stream, session: session, eof: false }
+    }
+
+    /// Returns the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the TLS session, which knows about things like the negotiated protocol and the
+    /// peer's certificates.
+    pub fn session(&self) -> &::rustls::Session {
+        &*self.session
+    }
+
+    /// Tells the peer that no more data is coming, and then returns the underlying stream.
+    pub fn close_notify(mut self) -> Promise<S> {
+        self.session.send_close_notify();
+        return self.flush().map(|stream| Ok(stream.stream));
+    }
+
+    fn handshake(self) -> Promise<TlsStream<S>> {
+        return self.flush().then(|stream| {
+            if !stream.session.is_handshaking() {
+                return Ok(Promise::fulfilled(stream));
+            } else if stream.eof {
+                return Err(Box::new(::std::io::Error::new(
+                    ::std::io::ErrorKind::UnexpectedEof, "connection closed during TLS handshake")));
+            }
+            return Ok(stream.fill().then(|stream| Ok(stream.handshake())));
+        });
+    }
+
+    /// Writes out whatever ciphertext the session has queued up.
+    fn flush(mut self) -> Promise<TlsStream<S>> {
+        if !self.session.wants_write() {
+            return Promise::fulfilled(self);
+        }
+        let mut ciphertext = Vec::new();
+        while self.session.wants_write() {
+            if let Err(e) = self.session.write_tls(&mut ciphertext) {
+                return Promise::rejected(Box::new(e));
+            }
+        }
+        let TlsStream { stream, session, eof } = self;
+        return stream.write(ciphertext).map(move |(stream, _)| {
+            Ok(TlsStream { stream: stream, session: session, eof: eof })
+        });
+    }
+
+    /// Reads whatever ciphertext has arrived, waiting for at least one byte, and hands it to the
+    /// session.
+    fn fill(self) -> Promise<TlsStream<S>> {
+        let TlsStream { stream, mut session, .. } = self;
+        return stream.try_read(vec![0u8; READ_CHUNK_SIZE], 1).map(move |(stream, buf, n)| {
+            let mut ciphertext = &buf[..n];
+            while !ciphertext.is_empty() {
+                try!(session.read_tls(&mut ciphertext));
+                try!(process_new_packets(&mut *session));
+            }
+            Ok(TlsStream { stream: stream, session: session, eof: n == 0 })
+        });
+    }
+}
+
+fn process_new_packets(session: &mut ::rustls::Session) -> Result<()> {
+    match session.process_new_packets() {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
+                                                     format!("TLS error: {:?}", e)))),
+    }
+}
+
+fn read_internal<S, T>(mut stream: TlsStream<S>, mut buf: T, mut already_read: usize,
+                       min_bytes: usize) -> Promise<(TlsStream<S>, T, usize)>
+    where S: AsyncRead + AsyncWrite, T: DerefMut<Target=[u8]>
+{
+    while already_read < buf.len() {
+        match stream.session.read(&mut buf[already_read..]) {
+            // No plaintext until more ciphertext arrives.
+            Ok(0) => break,
+            Ok(n) => already_read += n,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+            // This is how rustls reports that the peer sent close_notify.
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::ConnectionAborted => {
+                stream.eof = true;
+                break
+            }
+            Err(e) => return Promise::rejected(Box::new(e)),
+        }
+    }
+    if already_read >= min_bytes || stream.eof {
+        return Promise::fulfilled((stream, buf, already_read));
+    }
+
+    // Reading may have made the session want to say something, like a key update.
+    return stream.flush().then(|stream| Ok(stream.fill())).then(move |stream| {
+        return Ok(read_internal(stream, buf, already_read, min_bytes));
+    });
+}
+
+impl <S> AsyncRead for TlsStream<S> where S: AsyncRead + AsyncWrite {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return read_internal(self, buf, 0, min_bytes);
+    }
+}
+
+impl <S> AsyncWrite for TlsStream<S> where S: AsyncRead + AsyncWrite {
+    fn write<T>(mut self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        // The session buffers all of the plaintext, so this never comes up short.
+        if let Err(e) = self.session.write_all(&buf) {
+            return Promise::rejected(Box::new(e));
+        }
+        return self.flush().map(move |stream| Ok((stream, buf)));
+    }
+}
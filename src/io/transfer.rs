@@ -0,0 +1,131 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Sending a file's contents to a socket without copying them through userspace.
+
+use std::fs::File;
+use io::TcpStream;
+use Promise;
+
+/// Sends up to `len` bytes of `file`, starting at its current offset, to `stream`. On Linux
+/// this uses `sendfile()`, so the data goes from the page cache to the socket without passing
+/// through a userspace buffer; elsewhere it falls back to reading and writing.
+///
+/// Resolves to the file, whose offset has moved past the sent bytes, the stream, and the
+/// number of bytes sent, which is less than `len` only if the file ended first. Like other
+/// writes, a big transfer is split up so that it doesn't starve other events.
+pub fn transfer(file: File, stream: TcpStream, len: u64) -> Promise<(File, TcpStream, u64)> {
+    return ::io::under_deadline(Promise::fulfilled(()).then(move |()| {
+        return sys::transfer_internal(file, stream, len, 0);
+    }));
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::File;
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+    use io::{HasHandle, TcpStream};
+    use private::with_current_event_loop;
+    use {Promise, Result};
+
+    /// The most that Linux will send in one call.
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    extern "C" {
+        fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut i64, count: usize) -> isize;
+    }
+
+    pub fn transfer_internal(file: File, stream: TcpStream, len: u64,
+                             mut already_sent: u64) -> Result<Promise<(File, TcpStream, u64)>> {
+        let max_bytes = with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow().max_write_bytes_per_turn as u64
+        });
+        let mut sent_this_turn = 0;
+        while already_sent < len {
+            if sent_this_turn >= max_bytes {
+                with_current_event_loop(|event_loop| {
+                    event_loop.event_port.borrow_mut().stats.write_cap_hits += 1;
+                });
+                return Ok(Promise::fulfilled(()).then(move |()| {
+                    return transfer_internal(file, stream, len, already_sent);
+                }));
+            }
+            let count = ::std::cmp::min(::std::cmp::min(len - already_sent,
+                                                        max_bytes - sent_this_turn),
+                                        MAX_CHUNK);
+            let result = unsafe {
+                sendfile(stream.as_raw_fd(), file.as_raw_fd(), ::std::ptr::null_mut(),
+                         count as usize)
+            };
+            if result == 0 {
+                // The file ended.
+                break;
+            } else if result > 0 {
+                already_sent += result as u64;
+                sent_this_turn += result as u64;
+            } else {
+                let error = ::std::io::Error::last_os_error();
+                match error.kind() {
+                    ::std::io::ErrorKind::Interrupted => (),
+                    ::std::io::ErrorKind::WouldBlock => {
+                        return with_current_event_loop(move |event_loop| {
+                            let promise =
+                                event_loop.event_port.borrow_mut()
+                                .handler.observers[stream.get_handle()].when_becomes_writable();
+                            return Ok(promise.then(move |()| {
+                                return transfer_internal(file, stream, len, already_sent);
+                            }));
+                        });
+                    }
+                    _ => return Err(Box::new(error)),
+                }
+            }
+        }
+        return Ok(Promise::fulfilled((file, stream, already_sent)));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+    use std::io::Read;
+    use io::{AsyncWrite, TcpStream};
+    use {Promise, Result};
+
+    const CHUNK_SIZE: u64 = 64 * 1024;
+
+    pub fn transfer_internal(mut file: File, stream: TcpStream, len: u64,
+                             already_sent: u64) -> Result<Promise<(File, TcpStream, u64)>> {
+        if already_sent >= len {
+            return Ok(Promise::fulfilled((file, stream, already_sent)));
+        }
+        let mut buf = vec![0u8; ::std::cmp::min(len - already_sent, CHUNK_SIZE) as usize];
+        let n = try!(file.read(&mut buf));
+        if n == 0 {
+            return Ok(Promise::fulfilled((file, stream, already_sent)));
+        }
+        buf.truncate(n);
+        return Ok(stream.write(buf).then(move |(stream, _)| {
+            return transfer_internal(file, stream, len, already_sent + n as u64);
+        }));
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Terminal input.
+
+use std::ops::DerefMut;
+use std::os::unix::io::{AsRawFd, RawFd};
+use nix;
+use io::{AsyncRead, PipeReader, Tail, stdin};
+use stream::{spawn_stream, PromiseStream, StreamSender};
+use {Promise, Result};
+
+/// A key press, as decoded by `Tty::read_key()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+
+    /// A letter typed with the control key held down, such as `Ctrl('c')`. Control keys that
+    /// have their own variant, like `Tab` for Ctrl-I, are reported as that variant instead.
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Delete,
+
+    /// A sequence that isn't recognized, such as a function key.
+    Other(Vec<u8>),
+}
+
+/// The terminal on standard input. It reads bytes like any other `AsyncRead`, and it can also
+/// decode them into `Key`s.
+///
+/// In raw mode, each key arrives as soon as it is pressed, without echo or line editing, and
+/// Ctrl-C arrives as a key instead of a signal. Raw mode also stops the terminal from turning
+/// `\n` into `\r\n` on output, so a program in raw mode should write `\r\n` itself. The
+/// terminal's previous settings are restored when the `Tty` is dropped.
+pub struct Tty {
+    // Declared before `input`, so that the settings are restored before its descriptor closes.
+    raw_mode: Option<RawModeGuard>,
+    input: PipeReader,
+
+    // Bytes that have been read but not yet returned.
+    pending: Vec<u8>,
+}
+
+impl Tty {
+    /// Opens the terminal on standard input. Fails if standard input is not a terminal.
+    pub fn stdin() -> Result<Tty> {
+        if !try!(nix::unistd::isatty(0)) {
+            return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                                      "standard input is not a terminal")));
+        }
+        return Ok(Tty { raw_mode: None, input: try!(stdin()), pending: Vec::new() });
+    }
+
+    /// Puts the terminal into raw mode, if it isn't already.
+    pub fn enable_raw_mode(&mut self) -> Result<()> {
+        if self.raw_mode.is_none() {
+            let fd = self.input.as_raw_fd();
+            let original = try!(sys::get(fd));
+            try!(sys::set(fd, &sys::make_raw(original)));
+            self.raw_mode = Some(RawModeGuard { fd: fd, original: original });
+        }
+        return Ok(());
+    }
+
+    /// Restores the settings that the terminal had before `enable_raw_mode()`.
+    pub fn disable_raw_mode(&mut self) -> Result<()> {
+        if let Some(guard) = self.raw_mode.take() {
+            try!(guard.restore());
+            ::std::mem::forget(guard);
+        }
+        return Ok(());
+    }
+
+    pub fn is_raw_mode(&self) -> bool {
+        self.raw_mode.is_some()
+    }
+
+    /// Waits for the next key press. Meant for raw mode; otherwise keys only arrive once a line
+    /// is entered. Rejects at EOF.
+    pub fn read_key(self) -> Promise<(Tty, Key)> {
+        return self.next_key().map(|next| {
+            match next {
+                Some(next) => Ok(next),
+                None => Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::UnexpectedEof,
+                                                           "the terminal was closed"))),
+            }
+        });
+    }
+
+    /// Like `read_key()`, but resolves to `None` at EOF.
+    fn next_key(mut self) -> Promise<Option<(Tty, Key)>> {
+        if let Some((key, len)) = parse_key(&self.pending) {
+            self.pending.drain(..len);
+            return Promise::fulfilled(Some((self, key)));
+        }
+        if self.pending == [0x1b] {
+            // Escape sequences arrive all at once, so an escape byte that ended the last read is
+            // the escape key.
+            self.pending.clear();
+            return Promise::fulfilled(Some((self, Key::Escape)));
+        }
+        let Tty { raw_mode, input, pending } = self;
+        return input.try_read(vec![0u8; 64], 1).then(move |(input, buf, n)| {
+            if n == 0 {
+                return Ok(Promise::fulfilled(None));
+            }
+            let mut tty = Tty { raw_mode: raw_mode, input: input, pending: pending };
+            tty.pending.extend_from_slice(&buf[..n]);
+            return Ok(tty.next_key());
+        });
+    }
+
+    /// Turns the terminal into a stream of key presses, which ends at EOF. If reading fails, the
+    /// stream fails with the error. Each key is only read once the one before it has been taken
+    /// from the stream.
+    pub fn keys(self) -> PromiseStream<Key> {
+        return spawn_stream(move |sender| keys_loop(self, sender));
+    }
+}
+
+fn keys_loop(tty: Tty, mut sender: StreamSender<Key>) -> Promise<()> {
+    return tty.next_key().then(move |next| {
+        match next {
+            Some((tty, key)) => {
+                sender.send(key);
+                return Ok(sender.when_drained().then(move |()| Ok(keys_loop(tty, sender))));
+            }
+            None => return Ok(Promise::fulfilled(())),
+        }
+    });
+}
+
+/// Decodes the key at the start of `buf`, returning it and the number of bytes that it took up,
+/// or `None` if `buf` doesn't hold a whole key yet.
+fn parse_key(buf: &[u8]) -> Option<(Key, usize)> {
+    let first = match buf.first() {
+        Some(&first) => first,
+        None => return None,
+    };
+    match first {
+        0x1b => return parse_escape(buf),
+        b'\r' | b'\n' => return Some((Key::Enter, 1)),
+        b'\t' => return Some((Key::Tab, 1)),
+        0x7f | 0x08 => return Some((Key::Backspace, 1)),
+        1...26 => return Some((Key::Ctrl((b'a' + first - 1) as char), 1)),
+        0...31 => return Some((Key::Other(vec![first]), 1)),
+        _ => (),
+    }
+
+    let len = match first {
+        0x00...0x7f => 1,
+        0xc0...0xdf => 2,
+        0xe0...0xef => 3,
+        0xf0...0xf7 => 4,
+        _ => return Some((Key::Other(vec![first]), 1)),
+    };
+    if buf.len() < len {
+        return None;
+    }
+    match ::std::str::from_utf8(&buf[..len]) {
+        Ok(s) => return Some((Key::Char(s.chars().next().unwrap()), len)),
+        Err(_) => return Some((Key::Other(vec![first]), 1)),
+    }
+}
+
+/// Decodes a key that starts with an escape byte.
+fn parse_escape(buf: &[u8]) -> Option<(Key, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    if buf[1] != b'[' && buf[1] != b'O' {
+        // An escape followed by something else; `read_key()` handles a lone escape.
+        return Some((Key::Escape, 1));
+    }
+    // A control sequence ends with a byte in `@`..`~`.
+    let end = match buf[2..].iter().position(|&b| b >= 0x40 && b <= 0x7e) {
+        Some(index) => index + 2,
+        None => return None,
+    };
+    let key = match &buf[2..end + 1] {
+        b"A" => Key::Up,
+        b"B" => Key::Down,
+        b"C" => Key::Right,
+        b"D" => Key::Left,
+        b"H" | b"1~" | b"7~" => Key::Home,
+        b"F" | b"4~" | b"8~" => Key::End,
+        b"3~" => Key::Delete,
+        b"5~" => Key::PageUp,
+        b"6~" => Key::PageDown,
+        sequence => {
+            let mut bytes = buf[..2].to_vec();
+            bytes.extend_from_slice(sequence);
+            Key::Other(bytes)
+        }
+    };
+    return Some((key, end + 1));
+}
+
+impl AsyncRead for Tty {
+    fn try_read<T>(mut self, mut buf: T, min_bytes: usize) -> Promise<(Self, T, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        let n = ::std::cmp::min(self.pending.len(), buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        if n > 0 && n >= min_bytes {
+            return Promise::fulfilled((self, buf, n));
+        }
+
+        let Tty { raw_mode, input, pending } = self;
+        return input.try_read(Tail { buf: buf, start: n }, min_bytes - n).map(move |(input, tail, m)| {
+            Ok((Tty { raw_mode: raw_mode, input: input, pending: pending }, tail.buf, n + m))
+        });
+    }
+}
+
+/// Restores the terminal's original settings when dropped.
+struct RawModeGuard {
+    fd: RawFd,
+    original: sys::Termios,
+}
+
+impl RawModeGuard {
+    fn restore(&self) -> ::std::io::Result<()> {
+        sys::set(self.fd, &self.original)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = self.restore();
+    }
+}
+
+mod sys {
+    use std::os::unix::io::RawFd;
+    use libc;
+
+    pub type Termios = libc::termios;
+
+    pub fn get(fd: RawFd) -> ::std::io::Result<Termios> {
+        let mut termios: Termios = unsafe { ::std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } < 0 {
+            return Err(::std::io::Error::last_os_error());
+        }
+        Ok(termios)
+    }
+
+    pub fn set(fd: RawFd, termios: &Termios) -> ::std::io::Result<()> {
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } < 0 {
+            return Err(::std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn make_raw(mut termios: Termios) -> Termios {
+        unsafe { libc::cfmakeraw(&mut termios) };
+        termios
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Key, Tty, parse_key};
+
+    #[test]
+    fn plain_keys() {
+        assert_eq!(parse_key(b"a"), Some((Key::Char('a'), 1)));
+        assert_eq!(parse_key(b"\r"), Some((Key::Enter, 1)));
+        assert_eq!(parse_key(b"\t"), Some((Key::Tab, 1)));
+        assert_eq!(parse_key(b"\x7f"), Some((Key::Backspace, 1)));
+        assert_eq!(parse_key(b"\x03"), Some((Key::Ctrl('c'), 1)));
+        assert_eq!(parse_key(b"\x1c"), Some((Key::Other(vec![0x1c]), 1)));
+        assert_eq!(parse_key(b""), None);
+    }
+
+    #[test]
+    fn utf8() {
+        assert_eq!(parse_key("é!".as_bytes()), Some((Key::Char('é'), 2)));
+        assert_eq!(parse_key("€".as_bytes()), Some((Key::Char('€'), 3)));
+        assert_eq!(parse_key(&"€".as_bytes()[..2]), None);
+        assert_eq!(parse_key(b"\xff"), Some((Key::Other(vec![0xff]), 1)));
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(parse_key(b"\x1b[A"), Some((Key::Up, 3)));
+        assert_eq!(parse_key(b"\x1b[B"), Some((Key::Down, 3)));
+        assert_eq!(parse_key(b"\x1b[C"), Some((Key::Right, 3)));
+        assert_eq!(parse_key(b"\x1bOD"), Some((Key::Left, 3)));
+        for seq in &[&b"\x1b[H"[..], &b"\x1b[1~"[..], &b"\x1b[7~"[..]] {
+            assert_eq!(parse_key(seq), Some((Key::Home, seq.len())));
+        }
+        for seq in &[&b"\x1b[F"[..], &b"\x1b[4~"[..], &b"\x1b[8~"[..]] {
+            assert_eq!(parse_key(seq), Some((Key::End, seq.len())));
+        }
+        assert_eq!(parse_key(b"\x1b[3~x"), Some((Key::Delete, 4)));
+        assert_eq!(parse_key(b"\x1b[15~"), Some((Key::Other(b"\x1b[15~".to_vec()), 5)));
+        assert_eq!(parse_key(b"\x1bx"), Some((Key::Escape, 1)));
+    }
+
+    #[test]
+    fn incomplete_sequences() {
+        assert_eq!(parse_key(b"\x1b"), None);
+        assert_eq!(parse_key(b"\x1b["), None);
+        assert_eq!(parse_key(b"\x1b[1"), None);
+    }
+
+    #[test]
+    fn trailing_escape() {
+        ::EventLoop::top_level(|wait_scope| {
+            let (input, output) = try!(::io::pipe());
+            let tty = Tty { raw_mode: None, input: input, pending: Vec::new() };
+            let (_output, _) = try!(::io::AsyncWrite::write(output, b"\x1b\x1b".to_vec())
+                                    .wait(wait_scope));
+
+            let (tty, key) = try!(tty.read_key().wait(wait_scope));
+            assert_eq!(key, Key::Escape);
+            let (_tty, key) = try!(tty.read_key().wait(wait_scope));
+            assert_eq!(key, Key::Escape);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn keys_end_at_eof() {
+        ::EventLoop::top_level(|wait_scope| {
+            let (input, output) = try!(::io::pipe());
+            let tty = Tty { raw_mode: None, input: input, pending: Vec::new() };
+            let (output, _) = try!(::io::AsyncWrite::write(output, b"ab".to_vec())
+                                   .wait(wait_scope));
+            drop(output);
+
+            let mut keys = tty.keys();
+            assert_eq!(try!(keys.next().wait(wait_scope)), Some(Key::Char('a')));
+            assert_eq!(try!(keys.next().wait(wait_scope)), Some(Key::Char('b')));
+            assert_eq!(try!(keys.next().wait(wait_scope)), None);
+            Ok(())
+        }).unwrap();
+    }
+}
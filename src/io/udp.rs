@@ -0,0 +1,136 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Datagram sockets.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use handle_table::Handle;
+use io::{HasHandle, register_new_handle, retry_internal, under_deadline};
+use private::with_current_event_loop;
+use {Promise, Result};
+
+/// A UDP socket registered with the event loop's reactor. Like `TcpStream`, each operation takes
+/// the socket by value and hands it back, along with the buffer, once it completes.
+pub struct UdpSocket {
+    io: ::mio::deprecated::unix::Io,
+    handle: Handle,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        return with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.io);
+        });
+    }
+}
+
+impl HasHandle for UdpSocket {
+    fn get_handle(&self) -> Handle { self.handle }
+}
+
+impl UdpSocket {
+    /// Creates a socket bound to the first of `address`'s addresses that can be bound. Pass port
+    /// 0 to have the system pick a port, and then use `local_addr()` to find out which.
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<UdpSocket> {
+        let socket = try!(::std::net::UdpSocket::bind(address));
+        try!(socket.set_nonblocking(true));
+        let io = ::io::io_from_raw_fd(socket.into_raw_fd());
+        let handle = try!(register_new_handle(&io, "UdpSocket"));
+        return Ok(UdpSocket { io: io, handle: handle });
+    }
+
+    /// Returns the address that the socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        return Ok(try!(self.with_socket(|socket| socket.local_addr())));
+    }
+
+    /// Sets the peer that `send()` sends to and the only address that `recv()` accepts
+    /// datagrams from.
+    pub fn connect(&self, address: SocketAddr) -> Result<()> {
+        return Ok(try!(self.with_socket(|socket| socket.connect(address))));
+    }
+
+    /// Returns the address of the connected peer, if `connect()` has been called.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        return Ok(try!(self.with_socket(|socket| socket.peer_addr())));
+    }
+
+    /// Sends `buf` as a single datagram to `target`. Resolves to the socket, the buffer, and the
+    /// number of bytes sent.
+    pub fn send_to<T>(self, buf: T, target: SocketAddr) -> Promise<(UdpSocket, T, usize)>
+        where T: Deref<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, false, move |socket, buf| {
+                socket.with_socket(|s| s.send_to(&buf[..], target))
+            });
+        }));
+    }
+
+    /// Waits for a datagram and copies it into `buf`. Resolves to the socket, the buffer, the
+    /// number of bytes received, and the sender's address. If the datagram is longer than
+    /// `buf`, the rest of it is discarded.
+    pub fn recv_from<T>(self, buf: T) -> Promise<(UdpSocket, T, usize, SocketAddr)>
+        where T: DerefMut<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, true, |socket, buf| {
+                socket.with_socket(|s| s.recv_from(&mut buf[..]))
+            });
+        })).map(|(socket, buf, (n, address))| Ok((socket, buf, n, address)));
+    }
+
+    /// Sends `buf` as a single datagram to the connected peer.
+    pub fn send<T>(self, buf: T) -> Promise<(UdpSocket, T, usize)>
+        where T: Deref<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, false, |socket, buf| {
+                socket.with_socket(|s| s.send(&buf[..]))
+            });
+        }));
+    }
+
+    /// Waits for a datagram from the connected peer and copies it into `buf`.
+    pub fn recv<T>(self, buf: T) -> Promise<(UdpSocket, T, usize)>
+        where T: DerefMut<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, true, |socket, buf| {
+                socket.with_socket(|s| s.recv(&mut buf[..]))
+            });
+        }));
+    }
+
+    /// Runs `f` on a `std::net::UdpSocket` that borrows this socket's file descriptor.
+    fn with_socket<F, R>(&self, f: F) -> R
+        where F: FnOnce(&::std::net::UdpSocket) -> R
+    {
+        let socket = unsafe { ::std::net::UdpSocket::from_raw_fd(self.io.as_raw_fd()) };
+        let result = f(&socket);
+        // The descriptor still belongs to `self.io`, so it must not be closed here.
+        let _ = socket.into_raw_fd();
+        return result;
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Unix domain sockets, for talking to other processes on the same machine.
+
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use handle_table::Handle;
+use io::{AsyncRead, AsyncWrite, FdObserver, HasHandle, get_interest_internal, register_new_handle,
+         retry_internal, set_interest_internal, try_read_internal, under_deadline, vectored,
+         write_internal, DEFAULT_MAX_READ_BYTES_PER_TURN};
+use private::with_current_event_loop;
+use {Promise, Result};
+
+/// The path of a Unix domain socket. The counterpart of `NetworkAddress`.
+#[derive(Clone, Debug)]
+pub struct UnixAddress {
+    path: PathBuf,
+}
+
+impl UnixAddress {
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixAddress {
+        UnixAddress { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates a socket at this path and listens on it. Fails if something already exists at
+    /// the path. The socket file is removed when the receiver is dropped.
+    pub fn listen(self) -> Result<UnixConnectionReceiver> {
+        let listener = try!(::mio::deprecated::UnixListener::bind(&self.path));
+        let handle = FdObserver::new(::mio::Ready::readable(), listener.as_raw_fd(),
+                                     "UnixConnectionReceiver");
+
+        return with_current_event_loop(move |event_loop| {
+            try!(event_loop.event_port.borrow_mut().reactor.register(&listener, ::mio::Token(handle.to_token()),
+                                                                         ::mio::Ready::readable(),
+                                                                         ::mio::PollOpt::edge()));
+            Ok(UnixConnectionReceiver { listener: listener, handle: handle, path: self.path })
+        });
+    }
+
+    pub fn connect(self) -> Promise<UnixStream> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            // Connecting to a Unix socket doesn't involve a handshake, so it is done by the
+            // time `connect()` returns.
+            let stream = try!(::mio::deprecated::UnixStream::connect(&self.path));
+            let handle = try!(register_new_handle(&stream, "UnixStream"));
+            return Ok(Promise::fulfilled(UnixStream::new(stream, handle)));
+        }));
+    }
+}
+
+pub struct UnixConnectionReceiver {
+    listener: ::mio::deprecated::UnixListener,
+    handle: Handle,
+    path: PathBuf,
+}
+
+impl Drop for UnixConnectionReceiver {
+    fn drop(&mut self) {
+        with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.listener);
+        });
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+}
+
+impl UnixConnectionReceiver {
+    fn accept_internal(self) -> Result<Promise<(UnixConnectionReceiver, UnixStream)>> {
+        match try!(::mio::deprecated::TryAccept::accept(&self.listener)) {
+            Some(stream) => {
+                let handle = try!(register_new_handle(&stream, "UnixStream"));
+                return Ok(Promise::fulfilled((self, UnixStream::new(stream, handle))));
+            }
+            None => {
+                return with_current_event_loop(move |event_loop| {
+                    let promise =
+                        event_loop.event_port.borrow_mut().handler.observers[self.handle].when_becomes_readable();
+                    return Ok(promise.then(move |()| {
+                        return self.accept_internal();
+                    }));
+                });
+            }
+        }
+    }
+
+    pub fn accept(self) -> Promise<(UnixConnectionReceiver, UnixStream)> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| { return self.accept_internal(); }));
+    }
+}
+
+pub struct UnixStream {
+    stream: ::mio::deprecated::UnixStream,
+    handle: Handle,
+    max_read_bytes_per_turn: usize,
+}
+
+impl Drop for UnixStream {
+    fn drop(&mut self) {
+        with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.stream);
+        });
+    }
+}
+
+impl ::mio::deprecated::TryRead for UnixStream {
+    fn try_read(&mut self, buf: &mut [u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_read(buf)
+    }
+}
+
+impl ::mio::deprecated::TryWrite for UnixStream {
+    fn try_write(&mut self, buf: &[u8]) -> ::std::io::Result<Option<usize>> {
+        self.stream.try_write(buf)
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl HasHandle for UnixStream {
+    fn get_handle(&self) -> Handle { self.handle }
+    fn max_read_bytes_per_turn(&self) -> usize { self.max_read_bytes_per_turn }
+}
+
+impl UnixStream {
+    fn new(stream: ::mio::deprecated::UnixStream, handle: Handle) -> UnixStream {
+        UnixStream { stream: stream, handle: handle,
+                     max_read_bytes_per_turn: DEFAULT_MAX_READ_BYTES_PER_TURN }
+    }
+
+    /// See `TcpStream::set_max_read_bytes_per_turn()`.
+    pub fn set_max_read_bytes_per_turn(&mut self, bytes: usize) {
+        assert!(bytes > 0, "the read limit must be positive");
+        self.max_read_bytes_per_turn = bytes;
+    }
+
+    /// Returns the set of readiness events that this stream is currently registered for.
+    pub fn interest(&self) -> ::mio::Ready {
+        get_interest_internal(self.handle)
+    }
+
+    /// Reregisters the stream with the reactor under a new interest set.
+    /// See `TcpStream::set_interest()`.
+    pub fn set_interest(&mut self, interest: ::mio::Ready) -> Result<()> {
+        set_interest_internal(&self.stream, self.handle, interest)
+    }
+}
+
+impl AsyncRead for UnixStream {
+    fn try_read<T>(self, buf: T,
+               min_bytes: usize) -> Promise<(Self, T, usize)> where T: DerefMut<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return try_read_internal(self, buf, 0, min_bytes);
+        }));
+    }
+
+    fn try_read_vectored<T>(self, bufs: Vec<T>,
+                            min_bytes: usize) -> Promise<(Self, Vec<T>, usize)>
+        where T: DerefMut<Target=[u8]>
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::try_read_internal(self, bufs, 0, min_bytes);
+        }));
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn write<T>(self, buf: T) -> Promise<(Self, T)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return write_internal(self, buf, 0);
+        }));
+    }
+
+    fn write_vectored<T>(self, bufs: Vec<T>) -> Promise<(Self, Vec<T>)> where T: Deref<Target=[u8]> {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return vectored::write_internal(self, bufs, 0);
+        }));
+    }
+}
+
+/// A Unix domain datagram socket. Besides bytes, it can pass open file descriptors to the
+/// process on the other end, which is how a supervisor hands accepted connections to workers.
+/// Like `UdpSocket`, each operation takes the socket by value and hands it back when done.
+pub struct UnixDatagram {
+    io: ::mio::deprecated::unix::Io,
+    handle: Handle,
+}
+
+impl Drop for UnixDatagram {
+    fn drop(&mut self) {
+        with_current_event_loop(move |event_loop| {
+            event_loop.event_port.borrow_mut().handler.observers.remove(self.handle);
+            let _ = event_loop.event_port.borrow_mut().reactor.deregister(&self.io);
+        });
+    }
+}
+
+impl HasHandle for UnixDatagram {
+    fn get_handle(&self) -> Handle { self.handle }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+impl UnixDatagram {
+    fn from_std(socket: ::std::os::unix::net::UnixDatagram) -> Result<UnixDatagram> {
+        try!(socket.set_nonblocking(true));
+        let io = ::io::io_from_raw_fd(socket.into_raw_fd());
+        let handle = try!(register_new_handle(&io, "UnixDatagram"));
+        return Ok(UnixDatagram { io: io, handle: handle });
+    }
+
+    /// Creates a socket bound to `path`, which must not exist yet. Unlike
+    /// `UnixConnectionReceiver`, the socket doesn't remove the file when dropped.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixDatagram> {
+        UnixDatagram::from_std(try!(::std::os::unix::net::UnixDatagram::bind(path)))
+    }
+
+    /// Creates a socket that isn't bound to a path. It can send, but only a connected peer can
+    /// answer it.
+    pub fn unbound() -> Result<UnixDatagram> {
+        UnixDatagram::from_std(try!(::std::os::unix::net::UnixDatagram::unbound()))
+    }
+
+    /// Creates a pair of sockets that are connected to each other. One of them is typically
+    /// passed to a child process.
+    pub fn pair() -> Result<(UnixDatagram, UnixDatagram)> {
+        let (first, second) = try!(::std::os::unix::net::UnixDatagram::pair());
+        return Ok((try!(UnixDatagram::from_std(first)), try!(UnixDatagram::from_std(second))));
+    }
+
+    /// Sets the peer that `send()` sends to and the only socket that `recv()` accepts
+    /// datagrams from.
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        return Ok(try!(self.with_socket(|socket| socket.connect(path))));
+    }
+
+    /// Sends `buf` as a single datagram to the socket bound to `path`.
+    pub fn send_to<T>(self, buf: T, path: PathBuf) -> Promise<(UnixDatagram, T, usize)>
+        where T: Deref<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, false, move |socket, buf| {
+                socket.with_socket(|s| s.send_to(&buf[..], &path))
+            });
+        }));
+    }
+
+    /// Waits for a datagram and copies it into `buf`. Resolves to the socket, the buffer, the
+    /// number of bytes received, and the path of the sender, if it is bound to one.
+    pub fn recv_from<T>(self, buf: T) -> Promise<(UnixDatagram, T, usize, Option<PathBuf>)>
+        where T: DerefMut<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, true, |socket, buf| {
+                socket.with_socket(|s| s.recv_from(&mut buf[..]))
+            });
+        })).map(|(socket, buf, (n, address))| {
+            Ok((socket, buf, n, address.as_pathname().map(|path| path.to_path_buf())))
+        });
+    }
+
+    /// Sends `buf` as a single datagram to the connected peer.
+    pub fn send<T>(self, buf: T) -> Promise<(UnixDatagram, T, usize)>
+        where T: Deref<Target=[u8]> + 'static
+    {
+        return self.send_with_fds(buf, Vec::new());
+    }
+
+    /// Waits for a datagram from the connected peer and copies it into `buf`. Any file
+    /// descriptors that came with it are closed.
+    pub fn recv<T>(self, buf: T) -> Promise<(UnixDatagram, T, usize)>
+        where T: DerefMut<Target=[u8]> + 'static
+    {
+        return self.recv_with_fds(buf).map(|(socket, buf, n, fds)| {
+            for fd in fds {
+                drop(unsafe { <::std::fs::File as ::std::os::unix::io::FromRawFd>::from_raw_fd(fd) });
+            }
+            Ok((socket, buf, n))
+        });
+    }
+
+    /// Sends `buf` to the connected peer along with copies of `fds`, using `SCM_RIGHTS`. The
+    /// descriptors stay open here; the peer gets its own. `buf` must not be empty, since an
+    /// empty datagram can't carry them on every system.
+    pub fn send_with_fds<T>(self, buf: T, fds: Vec<RawFd>) -> Promise<(UnixDatagram, T, usize)>
+        where T: Deref<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, false, move |socket, buf| {
+                sys::send_with_fds(socket.as_raw_fd(), &buf[..], &fds)
+            });
+        }));
+    }
+
+    /// Waits for a datagram from the connected peer, copies it into `buf`, and takes the file
+    /// descriptors that came with it, up to `MAX_RECEIVED_FDS` of them. The caller owns the
+    /// descriptors and has to close them. Close-on-exec is set on them as they arrive.
+    pub fn recv_with_fds<T>(self, buf: T) -> Promise<(UnixDatagram, T, usize, Vec<RawFd>)>
+        where T: DerefMut<Target=[u8]> + 'static
+    {
+        return under_deadline(Promise::fulfilled(()).then(move |()| {
+            return retry_internal(self, buf, true, |socket, buf| {
+                sys::recv_with_fds(socket.as_raw_fd(), &mut buf[..])
+            });
+        })).map(|(socket, buf, (n, fds))| Ok((socket, buf, n, fds)));
+    }
+
+    /// Runs `f` on a `std::os::unix::net::UnixDatagram` that borrows this socket's file
+    /// descriptor.
+    fn with_socket<F, R>(&self, f: F) -> R
+        where F: FnOnce(&::std::os::unix::net::UnixDatagram) -> R
+    {
+        let socket = unsafe {
+            <::std::os::unix::net::UnixDatagram as FromRawFd>::from_raw_fd(self.io.as_raw_fd())
+        };
+        let result = f(&socket);
+        // The descriptor still belongs to `self.io`, so it must not be closed here.
+        let _ = socket.into_raw_fd();
+        return result;
+    }
+}
+
+/// The most file descriptors that `UnixDatagram::recv_with_fds()` takes from one datagram.
+/// Linux allows at most 253 per message.
+pub const MAX_RECEIVED_FDS: usize = 253;
+
+mod sys {
+    use std::os::unix::io::RawFd;
+    use nix::fcntl::{fcntl, FcntlArg, FD_CLOEXEC};
+    use nix::sys::socket::{recvmsg, sendmsg, CmsgSpace, ControlMessage, MsgFlags, MSG_CTRUNC};
+    use nix::sys::uio::IoVec;
+    use super::MAX_RECEIVED_FDS;
+
+    pub fn send_with_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> ::std::io::Result<usize> {
+        let iov = [IoVec::from_slice(buf)];
+        let rights = [ControlMessage::ScmRights(fds)];
+        let cmsgs = if fds.is_empty() { &rights[..0] } else { &rights[..] };
+        return Ok(try!(sendmsg(fd, &iov, cmsgs, MsgFlags::empty(), None)));
+    }
+
+    pub fn recv_with_fds(fd: RawFd, buf: &mut [u8]) -> ::std::io::Result<(usize, Vec<RawFd>)> {
+        let iov = [IoVec::from_mut_slice(buf)];
+        let mut space: CmsgSpace<[RawFd; MAX_RECEIVED_FDS]> = CmsgSpace::new();
+        let msg = try!(recvmsg(fd, &iov, Some(&mut space), MsgFlags::empty()));
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs() {
+            if let ControlMessage::ScmRights(received) = cmsg {
+                fds.extend_from_slice(received);
+            }
+        }
+
+        // nix's `MsgFlags` has no `MSG_CMSG_CLOEXEC`, so the flag is set right after instead.
+        let mut result = Ok(());
+        for &received in fds.iter() {
+            if let Err(error) = fcntl(received, FcntlArg::F_SETFD(FD_CLOEXEC)) {
+                result = Err(error);
+            }
+        }
+        if result.is_err() || msg.flags.contains(MSG_CTRUNC) {
+            for fd in fds {
+                drop(unsafe { <::std::fs::File as ::std::os::unix::io::FromRawFd>::from_raw_fd(fd) });
+            }
+            try!(result);
+            return Err(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                             "received more file descriptors than fit"));
+        }
+        Ok((msg.bytes, fds))
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Scattered reads and gathered writes on file descriptors, using `readv()` and `writev()`.
+
+use std::ops::{Deref, DerefMut};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
+use io::HasHandle;
+use private::with_current_event_loop;
+use {Promise, Result};
+
+/// The most buffers that one system call is given. POSIX only promises 16, but every system that
+/// we run on allows at least this many.
+const MAX_IOVECS: usize = 1024;
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+extern "C" {
+    fn readv(fd: c_int, iov: *const IoVec, count: c_int) -> isize;
+    fn writev(fd: c_int, iov: *const IoVec, count: c_int) -> isize;
+}
+
+fn total_len<T>(bufs: &[T]) -> usize where T: Deref<Target=[u8]> {
+    bufs.iter().fold(0, |total, buf| total + buf.len())
+}
+
+/// Describes the bytes of `bufs` that come after the first `skip` of them.
+fn iovecs<T>(bufs: &[T], mut skip: usize) -> Vec<IoVec> where T: Deref<Target=[u8]> {
+    let mut result = Vec::new();
+    for buf in bufs {
+        if result.len() == MAX_IOVECS {
+            break;
+        } else if skip >= buf.len() {
+            skip -= buf.len();
+        } else {
+            result.push(IoVec { base: buf[skip..].as_ptr() as *mut c_void, len: buf.len() - skip });
+            skip = 0;
+        }
+    }
+    return result;
+}
+
+/// Like `iovecs()`, but for buffers that are about to be written to.
+fn iovecs_mut<T>(bufs: &mut [T], mut skip: usize) -> Vec<IoVec> where T: DerefMut<Target=[u8]> {
+    let mut result = Vec::new();
+    for buf in bufs {
+        if result.len() == MAX_IOVECS {
+            break;
+        } else if skip >= buf.len() {
+            skip -= buf.len();
+        } else {
+            let len = buf.len() - skip;
+            result.push(IoVec { base: buf[skip..].as_mut_ptr() as *mut c_void, len: len });
+            skip = 0;
+        }
+    }
+    return result;
+}
+
+/// The vectored counterpart of `io::try_read_internal()`.
+pub fn try_read_internal<R, T>(reader: R,
+                               mut bufs: Vec<T>,
+                               mut already_read: usize,
+                               min_bytes: usize) -> Result<Promise<(R, Vec<T>, usize)>>
+    where T: DerefMut<Target=[u8]>, R: HasHandle + AsRawFd
+{
+    let total = total_len(&bufs);
+    let max_bytes = reader.max_read_bytes_per_turn();
+    let mut read_this_turn = 0;
+    while already_read < min_bytes && already_read < total {
+        if read_this_turn >= max_bytes {
+            with_current_event_loop(|event_loop| {
+                event_loop.event_port.borrow_mut().stats.read_cap_hits += 1;
+            });
+            return Ok(Promise::fulfilled(()).then(move |()| {
+                return try_read_internal(reader, bufs, already_read, min_bytes);
+            }));
+        }
+        let result = {
+            let iov = iovecs_mut(&mut bufs, already_read);
+            unsafe { readv(reader.as_raw_fd(), iov.as_ptr(), iov.len() as c_int) }
+        };
+        if result == 0 {
+            // EOF
+            break;
+        } else if result > 0 {
+            already_read += result as usize;
+            read_this_turn += result as usize;
+        } else {
+            let error = ::std::io::Error::last_os_error();
+            match error.kind() {
+                ::std::io::ErrorKind::Interrupted => (),
+                ::std::io::ErrorKind::WouldBlock => {
+                    return with_current_event_loop(move |event_loop| {
+                        let promise =
+                            event_loop.event_port.borrow_mut()
+                            .handler.observers[reader.get_handle()].when_becomes_readable();
+                        return Ok(promise.then(move |()| {
+                            return try_read_internal(reader, bufs, already_read, min_bytes);
+                        }));
+                    });
+                }
+                _ => return Err(Box::new(error)),
+            }
+        }
+    }
+
+    return Ok(Promise::fulfilled((reader, bufs, already_read)));
+}
+
+/// The vectored counterpart of `io::write_internal()`. Checks the per-turn write limit between
+/// system calls, so one call may go past it.
+pub fn write_internal<W, T>(writer: W,
+                            bufs: Vec<T>,
+                            mut already_written: usize) -> Result<Promise<(W, Vec<T>)>>
+    where T: Deref<Target=[u8]>, W: HasHandle + AsRawFd
+{
+    let total = total_len(&bufs);
+    let max_bytes = with_current_event_loop(|event_loop| {
+        event_loop.event_port.borrow().max_write_bytes_per_turn
+    });
+    let mut written_this_turn = 0;
+    while already_written < total {
+        if written_this_turn >= max_bytes {
+            with_current_event_loop(|event_loop| {
+                event_loop.event_port.borrow_mut().stats.write_cap_hits += 1;
+            });
+            return Ok(Promise::fulfilled(()).then(move |()| {
+                return write_internal(writer, bufs, already_written);
+            }));
+        }
+        let result = {
+            let iov = iovecs(&bufs, already_written);
+            unsafe { writev(writer.as_raw_fd(), iov.as_ptr(), iov.len() as c_int) }
+        };
+        if result >= 0 {
+            already_written += result as usize;
+            written_this_turn += result as usize;
+        } else {
+            let error = ::std::io::Error::last_os_error();
+            match error.kind() {
+                ::std::io::ErrorKind::Interrupted => (),
+                ::std::io::ErrorKind::WouldBlock => {
+                    return with_current_event_loop(move |event_loop| {
+                        let promise =
+                            event_loop.event_port.borrow_mut()
+                            .handler.observers[writer.get_handle()].when_becomes_writable();
+                        return Ok(promise.then(move |()| {
+                            return write_internal(writer, bufs, already_written);
+                        }));
+                    });
+                }
+                _ => return Err(Box::new(error)),
+            }
+        }
+    }
+
+    return Ok(Promise::fulfilled((writer, bufs)));
+}
@@ -0,0 +1,281 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Watching the file system for changes.
+
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use io::{AsyncRead, PipeReader};
+use io::pipe::reader_from_fd;
+use stream::{spawn_stream, PromiseStream, StreamSender};
+use {Promise, Result};
+
+/// The size of the buffer that a `Watcher` reads events into. Big enough for many events at
+/// once, and for any single event, whose name can be up to `NAME_MAX` bytes long.
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// What happened to a watched path, or to an entry in a watched directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+
+    /// The entry was renamed away. A `MovedTo` with the new name follows if it stayed within a
+    /// watched directory.
+    MovedFrom,
+    MovedTo,
+
+    /// Permissions, ownership, timestamps, or other metadata changed.
+    Attributes,
+
+    /// The system dropped events because they weren't read quickly enough. Anything watched may
+    /// have changed, so the caller should look again. The path of this change is empty.
+    Overflowed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// The watched path, joined with the name of the entry that changed if the watched path is a
+    /// directory and the change was to one of its entries.
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// Reports changes to files and directories. A watched directory reports changes to its
+/// entries, but not to their contents or to deeper levels.
+///
+/// The events are read through the event loop's reactor, so no helper thread is needed. This is
+/// built on inotify and so is only supported on Linux for now; elsewhere `Watcher::new()` fails.
+/// Like the streams, a `Watcher` is passed by value through `next()`.
+pub struct Watcher {
+    events: PipeReader,
+
+    // Paths that lead to the same file, such as through a symlink, share a watch.
+    watches: HashMap<i32, Vec<PathBuf>>,
+    pending: VecDeque<Change>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Watcher> {
+        let fd = try!(sys::init());
+        return Ok(Watcher { events: try!(reader_from_fd(fd)), watches: HashMap::new(),
+                            pending: VecDeque::new() });
+    }
+
+    /// Starts reporting changes to `path`. Watching a path that is already watched has no
+    /// effect.
+    pub fn watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let id = try!(sys::add_watch(self.events.as_raw_fd(), path));
+        let paths = self.watches.entry(id).or_insert(Vec::new());
+        if !paths.iter().any(|watched| watched == path) {
+            paths.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+
+    /// Stops reporting changes to `path`. Changes that were already reported may still come out
+    /// of `next()`.
+    pub fn unwatch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let id = match self.watches.iter().find(|&(_, paths)| paths.iter().any(|p| p == path)) {
+            Some((&id, _)) => id,
+            None => {
+                return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                                          "the path is not being watched")))
+            }
+        };
+        let remaining = {
+            let paths = self.watches.get_mut(&id).unwrap();
+            paths.retain(|watched| watched != path);
+            paths.len()
+        };
+        if remaining > 0 {
+            // Other paths still need the watch.
+            return Ok(());
+        }
+        self.watches.remove(&id);
+        return Ok(try!(sys::rm_watch(self.events.as_raw_fd(), id)));
+    }
+
+    /// Waits for the next change.
+    pub fn next(mut self) -> Promise<(Watcher, Change)> {
+        if let Some(change) = self.pending.pop_front() {
+            return Promise::fulfilled((self, change));
+        }
+        let Watcher { events, watches, pending } = self;
+        return events.try_read(vec![0u8; EVENT_BUFFER_SIZE], 1).then(move |(events, buf, n)| {
+            let mut watcher = Watcher { events: events, watches: watches, pending: pending };
+            for (id, kind, name) in sys::parse(&buf[..n]) {
+                match kind {
+                    Some(ChangeKind::Overflowed) => {
+                        watcher.pending.push_back(Change { path: PathBuf::new(),
+                                                           kind: ChangeKind::Overflowed });
+                    }
+                    Some(kind) => {
+                        // Nothing is found if the path was unwatched after this event was queued.
+                        if let Some(paths) = watcher.watches.get(&id) {
+                            for path in paths {
+                                let path = match name {
+                                    Some(ref name) => path.join(name),
+                                    None => path.clone(),
+                                };
+                                watcher.pending.push_back(Change { path: path, kind: kind });
+                            }
+                        }
+                    }
+                    None => {
+                        // The system dropped the watch, because the path is gone.
+                        watcher.watches.remove(&id);
+                    }
+                }
+            }
+            return Ok(watcher.next());
+        });
+    }
+
+    /// Turns the watcher into a stream of changes. Each change is only read once the one before
+    /// it has been taken from the stream.
+    pub fn changes(self) -> PromiseStream<Change> {
+        return spawn_stream(move |sender| changes_loop(self, sender));
+    }
+}
+
+fn changes_loop(watcher: Watcher, mut sender: StreamSender<Change>) -> Promise<()> {
+    return watcher.next().then(move |(watcher, change)| {
+        sender.send(change);
+        return Ok(sender.when_drained().then(move |()| Ok(changes_loop(watcher, sender))));
+    });
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::{CString, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+    use libc::{self, IN_ATTRIB, IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_IGNORED, IN_MODIFY,
+               IN_MOVED_FROM, IN_MOVED_TO, IN_MOVE_SELF, IN_Q_OVERFLOW};
+    use io::watcher::ChangeKind;
+
+    /// The size of `struct inotify_event` without its name.
+    const EVENT_HEADER_SIZE: usize = ::std::mem::size_of::<libc::inotify_event>();
+
+    pub fn init() -> ::std::io::Result<RawFd> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(fd) }
+    }
+
+    pub fn add_watch(fd: RawFd, path: &Path) -> ::std::io::Result<i32> {
+        let path = try!(CString::new(path.as_os_str().as_bytes()));
+        let mask = IN_CREATE | IN_MODIFY | IN_DELETE | IN_DELETE_SELF | IN_MOVED_FROM |
+                   IN_MOVED_TO | IN_MOVE_SELF | IN_ATTRIB;
+        let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) };
+        if wd < 0 { Err(::std::io::Error::last_os_error()) } else { Ok(wd) }
+    }
+
+    pub fn rm_watch(fd: RawFd, wd: i32) -> ::std::io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(fd, wd) } < 0 {
+            return Err(::std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    }
+
+    /// Splits `buf` into its events: the watch, what happened (or `None` if the watch is gone),
+    /// and the name of the directory entry involved, if any.
+    pub fn parse(buf: &[u8]) -> Vec<(i32, Option<ChangeKind>, Option<OsString>)> {
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + EVENT_HEADER_SIZE <= buf.len() {
+            let wd = read_u32(buf, offset) as i32;
+            let mask = read_u32(buf, offset + 4);
+            let len = read_u32(buf, offset + 12) as usize;
+            let name = &buf[offset + EVENT_HEADER_SIZE..offset + EVENT_HEADER_SIZE + len];
+            offset += EVENT_HEADER_SIZE + len;
+
+            // The name is padded with NULs.
+            let name = match name.iter().position(|&b| b == 0) {
+                Some(end) => &name[..end],
+                None => name,
+            };
+            let name = if name.is_empty() { None } else { Some(OsString::from_vec(name.to_vec())) };
+            let kind = if mask & IN_Q_OVERFLOW != 0 {
+                Some(ChangeKind::Overflowed)
+            } else if mask & IN_IGNORED != 0 {
+                None
+            } else if mask & IN_CREATE != 0 {
+                Some(ChangeKind::Created)
+            } else if mask & (IN_DELETE | IN_DELETE_SELF) != 0 {
+                Some(ChangeKind::Removed)
+            } else if mask & (IN_MOVED_FROM | IN_MOVE_SELF) != 0 {
+                Some(ChangeKind::MovedFrom)
+            } else if mask & IN_MOVED_TO != 0 {
+                Some(ChangeKind::MovedTo)
+            } else if mask & IN_MODIFY != 0 {
+                Some(ChangeKind::Modified)
+            } else if mask & IN_ATTRIB != 0 {
+                Some(ChangeKind::Attributes)
+            } else {
+                continue;
+            };
+            events.push((wd, kind, name));
+        }
+        return events;
+    }
+}
+
+// A kqueue (EVFILT_VNODE) backend for BSD and macOS has yet to be written.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::ffi::OsString;
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+    use io::watcher::ChangeKind;
+
+    fn unsupported() -> ::std::io::Error {
+        ::std::io::Error::new(::std::io::ErrorKind::Other,
+                              "file system watching is not supported on this platform")
+    }
+
+    pub fn init() -> ::std::io::Result<RawFd> {
+        Err(unsupported())
+    }
+
+    pub fn add_watch(_fd: RawFd, _path: &Path) -> ::std::io::Result<i32> {
+        Err(unsupported())
+    }
+
+    pub fn rm_watch(_fd: RawFd, _wd: i32) -> ::std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn parse(_buf: &[u8]) -> Vec<(i32, Option<ChangeKind>, Option<OsString>)> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Encodings for the values that gj's typed IPC helpers, such as `io::rpc`, send between event
+//! loops.
+//!
+//! Implement `Wire` for your message types to choose how they are encoded. With the `json`
+//! feature enabled, wrapping a type in `Json` encodes it as JSON using serde.
+
+use Result;
+
+/// A type that can be encoded as bytes and decoded again.
+pub trait Wire: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decodes a value from the entirety of `bytes`.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+fn invalid_data(message: &str) -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidData, message))
+}
+
+impl Wire for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.iter().cloned());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.as_bytes().iter().cloned());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(invalid_data("string is not valid UTF-8")),
+        }
+    }
+}
+
+impl Wire for () {
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(bytes: &[u8]) -> Result<()> {
+        if bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_data("expected an empty message"))
+        }
+    }
+}
+
+impl Wire for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        for shift in (0..8).rev() {
+            out.push((*self >> (shift * 8)) as u8);
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<u64> {
+        if bytes.len() != 8 {
+            return Err(invalid_data("expected 8 bytes"));
+        }
+        Ok(bytes.iter().fold(0u64, |value, &b| (value << 8) | b as u64))
+    }
+}
+
+/// Encodes the wrapped value as JSON.
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl <T> Wire for Json<T> where T: ::serde::Serialize + ::serde::Deserialize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(::serde_json::to_vec(&self.0).expect("failed to encode as JSON"));
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Json<T>> {
+        match ::serde_json::from_slice(bytes) {
+            Ok(value) => Ok(Json(value)),
+            Err(e) => Err(invalid_data(&format!("invalid JSON: {}", e))),
+        }
+    }
+}
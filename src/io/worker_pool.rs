@@ -0,0 +1,157 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The worker threads that run blocking calls for an event loop, such as the file system calls in
+//! `fs` and the name lookups of `NetworkAddress::resolve()`. The loop starts `WORKER_COUNT` of
+//! them the first time it needs one, and jobs beyond that wait their turn. Each worker hands its
+//! result back through a queue and wakes the loop with a `LoopWaker`, and the loop takes the
+//! results from the queue in an `EventLoop::on_wake()` callback, which stays registered only while
+//! jobs are outstanding. Dropping a returned promise doesn't stop a job that has already started.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use io::LoopWaker;
+use {EventLoop, Promise, PromiseFulfiller, new_promise_and_fulfiller};
+
+/// The number of worker threads in each event loop's pool.
+const WORKER_COUNT: usize = 4;
+
+/// Runs `func` on the current event loop's worker pool. Panics if the current thread has no event
+/// loop.
+pub fn run<F, R>(func: F) -> Promise<R>
+    where F: FnOnce() -> ::std::io::Result<R> + Send + 'static, R: Send + 'static
+{
+    let (promise, fulfiller) = new_promise_and_fulfiller();
+    with_pool(move |pool| pool.submit(func, fulfiller));
+    return promise;
+}
+
+type Job = Box<FnMut() + Send>;
+type Completion = (u64, Box<Any + Send>);
+
+struct Pool {
+    // Dropping this ends the workers once they finish the jobs they have.
+    jobs: Sender<Job>,
+    completions: Receiver<Completion>,
+    completion_sender: Sender<Completion>,
+    waker: LoopWaker,
+    next_id: u64,
+
+    // Hands each completion to the fulfiller of its job.
+    waiting: HashMap<u64, Box<FnMut(Box<Any + Send>)>>,
+
+    // Whether the pool has an `EventLoop::on_wake()` callback registered. It has one exactly
+    // while there are jobs in `waiting`, so that an idle pool doesn't keep `Promise::wait()` from
+    // reporting a deadlock.
+    listening: bool,
+}
+
+thread_local!(static POOL: RefCell<Option<Rc<RefCell<Pool>>>> = const { RefCell::new(None) });
+
+fn with_pool<F>(func: F) where F: FnOnce(&mut Pool) {
+    let pool = POOL.with(|pool| {
+        if let Some(ref pool) = *pool.borrow() {
+            return pool.clone();
+        }
+        let new_pool = Rc::new(RefCell::new(Pool::new()));
+        *pool.borrow_mut() = Some(new_pool.clone());
+        EventLoop::on_teardown(|| POOL.with(|pool| *pool.borrow_mut() = None));
+        new_pool
+    });
+    func(&mut *pool.borrow_mut());
+
+    let listen = {
+        let mut pool = pool.borrow_mut();
+        let listen = !pool.listening && !pool.waiting.is_empty();
+        pool.listening = pool.listening || listen;
+        listen
+    };
+    if listen {
+        EventLoop::on_wake(move || {
+            let mut pool = pool.borrow_mut();
+            pool.deliver();
+            pool.listening = !pool.waiting.is_empty();
+            pool.listening
+        });
+    }
+}
+
+impl Pool {
+    fn new() -> Pool {
+        let (jobs, job_receiver) = channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for _ in 0..WORKER_COUNT {
+            let job_receiver = job_receiver.clone();
+            ::std::thread::Builder::new().name("gj-worker".to_string()).spawn(move || {
+                loop {
+                    let job = job_receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(mut job) => job(),
+                        Err(_) => return,
+                    }
+                }
+            }).expect("failed to start a worker thread");
+        }
+        let (completion_sender, completions) = channel();
+        Pool { jobs: jobs, completions: completions, completion_sender: completion_sender,
+               waker: LoopWaker::current(), next_id: 0, waiting: HashMap::new(),
+               listening: false }
+    }
+
+    fn submit<F, R>(&mut self, func: F, fulfiller: Box<PromiseFulfiller<R>>)
+        where F: FnOnce() -> ::std::io::Result<R> + Send + 'static, R: Send + 'static
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut fulfiller = Some(fulfiller);
+        self.waiting.insert(id, Box::new(move |result: Box<Any + Send>| {
+            let fulfiller = fulfiller.take().expect("job completed twice");
+            match *result.downcast::<::std::io::Result<R>>().expect("wrong result type") {
+                Ok(value) => fulfiller.fulfill(value),
+                Err(error) => fulfiller.reject(Box::new(error)),
+            }
+        }));
+
+        let mut func = Some(func);
+        let sender = self.completion_sender.clone();
+        let waker = self.waker.clone();
+        let job: Job = Box::new(move || {
+            let result = (func.take().expect("job ran twice"))();
+            let _ = sender.send((id, Box::new(result)));
+            let _ = waker.wake();
+        });
+        self.jobs.send(job).expect("worker threads are gone");
+    }
+
+    /// Fulfills the promises of the jobs that have finished.
+    fn deliver(&mut self) {
+        while let Ok((id, result)) = self.completions.try_recv() {
+            if let Some(mut complete) = self.waiting.remove(&id) {
+                complete(result);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A queue of buffers waiting to be written to a stream.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use io::AsyncWrite;
+use {Promise, PromiseFulfiller, TaskSet, new_promise_and_fulfiller};
+
+/// Owns an `AsyncWrite` and writes the buffers pushed onto it, one after another, in order.
+/// Producers don't have to thread the writer through their code, and they can hold back while
+/// too much data is waiting by waiting on `ready()`.
+///
+/// If a write fails, that buffer's promise and those of all buffers behind it are rejected, and
+/// so is every later `push()`. Dropping the queue cancels any write in progress.
+pub struct WriteQueue<W> where W: AsyncWrite {
+    state: Rc<RefCell<State<W>>>,
+    tasks: TaskSet,
+}
+
+struct State<W> {
+    // `None` while a write is in progress and after a write has failed.
+    writer: Option<W>,
+    queue: VecDeque<(Vec<u8>, Box<PromiseFulfiller<()>>)>,
+
+    // The bytes in `queue` plus those of the write in progress.
+    queued_bytes: usize,
+    high_water_mark: usize,
+    ready_waiters: Vec<Box<PromiseFulfiller<()>>>,
+
+    // The message of the error that stopped the queue.
+    failure: Option<String>,
+}
+
+impl <W> WriteQueue<W> where W: AsyncWrite {
+    /// Creates a queue that writes to `writer`. `ready()` holds back producers while more than
+    /// `high_water_mark` bytes are waiting to be written.
+    pub fn new(writer: W, high_water_mark: usize) -> WriteQueue<W> {
+        WriteQueue {
+            state: Rc::new(RefCell::new(State {
+                writer: Some(writer),
+                queue: VecDeque::new(),
+                queued_bytes: 0,
+                high_water_mark: high_water_mark,
+                ready_waiters: Vec::new(),
+                failure: None,
+            })),
+            // The writes report their outcomes through the fulfillers, so they never fail.
+            tasks: TaskSet::new_with(|_error| {}),
+        }
+    }
+
+    /// Adds `buf` to the end of the queue. The returned promise resolves once all of `buf` has
+    /// been written. The buffer is queued even if the queue is over its high-water mark.
+    pub fn push(&mut self, buf: Vec<u8>) -> Promise<()> {
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        let writer = {
+            let state = &mut *self.state.borrow_mut();
+            if let Some(ref message) = state.failure {
+                return Promise::rejected(failed_error(message));
+            }
+            state.queued_bytes += buf.len();
+            state.queue.push_back((buf, fulfiller));
+            state.writer.take()
+        };
+        if let Some(writer) = writer {
+            self.tasks.add(write_loop(self.state.clone(), writer));
+        }
+        return promise;
+    }
+
+    /// Resolves once everything pushed so far has been written.
+    pub fn flush(&mut self) -> Promise<()> {
+        return self.push(Vec::new());
+    }
+
+    /// Resolves once no more than the high-water mark is waiting to be written, which is right
+    /// away if that's already the case. Rejects if the queue has failed.
+    pub fn ready(&self) -> Promise<()> {
+        let state = &mut *self.state.borrow_mut();
+        if let Some(ref message) = state.failure {
+            return Promise::rejected(failed_error(message));
+        }
+        if state.queued_bytes <= state.high_water_mark {
+            return Promise::fulfilled(());
+        }
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        state.ready_waiters.push(fulfiller);
+        return promise;
+    }
+
+    /// Returns the number of bytes that have been pushed but not yet written.
+    pub fn queued_bytes(&self) -> usize {
+        self.state.borrow().queued_bytes
+    }
+}
+
+fn failed_error(message: &str) -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::Other,
+                                   format!("write queue failed: {}", message)))
+}
+
+fn write_loop<W>(state: Rc<RefCell<State<W>>>, writer: W) -> Promise<()> where W: AsyncWrite {
+    let next = state.borrow_mut().queue.pop_front();
+    let (buf, fulfiller) = match next {
+        Some(entry) => entry,
+        None => {
+            state.borrow_mut().writer = Some(writer);
+            return Promise::fulfilled(());
+        }
+    };
+    let len = buf.len();
+    let written = writer.write(buf).map_else(|(writer, _buf)| Ok(Ok(writer)), |error| Ok(Err(error)));
+    return written.then(move |outcome| {
+        let writer = match outcome {
+            Ok(writer) => writer,
+            Err(error) => {
+                fail(&state, fulfiller, error);
+                return Ok(Promise::fulfilled(()));
+            }
+        };
+        fulfiller.fulfill(());
+        let waiters = {
+            let state = &mut *state.borrow_mut();
+            state.queued_bytes -= len;
+            if state.queued_bytes <= state.high_water_mark {
+                ::std::mem::replace(&mut state.ready_waiters, Vec::new())
+            } else {
+                Vec::new()
+            }
+        };
+        for waiter in waiters {
+            waiter.fulfill(());
+        }
+        return Ok(write_loop(state, writer));
+    });
+}
+
+/// Stops the queue after the write of `fulfiller`'s buffer failed with `error`.
+fn fail<W>(state: &Rc<RefCell<State<W>>>, fulfiller: Box<PromiseFulfiller<()>>, error: ::Error) {
+    let message = format!("{}", error);
+    fulfiller.reject(error);
+    let (queue, waiters) = {
+        let state = &mut *state.borrow_mut();
+        state.failure = Some(message.clone());
+        state.queued_bytes = 0;
+        (::std::mem::replace(&mut state.queue, VecDeque::new()),
+         ::std::mem::replace(&mut state.ready_waiters, Vec::new()))
+    };
+    for (_, fulfiller) in queue {
+        fulfiller.reject(failed_error(&message));
+    }
+    for waiter in waiters {
+        waiter.reject(failed_error(&message));
+    }
+}
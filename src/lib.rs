@@ -24,15 +24,48 @@
 //! Allows for coordination of asynchronous tasks using [promises](struct.Promise.html) as
 //! a basic building block.
 
+// Written in the style of Rust 1.x before `?` and `dyn`; the matching lints are quieted.
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
+extern crate libc;
 extern crate mio;
+extern crate net2;
 extern crate nix;
+#[cfg(feature = "log")] #[macro_use] extern crate log;
+#[cfg(feature = "json")] extern crate serde;
+#[cfg(feature = "json")] extern crate serde_json;
+#[cfg(feature = "tls")] extern crate rustls;
+#[cfg(feature = "tls")] extern crate webpki;
 
 use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use private::{promise_node, Event, BoolEvent, PromiseAndFulfillerHub,
               EVENT_LOOP, with_current_event_loop, PromiseNode};
 
+/// Like `try!()`, but for functions that return a `Promise<T>` rather than a `Result<T>`.
+///
+/// Unwraps a `Result<T>`. In the case of an error, returns early from the enclosing
+/// function with a rejected promise.
+#[macro_export]
+macro_rules! pry {
+    ($expr:expr) => (
+        match $expr {
+            ::std::result::Result::Ok(val) => val,
+            ::std::result::Result::Err(err) => {
+                return $crate::Promise::rejected(::std::convert::From::from(err))
+            }
+        })
+}
+
 pub mod io;
+#[cfg(feature = "debug-server")] pub use io::debug;
+pub mod net;
+pub mod prelude;
+pub mod scheduler;
+pub mod stream;
+pub mod test;
+pub mod util;
 
 mod private;
 mod handle_table;
@@ -96,6 +129,37 @@ impl <T> Promise <T> {
         self.map_else(func, |e| { return Err(e); })
     }
 
+    /// Calls `func` on a reference to the promise's value, if it is fulfilled, and passes the
+    /// value through unchanged. Handy for logging.
+    pub fn inspect<F>(self, func: F) -> Promise<T>
+        where F: 'static,
+              F: FnOnce(&T)
+    {
+        self.map(move |value| {
+            func(&value);
+            return Ok(value);
+        })
+    }
+
+    /// Calls `func` on a reference to the promise's error, if it is rejected, and passes the
+    /// error through unchanged.
+    pub fn inspect_err<F>(self, func: F) -> Promise<T>
+        where F: 'static,
+              F: FnOnce(&Error)
+    {
+        self.map_else(|value| { return Ok(value); }, move |error| {
+            func(&error);
+            return Err(error);
+        })
+    }
+
+    /// Returns a promise that resolves like `self`, unless `latch` is triggered first, in which
+    /// case it is rejected with `Interrupted` and `self` is cancelled. This is cheaper than an
+    /// `exclusive_join()` with `latch.when_triggered()`, as it allocates no extra events.
+    pub fn until(self, latch: &Latch) -> Promise<T> {
+        Promise { node: Box::new(promise_node::Until::new(self.node, latch.state.clone())) }
+    }
+
     /// Returns a new promise that resolves when either `self` or `other` resolves. The promise that
     /// doesn't resolve first is cancelled.
     pub fn exclusive_join(self, other: Promise<T>) -> Promise<T> {
@@ -116,8 +180,12 @@ impl <T> Promise <T> {
     ///
     /// The `WaitScope` argument ensures that `wait()` can only be called at the top level of a program.
     /// Waiting within event callbacks is disallowed.
+    ///
+    /// Fails with a `Deadlock` error if the loop runs out of events while the promise is still
+    /// pending and there is nothing that could ever produce another: no fd observers, timers,
+    /// idle callbacks, or custom event port.
     pub fn wait(mut self, _wait_scope: &WaitScope) -> Result<T> {
-        with_current_event_loop(move |event_loop| {
+        with_current_event_loop(move |event_loop| -> Result<T> {
             let fired = ::std::rc::Rc::new(::std::cell::Cell::new(false));
             let done_event = BoolEvent::new(fired.clone());
             let (handle, _dropper) = private::EventHandle::new();
@@ -127,23 +195,78 @@ impl <T> Promise <T> {
             //event_loop.running = true;
 
             while !fired.get() {
-                if !event_loop.turn() {
+                if event_loop.turn() {
+                    event_loop.poll_if_due();
+                } else {
                     // No events in the queue.
-                    event_loop.event_port.borrow_mut().wait();
+                    event_loop.run_idle_callbacks();
+                    if !event_loop.is_runnable() {
+                        if !event_loop.has_event_sources() {
+                            let live_fulfillers = event_loop.live_fulfillers.get();
+                            return Err(Box::new(Deadlock { live_fulfillers: live_fulfillers }));
+                        }
+                        event_loop.wait_for_events();
+                    }
                 }
             }
 
+            // Getting the value runs the callbacks of `map()` and the like.
+            let _guard = CallbackGuard::new(&event_loop.callback_depth);
             self.node.get()
         })
     }
 }
 
+impl Promise<()> {
+    /// Returns a promise that resolves on a later turn of the event loop, after the events that
+    /// are already queued have had theirs. A long computation can chain through `yield_now()`
+    /// between steps to let other callbacks run.
+    pub fn yield_now() -> Promise<()> {
+        // An immediate promise arms its event breadth-first, i.e. at the back of the queue.
+        Promise::fulfilled(())
+    }
+}
+
 /// A scope in which asynchronous programming can occur. Corresponds to the top level scope
 /// of some event loop.
 pub struct WaitScope(::std::marker::PhantomData<*mut u8>); // impl !Sync for WaitScope {}
 
+impl WaitScope {
+    /// Returns the timers of the event loop that this scope belongs to.
+    pub fn timer(&self) -> io::Timer {
+        io::Timer
+    }
+
+    /// Checks for I/O and expired timers without sleeping, then runs the events that are ready,
+    /// but not the ones that those events arm in turn. Returns true if events remain in the queue.
+    /// Useful for letting background tasks make progress between the phases of a synchronous
+    /// computation.
+    pub fn poll(&self) -> bool {
+        with_current_event_loop(|event_loop| {
+            event_loop.poll_for_events();
+            let ready = event_loop.queued_event_count();
+            event_loop.run_internal(ready as u32);
+            event_loop.is_runnable()
+        })
+    }
+
+    /// Like `poll()`, but runs at most `max_turn_count` turns, including any for events that are
+    /// armed along the way. Returns true if events remain in the queue.
+    pub fn poll_n(&self, max_turn_count: u32) -> bool {
+        with_current_event_loop(|event_loop| {
+            event_loop.poll_for_events();
+            event_loop.run_internal(max_turn_count);
+            event_loop.is_runnable()
+        })
+    }
+}
+
 /// Interface between an `EventLoop` and events originating from outside of the loop's thread.
-trait EventPort {
+///
+/// By default an event loop waits on a `io::MioEventPort`. `EventLoop::top_level_with_port()`
+/// installs a different one, e.g. an embedder's native event source or a simulation for tests.
+/// A port typically delivers events by fulfilling promise fulfillers.
+pub trait EventPort {
     /// Waits for an external event to arrive, sleeping if necessary.
     /// Returns true if wake() has been called from another thread.
     fn wait(&mut self) -> bool;
@@ -158,145 +281,1374 @@ trait EventPort {
     /// arrange to call run() on it soon. The default implementation does nothing.
     fn set_runnable(&mut self, _runnable: bool) { }
 
-
-    fn wake(&mut self) { unimplemented!(); }
+    /// Interrupts a `wait()` that is in progress, or makes the next one return right away, so
+    /// that the loop gets a chance to run. A port whose `wait()` never sleeps, or whose owner
+    /// wakes it some other way, can keep the default implementation, which does nothing.
+    fn wake(&mut self) { }
 }
 
 /// A queue of events being executed in a loop on a single thread.
 pub struct EventLoop {
-//    daemons: TaskSetImpl,
+    // Tasks added by `daemonize()`. Created on first use.
+    daemons: RefCell<Option<TaskSet>>,
+
     event_port: RefCell<io::MioEventPort>,
-    _running: bool,
-    _last_runnable_state: bool,
-    events: RefCell<handle_table::HandleTable<private::EventNode>>,
-    head: private::EventHandle,
-    tail: Cell<private::EventHandle>,
-    depth_first_insertion_point: Cell<private::EventHandle>,
+
+    // Set by `top_level_with_port()`, in which case it is waited on instead of `event_port`.
+    custom_port: RefCell<Option<Box<EventPort>>>,
+    // The number of event or idle callbacks that are running right now. See `scoped()`.
+    callback_depth: Cell<u32>,
+
+    // Whether the queue was non-empty when the runnable callback was last told about it.
+    last_runnable_state: Cell<bool>,
+
+    // Likewise, for the custom port's `set_runnable()`.
+    port_runnable_state: Cell<bool>,
+
+    // Set by `set_runnable_callback()`.
+    runnable_callback: RefCell<Option<Box<FnMut(bool)>>>,
+
+    // Set by `set_loop_observer()`.
+    loop_observer: RefCell<Option<Box<LoopObserver>>>,
+
+    // Every event that exists, armed or not.
+    all_events: private::EventRegistry,
+
+    // The id to give the next event that is created. See `EventId`.
+    next_event_id: Cell<usize>,
+
+    // One queue of armed events per `Priority`, highest first.
+    queues: [private::EventQueue; 3],
+
+    // The priority of the events that are armed right now. See `with_priority()`.
+    arm_priority: Cell<Priority>,
+
+    // Number of promise fulfillers that are still alive and have not yet been fired.
+    live_fulfillers: Cell<usize>,
+
+    #[cfg(feature = "node-counters")]
+    node_counts: Cell<NodeCounts>,
+
+    // The deadline set by the innermost `with_deadline()` for the code that is currently running.
+    deadline: Cell<Option<::std::time::Instant>>,
+
+    // The task sets that have been created on this loop, for introspection.
+    task_sets: RefCell<Vec<::std::rc::Weak<RefCell<private::TaskSetImpl>>>>,
+
+    // Closures queued by `defer()`, to be run once the current event has finished firing.
+    deferred: RefCell<::std::collections::VecDeque<Box<FnMut()>>>,
+
+    // Closures registered by `on_teardown()`, in the order in which they were registered.
+    teardown_hooks: RefCell<Vec<Box<FnMut()>>>,
+
+    // Closures registered by `on_idle()`.
+    idle_callbacks: RefCell<Vec<Box<FnMut() -> bool>>>,
+
+    // Closures registered by `on_wake()`.
+    wake_callbacks: RefCell<Vec<Box<FnMut() -> bool>>>,
+
+    // Set by `set_catch_panics()`.
+    catch_panics: Cell<bool>,
+
+    // Set by `set_log_level()`.
+    #[cfg(feature = "log")]
+    log_level: Cell<::log::LogLevelFilter>,
+
+    // Set by `set_turn_budget()`.
+    turn_budget: Cell<Option<::std::time::Duration>>,
+
+    // Set by `set_poll_interval()`.
+    poll_interval: Cell<Option<u32>>,
+
+    // See `EventLoopBuilder::max_turns_per_poll()`.
+    max_turns_per_poll: Cell<u32>,
+
+    // See `EventLoopBuilder::poll_strategy()`.
+    poll_strategy: PollStrategy,
+
+    // The number of turns that `Promise::wait()` has run since the loop last checked for external
+    // events.
+    turns_since_poll: Cell<u32>,
+
+    // When the loop last started running events after checking for external ones, if the turn
+    // budget is set.
+    busy_since: Cell<Option<::std::time::Instant>>,
+
+    // Counters reported by `loop_stats()`.
+    turns: Cell<u64>,
+    busy_time: Cell<::std::time::Duration>,
+    sleep_time: Cell<::std::time::Duration>,
 }
 
 
 
 impl EventLoop {
     /// Creates an event loop for the current thread, panicking if one already exists. Runs the given
-    /// closure and then drops the event loop.
-    pub fn top_level<F>(main: F) -> Result<()>
-        where F: FnOnce(&WaitScope) -> Result<()>
+    /// closure and then drops the event loop. Returns whatever the closure returned.
+    pub fn top_level<T, F>(main: F) -> Result<T>
+        where F: FnOnce(&WaitScope) -> Result<T>
+    {
+        EventLoopBuilder::new().top_level(main)
+    }
+
+    /// Like `top_level()`, but the loop waits for events on `port` instead of on its built-in
+    /// `io::MioEventPort`. The built-in port still exists, since the sockets and timers in `io`
+    /// are registered with it, but it is never waited on, so those only make progress if `port`
+    /// calls `io::MioEventPort::poll_current()`.
+    pub fn top_level_with_port<T, P, F>(port: P, main: F) -> Result<T>
+        where P: EventPort + 'static,
+              F: FnOnce(&WaitScope) -> Result<T>
     {
-        let mut events = handle_table::HandleTable::<private::EventNode>::new();
-        let dummy = private::EventNode { event: None, next: None, prev: None };
-        let head_handle = private::EventHandle(events.push(dummy));
+        EventLoopBuilder::new().event_port(port).top_level(main)
+    }
+
+    /// Like `top_level()`, but fails with a `LoopExists` error instead of panicking if the
+    /// current thread already has an event loop.
+    pub fn try_top_level<T, F>(main: F) -> Result<T>
+        where F: FnOnce(&WaitScope) -> Result<T>
+    {
+        if EventLoop::exists() {
+            return Err(Box::new(LoopExists));
+        }
+        EventLoop::top_level(main)
+    }
+
+    /// Runs `main` with a `WaitScope`, for code that needs to wait on promises but does not know
+    /// whether the current thread already has an event loop, such as a library with a blocking
+    /// API. If the thread has no loop, this is the same as `top_level()`: a loop is created for
+    /// the duration of `main` and torn down afterwards. If the thread has a loop and `main` is
+    /// being called from ordinary synchronous code, i.e. from within the loop's `top_level()`
+    /// but not from one of its callbacks, `main` runs on that loop, and any promises that it
+    /// leaves pending stay pending there. If `main` would be called from a callback, which must
+    /// not block, this fails with a `LoopBusy` error without calling it.
+    pub fn scoped<T, F>(main: F) -> Result<T>
+        where F: FnOnce(&WaitScope) -> Result<T>
+    {
+        let in_callback = EVENT_LOOP.with(|maybe_event_loop| {
+            match &*maybe_event_loop.borrow() {
+                &None => None,
+                &Some(ref event_loop) => Some(event_loop.callback_depth.get() > 0),
+            }
+        });
+        match in_callback {
+            None => EventLoop::top_level(main),
+            Some(false) => main(&WaitScope(::std::marker::PhantomData)),
+            Some(true) => Err(Box::new(LoopBusy)),
+        }
+    }
+
+    /// Returns true if the current thread has an event loop.
+    pub fn exists() -> bool {
+        EVENT_LOOP.with(|maybe_event_loop| maybe_event_loop.borrow().is_some())
+    }
+
+    fn top_level_internal<T, F>(config: EventLoopBuilder, main: F) -> Result<T>
+        where F: FnOnce(&WaitScope) -> Result<T>
+    {
+        let EventLoopBuilder { custom_port, poll_interval, turn_budget, max_turns_per_poll,
+                               poll_strategy, max_events_per_poll, observer_capacity,
+                               catch_panics, loop_observer, runnable_callback } = config;
+        let mut event_port = io::MioEventPort::new().unwrap();
+        event_port.reserve_observers(observer_capacity);
+        event_port.set_max_events_per_poll(max_events_per_poll);
+        let queues = [private::EventQueue::new(),
+                      private::EventQueue::new(),
+                      private::EventQueue::new()];
 
         EVENT_LOOP.with(move |maybe_event_loop| {
             let event_loop = EventLoop {
-                event_port: RefCell::new(io::MioEventPort::new().unwrap()),
-                _running: false,
-                _last_runnable_state: false,
-                events: RefCell::new(events),
-                head: head_handle,
-                tail: Cell::new(head_handle),
-                depth_first_insertion_point: Cell::new(head_handle), // insert after this node
+                event_port: RefCell::new(event_port),
+                custom_port: RefCell::new(custom_port),
+                daemons: RefCell::new(None),
+                callback_depth: Cell::new(0),
+                last_runnable_state: Cell::new(false),
+                port_runnable_state: Cell::new(false),
+                runnable_callback: RefCell::new(runnable_callback),
+                loop_observer: RefCell::new(loop_observer),
+                all_events: private::EventRegistry::new(),
+                next_event_id: Cell::new(1),
+                queues: queues,
+                arm_priority: Cell::new(Priority::Normal),
+                live_fulfillers: Cell::new(0),
+                #[cfg(feature = "node-counters")]
+                node_counts: Cell::new(NodeCounts::default()),
+                deadline: Cell::new(None),
+                task_sets: RefCell::new(Vec::new()),
+                deferred: RefCell::new(::std::collections::VecDeque::new()),
+                teardown_hooks: RefCell::new(Vec::new()),
+                idle_callbacks: RefCell::new(Vec::new()),
+                wake_callbacks: RefCell::new(Vec::new()),
+                catch_panics: Cell::new(catch_panics),
+                #[cfg(feature = "log")]
+                log_level: Cell::new(::log::LogLevelFilter::Trace),
+                turn_budget: Cell::new(turn_budget),
+                max_turns_per_poll: Cell::new(max_turns_per_poll),
+                poll_strategy: poll_strategy,
+                poll_interval: Cell::new(poll_interval),
+                turns_since_poll: Cell::new(0),
+                busy_since: Cell::new(None),
+                turns: Cell::new(0),
+                busy_time: Cell::new(::std::time::Duration::new(0, 0)),
+                sleep_time: Cell::new(::std::time::Duration::new(0, 0)),
             };
 
-            assert!(maybe_event_loop.borrow().is_none());
+            assert!(maybe_event_loop.borrow().is_none(), "this thread already has an event loop");
             *maybe_event_loop.borrow_mut() = Some(event_loop);
         });
+        let _guard = EventLoopGuard;
         let wait_scope = WaitScope(::std::marker::PhantomData );
 
         let result = main(&wait_scope);
+        if let Err(ref e) = result {
+            let _ = io::post_mortem::dump(&format!("top_level() returned an error: {}", e));
+        }
+        return result;
+    }
 
-        EVENT_LOOP.with(move |maybe_event_loop| {
-            *maybe_event_loop.borrow_mut() = None;
+    /// Destroys the current thread's event loop.
+    ///
+    /// Teardown hooks run first, newest first. Then daemon tasks are cancelled. Then pending events, deferred closures, and idle
+    /// callbacks are dropped, while the loop is still installed, so that the promise nodes they
+    /// own can unregister themselves. Next the fd observers are dropped, along with any
+    /// fulfillers waiting on them, and then any custom event port. Finally the loop is removed
+    /// from the thread, which closes the reactor and cancels any timers that have not yet
+    /// expired.
+    ///
+    /// If the thread is panicking, a post-mortem dump is written before anything is dropped.
+    fn teardown() {
+        if ::std::thread::panicking() {
+            let _ = io::post_mortem::dump("panicked");
+        }
+
+        loop {
+            let hook = with_current_event_loop(|event_loop| event_loop.teardown_hooks.borrow_mut().pop());
+            match hook {
+                Some(mut hook) => hook(),
+                None => break,
+            }
+        }
+
+        let daemons = with_current_event_loop(|event_loop| event_loop.daemons.borrow_mut().take());
+        drop(daemons);
+
+        loop {
+            let events: Vec<Box<Event>> = with_current_event_loop(|event_loop| {
+                event_loop.all_events.take_events()
+            });
+            let deferred: Vec<Box<FnMut()>> = with_current_event_loop(|event_loop| {
+                event_loop.deferred.borrow_mut().drain(..).collect()
+            });
+            let idle_callbacks = with_current_event_loop(|event_loop| {
+                ::std::mem::replace(&mut *event_loop.idle_callbacks.borrow_mut(), Vec::new())
+            });
+            let wake_callbacks = with_current_event_loop(|event_loop| {
+                ::std::mem::replace(&mut *event_loop.wake_callbacks.borrow_mut(), Vec::new())
+            });
+            if events.is_empty() && deferred.is_empty() && idle_callbacks.is_empty() &&
+                wake_callbacks.is_empty() {
+                break;
+            }
+            drop(events);
+            drop(deferred);
+            drop(idle_callbacks);
+            drop(wake_callbacks);
+        }
+
+        with_current_event_loop(|event_loop| {
+            event_loop.event_port.borrow_mut().clear_observers();
         });
 
-        return result;
+        let custom_port = with_current_event_loop(|event_loop| {
+            event_loop.custom_port.borrow_mut().take()
+        });
+        drop(custom_port);
+
+        EVENT_LOOP.with(|maybe_event_loop| {
+            let event_loop = ::std::mem::replace(&mut *maybe_event_loop.borrow_mut(), None);
+            drop(event_loop);
+        });
+    }
+
+    /// Returns the number of promise nodes of each kind that currently exist on this thread's
+    /// event loop, and that have been created since the loop started. Useful for tracking down
+    /// combinator explosions. Panics if the current thread has no event loop.
+    #[cfg(feature = "node-counters")]
+    pub fn node_counts() -> NodeCounts {
+        with_current_event_loop(|event_loop| event_loop.node_counts.get())
+    }
+
+    /// Returns runtime counters for the current thread's event loop, for diagnosing latency.
+    /// Panics if the current thread has no event loop.
+    pub fn loop_stats() -> LoopStats {
+        with_current_event_loop(|event_loop| {
+            let event_port = event_loop.event_port.borrow();
+            LoopStats {
+                turns: event_loop.turns.get(),
+                queued_events: event_loop.queued_event_count(),
+                fd_observers: event_port.observer_count(),
+                pending_timers: event_port.pending_timer_count(),
+                busy: event_loop.busy_time.get(),
+                sleeping: event_loop.sleep_time.get(),
+            }
+        })
+    }
+
+    /// Schedules `func` to run as soon as the event that is currently firing has finished, before
+    /// any other queued event. Closures deferred by the same event run in the order in which they
+    /// were deferred, and closures that they defer run before the next event too. If no event is
+    /// firing, `func` runs at the start of the next turn of the loop.
+    ///
+    /// This is useful for getting out from under a `RefCell` borrow that the current callback's
+    /// caller holds. Panics if the current thread has no event loop.
+    pub fn defer<F>(func: F) where F: 'static, F: FnOnce() {
+        let mut func = Some(func);
+        with_current_event_loop(move |event_loop| {
+            event_loop.deferred.borrow_mut().push_back(Box::new(move || {
+                (func.take().expect("deferred closure called twice"))()
+            }));
+        });
+    }
+
+    /// Registers `func` to run when the current thread's event loop is torn down at the end of
+    /// `top_level()`, whether `main` returned or panicked. Hooks run before anything else is
+    /// dropped, while daemon tasks, fd observers, and timers still exist, in the reverse of the
+    /// order in which they were registered, like destructors. A hook registered by another hook
+    /// runs next. Hooks cannot wait on promises, but they can use the loop to close listeners,
+    /// cancel timers, or flush logs deterministically. Panics if the current thread has no event
+    /// loop.
+    pub fn on_teardown<F>(func: F) where F: 'static, F: FnOnce() {
+        let mut func = Some(func);
+        with_current_event_loop(move |event_loop| {
+            event_loop.teardown_hooks.borrow_mut().push(Box::new(move || {
+                (func.take().expect("teardown hook called twice"))()
+            }));
+        });
+    }
+
+    /// If `catch_panics` is true, a panic in a `then()` or `map()` callback, or one of their
+    /// variants, rejects the promise that the callback was to resolve with a `Panicked` error
+    /// instead of unwinding through the event loop. Other panics while an event fires still
+    /// unwind, but leave the loop's queue consistent, so that the loop can keep running if the
+    /// panic is caught. Off by default. Panics if the current thread has no event loop.
+    pub fn set_catch_panics(catch_panics: bool) {
+        with_current_event_loop(|event_loop| event_loop.catch_panics.set(catch_panics));
+    }
+
+    /// Caps the level of the records that gj logs on the current thread's event loop, on top of
+    /// whatever the installed logger filters out. `Trace` by default, which leaves it all to the
+    /// logger. Panics if the current thread has no event loop.
+    #[cfg(feature = "log")]
+    pub fn set_log_level(level: ::log::LogLevelFilter) {
+        with_current_event_loop(|event_loop| event_loop.log_level.set(level));
+    }
+
+    /// Returns the level set by `set_log_level()`. Panics if the current thread has no event loop.
+    #[cfg(feature = "log")]
+    pub fn log_level() -> ::log::LogLevelFilter {
+        with_current_event_loop(|event_loop| event_loop.log_level.get())
+    }
+
+    /// Runs `promise` as a background task owned by the current thread's event loop, for
+    /// fire-and-forget work such as periodic log flushes or keepalives. If the promise fails,
+    /// `error_handler` is called with the error. Daemon tasks that are still running when
+    /// `top_level()` returns are cancelled. Panics if the current thread has no event loop.
+    pub fn daemonize<F>(promise: Promise<()>, error_handler: F)
+        where F: 'static, F: FnOnce(Error)
+    {
+        let task = promise.map_else(|()| Ok(()), move |error| {
+            error_handler(error);
+            Ok(())
+        });
+        let needs_set = with_current_event_loop(|event_loop| event_loop.daemons.borrow().is_none());
+        if needs_set {
+            // Errors are all handled above.
+            let daemons = TaskSet::new_with(|_error| {});
+            with_current_event_loop(move |event_loop| {
+                *event_loop.daemons.borrow_mut() = Some(daemons);
+            });
+        }
+        with_current_event_loop(move |event_loop| {
+            event_loop.daemons.borrow_mut().as_mut().expect("no daemon task set?").add(task);
+        });
+    }
+
+    /// Sets how long `Promise::wait()` may keep running ready events before it checks for I/O and
+    /// expired timers. This complements `set_poll_interval()` for loops whose turns vary a lot in
+    /// length: once the budget has been used up, the check happens between turns, and the events
+    /// that it arms are run before the rest of the queue. `None`, the default, disables the
+    /// budget. Panics if the current thread has no event loop.
+    pub fn set_turn_budget(budget: Option<::std::time::Duration>) {
+        with_current_event_loop(|event_loop| {
+            event_loop.turn_budget.set(budget);
+            event_loop.busy_since.set(None);
+        });
+    }
+
+    /// Sets how many turns `Promise::wait()` may run before it checks for I/O and expired timers,
+    /// as it otherwise only does once the queue is empty. The events that the check arms are run
+    /// before the rest of the queue, so a busy chain of ready promises cannot starve socket
+    /// handling. Defaults to `DEFAULT_POLL_INTERVAL`; `None` disables the periodic check. Panics
+    /// if the current thread has no event loop.
+    pub fn set_poll_interval(turns: Option<u32>) {
+        with_current_event_loop(|event_loop| {
+            event_loop.poll_interval.set(turns);
+            event_loop.turns_since_poll.set(0);
+        });
+    }
+
+    /// Called after each turn in `Promise::wait()`. Checks for external events if the poll
+    /// interval or the turn budget has been used up since the loop last did.
+    fn poll_if_due(&self) {
+        let mut due = false;
+        if let Some(interval) = self.poll_interval.get() {
+            let turns = self.turns_since_poll.get() + 1;
+            self.turns_since_poll.set(turns);
+            due = turns >= interval;
+        }
+        if let Some(budget) = self.turn_budget.get() {
+            match self.busy_since.get() {
+                None => self.busy_since.set(Some(::std::time::Instant::now())),
+                Some(since) => due = due || since.elapsed() >= budget,
+            }
+        }
+        if due {
+            self.poll_for_events();
+        }
+    }
+
+    /// Registers `callback` to run whenever the current thread's event queue has emptied and the
+    /// loop is about to sleep waiting for external events, i.e. during `Promise::wait()`. This is a
+    /// good time to flush batched writes or do incremental maintenance. The callback stays
+    /// registered for as long as it returns true. Callbacks run in the order in which they were
+    /// registered; if they arm any events, those are run before the loop sleeps. Panics if the
+    /// current thread has no event loop.
+    pub fn on_idle<F>(callback: F) where F: 'static, F: FnMut() -> bool {
+        with_current_event_loop(move |event_loop| {
+            event_loop.idle_callbacks.borrow_mut().push(Box::new(callback));
+        });
+    }
+
+    /// Registers `callback` to run each time the current thread's loop is woken by a
+    /// `io::LoopWaker`, once the port has returned. This is how work that other threads finish
+    /// gets back onto the loop: the thread queues its result and calls `wake()`, and the callback
+    /// takes it from the queue. The callback stays registered for as long as it returns true, and
+    /// while it is registered, `Promise::wait()` counts it as something that could arm an event,
+    /// so it should return false once it has nothing left to wait for. Panics if the current
+    /// thread has no event loop.
+    pub fn on_wake<F>(callback: F) where F: 'static, F: FnMut() -> bool {
+        with_current_event_loop(move |event_loop| {
+            event_loop.wake_callbacks.borrow_mut().push(Box::new(callback));
+        });
+    }
+
+    /// Runs the callbacks registered by `on_idle()`, dropping those that return false. Callbacks
+    /// registered in the meantime are kept, but not run until the next time the loop goes idle.
+    fn run_idle_callbacks(&self) {
+        self.run_callbacks(&self.idle_callbacks);
+    }
+
+    /// Runs the callbacks registered by `on_wake()` if a `LoopWaker` has woken the built-in port
+    /// since the last time.
+    fn run_wake_callbacks(&self) {
+        if self.event_port.borrow_mut().take_wakeup() {
+            self.run_callbacks(&self.wake_callbacks);
+        }
+    }
+
+    /// Runs `callbacks` in order, dropping those that return false. Callbacks that are
+    /// registered in the meantime are kept for next time.
+    fn run_callbacks(&self, callbacks: &RefCell<Vec<Box<FnMut() -> bool>>>) {
+        let _guard = CallbackGuard::new(&self.callback_depth);
+        let running = ::std::mem::replace(&mut *callbacks.borrow_mut(), Vec::new());
+        let mut kept = Vec::with_capacity(running.len());
+        for mut callback in running.into_iter() {
+            if callback() {
+                kept.push(callback);
+            }
+        }
+        let added = ::std::mem::replace(&mut *callbacks.borrow_mut(), kept);
+        callbacks.borrow_mut().extend(added);
+    }
+
+    /// Returns true if there is an event or deferred closure waiting to run.
+    fn is_runnable(&self) -> bool {
+        self.has_queued_events() || !self.deferred.borrow().is_empty()
+    }
+
+    /// Returns true if anything could arm an event while the queue is empty, i.e. if there is a
+    /// custom event port, an idle or wake callback, or an fd observer or timer on the built-in
+    /// port. Timers on a custom clock don't count, since only the program can move the clock on.
+    fn has_event_sources(&self) -> bool {
+        if self.custom_port.borrow().is_some() || !self.idle_callbacks.borrow().is_empty() ||
+            !self.wake_callbacks.borrow().is_empty() {
+            return true;
+        }
+        let event_port = self.event_port.borrow();
+        let timers_can_fire = event_port.pending_timer_count() > 0 && !event_port.has_custom_clock();
+        event_port.observer_count() > 0 || timers_can_fire
+    }
+
+    /// Returns true if any of the queues has an armed event.
+    fn has_queued_events(&self) -> bool {
+        self.queues.iter().any(|queue| queue.head.next().is_some())
+    }
+
+    /// Runs `func`, giving the events that it arms the given priority instead of the default.
+    /// When an event fires, the events that it arms get its priority, so that follow-up work
+    /// stays in the same class. For example, the built-in port fulfills timers at
+    /// `Priority::High`, so timer callbacks run before the I/O continuations that are already
+    /// queued. Panics if the current thread has no event loop.
+    pub fn with_priority<F, R>(priority: Priority, func: F) -> R where F: FnOnce() -> R {
+        let previous = with_current_event_loop(|event_loop| {
+            let previous = event_loop.arm_priority.get();
+            event_loop.arm_priority.set(priority);
+            previous
+        });
+        let _guard = PriorityGuard { previous: previous };
+        return func();
+    }
+
+    /// Runs the closures queued by `defer()`, including any that they queue in turn. Returns
+    /// whether there were any.
+    fn run_deferred(&self) -> bool {
+        let mut ran = false;
+        loop {
+            let next = self.deferred.borrow_mut().pop_front();
+            match next {
+                Some(mut func) => {
+                    func();
+                    ran = true;
+                }
+                None => return ran,
+            }
+        }
+    }
+
+    /// Returns the number of events that are armed and waiting to fire.
+    fn queued_event_count(&self) -> usize {
+        let mut count = 0;
+        for queue in self.queues.iter() {
+            let mut next = queue.head.next();
+            while let Some(event_handle) = next {
+                count += 1;
+                next = event_handle.next();
+            }
+        }
+        return count;
+    }
+
+    /// Returns true if the event is already in the queue. Arming a queued event has no effect.
+    fn is_armed(&self, event_handle: private::EventHandle) -> bool {
+        event_handle.prev().is_some()
+    }
+
+    /// Returns the queue that events armed right now go in.
+    fn arm_queue(&self) -> &private::EventQueue {
+        &self.queues[self.arm_priority.get().index()]
     }
 
     fn arm_depth_first(&self, event_handle: private::EventHandle) {
+        if self.is_armed(event_handle) {
+            return;
+        }
 
-        let insertion_node_next = self.events.borrow()[self.depth_first_insertion_point.get().0].next;
+        let queue = self.arm_queue();
+        let insertion_point = queue.depth_first_insertion_point.get();
 
-        match insertion_node_next {
+        match insertion_point.next() {
             Some(next_handle) => {
-                self.events.borrow_mut()[next_handle.0].prev = Some(event_handle);
-                self.events.borrow_mut()[event_handle.0].next = Some(next_handle);
+                next_handle.set_prev(Some(event_handle));
+                event_handle.set_next(Some(next_handle));
             }
             None => {
-                self.tail.set(event_handle);
+                queue.tail.set(event_handle);
             }
         }
 
-        self.events.borrow_mut()[event_handle.0].prev = Some(self.depth_first_insertion_point.get());
-        self.events.borrow_mut()[self.depth_first_insertion_point.get().0].next = Some(event_handle);
-        self.depth_first_insertion_point.set(event_handle);
+        event_handle.set_prev(Some(insertion_point));
+        insertion_point.set_next(Some(event_handle));
+        queue.depth_first_insertion_point.set(event_handle);
+        self.notify_runnable();
     }
 
     fn arm_breadth_first(&self, event_handle: private::EventHandle) {
-        let events = &mut *self.events.borrow_mut();
-        events[self.tail.get().0].next = Some(event_handle);
-        events[event_handle.0].prev = Some(self.tail.get());
-        self.tail.set(event_handle);
+        if self.is_armed(event_handle) {
+            return;
+        }
+        {
+            let queue = self.arm_queue();
+            queue.tail.get().set_next(Some(event_handle));
+            event_handle.set_prev(Some(queue.tail.get()));
+            queue.tail.set(event_handle);
+        }
+        self.notify_runnable();
+    }
+
+    /// Picks the queue to take the next event from: the highest-priority one that has events,
+    /// unless a lower one has been passed over for `STARVATION_LIMIT` turns in a row. Returns
+    /// `None` if all of the queues are empty.
+    fn next_queue(&self) -> Option<usize> {
+        let mut chosen = None;
+        for (index, queue) in self.queues.iter().enumerate() {
+            if queue.head.next().is_none() {
+                continue;
+            }
+            if chosen.is_none() || queue.starved_turns.get() >= STARVATION_LIMIT {
+                chosen = Some(index);
+                if queue.starved_turns.get() >= STARVATION_LIMIT {
+                    break;
+                }
+            }
+        }
+
+        for (index, queue) in self.queues.iter().enumerate() {
+            if Some(index) == chosen || queue.head.next().is_none() {
+                queue.starved_turns.set(0);
+            } else {
+                queue.starved_turns.set(queue.starved_turns.get() + 1);
+            }
+        }
+        return chosen;
     }
 
     /// Runs the event loop for `max_turn_count` turns or until there is nothing left to be done,
-    /// whichever comes first. This never calls the `EventPort`'s `sleep()` or `poll()`. It will
-    /// call the `EventPort`'s `set_runnable(false)` if the queue becomes empty.
-    fn _run(&mut self, max_turn_count: u32) {
-        self._running = true;
+    /// whichever comes first, and returns the number of turns that were run. Each turn fires one
+    /// event. This never sleeps or checks for I/O, so it is suitable for pumping the loop from
+    /// another program's main loop, e.g. once per frame. Like `Promise::wait()`, it can only be
+    /// called at the top level.
+    pub fn run(_wait_scope: &WaitScope, max_turn_count: u32) -> u32 {
+        with_current_event_loop(|event_loop| event_loop.run_internal(max_turn_count))
+    }
 
-        for _ in 0..max_turn_count {
-            if !self.turn() {
-                break;
+    /// Runs the event loop until there are no events left in the queue, without sleeping or
+    /// checking for I/O. Returns the number of turns that were run. Events that are armed along
+    /// the way are run too, so this does not return while callbacks keep scheduling more work.
+    pub fn run_until_idle(_wait_scope: &WaitScope) -> u32 {
+        with_current_event_loop(|event_loop| event_loop.run_internal(u32::max_value()))
+    }
+
+    /// Checks for I/O and expired timers without sleeping, then runs the event loop until it is
+    /// idle, or for at most as many turns as were set by `EventLoopBuilder::max_turns_per_poll()`.
+    /// Returns the number of turns that were run.
+    pub fn poll(_wait_scope: &WaitScope) -> u32 {
+        with_current_event_loop(|event_loop| {
+            event_loop.poll_for_events();
+            event_loop.run_internal(event_loop.max_turns_per_poll.get())
+        })
+    }
+
+    /// Sleeps until an external event arrives, or with `PollStrategy::Spin`, checks for one
+    /// without sleeping.
+    fn wait_for_events(&self) {
+        let start = ::std::time::Instant::now();
+        let spin = self.poll_strategy == PollStrategy::Spin;
+
+        // The custom port is taken out while it waits, so that it may fulfill promises.
+        let custom_port = self.custom_port.borrow_mut().take();
+        match custom_port {
+            Some(mut port) => {
+                if spin { port.poll(); } else { port.wait(); }
+                *self.custom_port.borrow_mut() = Some(port);
+            }
+            None => {
+                let event_port = &mut *self.event_port.borrow_mut();
+                if spin { event_port.poll(); } else { event_port.wait(); }
             }
         }
+        self.sleep_time.set(self.sleep_time.get() + start.elapsed());
+        self.busy_since.set(None);
+        self.turns_since_poll.set(0);
+        self.run_wake_callbacks();
+        self.notify_runnable();
+    }
+
+    /// Checks for external events without sleeping.
+    fn poll_for_events(&self) {
+        let custom_port = self.custom_port.borrow_mut().take();
+        match custom_port {
+            Some(mut port) => {
+                port.poll();
+                *self.custom_port.borrow_mut() = Some(port);
+            }
+            None => { self.event_port.borrow_mut().poll(); }
+        }
+        self.busy_since.set(None);
+        self.turns_since_poll.set(0);
+        self.run_wake_callbacks();
+        self.notify_runnable();
+    }
+
+    /// Registers `callback` to be called with `true` when the current thread's event queue goes
+    /// from empty to non-empty, and with `false` when it empties again. A program that drives gj
+    /// from another event loop, such as a GUI toolkit's, can use this to schedule a call to
+    /// `run_until_idle()` on that loop. The callback runs in the middle of whatever armed the
+    /// event, so it must not run the event loop itself. Replaces any previous callback. Panics if
+    /// the current thread has no event loop.
+    pub fn set_runnable_callback<F>(callback: F) where F: 'static, F: FnMut(bool) {
+        with_current_event_loop(move |event_loop| {
+            *event_loop.runnable_callback.borrow_mut() = Some(Box::new(callback));
+        });
+    }
+
+    /// Installs `observer` to be told about every event that the current thread's loop fires.
+    /// Replaces any previous observer. Panics if the current thread has no event loop.
+    pub fn set_loop_observer<O>(observer: O) where O: LoopObserver + 'static {
+        with_current_event_loop(move |event_loop| {
+            *event_loop.loop_observer.borrow_mut() = Some(Box::new(observer));
+        });
+    }
+
+    /// Removes the observer installed by `set_loop_observer()`, if any. Panics if the current
+    /// thread has no event loop.
+    pub fn clear_loop_observer() {
+        with_current_event_loop(|event_loop| {
+            *event_loop.loop_observer.borrow_mut() = None;
+        });
+    }
+
+    /// Calls `func` on the loop observer, if there is one. The observer is taken out while `func`
+    /// runs, so that it may arm events.
+    fn with_loop_observer<F>(&self, func: F) where F: FnOnce(&mut LoopObserver) {
+        let observer = self.loop_observer.borrow_mut().take();
+        if let Some(mut observer) = observer {
+            func(&mut *observer);
+            let slot = &mut *self.loop_observer.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(observer);
+            }
+        }
+    }
+
+    /// Tells the runnable callback and the custom port's `set_runnable()` when the queue has gone
+    /// from empty to non-empty or back since they were last told. A port that is busy waiting is
+    /// told once it is done.
+    fn notify_runnable(&self) {
+        let runnable = self.has_queued_events();
+        if runnable != self.last_runnable_state.get() {
+            self.last_runnable_state.set(runnable);
+            let callback = self.runnable_callback.borrow_mut().take();
+            if let Some(mut callback) = callback {
+                callback(runnable);
+                let slot = &mut *self.runnable_callback.borrow_mut();
+                if slot.is_none() {
+                    *slot = Some(callback);
+                }
+            }
+        }
+        if runnable != self.port_runnable_state.get() {
+            let custom_port = self.custom_port.borrow_mut().take();
+            if let Some(mut port) = custom_port {
+                self.port_runnable_state.set(runnable);
+                port.set_runnable(runnable);
+                *self.custom_port.borrow_mut() = Some(port);
+            }
+        }
+    }
+
+    fn run_internal(&self, max_turn_count: u32) -> u32 {
+        let mut turns = 0;
+        while turns < max_turn_count && self.turn() {
+            turns += 1;
+        }
+        return turns;
     }
 
     /// Runs the event loop for a single step.
     fn turn(&self) -> bool {
+        let start = ::std::time::Instant::now();
+        let fired = {
+            let _guard = CallbackGuard::new(&self.callback_depth);
+            self.fire_next()
+        };
+        if !fired {
+            return false;
+        }
+        self.turns.set(self.turns.get() + 1);
+        self.busy_time.set(self.busy_time.get() + start.elapsed());
+        return true;
+    }
 
-        let event_handle = match self.events.borrow()[self.head.0].next {
+    /// Runs the deferred closures if there are any, or else fires the next queued event. Returns
+    /// false if there was nothing to do.
+    fn fire_next(&self) -> bool {
+        if self.run_deferred() {
+            return true;
+        }
+
+        let index = match self.next_queue() {
             None => return false,
-            Some(event_handle) => { event_handle }
+            Some(index) => index,
         };
-        self.depth_first_insertion_point.set(event_handle);
+        let queue = &self.queues[index];
+        let event_handle = queue.head.next().expect("chose an empty queue?");
 
-        let mut event = ::std::mem::replace(&mut self.events.borrow_mut()[event_handle.0].event, None)
-            .expect("No event to fire?");
-        let _dropper = event.fire();
-
-        let maybe_next = self.events.borrow()[event_handle.0].next;
-        self.events.borrow_mut()[self.head.0].next = maybe_next;
+        // Unlink the event before firing it. Firing it may drop its `EventDropper`, which frees
+        // the node, so the node must not be touched afterwards.
+        let maybe_next = event_handle.next();
+        queue.head.set_next(maybe_next);
         match maybe_next {
             Some(e) => {
-                self.events.borrow_mut()[e.0].prev = Some(self.head);
+                e.set_prev(Some(queue.head));
             }
             None => {}
         }
 
-        self.events.borrow_mut()[event_handle.0].next = None;
-        self.events.borrow_mut()[event_handle.0].prev = None;
+        event_handle.set_next(None);
+        event_handle.set_prev(None);
+
+        if queue.tail.get() == event_handle {
+            queue.tail.set(queue.head);
+        }
+        queue.depth_first_insertion_point.set(queue.head);
 
-        if self.tail.get() == event_handle {
-            self.tail.set(self.head);
+        let mut event = event_handle.take_event().expect("No event to fire?");
+        let id = EventId(event_handle.id());
+        let observed = self.loop_observer.borrow().is_some();
+        let start = if observed {
+            self.with_loop_observer(|observer| observer.before_fire(id));
+            Some(::std::time::Instant::now())
+        } else {
+            None
+        };
+        let previous_priority = self.arm_priority.get();
+        self.arm_priority.set(PRIORITIES[index]);
+        let fired = if self.catch_panics.get() {
+            ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| event.fire()))
+        } else {
+            Ok(event.fire())
+        };
+        self.arm_priority.set(previous_priority);
+        if let Some(start) = start {
+            let elapsed = start.elapsed();
+            self.with_loop_observer(|observer| observer.after_fire(id, elapsed));
         }
 
-        self.depth_first_insertion_point.set(self.head);
+        let dropper = match fired {
+            Ok(dropper) => dropper,
+            Err(payload) => {
+                // The event is out of the queue, so it is safe to let the panic continue.
+                drop(event);
+                for queue in self.queues.iter() {
+                    queue.depth_first_insertion_point.set(queue.head);
+                }
+                self.notify_runnable();
+                ::std::panic::resume_unwind(payload);
+            }
+        };
+        drop(dropper);
+        drop(event);
+        for queue in self.queues.iter() {
+            queue.depth_first_insertion_point.set(queue.head);
+        }
+        self.run_deferred();
+        self.notify_runnable();
         return true;
     }
 }
 
-/// A callback that can be used to fulfill or reject a promise.
-pub trait PromiseFulfiller<T> where T: 'static {
+/// The priority class of an armed event. Each turn of the event loop fires the next event of the
+/// highest priority that has any, except that a lower priority that has been passed over for
+/// too many turns in a row gets a turn, so that it cannot be starved. See
+/// `EventLoop::with_priority()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    /// For work that should jump ahead of everything else, like timer expirations and
+    /// cancellation.
+    High,
+
+    /// The default.
+    Normal,
+
+    /// For bulk work that should only run when nothing more important is waiting.
+    Low,
+}
+
+impl Priority {
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+const PRIORITIES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+/// How an event loop waits for external events once it has run out of work. See
+/// `EventLoopBuilder::poll_strategy()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// Sleep in the event port until something happens. The default.
+    Block,
+
+    /// Check the event port without sleeping, over and over, which keeps a core busy but
+    /// shaves the wakeup latency off of each event.
+    Spin,
+}
+
+/// The number of turns that `Promise::wait()` runs between checks for I/O, unless changed with
+/// `EventLoop::set_poll_interval()`.
+pub const DEFAULT_POLL_INTERVAL: u32 = 64;
+
+/// The number of turns in a row that a queue with events can be passed over for higher-priority
+/// ones before it gets a turn anyway.
+const STARVATION_LIMIT: u32 = 32;
+
+/// Restores the arming priority that was in effect before `with_priority()`.
+struct PriorityGuard {
+    previous: Priority,
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        private::if_current_event_loop(|event_loop| event_loop.arm_priority.set(self.previous));
+    }
+}
+
+/// Counts a callback as running for as long as it is alive, even if the callback panics.
+struct CallbackGuard<'a> {
+    depth: &'a Cell<u32>,
+}
+
+impl <'a> CallbackGuard<'a> {
+    fn new(depth: &'a Cell<u32>) -> CallbackGuard<'a> {
+        depth.set(depth.get() + 1);
+        CallbackGuard { depth: depth }
+    }
+}
+
+impl <'a> Drop for CallbackGuard<'a> {
+    fn drop(&mut self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+/// Configures an event loop before it starts. `EventLoop::top_level()` is shorthand for
+/// `EventLoopBuilder::new().top_level()`.
+///
+/// ```ignore
+/// let value = EventLoopBuilder::new()
+///     .poll_interval(Some(16))
+///     .catch_panics(true)
+///     .top_level(|wait_scope| { ... });
+/// ```
+pub struct EventLoopBuilder {
+    custom_port: Option<Box<EventPort>>,
+    poll_interval: Option<u32>,
+    turn_budget: Option<::std::time::Duration>,
+    max_turns_per_poll: u32,
+    poll_strategy: PollStrategy,
+    max_events_per_poll: usize,
+    observer_capacity: usize,
+    catch_panics: bool,
+    loop_observer: Option<Box<LoopObserver>>,
+    runnable_callback: Option<Box<FnMut(bool)>>,
+}
+
+impl EventLoopBuilder {
+    /// Returns a builder with the default configuration.
+    pub fn new() -> EventLoopBuilder {
+        EventLoopBuilder {
+            custom_port: None,
+            poll_interval: Some(DEFAULT_POLL_INTERVAL),
+            turn_budget: None,
+            max_turns_per_poll: u32::max_value(),
+            poll_strategy: PollStrategy::Block,
+            max_events_per_poll: usize::max_value(),
+            observer_capacity: 0,
+            catch_panics: false,
+            loop_observer: None,
+            runnable_callback: None,
+        }
+    }
+
+    /// Waits for events on `port` instead of the built-in port. See
+    /// `EventLoop::top_level_with_port()`.
+    pub fn event_port<P>(mut self, port: P) -> EventLoopBuilder where P: EventPort + 'static {
+        self.custom_port = Some(Box::new(port));
+        self
+    }
+
+    /// See `EventLoop::set_poll_interval()`.
+    pub fn poll_interval(mut self, turns: Option<u32>) -> EventLoopBuilder {
+        self.poll_interval = turns;
+        self
+    }
+
+    /// See `EventLoop::set_turn_budget()`.
+    pub fn turn_budget(mut self, budget: Option<::std::time::Duration>) -> EventLoopBuilder {
+        self.turn_budget = budget;
+        self
+    }
+
+    /// Limits the number of turns that a call to `EventLoop::poll()` runs, so that a program that
+    /// pumps the loop from its own main loop gets control back promptly. Unlimited by default.
+    pub fn max_turns_per_poll(mut self, turns: u32) -> EventLoopBuilder {
+        self.max_turns_per_poll = turns;
+        self
+    }
+
+    /// Sets how the loop waits for external events when it has nothing else to do.
+    /// `PollStrategy::Block` by default.
+    pub fn poll_strategy(mut self, strategy: PollStrategy) -> EventLoopBuilder {
+        self.poll_strategy = strategy;
+        self
+    }
+
+    /// Limits the number of I/O readiness events that the built-in port delivers each time it is
+    /// waited on or polled. The rest are kept, in order, for the next time, so that a burst of
+    /// activity on many sockets is worked through a slice at a time. Must be positive.
+    /// Unlimited by default.
+    pub fn max_events_per_poll(mut self, events: usize) -> EventLoopBuilder {
+        assert!(events > 0, "max_events_per_poll must be positive");
+        self.max_events_per_poll = events;
+        self
+    }
+
+    /// Reserves room in the built-in port's table of fd observers for `observers` of them, so
+    /// that a server that expects that many connections does not grow the table as they arrive.
+    pub fn observer_capacity(mut self, observers: usize) -> EventLoopBuilder {
+        self.observer_capacity = observers;
+        self
+    }
+
+    /// See `EventLoop::set_catch_panics()`.
+    pub fn catch_panics(mut self, catch_panics: bool) -> EventLoopBuilder {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// See `EventLoop::set_loop_observer()`.
+    pub fn loop_observer<O>(mut self, observer: O) -> EventLoopBuilder where O: LoopObserver + 'static {
+        self.loop_observer = Some(Box::new(observer));
+        self
+    }
+
+    /// See `EventLoop::set_runnable_callback()`.
+    pub fn runnable_callback<F>(mut self, callback: F) -> EventLoopBuilder
+        where F: 'static, F: FnMut(bool)
+    {
+        self.runnable_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Like `EventLoop::top_level()`, but creates the loop with this configuration.
+    pub fn top_level<T, F>(self, main: F) -> Result<T>
+        where F: FnOnce(&WaitScope) -> Result<T>
+    {
+        EventLoop::top_level_internal(self, main)
+    }
+}
+
+/// Identifies an event, for `LoopObserver`. Each event created by a loop gets a new id.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventId(pub usize);
+
+impl ::std::fmt::Display for EventId {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::result::Result<(), ::std::fmt::Error> {
+        write!(fmt, "event#{}", self.0)
+    }
+}
+
+/// Instrumentation hooks that are called around every event that an event loop fires. Useful for
+/// latency tracing and profiling. See `EventLoop::set_loop_observer()`.
+///
+/// The hooks run on the loop's thread, in the middle of a turn, so they should be quick. They may
+/// arm events but must not run the event loop.
+pub trait LoopObserver {
+    /// Called just before `event` fires.
+    fn before_fire(&mut self, _event: EventId) {}
+
+    /// Called just after `event` has fired, with the time that firing it took.
+    fn after_fire(&mut self, _event: EventId, _elapsed: ::std::time::Duration) {}
+}
+
+/// Runtime counters for an event loop. See `EventLoop::loop_stats()`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LoopStats {
+    /// Number of turns that the loop has run since it started. Each turn fires one event, or runs
+    /// the closures queued by `EventLoop::defer()`.
+    pub turns: u64,
+
+    /// Number of events that are armed and waiting to fire.
+    pub queued_events: usize,
+
+    /// Number of fd observers registered with the built-in event port.
+    pub fd_observers: usize,
+
+    /// Number of timers that have been set and have neither fired nor been cancelled.
+    pub pending_timers: usize,
+
+    /// Total time spent running turns, i.e. in callbacks.
+    pub busy: ::std::time::Duration,
+
+    /// Total time spent sleeping while waiting for external events.
+    pub sleeping: ::std::time::Duration,
+}
+
+/// Counts of promise nodes of a particular kind. See `EventLoop::node_counts()`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NodeCount {
+    /// Number of nodes that currently exist.
+    pub live: u64,
+
+    /// Number of nodes that have been created since the event loop started.
+    pub created: u64,
+}
+
+/// Counts of promise nodes, broken down by kind. See `EventLoop::node_counts()`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NodeCounts {
+    /// Nodes created by `then()`, `map()`, and their variants.
+    pub transform: NodeCount,
+
+    /// Nodes that flatten the promises returned by `then()` callbacks.
+    pub chain: NodeCount,
+
+    /// Nodes created by `join_promises()`, `exclusive_join()`, `race_ok()`, and the like.
+    pub join: NodeCount,
+
+    /// Nodes created by `Promise::fulfilled()` and `Promise::rejected()`.
+    pub immediate: NodeCount,
+}
+
+impl NodeCounts {
+    #[cfg(feature = "node-counters")]
+    fn get_mut(&mut self, kind: private::promise_node::NodeKind) -> &mut NodeCount {
+        use private::promise_node::NodeKind;
+        match kind {
+            NodeKind::Transform => &mut self.transform,
+            NodeKind::Chain => &mut self.chain,
+            NodeKind::Join => &mut self.join,
+            NodeKind::Immediate => &mut self.immediate,
+        }
+    }
+}
+
+/// Tears down the current thread's event loop when dropped. This ensures that `top_level()`
+/// leaves the thread without an event loop even if its `main` closure panics.
+struct EventLoopGuard;
+
+impl Drop for EventLoopGuard {
+    fn drop(&mut self) {
+        EventLoop::teardown();
+    }
+}
+
+/// A callback that can be used to fulfill or reject a promise. Obtained from
+/// `new_promise_and_fulfiller()`; this trait cannot be implemented outside of gj.
+pub trait PromiseFulfiller<T>: private::Sealed where T: 'static {
     fn fulfill(self: Box<Self>, value: T);
     fn reject(self: Box<Self>, error: Error);
+
+    /// Returns false if the promise that this fulfiller would resolve has been dropped, in which
+    /// case fulfilling or rejecting it would have no effect.
+    fn is_waiting(&self) -> bool;
 }
 
 /// Creates a new promise/fulfiller pair.
 pub fn new_promise_and_fulfiller<T>() -> (Promise<T>, Box<PromiseFulfiller<T>>) where T: 'static {
     let result = ::std::rc::Rc::new(::std::cell::RefCell::new(PromiseAndFulfillerHub::new()));
-    let result_promise : Promise<T> = Promise { node: Box::new(result.clone())};
-    (result_promise, Box::new(result))
+    let result_promise : Promise<T> = Promise { node: Box::new(private::HubNode::new(result.clone()))};
+    (result_promise, Box::new(private::Fulfiller::new(result)))
+}
+
+/// A fulfiller that can also report the progress of the operation whose result it will provide.
+pub struct ProgressFulfiller<T, P> where T: 'static, P: 'static {
+    fulfiller: Box<PromiseFulfiller<T>>,
+    progress: stream::StreamSender<P>,
+}
+
+impl <T, P> ProgressFulfiller<T, P> {
+    /// Sends an intermediate progress value.
+    pub fn progress(&mut self, value: P) {
+        self.progress.send(value);
+    }
+}
+
+impl <T, P> private::Sealed for ProgressFulfiller<T, P> {}
+
+impl <T, P> PromiseFulfiller<T> for ProgressFulfiller<T, P> {
+    fn fulfill(self: Box<Self>, value: T) {
+        self.fulfiller.fulfill(value);
+    }
+
+    fn reject(self: Box<Self>, error: Error) {
+        self.fulfiller.reject(error);
+    }
+
+    fn is_waiting(&self) -> bool {
+        return self.fulfiller.is_waiting();
+    }
+}
+
+/// Like `new_promise_and_fulfiller()`, but also returns a stream of the progress values reported
+/// by the fulfiller. The stream ends when the fulfiller is used or dropped.
+pub fn new_promise_fulfiller_with_progress<T, P>()
+    -> (Promise<T>, stream::PromiseStream<P>, Box<ProgressFulfiller<T, P>>)
+    where T: 'static, P: 'static
+{
+    let (promise, fulfiller) = new_promise_and_fulfiller();
+    let (progress, sender) = stream::new_stream();
+    (promise, progress, Box::new(ProgressFulfiller { fulfiller: fulfiller, progress: sender }))
+}
+
+
+/// Runs `func` with a deadline `duration` from now, or with the current deadline if that is sooner.
+///
+/// The deadline applies to the IO operations that `func` starts, and also to those started by
+/// any continuations that `func` attaches to promises, however long the chain. Operations that
+/// are still pending when the deadline passes fail with a `TimedOut` error.
+pub fn with_deadline<F, R>(duration: ::std::time::Duration, func: F) -> R
+    where F: FnOnce() -> R
+{
+    let mut deadline = io::Timer.now() + duration;
+    if let Some(current) = current_deadline() {
+        if current < deadline {
+            deadline = current;
+        }
+    }
+    private::with_ambient_deadline(Some(deadline), func)
+}
+
+/// Returns the deadline that applies to the code that is currently running, if any.
+pub fn current_deadline() -> Option<::std::time::Instant> {
+    private::current_deadline()
+}
+
+/// A one-shot signal, typically used to tell all of a server's operations to shut down.
+/// Clones of a latch share the same state. See `Promise::until()`.
+#[derive(Clone)]
+pub struct Latch {
+    state: Rc<promise_node::LatchState>,
+}
+
+impl Latch {
+    pub fn new() -> Latch {
+        Latch { state: Rc::new(promise_node::LatchState::new()) }
+    }
+
+    /// Triggers the latch. Has no effect if it has already been triggered.
+    pub fn trigger(&self) {
+        self.state.trigger();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.state.is_triggered()
+    }
+
+    /// Returns a promise that resolves once the latch has been triggered.
+    pub fn when_triggered(&self) -> Promise<()> {
+        Promise { node: Box::new(promise_node::LatchWait::new(self.state.clone())) }
+    }
+}
+
+/// The error with which `Promise::until()` rejects a promise that was interrupted by its latch.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl ::std::fmt::Display for Interrupted {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "interrupted by latch")
+    }
+}
+
+impl ::std::error::Error for Interrupted {
+    fn description(&self) -> &str {
+        "interrupted by latch"
+    }
+}
+
+/// The error with which `EventLoop::try_top_level()` fails when the current thread already has
+/// an event loop.
+#[derive(Debug)]
+pub struct LoopExists;
+
+impl ::std::fmt::Display for LoopExists {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "this thread already has an event loop")
+    }
+}
+
+impl ::std::error::Error for LoopExists {
+    fn description(&self) -> &str {
+        "event loop exists"
+    }
+}
+
+/// The error with which `EventLoop::scoped()` fails when it is called from one of the event
+/// loop's callbacks, where waiting would block the loop that is running it.
+#[derive(Debug)]
+pub struct LoopBusy;
+
+impl ::std::fmt::Display for LoopBusy {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "cannot wait on the event loop from inside one of its callbacks")
+    }
+}
+
+impl ::std::error::Error for LoopBusy {
+    fn description(&self) -> &str {
+        "event loop busy"
+    }
+}
+
+/// The error with which `Promise::wait()` fails when the promise can never resolve, because there
+/// are no events left to run and nothing that could produce any.
+#[derive(Debug)]
+pub struct Deadlock {
+    live_fulfillers: usize,
+}
+
+impl Deadlock {
+    /// The number of promise fulfillers that were still alive, none of which can now be used.
+    /// If this is zero, any fulfiller that the promise was waiting on has been dropped.
+    pub fn live_fulfillers(&self) -> usize {
+        self.live_fulfillers
+    }
+}
+
+impl ::std::fmt::Display for Deadlock {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "deadlock: wait() can never complete, since no events are queued and there \
+                     are no fd observers, timers, or idle callbacks to produce any \
+                     ({} promise fulfillers still alive)", self.live_fulfillers)
+    }
+}
+
+impl ::std::error::Error for Deadlock {
+    fn description(&self) -> &str {
+        "deadlock"
+    }
+}
+
+/// The error with which a task fails when one of its callbacks panics, if the task's set was
+/// told to catch panics, or with which a promise is rejected when its callback panics, if the
+/// event loop was. See `TaskSet::set_catch_panics()` and `EventLoop::set_catch_panics()`.
+#[derive(Debug)]
+pub struct Panicked {
+    message: String,
+}
+
+impl Panicked {
+    /// The message that was passed to `panic!()`.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
+impl ::std::fmt::Display for Panicked {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "task panicked: {}", self.message)
+    }
+}
+
+impl ::std::error::Error for Panicked {
+    fn description(&self) -> &str {
+        "task panicked"
+    }
+}
 
 /// Holds a collection of `Promise<()>`s and ensures that each executes to completion.
 /// Destroying a TaskSet automatically cancels all of its unfinished promises.
@@ -306,17 +1658,233 @@ pub struct TaskSet {
 
 impl TaskSet {
     pub fn new(error_handler: Box<ErrorHandler>) -> TaskSet {
-        TaskSet { task_set_impl : Rc::new(RefCell::new(private::TaskSetImpl::new(error_handler))) }
+        TaskSet::new_internal(error_handler, None)
+    }
+
+    /// Creates a task set whose error handler is the closure `error_handler`.
+    pub fn new_with<F>(error_handler: F) -> TaskSet
+        where F: 'static,
+              F: FnMut(Error)
+    {
+        TaskSet::new(Box::new(error_handler))
+    }
+
+    /// Creates a task set that runs at most `limit` tasks at a time. Tasks added while the set is
+    /// at its limit wait in a queue, in the order they were added, and start as running tasks
+    /// finish. Use `add_factory()` to defer creating a task's promise until its turn comes.
+    pub fn with_limit(error_handler: Box<ErrorHandler>, limit: usize) -> TaskSet {
+        assert!(limit > 0, "a task set's limit must be positive");
+        TaskSet::new_internal(error_handler, Some(limit))
+    }
+
+    fn new_internal(error_handler: Box<ErrorHandler>, limit: Option<usize>) -> TaskSet {
+        let task_set_impl = Rc::new(RefCell::new(private::TaskSetImpl::new(error_handler, limit)));
+        private::if_current_event_loop(|event_loop| {
+            let task_sets = &mut *event_loop.task_sets.borrow_mut();
+            task_sets.retain(|task_set| task_set.upgrade().is_some());
+            task_sets.push(Rc::downgrade(&task_set_impl));
+        });
+        TaskSet { task_set_impl : task_set_impl }
+    }
+
+    /// Adds a task to the set. The returned handle can be used to cancel the task individually.
+    pub fn add(&mut self, promise: Promise<()>) -> TaskHandle {
+        let id = private::TaskSetImpl::add(self.task_set_impl.clone(), promise.node, None);
+        TaskHandle { task_set: Rc::downgrade(&self.task_set_impl), id: id }
     }
 
-    pub fn add(&mut self, promise: Promise<()>) {
-        private::TaskSetImpl::add(self.task_set_impl.clone(), promise.node);
+    /// Like `add()`, but gives the task a name, which is reported by `tasks()` and passed to
+    /// `ErrorHandler::task_failed_with_info()` if the task fails.
+    pub fn add_named(&mut self, promise: Promise<()>, name: &str) -> TaskHandle {
+        let id = private::TaskSetImpl::add(self.task_set_impl.clone(), promise.node,
+                                           Some(name.to_string()));
+        TaskHandle { task_set: Rc::downgrade(&self.task_set_impl), id: id }
+    }
+
+    /// Adds the task returned by `factory`. If the set is at its limit, `factory` is not called
+    /// until the task reaches the front of the queue.
+    pub fn add_factory<F>(&mut self, factory: F) -> TaskHandle
+        where F: 'static,
+              F: FnOnce() -> Promise<()>
+    {
+        let mut factory = Some(factory);
+        let id = private::TaskSetImpl::add_factory(self.task_set_impl.clone(), Box::new(move || {
+            (factory.take().expect("factory called twice"))().node
+        }), None);
+        TaskHandle { task_set: Rc::downgrade(&self.task_set_impl), id: id }
+    }
+
+    /// Returns a promise that resolves the next time the set has no outstanding tasks, or
+    /// immediately if it has none now. Useful for draining connections before shutting down.
+    pub fn on_empty(&mut self) -> Promise<()> {
+        self.task_set_impl.borrow_mut().on_empty()
+    }
+
+    /// Stops the set from accepting new tasks and returns a promise that resolves once the tasks
+    /// it already has, including queued ones, have finished. Tasks added from then on are
+    /// cancelled straight away. If `deadline_ms` is given, any tasks still outstanding after that
+    /// many milliseconds are cancelled, and the promise resolves then.
+    pub fn drain(&mut self, deadline_ms: Option<u64>) -> Promise<()> {
+        self.task_set_impl.borrow_mut().start_draining();
+        let empty = self.on_empty();
+        match deadline_ms {
+            None => return empty,
+            Some(delay) => {
+                let task_set = Rc::downgrade(&self.task_set_impl);
+                return empty.exclusive_join(io::Timer.after_delay_ms(delay).map(move |()| {
+                    if let Some(task_set) = task_set.upgrade() {
+                        private::TaskSetImpl::cancel_all(&task_set);
+                    }
+                    Ok(())
+                }));
+            }
+        }
+    }
+
+    /// If `catch_panics` is true, a panic in a callback that runs while one of the set's tasks is
+    /// being evaluated fails that task with a `Panicked` error, which is passed to the error
+    /// handler, instead of unwinding through the event loop. Off by default. Callbacks of promises
+    /// that the task's promise merely waits on, such as those of a promise returned from a
+    /// `then()` callback, run in events of their own and are not covered.
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.task_set_impl.borrow_mut().set_catch_panics(catch_panics);
+    }
+
+    /// Returns true if `drain()` has been called.
+    pub fn is_draining(&self) -> bool {
+        self.task_set_impl.borrow().is_draining()
+    }
+
+    /// Returns counts of the outcomes of the tasks that have been added to the set.
+    pub fn stats(&self) -> TaskSetStats {
+        self.task_set_impl.borrow().stats()
+    }
+
+    /// Returns the number of tasks that are running or queued.
+    pub fn len(&self) -> usize {
+        self.task_set_impl.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a snapshot of the tasks that are running or queued, in the order they were added.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.task_set_impl.borrow().task_infos()
+    }
+}
+
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        // Each unfinished task holds a reference to the set, so we need to cancel them explicitly.
+        private::TaskSetImpl::cancel_all(&self.task_set_impl);
+        log_task_set_stats(self.stats());
+    }
+}
+
+#[cfg(feature = "log")]
+fn log_task_set_stats(stats: TaskSetStats) {
+    let mut enabled = true;
+    private::if_current_event_loop(|event_loop| {
+        enabled = ::log::LogLevel::Debug <= event_loop.log_level.get();
+    });
+    if enabled {
+        debug!("dropped task set: {:?}", stats);
+    }
+}
+
+#[cfg(not(feature = "log"))]
+fn log_task_set_stats(_stats: TaskSetStats) {}
+
+/// Counts of the tasks that have passed through a `TaskSet`, as returned by `TaskSet::stats()`.
+/// A task that is still running or queued when the set is dropped counts as cancelled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskSetStats {
+    pub added: u64,
+    pub fulfilled: u64,
+    pub rejected: u64,
+    pub cancelled: u64,
+}
+
+impl TaskSetStats {
+    /// Returns the number of tasks that have not yet finished.
+    pub fn outstanding(&self) -> u64 {
+        self.added - self.fulfilled - self.rejected - self.cancelled
+    }
+}
+
+/// Describes a task in a `TaskSet`, as returned by `TaskSet::tasks()`.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// Identifies the task within its set. Matches `TaskHandle::id()`.
+    pub id: u64,
+
+    /// The name given to `TaskSet::add_named()`, if any.
+    pub name: Option<String>,
+
+    /// Time since the task was added to the set.
+    pub age: ::std::time::Duration,
+
+    /// True if the task is waiting for a slot in a set created by `TaskSet::with_limit()`.
+    pub queued: bool,
+}
+
+/// Refers to a single task in a `TaskSet`. Does not keep the task or the set alive.
+#[derive(Clone)]
+pub struct TaskHandle {
+    task_set: ::std::rc::Weak<RefCell<private::TaskSetImpl>>,
+    id: u64,
+}
+
+impl TaskHandle {
+    /// Returns the task's id, which is unique within its set.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancels the task, counting it as cancelled in the set's stats. Returns false if the task
+    /// had already finished, or if the set has been dropped.
+    pub fn cancel(&self) -> bool {
+        match self.task_set.upgrade() {
+            Some(task_set) => private::TaskSetImpl::cancel(&task_set, self.id),
+            None => false,
+        }
+    }
+
+    /// Returns true if the task is running or waiting in the set's queue.
+    pub fn is_pending(&self) -> bool {
+        match self.task_set.upgrade() {
+            Some(task_set) => task_set.borrow().is_pending(self.id),
+            None => false,
+        }
+    }
+
+    /// Returns true if the task is waiting for a slot in a set created by `TaskSet::with_limit()`.
+    pub fn is_queued(&self) -> bool {
+        match self.task_set.upgrade() {
+            Some(task_set) => task_set.borrow().is_queued(self.id),
+            None => false,
+        }
     }
 }
 
 /// A callback to be invoked when a task in a `TaskSet` fails.
 pub trait ErrorHandler {
     fn task_failed(&mut self, error: Error);
+
+    /// Called when a task fails, with a description of the task. The default implementation
+    /// calls `task_failed()`. Override this to find out which task failed, for example by the
+    /// name it was given in `TaskSet::add_named()`.
+    fn task_failed_with_info(&mut self, _task: &TaskInfo, error: Error) {
+        self.task_failed(error);
+    }
+}
+
+impl <F> ErrorHandler for F where F: FnMut(Error) {
+    fn task_failed(&mut self, error: Error) {
+        self(error);
+    }
 }
 
 /// Transforms a vector of promises into a promise for a vector.
@@ -324,3 +1892,47 @@ pub fn join_promises<T>(promises: Vec<Promise<T>>) -> Promise<Vec<T>> {
     let nodes = promises.into_iter().map(|p| { p.node }).collect();
     Promise { node: Box::new(private::promise_node::ArrayJoin::new(nodes)) }
 }
+
+/// Like `join_promises()`, but if any of the promises is rejected, the result is rejected
+/// immediately with that error and the remaining promises are cancelled.
+pub fn join_promises_fail_fast<T>(promises: Vec<Promise<T>>) -> Promise<Vec<T>> {
+    let nodes = promises.into_iter().map(|p| { p.node }).collect();
+    Promise { node: Box::new(private::promise_node::ArrayJoinFailFast::new(nodes)) }
+}
+
+/// Transforms a vector of promises into a promise for the first of them to be fulfilled
+/// successfully. The other promises are then cancelled. If every promise is rejected, the result
+/// is rejected with an `AggregateError` holding all of the errors.
+pub fn race_ok<T>(promises: Vec<Promise<T>>) -> Promise<T> {
+    let nodes = promises.into_iter().map(|p| { p.node }).collect();
+    Promise { node: Box::new(private::promise_node::RaceOk::new(nodes)) }
+}
+
+/// An error made up of several other errors, for example the rejections of all the branches of a
+/// `race_ok()`.
+#[derive(Debug)]
+pub struct AggregateError {
+    pub errors: Vec<Error>,
+}
+
+impl AggregateError {
+    pub fn new(errors: Vec<Error>) -> AggregateError {
+        AggregateError { errors: errors }
+    }
+}
+
+impl ::std::fmt::Display for AggregateError {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        try!(write!(fmt, "all {} branches failed", self.errors.len()));
+        for error in &self.errors {
+            try!(write!(fmt, "; {}", error));
+        }
+        Ok(())
+    }
+}
+
+impl ::std::error::Error for AggregateError {
+    fn description(&self) -> &str {
+        "all branches failed"
+    }
+}
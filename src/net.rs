@@ -0,0 +1,174 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Transport-independent endpoint addresses, for configuration files and the like.
+
+use std::path::PathBuf;
+use io::{AsyncRead, AsyncWrite, NetworkAddress, TcpStream, UnixAddress, UnixStream};
+#[cfg(feature = "tls")] use io::tls::{TlsConnector, TlsStream};
+use {Promise, Result};
+
+/// Something that can open connections.
+pub trait Connector {
+    type Stream: AsyncRead + AsyncWrite;
+
+    fn connect(&self) -> Promise<Self::Stream>;
+}
+
+impl Connector for NetworkAddress {
+    type Stream = TcpStream;
+
+    fn connect(&self) -> Promise<TcpStream> {
+        NetworkAddress::connect(self)
+    }
+}
+
+impl Connector for UnixAddress {
+    type Stream = UnixStream;
+
+    fn connect(&self) -> Promise<UnixStream> {
+        UnixAddress::connect(self.clone())
+    }
+}
+
+/// Connects to a TLS server: opens a TCP connection to `address`, then runs a handshake that
+/// checks the server's certificate against `host`. See `Endpoint::tls_connector()`.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct TlsAddress {
+    pub address: NetworkAddress,
+    pub host: String,
+    pub tls: TlsConnector,
+}
+
+#[cfg(feature = "tls")]
+impl Connector for TlsAddress {
+    type Stream = TlsStream<TcpStream>;
+
+    fn connect(&self) -> Promise<TlsStream<TcpStream>> {
+        let host = self.host.clone();
+        let tls = self.tls.clone();
+        return self.address.connect().then(move |stream| Ok(tls.connect(&host, stream)));
+    }
+}
+
+/// An endpoint parsed by `parse_addr()`.
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// `tcp://host:port`, or just `host:port`.
+    Tcp(NetworkAddress),
+
+    /// `unix:/path/to/socket`.
+    Unix(PathBuf),
+
+    /// `tls://host:port`. `host` is the name to verify the server's certificate against.
+    Tls { address: NetworkAddress, host: String },
+}
+
+impl Endpoint {
+    /// Returns the connector for a TCP endpoint.
+    pub fn tcp_connector(&self) -> Option<NetworkAddress> {
+        match self {
+            &Endpoint::Tcp(ref address) => Some(address.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the connector for a Unix endpoint.
+    pub fn unix_connector(&self) -> Option<UnixAddress> {
+        match self {
+            &Endpoint::Unix(ref path) => Some(UnixAddress::new(path)),
+            _ => None,
+        }
+    }
+
+    /// Returns the connector for a TLS endpoint, which runs its handshakes with `tls`.
+    #[cfg(feature = "tls")]
+    pub fn tls_connector(&self, tls: TlsConnector) -> Option<TlsAddress> {
+        match self {
+            &Endpoint::Tls { ref address, ref host } => {
+                Some(TlsAddress { address: address.clone(), host: host.clone(), tls: tls })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses a URI-style endpoint: `tcp://host:port`, `unix:/path`, or `tls://host:port`. A string
+/// without a scheme is taken to be a TCP address. Use the `*_connector()` methods of the result
+/// to get something that can connect to it.
+///
+/// Host names are not looked up here. They are kept with `NetworkAddress::from_host()`, and
+/// looked up off the event loop thread each time the connector connects.
+pub fn parse_addr(address: &str) -> Result<Endpoint> {
+    if let Some(rest) = strip_scheme(address, "tcp://") {
+        return Ok(Endpoint::Tcp(try!(tcp_address(rest))));
+    } else if let Some(rest) = strip_scheme(address, "tls://") {
+        let host = try!(host_of(rest));
+        return Ok(Endpoint::Tls { address: try!(tcp_address(rest)), host: host });
+    } else if let Some(rest) = strip_scheme(address, "unix:") {
+        if rest.is_empty() {
+            return Err(invalid(address));
+        }
+        return Ok(Endpoint::Unix(PathBuf::from(rest)));
+    } else if address.contains("://") {
+        return Err(invalid(address));
+    } else {
+        return Ok(Endpoint::Tcp(try!(tcp_address(address))));
+    }
+}
+
+/// Takes `host:port` apart. A literal IP address is used as it is, and a host name is left for
+/// `connect()` to look up.
+fn tcp_address(host_and_port: &str) -> Result<NetworkAddress> {
+    if let Ok(addr) = host_and_port.parse::<::std::net::SocketAddr>() {
+        return NetworkAddress::new(addr);
+    }
+    try!(host_of(host_and_port));
+    let port = &host_and_port[host_and_port.rfind(':').unwrap_or(0) + 1..];
+    if port.parse::<u16>().is_err() {
+        return Err(invalid(host_and_port));
+    }
+    return Ok(NetworkAddress::from_host(host_and_port));
+}
+
+fn strip_scheme<'a>(address: &'a str, scheme: &str) -> Option<&'a str> {
+    if address.starts_with(scheme) {
+        Some(&address[scheme.len()..])
+    } else {
+        None
+    }
+}
+
+/// Returns the host part of `host:port`, without the brackets around an IPv6 address.
+fn host_of(host_and_port: &str) -> Result<String> {
+    match host_and_port.rfind(':') {
+        Some(colon) if colon > 0 => {
+            Ok(host_and_port[..colon].trim_left_matches('[').trim_right_matches(']').to_string())
+        }
+        _ => Err(invalid(host_and_port)),
+    }
+}
+
+fn invalid(address: &str) -> ::Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                   format!("invalid endpoint address: {}", address)))
+}
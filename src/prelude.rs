@@ -0,0 +1,35 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The types and traits that most users of gj need, for glob importing:
+//!
+//! ```
+//! use gj::prelude::*;
+//! ```
+//!
+//! Everything here is also available at its usual path. Items are only added to the prelude
+//! once their API has settled, so a glob import of it should keep compiling across releases.
+
+pub use {Promise, PromiseFulfiller, new_promise_and_fulfiller, join_promises,
+         join_promises_fail_fast, race_ok, EventLoop, WaitScope, Error, Result,
+         TaskSet, TaskHandle, ErrorHandler, Latch};
+pub use io::{AsyncRead, AsyncWrite, Timer};
+pub use stream::{PromiseStream, StreamSender, new_stream};
@@ -19,15 +19,15 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use std::collections::HashMap;
-use handle_table::{Handle};
-use {Error, Result, PromiseFulfiller, EventLoop, ErrorHandler};
+use std::collections::{HashMap, VecDeque};
+use {Error, Result, PromiseFulfiller, EventLoop, ErrorHandler, Panicked, TaskInfo, TaskSetStats};
+use std::time::Instant;
 
 pub mod promise_node;
 
-thread_local!(pub static EVENT_LOOP: RefCell<Option<EventLoop>> = RefCell::new(None));
+thread_local!(pub static EVENT_LOOP: RefCell<Option<EventLoop>> = const { RefCell::new(None) });
 
 pub fn with_current_event_loop<F, R>(f: F) -> R
     where F: FnOnce(&EventLoop) -> R
@@ -40,34 +40,154 @@ pub fn with_current_event_loop<F, R>(f: F) -> R
     })
 }
 
+/// Like `with_current_event_loop()`, but does nothing if the current thread has no event loop.
+/// Useful in destructors, which may run while the loop is being torn down.
+pub fn if_current_event_loop<F>(f: F)
+    where F: FnOnce(&EventLoop)
+{
+    EVENT_LOOP.with(|maybe_event_loop| {
+        match &*maybe_event_loop.borrow() {
+            &None => {}
+            &Some(ref event_loop) => f(event_loop),
+        }
+    })
+}
+
+/// Returns the deadline in effect for the code that is currently running. See `::with_deadline()`.
+pub fn current_deadline() -> Option<::std::time::Instant> {
+    EVENT_LOOP.with(|maybe_event_loop| {
+        match &*maybe_event_loop.borrow() {
+            &None => None,
+            &Some(ref event_loop) => event_loop.deadline.get(),
+        }
+    })
+}
+
+/// Returns true if the current thread's event loop was told to catch panics in callbacks. See
+/// `::EventLoop::set_catch_panics()`.
+pub fn catch_panics_enabled() -> bool {
+    EVENT_LOOP.with(|maybe_event_loop| {
+        match &*maybe_event_loop.borrow() {
+            &None => false,
+            &Some(ref event_loop) => event_loop.catch_panics.get(),
+        }
+    })
+}
+
+/// Restores the previous ambient deadline when dropped.
+struct DeadlineGuard {
+    previous: Option<::std::time::Instant>,
+}
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        if_current_event_loop(|event_loop| event_loop.deadline.set(previous));
+    }
+}
+
+/// Runs `f` with `deadline` as the ambient deadline.
+pub fn with_ambient_deadline<F, R>(deadline: Option<::std::time::Instant>, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let previous = with_current_event_loop(|event_loop| {
+        let previous = event_loop.deadline.get();
+        event_loop.deadline.set(deadline);
+        previous
+    });
+    let _guard = DeadlineGuard { previous: previous };
+    return f();
+}
+
+/// Supertrait of the public traits that only this crate may implement, such as
+/// `PromiseFulfiller`. Lives in this private module so that downstream crates cannot name it.
+pub trait Sealed {}
+
 pub trait PromiseNode<T> {
     /// Arms the given event when the promised value is ready.
     fn on_ready(&mut self, event: EventHandle);
 
-    fn set_self_pointer(&mut self) {}
     fn get(self: Box<Self>) -> Result<T>;
+
+    /// Returns `Some` if this node is a `Chain`. Allows chains to be flattened.
+    fn as_chain(&mut self) -> Option<&mut promise_node::Chain<T>> where T: 'static { None }
 }
 
 pub trait Event {
     fn fire(&mut self) -> Option<EventDropper>;
 }
 
+/// Refers to an `EventNode`. The node is heap-allocated by `EventHandle::new()` and freed when the
+/// matching `EventDropper` is dropped; a handle must not be used after that. Promise nodes
+/// uphold this by dropping their dependencies, which hold their event handles, no later than
+/// their droppers.
 #[derive(PartialEq, Eq, Copy, Clone, Hash)]
-pub struct EventHandle(pub Handle);
+pub struct EventHandle(*const EventNode);
 
 impl EventHandle {
     pub fn new() -> (EventHandle, EventDropper) {
         return with_current_event_loop(|event_loop| {
-            let node = EventNode { event: None, next: None, prev: None };
-            let handle = EventHandle(event_loop.events.borrow_mut().push(node));
+            let id = event_loop.next_event_id.get();
+            event_loop.next_event_id.set(id + 1);
+            let handle = EventHandle::alloc(id);
+            event_loop.all_events.insert(handle);
             return (handle, EventDropper { event_handle: handle });
         });
     }
 
+    /// Allocates a node that is not in any list.
+    fn alloc(id: usize) -> EventHandle {
+        let node = EventNode {
+            event: RefCell::new(None),
+            next: Cell::new(None),
+            prev: Cell::new(None),
+            all_next: Cell::new(None),
+            all_prev: Cell::new(None),
+            id: id,
+        };
+        EventHandle(Box::into_raw(Box::new(node)))
+    }
+
+    /// Frees the node. It must not be in any list.
+    unsafe fn free(self) {
+        drop(Box::from_raw(self.0 as *mut EventNode));
+    }
+
+    fn node(&self) -> &EventNode {
+        unsafe { &*self.0 }
+    }
+
     pub fn set(&self, event: Box<Event>) {
-        return with_current_event_loop(|event_loop| {
-            event_loop.events.borrow_mut()[self.0].event = Some(event);
-        });
+        *self.node().event.borrow_mut() = Some(event);
+    }
+
+    /// Takes the event out of the node, e.g. to fire it.
+    pub fn take_event(&self) -> Option<Box<Event>> {
+        self.node().event.borrow_mut().take()
+    }
+
+    /// A number that identifies the event for as long as its loop exists.
+    pub fn id(&self) -> usize {
+        self.node().id
+    }
+
+    /// The next event in the queue that this one is armed in.
+    pub fn next(&self) -> Option<EventHandle> {
+        self.node().next.get()
+    }
+
+    pub fn set_next(&self, next: Option<EventHandle>) {
+        self.node().next.set(next)
+    }
+
+    /// The previous event in the queue that this one is armed in, which is the queue's head if
+    /// this is the first. `None` if the event is not armed.
+    pub fn prev(&self) -> Option<EventHandle> {
+        self.node().prev.get()
+    }
+
+    pub fn set_prev(&self, prev: Option<EventHandle>) {
+        self.node().prev.set(prev)
     }
 
     pub fn arm_breadth_first(self) {
@@ -83,10 +203,112 @@ impl EventHandle {
     }
 }
 
+/// An event, along with its links in the event loop's intrusive lists. Arming an event or firing
+/// it only relinks nodes, without allocating or looking anything up.
 pub struct EventNode {
-    pub event: Option<Box<Event>>,
-    pub next: Option<EventHandle>,
-    pub prev: Option<EventHandle>
+    event: RefCell<Option<Box<Event>>>,
+
+    // Neighbours in the queue in which the event is armed. Both are `None` if it is not armed.
+    next: Cell<Option<EventHandle>>,
+    prev: Cell<Option<EventHandle>>,
+
+    // Neighbours in the loop's list of all of its events. See `EventRegistry`.
+    all_next: Cell<Option<EventHandle>>,
+    all_prev: Cell<Option<EventHandle>>,
+
+    id: usize,
+}
+
+/// The armed events of one priority class, as a doubly-linked list threaded through the event
+/// nodes. The list starts at a dummy node, which is never fired.
+pub struct EventQueue {
+    pub head: EventHandle,
+    pub tail: Cell<EventHandle>,
+
+    // Events armed depth-first are inserted after this node.
+    pub depth_first_insertion_point: Cell<EventHandle>,
+
+    // The number of turns in a row in which this queue had events but another was served.
+    pub starved_turns: Cell<u32>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        let head = EventHandle::alloc(0);
+        EventQueue {
+            head: head,
+            tail: Cell::new(head),
+            depth_first_insertion_point: Cell::new(head),
+            starved_turns: Cell::new(0),
+        }
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        unsafe { self.head.free(); }
+    }
+}
+
+/// Every event node of a loop, armed or not, in a doubly-linked list starting at a dummy node.
+/// Lets the loop drop all of its events when it is torn down, which breaks the reference cycles
+/// between events and the promise nodes that own them.
+pub struct EventRegistry {
+    head: EventHandle,
+    len: Cell<usize>,
+}
+
+impl EventRegistry {
+    pub fn new() -> EventRegistry {
+        EventRegistry { head: EventHandle::alloc(0), len: Cell::new(0) }
+    }
+
+    fn insert(&self, event: EventHandle) {
+        let next = self.head.node().all_next.get();
+        event.node().all_next.set(next);
+        event.node().all_prev.set(Some(self.head));
+        if let Some(next) = next {
+            next.node().all_prev.set(Some(event));
+        }
+        self.head.node().all_next.set(Some(event));
+        self.len.set(self.len.get() + 1);
+    }
+
+    fn remove(&self, event: EventHandle) {
+        let next = event.node().all_next.get();
+        let prev = event.node().all_prev.get().expect("event is not registered");
+        prev.node().all_next.set(next);
+        if let Some(next) = next {
+            next.node().all_prev.set(Some(prev));
+        }
+        event.node().all_next.set(None);
+        event.node().all_prev.set(None);
+        self.len.set(self.len.get() - 1);
+    }
+
+    /// The number of events that exist.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Takes the events out of all of the nodes.
+    pub fn take_events(&self) -> Vec<Box<Event>> {
+        let mut result = Vec::new();
+        let mut next = self.head.node().all_next.get();
+        while let Some(handle) = next {
+            if let Some(event) = handle.take_event() {
+                result.push(event);
+            }
+            next = handle.node().all_next.get();
+        }
+        return result;
+    }
+}
+
+impl Drop for EventRegistry {
+    fn drop(&mut self) {
+        unsafe { self.head.free(); }
+    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -96,31 +318,38 @@ pub struct EventDropper {
 
 impl Drop for EventDropper {
     fn drop(&mut self) {
+        let event_handle = self.event_handle;
         with_current_event_loop(|event_loop| {
-            let maybe_event_node = event_loop.events.borrow_mut().remove(self.event_handle.0);
-
-            match maybe_event_node {
+            // event_node.next.prev = event_node.prev
+            match event_handle.next() {
+                Some(e) => {
+                    e.set_prev(event_handle.prev());
+                }
                 None => {}
-                Some(event_node) => {
-
-                    // event_node.next.prev = event_node.prev
-                    match event_node.next {
-                        Some(e) => {
-                            event_loop.events.borrow_mut()[e.0].prev = event_node.prev;
+            }
+            // event_node.prev.next = event_node.next
+            match event_handle.prev() {
+                Some(e) => {
+                    e.set_next(event_handle.next());
+                    for queue in event_loop.queues.iter() {
+                        if queue.tail.get() == event_handle {
+                            queue.tail.set(e);
                         }
-                        None => {}
-                    }
-                    // event_node.prev.next = event_node.next
-                    match event_node.prev {
-                        Some(e) => {
-                            event_loop.events.borrow_mut()[e.0].next = event_node.next;
-                            event_loop.tail.set(e);
+                        if queue.depth_first_insertion_point.get() == event_handle {
+                            queue.depth_first_insertion_point.set(e);
                         }
-                        None => {}
                     }
                 }
+                None => {}
             }
+            event_handle.set_next(None);
+            event_handle.set_prev(None);
+            event_loop.all_events.remove(event_handle);
         });
+
+        // Dropping the event may drop other events' droppers, so it happens outside of the
+        // closure above.
+        unsafe { event_handle.free(); }
     }
 }
 
@@ -210,55 +439,314 @@ impl <T> PromiseAndFulfillerHub<T> {
         if self.result.is_none() {
             self.result = Some(Err(error));
         }
+        self.on_ready_event.arm();
     }
 }
 
-impl <T> PromiseNode<T> for ::std::rc::Rc<::std::cell::RefCell<PromiseAndFulfillerHub<T>>> {
+/// The promise half of a `PromiseAndFulfillerHub`.
+pub struct HubNode<T> where T: 'static {
+    hub: Rc<RefCell<PromiseAndFulfillerHub<T>>>,
+}
+
+impl <T> HubNode<T> {
+    pub fn new(hub: Rc<RefCell<PromiseAndFulfillerHub<T>>>) -> HubNode<T> {
+        HubNode { hub: hub }
+    }
+}
+
+impl <T> PromiseNode<T> for HubNode<T> {
     fn on_ready(&mut self, event: EventHandle) {
-        self.borrow_mut().on_ready_event.init(event);
+        self.hub.borrow_mut().on_ready_event.init(event);
     }
     fn get(self: Box<Self>) -> Result<T> {
-        match ::std::mem::replace(&mut self.borrow_mut().result, None) {
+        match ::std::mem::replace(&mut self.hub.borrow_mut().result, None) {
             None => panic!("no result!"),
             Some(r) => r
         }
     }
 }
 
-impl <T> PromiseFulfiller<T> for Rc<RefCell<PromiseAndFulfillerHub<T>>> where T: 'static {
+impl <T> Drop for HubNode<T> {
+    fn drop(&mut self) {
+        // The event belongs to whoever was waiting on this node, and may be gone by the time the
+        // fulfiller is used.
+        self.hub.borrow_mut().on_ready_event = OnReadyEvent::Empty;
+    }
+}
+
+/// The fulfiller half of a `PromiseAndFulfillerHub`. Keeps count of the live fulfillers on the
+/// current event loop.
+pub struct Fulfiller<T> where T: 'static {
+    hub: Rc<RefCell<PromiseAndFulfillerHub<T>>>,
+}
+
+impl <T> Fulfiller<T> {
+    pub fn new(hub: Rc<RefCell<PromiseAndFulfillerHub<T>>>) -> Fulfiller<T> {
+        with_current_event_loop(|event_loop| {
+            event_loop.live_fulfillers.set(event_loop.live_fulfillers.get() + 1);
+        });
+        Fulfiller { hub: hub }
+    }
+}
+
+impl <T> Drop for Fulfiller<T> {
+    fn drop(&mut self) {
+        if_current_event_loop(|event_loop| {
+            event_loop.live_fulfillers.set(event_loop.live_fulfillers.get() - 1);
+        });
+    }
+}
+
+impl <T> Sealed for Fulfiller<T> {}
+
+impl <T> PromiseFulfiller<T> for Fulfiller<T> where T: 'static {
     fn fulfill(self: Box<Self>, value: T) {
-        self.borrow_mut().fulfill(value);
+        self.hub.borrow_mut().fulfill(value);
     }
 
     fn reject(self: Box<Self>, error: Error) {
-        self.borrow_mut().reject(error);
+        self.hub.borrow_mut().reject(error);
+    }
+
+    fn is_waiting(&self) -> bool {
+        return Rc::strong_count(&self.hub) > 1;
     }
 }
 
 pub struct TaskSetImpl {
     error_handler: Box<ErrorHandler>,
-    tasks: HashMap<EventHandle, EventDropper>,
+    tasks: HashMap<u64, RunningTask>,
+    stats: TaskSetStats,
+    empty_fulfillers: Vec<Box<PromiseFulfiller<()>>>,
+
+    // Tasks are identified by ids rather than by their event handles, which get reused.
+    next_id: u64,
+
+    // If set, the maximum number of tasks that may run at once. Further tasks wait in `queue`.
+    limit: Option<usize>,
+    queue: VecDeque<QueuedTask>,
+
+    // Set by `TaskSet::drain()`. Tasks added to a draining set are cancelled straight away.
+    draining: bool,
+
+    // Set by `TaskSet::set_catch_panics()`.
+    catch_panics: bool,
+}
+
+/// What a task set knows about one of its tasks, besides its promise.
+struct TaskMeta {
+    added: Instant,
+    name: Option<String>,
+}
+
+impl TaskMeta {
+    fn info(&self, id: u64, now: Instant, queued: bool) -> TaskInfo {
+        TaskInfo { id: id, name: self.name.clone(), age: now.duration_since(self.added), queued: queued }
+    }
+}
+
+struct RunningTask {
+    dropper: EventDropper,
+    meta: TaskMeta,
+}
+
+struct QueuedTask {
+    id: u64,
+    meta: TaskMeta,
+    factory: Box<FnMut() -> Box<PromiseNode<()>>>,
 }
 
 impl TaskSetImpl {
-    pub fn new(error_handler: Box<ErrorHandler>) -> TaskSetImpl {
+    pub fn new(error_handler: Box<ErrorHandler>, limit: Option<usize>) -> TaskSetImpl {
         TaskSetImpl { error_handler: error_handler,
-                      tasks: HashMap::new() }
+                      tasks: HashMap::new(),
+                      stats: TaskSetStats::default(),
+                      empty_fulfillers: Vec::new(),
+                      next_id: 0,
+                      limit: limit,
+                      queue: VecDeque::new(),
+                      draining: false,
+                      catch_panics: false }
+    }
+
+    pub fn on_empty(&mut self) -> ::Promise<()> {
+        if self.is_empty() {
+            return ::Promise::fulfilled(());
+        }
+        let (promise, fulfiller) = ::new_promise_and_fulfiller();
+        self.empty_fulfillers.push(fulfiller);
+        return promise;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty() && self.queue.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.tasks.len() >= limit,
+            None => false,
+        }
+    }
+
+    fn notify_if_empty(&mut self) {
+        if self.is_empty() {
+            for fulfiller in ::std::mem::replace(&mut self.empty_fulfillers, Vec::new()) {
+                fulfiller.fulfill(());
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<EventDropper> {
+        let result = self.tasks.remove(&id).map(|task| task.dropper);
+        self.notify_if_empty();
+        return result;
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len() + self.queue.len()
+    }
+
+    pub fn task_infos(&self) -> Vec<TaskInfo> {
+        let now = Instant::now();
+        let mut result: Vec<TaskInfo> =
+            self.tasks.iter().map(|(&id, task)| task.meta.info(id, now, false))
+            .chain(self.queue.iter().map(|task| task.meta.info(task.id, now, true)))
+            .collect();
+        result.sort_by(|a, b| a.id.cmp(&b.id));
+        return result;
+    }
+
+    pub fn stats(&self) -> TaskSetStats {
+        self.stats
     }
 
-      pub fn add(task_set: Rc<RefCell<Self>>, mut node: Box<PromiseNode<()>>) {
-          let (handle, dropper) = EventHandle::new();
-          node.on_ready(handle);
-          let task = Task { task_set: task_set.clone(), node: Some(node), event_handle: handle };
-          handle.set(Box::new(task));
-          task_set.borrow_mut().tasks.insert(handle, dropper);
+    pub fn is_pending(&self, id: u64) -> bool {
+        self.tasks.contains_key(&id) || self.is_queued(id)
+    }
+
+    pub fn is_queued(&self, id: u64) -> bool {
+        self.queue.iter().any(|task| task.id == id)
+    }
+
+    pub fn add(task_set: Rc<RefCell<Self>>, node: Box<PromiseNode<()>>, name: Option<String>) -> u64 {
+        let mut node = Some(node);
+        return TaskSetImpl::add_factory(task_set,
+                                        Box::new(move || node.take().expect("factory called twice")),
+                                        name);
+    }
+
+    /// Starts the task that `factory` produces, or queues the factory if the set is at its limit.
+    /// Returns the task's id.
+    pub fn add_factory(task_set: Rc<RefCell<Self>>,
+                       mut factory: Box<FnMut() -> Box<PromiseNode<()>>>,
+                       name: Option<String>) -> u64 {
+        let meta = TaskMeta { added: Instant::now(), name: name };
+        let id = task_set.borrow().next_id;
+        let start = {
+            let inner = &mut *task_set.borrow_mut();
+            inner.next_id += 1;
+            inner.stats.added += 1;
+            if inner.draining {
+                inner.stats.cancelled += 1;
+                None
+            } else if inner.is_full() {
+                inner.queue.push_back(QueuedTask { id: id, meta: meta, factory: factory });
+                return id;
+            } else {
+                Some(meta)
+            }
+        };
+        match start {
+            Some(meta) => TaskSetImpl::start(task_set, id, meta, factory()),
+            None => drop(factory),
+        }
+        return id;
+    }
+
+    fn start(task_set: Rc<RefCell<Self>>, id: u64, meta: TaskMeta, mut node: Box<PromiseNode<()>>) {
+        let (handle, dropper) = EventHandle::new();
+        node.on_ready(handle);
+        let task = Task { task_set: task_set.clone(), node: Some(node), id: id };
+        handle.set(Box::new(task));
+        task_set.borrow_mut().tasks.insert(id, RunningTask { dropper: dropper, meta: meta });
+    }
+
+    /// Starts queued tasks until the set is at its limit again.
+    fn start_queued(task_set: &Rc<RefCell<Self>>) {
+        loop {
+            let mut queued = {
+                let inner = &mut *task_set.borrow_mut();
+                if inner.is_full() {
+                    return;
+                }
+                match inner.queue.pop_front() {
+                    Some(queued) => queued,
+                    None => return,
+                }
+            };
+            let node = (queued.factory)();
+            TaskSetImpl::start(task_set.clone(), queued.id, queued.meta, node);
+        }
+    }
+
+    /// Cancels the task with the given id. Returns false if it had already finished.
+    pub fn cancel(task_set: &Rc<RefCell<Self>>, id: u64) -> bool {
+        let dropper = {
+            let inner = &mut *task_set.borrow_mut();
+            match inner.tasks.remove(&id) {
+                Some(task) => task.dropper,
+                None => {
+                    let position = inner.queue.iter().position(|task| task.id == id);
+                    match position {
+                        Some(position) => {
+                            inner.queue.remove(position);
+                            inner.stats.cancelled += 1;
+                            inner.notify_if_empty();
+                            return true;
+                        }
+                        None => return false,
+                    }
+                }
+            }
+        };
+
+        // Dropping the task's event updates the stats, so we must not hold a borrow here.
+        drop(dropper);
+        task_set.borrow_mut().notify_if_empty();
+        TaskSetImpl::start_queued(task_set);
+        return true;
+    }
+
+    pub fn cancel_all(task_set: &Rc<RefCell<Self>>) {
+        let (tasks, queue) = {
+            let inner = &mut *task_set.borrow_mut();
+            inner.stats.cancelled += inner.queue.len() as u64;
+            (::std::mem::replace(&mut inner.tasks, HashMap::new()),
+             ::std::mem::replace(&mut inner.queue, VecDeque::new()))
+        };
+        drop(queue);
+        drop(tasks);
+        task_set.borrow_mut().notify_if_empty();
+    }
+
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.catch_panics = catch_panics;
     }
 }
 
 pub struct Task {
     task_set: Rc<RefCell<TaskSetImpl>>,
     node: Option<Box<PromiseNode<()>>>,
-    event_handle: EventHandle,
+    id: u64,
 }
 
 impl Event for Task {
@@ -269,13 +757,31 @@ impl Event for Task {
                 panic!()
             }
             Some(node) => {
-                match node.get() {
+                let result = if self.task_set.borrow().catch_panics {
+                    get_catching_panics(node)
+                } else {
+                    node.get()
+                };
+                match result {
                     Ok(()) => {
-                        return self.task_set.borrow_mut().tasks.remove(&self.event_handle);
+                        let dropper = {
+                            let task_set = &mut *self.task_set.borrow_mut();
+                            task_set.stats.fulfilled += 1;
+                            task_set.remove(self.id)
+                        };
+                        TaskSetImpl::start_queued(&self.task_set);
+                        return dropper;
                     }
                     Err(e) => {
-                        self.task_set.borrow_mut().error_handler.task_failed(e);
-                        return None;
+                        let dropper = {
+                            let task_set = &mut *self.task_set.borrow_mut();
+                            task_set.stats.rejected += 1;
+                            let info = task_set.tasks[&self.id].meta.info(self.id, Instant::now(), false);
+                            task_set.error_handler.task_failed_with_info(&info, e);
+                            task_set.remove(self.id)
+                        };
+                        TaskSetImpl::start_queued(&self.task_set);
+                        return dropper;
                     }
                 }
             }
@@ -283,3 +789,33 @@ impl Event for Task {
     }
 }
 
+/// Evaluates `node`, turning a panic in one of its callbacks into an error.
+fn get_catching_panics(node: Box<PromiseNode<()>>) -> Result<()> {
+    catching_panics(move || node.get())
+}
+
+/// Runs `func`, turning a panic into a `Panicked` error.
+pub fn catching_panics<T, F>(func: F) -> Result<T> where F: FnOnce() -> Result<T> {
+    match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(func)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&'static str>() {
+                Some(message) => message.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<Any>".to_string(),
+                },
+            };
+            Err(Box::new(Panicked { message: message }))
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.node.is_some() {
+            self.task_set.borrow_mut().stats.cancelled += 1;
+        }
+    }
+}
+
@@ -21,10 +21,58 @@
 
 #![allow(dead_code)]
 
-use std::rc::Rc;
-use std::cell::RefCell;
-use {Result, Error, Promise};
-use private::{Event, EventDropper, EventHandle, OnReadyEvent, PromiseNode};
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use {AggregateError, Interrupted, Result, Error, Promise};
+use handle_table::{Handle, HandleTable};
+use private::{catch_panics_enabled, catching_panics, current_deadline, with_ambient_deadline,
+              Event, EventDropper, EventHandle, OnReadyEvent, PromiseNode};
+
+#[derive(Copy, Clone)]
+pub enum NodeKind { Transform, Chain, Join, Immediate }
+
+/// Keeps count of the live promise nodes of a given kind on the current event loop.
+#[cfg(feature = "node-counters")]
+pub struct NodeCounter {
+    kind: NodeKind,
+}
+
+#[cfg(feature = "node-counters")]
+impl NodeCounter {
+    pub fn new(kind: NodeKind) -> NodeCounter {
+        ::private::if_current_event_loop(|event_loop| {
+            let mut counts = event_loop.node_counts.get();
+            {
+                let count = counts.get_mut(kind);
+                count.live += 1;
+                count.created += 1;
+            }
+            event_loop.node_counts.set(counts);
+        });
+        NodeCounter { kind: kind }
+    }
+}
+
+#[cfg(feature = "node-counters")]
+impl Drop for NodeCounter {
+    fn drop(&mut self) {
+        let kind = self.kind;
+        ::private::if_current_event_loop(|event_loop| {
+            let mut counts = event_loop.node_counts.get();
+            counts.get_mut(kind).live -= 1;
+            event_loop.node_counts.set(counts);
+        });
+    }
+}
+
+#[cfg(not(feature = "node-counters"))]
+pub struct NodeCounter;
+
+#[cfg(not(feature = "node-counters"))]
+impl NodeCounter {
+    #[inline]
+    pub fn new(_kind: NodeKind) -> NodeCounter { NodeCounter }
+}
 
 
 /// A PromiseNode that transforms the result of another PromiseNode through an application-provided
@@ -34,6 +82,11 @@ where Func: FnOnce(DepT) -> Result<T>, ErrorFunc: FnOnce(Error) -> Result<T> {
     dependency: Box<PromiseNode<DepT>>,
     func: Func,
     error_handler: ErrorFunc,
+
+    // The ambient deadline when the transform was created, which is reinstated while its
+    // functions run.
+    deadline: Option<::std::time::Instant>,
+    _counter: NodeCounter,
 }
 
 impl <T, DepT, Func, ErrorFunc> Transform<T, DepT, Func, ErrorFunc>
@@ -41,7 +94,9 @@ where Func: FnOnce(DepT) -> Result<T>, ErrorFunc: FnOnce(Error) -> Result<T> {
     pub fn new(dependency: Box<PromiseNode<DepT>>, func: Func, error_handler: ErrorFunc)
            -> Transform<T, DepT, Func, ErrorFunc> {
         Transform { dependency : dependency,
-                    func: func, error_handler: error_handler }
+                    func: func, error_handler: error_handler,
+                    deadline: current_deadline(),
+                    _counter: NodeCounter::new(NodeKind::Transform) }
     }
 }
 
@@ -52,14 +107,28 @@ where Func: FnOnce(DepT) -> Result<T>, ErrorFunc: FnOnce(Error) -> Result<T> {
     }
     fn get(self: Box<Self>) -> Result<T> {
         let tmp = *self;
-        let Transform {dependency, func, error_handler} = tmp;
-        match dependency.get() {
-            Ok(value) => {
-                func(value)
+        let Transform {dependency, func, error_handler, deadline, ..} = tmp;
+        let result = dependency.get();
+        let run = move || {
+            match result {
+                Ok(value) => {
+                    func(value)
+                }
+                Err(e) => {
+                    error_handler(e)
+                }
             }
-            Err(e) => {
-                error_handler(e)
+        };
+        let run = move || {
+            match deadline {
+                None => run(),
+                Some(_) => with_ambient_deadline(deadline, run),
             }
+        };
+        if catch_panics_enabled() {
+            catching_panics(run)
+        } else {
+            run()
         }
     }
 }
@@ -67,11 +136,12 @@ where Func: FnOnce(DepT) -> Result<T>, ErrorFunc: FnOnce(Error) -> Result<T> {
 /// A promise that has already been resolved to an immediate value or error.
 pub struct Immediate<T> {
     result: Result<T>,
+    _counter: NodeCounter,
 }
 
 impl <T> Immediate<T> {
     pub fn new(result: Result<T>) -> Immediate<T> {
-        Immediate { result: result }
+        Immediate { result: result, _counter: NodeCounter::new(NodeKind::Immediate) }
     }
 }
 
@@ -84,75 +154,107 @@ impl <T> PromiseNode<T> for Immediate<T> {
     }
 }
 
-enum ChainState<T> {
+enum ChainState<T> where T: 'static {
     Step1(Box<PromiseNode<Promise<T>>>, Option<EventHandle>),
-    Step2(Box<PromiseNode<T>>, Option<EventHandle>),
+    Step2(Box<PromiseNode<T>>),
     Step3 // done
 }
 
-struct ChainEvent<T> {
-    state: Rc<RefCell<ChainState<T>>>,
+struct ChainInner<T> where T: 'static {
+    state: ChainState<T>,
+
+    // The event that fires when the step 1 dependency is ready.
+    dropper: Option<EventDropper>,
+}
+
+struct ChainEvent<T> where T: 'static {
+    inner: Weak<RefCell<ChainInner<T>>>,
 }
 
 impl <T> Event for ChainEvent<T> where T: 'static {
     fn fire(&mut self) -> Option<EventDropper> {
-        let state = ::std::mem::replace(&mut *self.state.borrow_mut(), ChainState::Step3);
-        match state {
-            ChainState::Step1(inner, on_ready_event) => {
-                match inner.get() {
-                    Ok(mut intermediate) => {
-                        match on_ready_event {
-                            Some(event) => {
-                                intermediate.node.on_ready(event);
-                            }
-                            None => {}
-                        }
-
-                        *self.state.borrow_mut() = ChainState::Step2(intermediate.node, None);
-                    }
-                    Err(e) => {
-                        let mut node = Immediate::new(Err(e));
-                        match on_ready_event {
-                            Some(event) => {
-                                node.on_ready(event);
-                            }
-                            None => {}
-                        }
-
-                        *self.state.borrow_mut() = ChainState::Step2(Box::new(node), None);
-                    }
+        let inner = self.inner.upgrade().expect("chain event fired after its chain was dropped");
+        let state = ::std::mem::replace(&mut inner.borrow_mut().state, ChainState::Step3);
+        let (dependency, on_ready_event) = match state {
+            ChainState::Step1(dependency, on_ready_event) => (dependency, on_ready_event),
+            _ => panic!("should be in step 1"),
+        };
+
+        let mut node: Box<PromiseNode<T>> = match dependency.get() {
+            Ok(intermediate) => intermediate.node,
+            Err(e) => Box::new(Immediate::new(Err(e))),
+        };
+
+        loop {
+            // If the intermediate promise is itself a chain, absorb it instead of nesting it.
+            // Recursive promise loops return a fresh chain from each continuation, so without
+            // this they would build up an arbitrarily long list of chains.
+            let absorbed = node.as_chain().map(|chain| chain.take());
+            match absorbed {
+                None => break,
+                Some((ChainState::Step2(next), _dropper)) => {
+                    node = next;
+                }
+                Some((ChainState::Step1(next_dependency, None), Some(dropper))) => {
+                    // Take over the intermediate chain's pending event. Our own event is
+                    // currently firing, so return it and let the event loop drop it once the
+                    // turn is over.
+                    dropper.event_handle.set(Box::new(ChainEvent { inner: Rc::downgrade(&inner) }));
+                    let inner = &mut *inner.borrow_mut();
+                    inner.state = ChainState::Step1(next_dependency, on_ready_event);
+                    return ::std::mem::replace(&mut inner.dropper, Some(dropper));
                 }
+                Some(_) => panic!("intermediate chain in unexpected state"),
             }
-            _ => panic!("should be in step 1"),
         }
+
+        match on_ready_event {
+            Some(event) => {
+                node.on_ready(event);
+            }
+            None => {}
+        }
+
+        inner.borrow_mut().state = ChainState::Step2(node);
         return None;
     }
 }
 
 /// Promise node that reduces Promise<Promise<T>> to Promise<T>.
-pub struct Chain<T> {
-    state: Rc<RefCell<ChainState<T>>>,
-    dropper: EventDropper,
+pub struct Chain<T> where T: 'static {
+    inner: Rc<RefCell<ChainInner<T>>>,
+    _counter: NodeCounter,
 }
 
 impl <T> Chain<T> where T: 'static {
-    pub fn new(mut inner: Box<PromiseNode<Promise<T>>>) -> Chain<T> {
+    pub fn new(mut dependency: Box<PromiseNode<Promise<T>>>) -> Chain<T> {
 
-        let state = Rc::new(RefCell::new(ChainState::Step3));
-        let event = Box::new(ChainEvent { state: state.clone() });
+        let inner = Rc::new(RefCell::new(ChainInner { state: ChainState::Step3, dropper: None }));
+        let event = Box::new(ChainEvent { inner: Rc::downgrade(&inner) });
         let (handle, dropper) = EventHandle::new();
         handle.set(event);
-        inner.on_ready(handle);
-        *state.borrow_mut() = ChainState::Step1(inner, None);
+        dependency.on_ready(handle);
+        {
+            let inner = &mut *inner.borrow_mut();
+            inner.state = ChainState::Step1(dependency, None);
+            inner.dropper = Some(dropper);
+        }
+
+        Chain { inner: inner, _counter: NodeCounter::new(NodeKind::Chain) }
+    }
 
-        Chain { state: state, dropper: dropper }
+    /// Moves out this chain's state and pending event, leaving it empty.
+    fn take(&mut self) -> (ChainState<T>, Option<EventDropper>) {
+        let inner = &mut *self.inner.borrow_mut();
+        let state = ::std::mem::replace(&mut inner.state, ChainState::Step3);
+        return (state, ::std::mem::replace(&mut inner.dropper, None));
     }
 }
 
-impl <T> PromiseNode<T> for Chain<T> {
+impl <T> PromiseNode<T> for Chain<T> where T: 'static {
     fn on_ready(&mut self, event: EventHandle) {
-        match &mut *self.state.borrow_mut() {
-            &mut ChainState::Step2(ref mut inner, _) => {
+        match &mut self.inner.borrow_mut().state {
+            &mut ChainState::Step2(ref mut inner) => {
                 inner.on_ready(event);
             }
             &mut ChainState::Step1(_, Some(_)) => {
@@ -165,9 +267,9 @@ impl <T> PromiseNode<T> for Chain<T> {
         }
     }
     fn get(self: Box<Self>) -> Result<T> {
-        let state = ::std::mem::replace(&mut *self.state.borrow_mut(), ChainState::Step3);
+        let state = ::std::mem::replace(&mut self.inner.borrow_mut().state, ChainState::Step3);
         match state {
-            ChainState::Step2(inner, _) => {
+            ChainState::Step2(inner) => {
                 inner.get()
             }
             _ => {
@@ -175,6 +277,9 @@ impl <T> PromiseNode<T> for Chain<T> {
             }
         }
     }
+    fn as_chain(&mut self) -> Option<&mut Chain<T>> {
+        Some(self)
+    }
 }
 
 
@@ -201,6 +306,7 @@ struct ArrayJoinState {
 pub struct ArrayJoin<T> {
     state: Rc<RefCell<ArrayJoinState>>,
     branches: Vec<(Box<PromiseNode<T>>, EventDropper)>,
+    _counter: NodeCounter,
 }
 
 impl<T> ArrayJoin<T> {
@@ -215,7 +321,8 @@ impl<T> ArrayJoin<T> {
                 handle.set(Box::new(ArrayJoinBranch { state: state.clone()}));
                 return (node, dropper);
             }).collect();
-        return ArrayJoin {state: state, branches: branches};
+        return ArrayJoin {state: state, branches: branches,
+                          _counter: NodeCounter::new(NodeKind::Join)};
     }
 }
 
@@ -232,16 +339,111 @@ impl <T> PromiseNode<Vec<T>> for ArrayJoin<T> {
     }
 }
 
+struct ArrayJoinFailFastBranch<T> {
+    state: Weak<RefCell<ArrayJoinFailFastState<T>>>,
+    index: usize,
+}
+
+impl<T> Event for ArrayJoinFailFastBranch<T> {
+    fn fire(&mut self) -> Option<EventDropper> {
+        let state = self.state.upgrade().expect("join branch fired after its node was dropped");
+        let state = &mut *state.borrow_mut();
+        let (node, dropper) = state.branches[self.index].take().expect("join branch fired twice?");
+        match node.get() {
+            Ok(value) => {
+                state.results[self.index] = Some(value);
+                state.count_left -= 1;
+                if state.count_left == 0 {
+                    state.on_ready_event.arm();
+                }
+            }
+            Err(e) => {
+                state.error = Some(e);
+
+                // Cancel the branches that are still running.
+                state.branches.clear();
+                state.on_ready_event.arm();
+            }
+        }
+
+        // This branch's event is currently firing, so let the event loop drop it.
+        return Some(dropper);
+    }
+}
+
+struct ArrayJoinFailFastState<T> {
+    on_ready_event: OnReadyEvent,
+    branches: Vec<Option<(Box<PromiseNode<T>>, EventDropper)>>,
+    results: Vec<Option<T>>,
+    count_left: usize,
+    error: Option<Error>,
+}
+
+/// Like `ArrayJoin`, but collects each branch's result as soon as it is ready, and gives up on
+/// the remaining branches as soon as any of them fails.
+pub struct ArrayJoinFailFast<T> {
+    state: Rc<RefCell<ArrayJoinFailFastState<T>>>,
+    _counter: NodeCounter,
+}
+
+impl<T> ArrayJoinFailFast<T> where T: 'static {
+    pub fn new(nodes: Vec<Box<PromiseNode<T>>>) -> ArrayJoinFailFast<T> {
+        let state = Rc::new(RefCell::new(ArrayJoinFailFastState {
+            on_ready_event: OnReadyEvent::Empty,
+            branches: Vec::new(),
+            results: nodes.iter().map(|_| None).collect(),
+            count_left: nodes.len(),
+            error: None,
+        }));
+
+        for (index, mut node) in nodes.into_iter().enumerate() {
+            let (handle, dropper) = EventHandle::new();
+            handle.set(Box::new(ArrayJoinFailFastBranch { state: Rc::downgrade(&state), index: index }));
+            node.on_ready(handle);
+            state.borrow_mut().branches.push(Some((node, dropper)));
+        }
+
+        {
+            let state = &mut *state.borrow_mut();
+            if state.count_left == 0 {
+                state.on_ready_event.arm();
+            }
+        }
+
+        return ArrayJoinFailFast { state: state, _counter: NodeCounter::new(NodeKind::Join) };
+    }
+}
+
+impl <T> PromiseNode<Vec<T>> for ArrayJoinFailFast<T> {
+    fn on_ready(&mut self, event: EventHandle) {
+        self.state.borrow_mut().on_ready_event.init(event);
+    }
+    fn get(self: Box<Self>) -> Result<Vec<T>> {
+        let state = &mut *self.state.borrow_mut();
+        match ::std::mem::replace(&mut state.error, None) {
+            Some(e) => return Err(e),
+            None => {}
+        }
+        let results = ::std::mem::replace(&mut state.results, Vec::new());
+        return Ok(results.into_iter().map(|r| r.expect("join branch has no result")).collect());
+    }
+}
+
 enum ExclusiveJoinSide { Left, Right }
 
 struct ExclusiveJoinBranch<T> {
-    state: Rc<RefCell<ExclusiveJoinState<T>>>,
+    // Weak, so that the branches don't keep the state alive after the node is dropped.
+    state: Weak<RefCell<ExclusiveJoinState<T>>>,
     side: ExclusiveJoinSide,
 }
 
 impl<T> Event for ExclusiveJoinBranch<T> {
     fn fire(&mut self) -> Option<EventDropper> {
-        let state = &mut *self.state.borrow_mut();
+        let state = match self.state.upgrade() {
+            Some(state) => state,
+            None => return None,
+        };
+        let state = &mut *state.borrow_mut();
         match self.side {
             ExclusiveJoinSide::Left => {
                 state.right = None
@@ -264,6 +466,7 @@ struct ExclusiveJoinState<T> {
 
 pub struct ExclusiveJoin<T> where T: 'static {
     state: Rc<RefCell<ExclusiveJoinState<T>>>,
+    _counter: NodeCounter,
 }
 
 impl<T> ExclusiveJoin<T> {
@@ -276,7 +479,7 @@ impl<T> ExclusiveJoin<T> {
         {
             let (handle, dropper) = EventHandle::new();
             left.on_ready(handle);
-            handle.set(Box::new(ExclusiveJoinBranch { state: state.clone(),
+            handle.set(Box::new(ExclusiveJoinBranch { state: Rc::downgrade(&state),
                                                       side: ExclusiveJoinSide::Left }));
 
             state.borrow_mut().left = Some((left, dropper))
@@ -285,13 +488,13 @@ impl<T> ExclusiveJoin<T> {
         {
             let (handle, dropper) = EventHandle::new();
             right.on_ready(handle);
-            handle.set(Box::new(ExclusiveJoinBranch { state: state.clone(),
+            handle.set(Box::new(ExclusiveJoinBranch { state: Rc::downgrade(&state),
                                                       side: ExclusiveJoinSide::Right }));
 
             state.borrow_mut().right = Some((right, dropper))
         }
 
-        return ExclusiveJoin { state: state };
+        return ExclusiveJoin { state: state, _counter: NodeCounter::new(NodeKind::Join) };
     }
 }
 
@@ -317,6 +520,96 @@ impl <T> PromiseNode<T> for ExclusiveJoin<T> {
     }
 }
 
+struct RaceOkBranch<T> {
+    state: Weak<RefCell<RaceOkState<T>>>,
+    index: usize,
+}
+
+impl<T> Event for RaceOkBranch<T> {
+    fn fire(&mut self) -> Option<EventDropper> {
+        let state = self.state.upgrade().expect("race_ok branch fired after its node was dropped");
+        let state = &mut *state.borrow_mut();
+        let (node, dropper) = state.branches[self.index].take().expect("race_ok branch fired twice?");
+        match node.get() {
+            Ok(value) => {
+                state.result = Some(Ok(value));
+
+                // Cancel the branches that are still running.
+                state.branches.clear();
+                state.on_ready_event.arm();
+            }
+            Err(e) => {
+                state.errors.push(e);
+                state.count_left -= 1;
+                if state.count_left == 0 {
+                    let errors = ::std::mem::replace(&mut state.errors, Vec::new());
+                    state.result = Some(Err(Box::new(AggregateError::new(errors))));
+                    state.on_ready_event.arm();
+                }
+            }
+        }
+
+        // This branch's event is currently firing, so let the event loop drop it.
+        return Some(dropper);
+    }
+}
+
+struct RaceOkState<T> {
+    on_ready_event: OnReadyEvent,
+    branches: Vec<Option<(Box<PromiseNode<T>>, EventDropper)>>,
+    count_left: usize,
+    errors: Vec<Error>,
+    result: Option<Result<T>>,
+}
+
+/// Promise node that resolves to the first successful result among its branches, or to an
+/// `AggregateError` if they all fail.
+pub struct RaceOk<T> where T: 'static {
+    state: Rc<RefCell<RaceOkState<T>>>,
+    _counter: NodeCounter,
+}
+
+impl<T> RaceOk<T> {
+    pub fn new(nodes: Vec<Box<PromiseNode<T>>>) -> RaceOk<T> {
+        let state = Rc::new(RefCell::new(RaceOkState {
+            on_ready_event: OnReadyEvent::Empty,
+            branches: Vec::new(),
+            count_left: nodes.len(),
+            errors: Vec::new(),
+            result: None,
+        }));
+
+        for (index, mut node) in nodes.into_iter().enumerate() {
+            let (handle, dropper) = EventHandle::new();
+            handle.set(Box::new(RaceOkBranch { state: Rc::downgrade(&state), index: index }));
+            node.on_ready(handle);
+            state.borrow_mut().branches.push(Some((node, dropper)));
+        }
+
+        {
+            let state = &mut *state.borrow_mut();
+            if state.count_left == 0 {
+                state.result = Some(Err(Box::new(AggregateError::new(Vec::new()))));
+                state.on_ready_event.arm();
+            }
+        }
+
+        return RaceOk { state: state, _counter: NodeCounter::new(NodeKind::Join) };
+    }
+}
+
+impl <T> PromiseNode<T> for RaceOk<T> {
+    fn on_ready(&mut self, event: EventHandle) {
+        self.state.borrow_mut().on_ready_event.init(event);
+    }
+    fn get(self: Box<Self>) -> Result<T> {
+        match ::std::mem::replace(&mut self.state.borrow_mut().result, None) {
+            Some(result) => result,
+            None => panic!("race_ok node is not ready"),
+        }
+    }
+}
+
 pub struct Wrapper<T, U> where T: 'static {
     node: Box<PromiseNode<T>>,
     inner: U,
@@ -337,3 +630,118 @@ impl <T, U> PromiseNode<T> for Wrapper<T, U> {
     }
 }
 
+
+/// The shared state of a `Latch`: whether it has been triggered, and the events to arm when
+/// it is.
+pub struct LatchState {
+    triggered: Cell<bool>,
+    waiters: RefCell<HandleTable<EventHandle>>,
+}
+
+impl LatchState {
+    pub fn new() -> LatchState {
+        LatchState { triggered: Cell::new(false), waiters: RefCell::new(HandleTable::new()) }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.get()
+    }
+
+    pub fn trigger(&self) {
+        if self.triggered.get() {
+            return;
+        }
+        self.triggered.set(true);
+
+        // The table is left as it is, since registrations won't touch it once the latch is
+        // triggered.
+        // Latches are mostly used for cancellation, which should take effect before more work is
+        // done on whatever is being cancelled.
+        let events: Vec<EventHandle> = self.waiters.borrow_mut().iter_mut().map(|e| *e).collect();
+        ::EventLoop::with_priority(::Priority::High, move || {
+            for event in events {
+                event.arm_breadth_first();
+            }
+        });
+    }
+}
+
+/// An event that is waiting on a latch.
+struct LatchRegistration {
+    latch: Rc<LatchState>,
+    handle: Option<Handle>,
+}
+
+impl LatchRegistration {
+    fn register(&mut self, event: EventHandle) {
+        if self.latch.is_triggered() {
+            event.arm_breadth_first();
+        } else {
+            assert!(self.handle.is_none(), "on_ready() called twice?");
+            self.handle = Some(self.latch.waiters.borrow_mut().push(event));
+        }
+    }
+}
+
+impl Drop for LatchRegistration {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if !self.latch.is_triggered() {
+                self.latch.waiters.borrow_mut().remove(handle);
+            }
+        }
+    }
+}
+
+/// Resolves like its dependency, unless the latch is triggered first, in which case it is
+/// rejected with `Interrupted`. Unlike an `ExclusiveJoin`, it needs no events of its own: the
+/// latch arms the same event that the dependency would.
+pub struct Until<T> {
+    dependency: Option<Box<PromiseNode<T>>>,
+    registration: LatchRegistration,
+    _counter: NodeCounter,
+}
+
+impl <T> Until<T> {
+    pub fn new(dependency: Box<PromiseNode<T>>, latch: Rc<LatchState>) -> Until<T> {
+        Until { dependency: Some(dependency),
+                registration: LatchRegistration { latch: latch, handle: None },
+                _counter: NodeCounter::new(NodeKind::Join) }
+    }
+}
+
+impl <T> PromiseNode<T> for Until<T> {
+    fn on_ready(&mut self, event: EventHandle) {
+        self.registration.register(event);
+        if let Some(ref mut dependency) = self.dependency {
+            dependency.on_ready(event);
+        }
+    }
+    fn get(mut self: Box<Self>) -> Result<T> {
+        if self.registration.latch.is_triggered() {
+            return Err(Box::new(Interrupted));
+        }
+        let dependency = self.dependency.take().expect("Until node has no dependency");
+        return dependency.get();
+    }
+}
+
+/// Resolves when the latch is triggered.
+pub struct LatchWait {
+    registration: LatchRegistration,
+}
+
+impl LatchWait {
+    pub fn new(latch: Rc<LatchState>) -> LatchWait {
+        LatchWait { registration: LatchRegistration { latch: latch, handle: None } }
+    }
+}
+
+impl PromiseNode<()> for LatchWait {
+    fn on_ready(&mut self, event: EventHandle) {
+        self.registration.register(event);
+    }
+    fn get(self: Box<Self>) -> Result<()> {
+        return Ok(());
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Running promise factories on schedules, like cron, from within the event loop.
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use {Error, ErrorHandler, Promise, Result, TaskHandle, TaskSet};
+use io::Timer;
+
+/// When a `Scheduler` job runs.
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// Every `period`, counted from when the job was added, however long the runs take.
+    FixedRate(Duration),
+
+    /// `delay` after the previous run finished, and `delay` after the job was added for the
+    /// first run.
+    FixedDelay(Duration),
+
+    /// At the times that match a cron-style spec.
+    Calendar(CalendarSpec),
+}
+
+/// What a `Scheduler` does when a `FixedRate` or `Calendar` job is still running at the time of
+/// its next run. Runs of a job never overlap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlap {
+    /// Skips the runs whose times passed while the job was running. The next run is the first
+    /// one scheduled after the current one finishes.
+    Skip,
+
+    /// Makes up for the runs whose times passed while the job was running, by running them back
+    /// to back once the current one finishes.
+    Queue,
+}
+
+/// Runs jobs, each a factory of promises, on schedules. A failed run is reported to the
+/// scheduler's error handler, and the job carries on with its schedule.
+///
+/// Everything runs on the event loop's thread, driven by `io::Timer`. Dropping the scheduler
+/// cancels its jobs, including any runs in progress.
+pub struct Scheduler {
+    tasks: TaskSet,
+    error_handler: Rc<RefCell<Box<ErrorHandler>>>,
+}
+
+impl Scheduler {
+    pub fn new(error_handler: Box<ErrorHandler>) -> Scheduler {
+        Scheduler {
+            tasks: TaskSet::new_with(|error| panic!("unexpected job failure: {}", error)),
+            error_handler: Rc::new(RefCell::new(error_handler)),
+        }
+    }
+
+    /// Adds a job that calls `factory` and waits on the promise it returns each time `schedule`
+    /// comes around. Cancelling the returned handle removes the job. A `Calendar` job whose spec
+    /// never matches again is removed once its last run has finished.
+    pub fn schedule<F>(&mut self, name: &str, schedule: Schedule, overlap: Overlap,
+                       factory: F) -> TaskHandle
+        where F: 'static,
+              F: FnMut() -> Promise<()>
+    {
+        let start = Timer.now();
+        let job = Job {
+            schedule: schedule,
+            overlap: overlap,
+            start: start,
+            factory: Box::new(factory),
+            error_handler: Rc::downgrade(&self.error_handler),
+        };
+        let promise = match job.next_after(start) {
+            Some(first) => job.run(first),
+            None => Promise::fulfilled(()),
+        };
+        self.tasks.add_named(promise, name)
+    }
+
+    /// The number of jobs.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+struct Job {
+    schedule: Schedule,
+    overlap: Overlap,
+    start: Instant,
+    factory: Box<FnMut() -> Promise<()>>,
+    error_handler: Weak<RefCell<Box<ErrorHandler>>>,
+}
+
+impl Job {
+    fn run(mut self, time: Instant) -> Promise<()> {
+        // A run that is already due, as queued runs are, starts without waiting for the timer.
+        let due = if time <= Timer.now() { Promise::fulfilled(()) } else { Timer.at(time) };
+        return due.then(move |()| {
+            let run = (self.factory)();
+            return Ok(run.map_else(|()| Ok(None), |error| Ok(Some(error))).then(move |error| {
+                if let Some(error) = error {
+                    self.report(error);
+                }
+                let after = match (&self.schedule, self.overlap) {
+                    (&Schedule::FixedDelay(_), _) | (_, Overlap::Skip) => Timer.now(),
+                    (_, Overlap::Queue) => time,
+                };
+                return match self.next_after(after) {
+                    Some(next) => Ok(self.run(next)),
+                    None => Ok(Promise::fulfilled(())),
+                };
+            }));
+        });
+    }
+
+    fn report(&self, error: Error) {
+        if let Some(error_handler) = self.error_handler.upgrade() {
+            error_handler.borrow_mut().task_failed(error);
+        }
+    }
+
+    /// The time of the first run after `time`, or `None` if there is none.
+    fn next_after(&self, time: Instant) -> Option<Instant> {
+        match self.schedule {
+            Schedule::FixedRate(period) => {
+                let period = to_nanos(period);
+                assert!(period > 0, "a job's period must be nonzero");
+                let elapsed = if time > self.start { to_nanos(time - self.start) } else { 0 };
+                let runs = elapsed / period + 1;
+                Some(self.start + from_nanos(runs * period))
+            }
+            Schedule::FixedDelay(delay) => Some(time + delay),
+            Schedule::Calendar(ref spec) => {
+                // Map the loop's clock to the calendar through the current time of each.
+                let now = Timer.now();
+                let wall_now = SystemTime::now();
+                let wall_time = if time >= now { wall_now + (time - now) } else { wall_now - (now - time) };
+                spec.next_after(wall_time).map(|next| {
+                    match next.duration_since(wall_now) {
+                        Ok(ahead) => now + ahead,
+                        Err(_) => now,
+                    }
+                })
+            }
+        }
+    }
+}
+
+fn to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn from_nanos(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// A cron-style schedule: five space-separated fields giving the minute (0-59), hour (0-23), day
+/// of the month (1-31), month (1-12), and day of the week (0-7, where both 0 and 7 are Sunday) at
+/// which to run, evaluated in UTC. Each field is `*`, a number, a range such as `1-5`, or a
+/// comma-separated list of those, and `*` and ranges can take a step, as in `*/15`. As with
+/// cron, if both the day of the month and the day of the week are restricted, a day matching
+/// either will do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalendarSpec {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+// A spec that matches at most once every eight years, as `0 0 29 2 1` might, is given up on.
+const MAX_SEARCH_DAYS: u64 = 8 * 366;
+
+impl CalendarSpec {
+    pub fn parse(spec: &str) -> Result<CalendarSpec> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid_spec(spec));
+        }
+        let (minutes, _) = try!(parse_field(fields[0], 0, 59).ok_or_else(|| invalid_spec(spec)));
+        let (hours, _) = try!(parse_field(fields[1], 0, 23).ok_or_else(|| invalid_spec(spec)));
+        let (days, days_restricted) =
+            try!(parse_field(fields[2], 1, 31).ok_or_else(|| invalid_spec(spec)));
+        let (months, _) = try!(parse_field(fields[3], 1, 12).ok_or_else(|| invalid_spec(spec)));
+        let (mut weekdays, weekdays_restricted) =
+            try!(parse_field(fields[4], 0, 7).ok_or_else(|| invalid_spec(spec)));
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(CalendarSpec {
+            minutes: minutes,
+            hours: hours,
+            days: days,
+            months: months,
+            weekdays: weekdays,
+            days_restricted: days_restricted,
+            weekdays_restricted: weekdays_restricted,
+        })
+    }
+
+    /// Returns the first time after `time` that matches the spec, or `None` if there is none in
+    /// the next eight years.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+        let mut minute = secs / 60 + 1;
+        let last_day = minute / 1440 + MAX_SEARCH_DAYS;
+        while minute / 1440 <= last_day {
+            let day = minute / 1440;
+            if !self.matches_day(day) {
+                minute = (day + 1) * 1440;
+                continue;
+            }
+            if self.hours & (1 << ((minute % 1440) / 60)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) == 0 {
+                minute += 1;
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+        }
+        return None;
+    }
+
+    /// `day` counts days since 1970-01-01.
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = civil_from_days(day);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // 1970-01-01 was a Thursday.
+        let weekday = (day + 4) % 7;
+        let day_matches = self.days & (1 << day_of_month) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+}
+
+fn invalid_spec(spec: &str) -> Error {
+    Box::new(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                   format!("invalid calendar spec: {:?}", spec)))
+}
+
+/// Parses one field of a `CalendarSpec` into a bit mask of the values it allows, along with
+/// whether it restricts them at all. Returns `None` if the field is malformed.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<(u64, bool)> {
+    let mut mask = 0u64;
+    let mut restricted = false;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(slash) => (&part[..slash], match part[slash + 1..].parse::<u32>() {
+                Ok(step) if step > 0 => step,
+                _ => return None,
+            }),
+            None => (part, 1),
+        };
+        let (low, high) = if range == "*" {
+            (min, max)
+        } else {
+            restricted = true;
+            match range.find('-') {
+                Some(dash) => match (range[..dash].parse(), range[dash + 1..].parse()) {
+                    (Ok(low), Ok(high)) => (low, high),
+                    _ => return None,
+                },
+                None => match range.parse() {
+                    Ok(value) => (value, value),
+                    Err(_) => return None,
+                },
+            }
+        };
+        if low < min || high > max || low > high {
+            return None;
+        }
+        if range == "*" && step > 1 {
+            restricted = true;
+        }
+        let mut value = low;
+        while value <= high {
+            mask |= 1 << value;
+            value = match value.checked_add(step) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+    }
+    Some((mask, restricted))
+}
+
+/// Converts a count of days since 1970-01-01 to a (year, month, day) date in the proleptic
+/// Gregorian calendar. See http://howardhinnant.github.io/date_algorithms.html.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,523 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Streams of values that become available over time.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use {join_promises, new_promise_and_fulfiller, Error, ErrorHandler, Promise,
+     PromiseFulfiller, Result, TaskSet};
+use io::Timer;
+
+struct StreamState<T> where T: 'static {
+    queue: VecDeque<T>,
+    waiters: VecDeque<Box<PromiseFulfiller<Option<T>>>>,
+    closed: bool,
+    error: Option<Error>,
+
+    // Producers waiting for `queue` to be emptied. See `StreamSender::when_drained()`.
+    drain_waiters: Vec<Box<PromiseFulfiller<()>>>,
+    receiver_dropped: bool,
+}
+
+impl <T> StreamState<T> {
+    fn next_waiter(&mut self) -> Option<Box<PromiseFulfiller<Option<T>>>> {
+        while let Some(waiter) = self.waiters.pop_front() {
+            if waiter.is_waiting() {
+                return Some(waiter);
+            }
+        }
+        return None;
+    }
+
+    fn fail(&mut self, error: Error) {
+        match self.next_waiter() {
+            Some(waiter) => waiter.reject(error),
+            None => self.error = Some(error),
+        }
+    }
+
+    /// Ends the stream, optionally with an error. Readers that are waiting get `None`, except for
+    /// the first, which gets the error if there is one.
+    fn close(&mut self, error: Option<Error>) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        if let Some(error) = error {
+            self.fail(error);
+        }
+        for waiter in ::std::mem::replace(&mut self.waiters, VecDeque::new()) {
+            waiter.fulfill(None);
+        }
+    }
+
+    fn notify_drained(&mut self) {
+        if self.queue.is_empty() || self.receiver_dropped {
+            for waiter in ::std::mem::replace(&mut self.drain_waiters, Vec::new()) {
+                waiter.fulfill(());
+            }
+        }
+    }
+}
+
+/// The receiving end of a stream of values. Created by `new_stream()` or `spawn_stream()`.
+pub struct PromiseStream<T> where T: 'static {
+    state: Rc<RefCell<StreamState<T>>>,
+
+    // Drives the producer of a stream created by `spawn_stream()`. May be shared by several
+    // streams, as with `demux()`.
+    driver: Option<Rc<TaskSet>>,
+}
+
+impl <T> Drop for PromiseStream<T> {
+    fn drop(&mut self) {
+        let state = &mut *self.state.borrow_mut();
+        state.receiver_dropped = true;
+        state.queue.clear();
+        state.notify_drained();
+    }
+}
+
+impl <T> PromiseStream<T> {
+    /// Returns a promise for the next value in the stream, or for `None` if the stream has ended.
+    /// If the stream was ended by `StreamSender::fail()`, the promise is rejected with the error
+    /// instead, and subsequent calls return `None`.
+    pub fn next(&mut self) -> Promise<Option<T>> {
+        let state = &mut *self.state.borrow_mut();
+        if let Some(value) = state.queue.pop_front() {
+            state.notify_drained();
+            return Promise::fulfilled(Some(value));
+        }
+        if let Some(error) = state.error.take() {
+            return Promise::rejected(error);
+        }
+        if state.closed {
+            return Promise::fulfilled(None);
+        }
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        state.waiters.push_back(fulfiller);
+        return promise;
+    }
+
+    /// Returns a stream that delivers a value from this stream only once no newer value has
+    /// arrived for `delay` milliseconds. When this stream ends, the most recent value is
+    /// delivered immediately.
+    pub fn debounce_ms(self, delay: u64) -> PromiseStream<T> {
+        return spawn_stream(move |sender| debounce_loop(self, sender, None, delay));
+    }
+
+    /// Returns a stream that delivers at most one value from this stream every `interval`
+    /// milliseconds. The first value is delivered immediately; of the values that arrive while
+    /// waiting out an interval, only the most recent is delivered, at the end of the interval.
+    pub fn throttle_ms(self, interval: u64) -> PromiseStream<T> {
+        return spawn_stream(move |sender| throttle_loop(self, sender, None, None, interval));
+    }
+}
+
+fn debounce_loop<T>(mut input: PromiseStream<T>, mut sender: StreamSender<T>,
+                    pending: Option<T>, delay: u64) -> Promise<()> {
+    let next = input.next().map(|value| Ok(Some(value)));
+    let event = if pending.is_some() {
+        next.exclusive_join(Timer.after_delay_ms(delay).map(|()| Ok(None)))
+    } else {
+        next
+    };
+    return event.then(move |event| {
+        match event {
+            Some(Some(value)) => {
+                return Ok(debounce_loop(input, sender, Some(value), delay));
+            }
+            Some(None) => {
+                if let Some(value) = pending {
+                    sender.send(value);
+                }
+                return Ok(Promise::fulfilled(()));
+            }
+            None => {
+                sender.send(pending.unwrap());
+                return Ok(debounce_loop(input, sender, None, delay));
+            }
+        }
+    });
+}
+
+fn duration_to_ms(duration: Duration) -> u64 {
+    return duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+}
+
+/// `deadline` is the end of the current interval, or `None` if no interval is in progress.
+fn throttle_loop<T>(mut input: PromiseStream<T>, mut sender: StreamSender<T>,
+                    pending: Option<T>, deadline: Option<Instant>, interval: u64) -> Promise<()> {
+    let next = input.next().map(|value| Ok(Some(value)));
+    let event = match deadline {
+        Some(deadline) => {
+            let now = Timer.now();
+            let remaining = if deadline > now { duration_to_ms(deadline - now) } else { 0 };
+            next.exclusive_join(Timer.after_delay_ms(remaining).map(|()| Ok(None)))
+        }
+        None => next,
+    };
+    return event.then(move |event| {
+        match event {
+            Some(Some(value)) => {
+                if deadline.is_some() {
+                    return Ok(throttle_loop(input, sender, Some(value), deadline, interval));
+                } else {
+                    sender.send(value);
+                    let deadline = Timer.now() + Duration::from_millis(interval);
+                    return Ok(throttle_loop(input, sender, None, Some(deadline), interval));
+                }
+            }
+            Some(None) => {
+                if let Some(value) = pending {
+                    sender.send(value);
+                }
+                return Ok(Promise::fulfilled(()));
+            }
+            None => {
+                match pending {
+                    Some(value) => {
+                        sender.send(value);
+                        let deadline = Timer.now() + Duration::from_millis(interval);
+                        return Ok(throttle_loop(input, sender, None, Some(deadline), interval));
+                    }
+                    None => {
+                        return Ok(throttle_loop(input, sender, None, None, interval));
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// The sending end of a stream of values. Dropping the sender ends the stream.
+pub struct StreamSender<T> where T: 'static {
+    state: Rc<RefCell<StreamState<T>>>,
+}
+
+impl <T> StreamSender<T> {
+    /// Adds a value to the end of the stream. The value is discarded if the receiver has been
+    /// dropped.
+    pub fn send(&mut self, value: T) {
+        let state = &mut *self.state.borrow_mut();
+        if state.closed || state.receiver_dropped {
+            return;
+        }
+        match state.next_waiter() {
+            Some(waiter) => waiter.fulfill(Some(value)),
+            None => state.queue.push_back(value),
+        }
+    }
+
+    /// Ends the stream with an error. Values that have already been sent are still delivered
+    /// before the error.
+    pub fn fail(self, error: Error) {
+        self.state.borrow_mut().close(Some(error));
+    }
+
+    /// Returns a promise that resolves once the receiver has taken every value sent so far, or
+    /// has been dropped. Producers can wait on this to avoid getting ahead of a slow consumer.
+    pub fn when_drained(&mut self) -> Promise<()> {
+        let state = &mut *self.state.borrow_mut();
+        if state.queue.is_empty() || state.receiver_dropped {
+            return Promise::fulfilled(());
+        }
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        state.drain_waiters.push(fulfiller);
+        return promise;
+    }
+
+    fn is_closed(&self) -> bool {
+        let state = self.state.borrow();
+        state.closed || state.receiver_dropped
+    }
+}
+
+impl <T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        self.state.borrow_mut().close(None);
+    }
+}
+
+/// Creates a new stream, returning its receiving and sending ends.
+pub fn new_stream<T>() -> (PromiseStream<T>, StreamSender<T>) where T: 'static {
+    let state = Rc::new(RefCell::new(StreamState {
+        queue: VecDeque::new(),
+        waiters: VecDeque::new(),
+        closed: false,
+        error: None,
+        drain_waiters: Vec::new(),
+        receiver_dropped: false,
+    }));
+    return (PromiseStream { state: state.clone(), driver: None }, StreamSender { state: state });
+}
+
+struct DriverErrorHandler<T> where T: 'static {
+    state: Rc<RefCell<StreamState<T>>>,
+}
+
+impl <T> ErrorHandler for DriverErrorHandler<T> {
+    fn task_failed(&mut self, error: Error) {
+        self.state.borrow_mut().fail(error);
+    }
+}
+
+/// Creates a stream whose values are produced by the promise that `driver` returns when passed
+/// the stream's sender. The stream ends when the sender is dropped. If the promise is rejected,
+/// the error is delivered to the next reader that isn't already waiting. Dropping the stream
+/// cancels the promise.
+pub fn spawn_stream<T, F>(driver: F) -> PromiseStream<T>
+    where T: 'static,
+          F: FnOnce(StreamSender<T>) -> Promise<()>
+{
+    let (mut stream, sender) = new_stream();
+    let mut tasks = TaskSet::new(Box::new(DriverErrorHandler { state: stream.state.clone() }));
+    tasks.add(driver(sender));
+    stream.driver = Some(Rc::new(tasks));
+    return stream;
+}
+
+/// Sends the values of `input` to `output`, one at a time, waiting for the receiver of `output`
+/// to take each value before reading the next. If `input` fails, `output` fails too.
+fn forward<T>(mut input: PromiseStream<T>, output: Rc<RefCell<StreamSender<T>>>) -> Promise<()> {
+    if output.borrow().is_closed() {
+        return Promise::fulfilled(());
+    }
+    let output1 = output.clone();
+    return input.next().then_else(
+        move |value| {
+            match value {
+                Some(value) => {
+                    output.borrow_mut().send(value);
+                    let drained = output.borrow_mut().when_drained();
+                    return Ok(drained.then(move |()| Ok(forward(input, output))));
+                }
+                None => return Ok(Promise::fulfilled(())),
+            }
+        },
+        move |error| {
+            output1.borrow().state.borrow_mut().close(Some(error));
+            return Ok(Promise::fulfilled(()));
+        });
+}
+
+/// Combines several streams into one, which delivers the values of each in the order they
+/// arrive, and ends once all of them have ended. Values are only read from an input once the
+/// previous value from that input has been taken from the merged stream. If any input fails, the
+/// merged stream fails with the same error.
+pub fn merge<T>(inputs: Vec<PromiseStream<T>>) -> PromiseStream<T> where T: 'static {
+    return spawn_stream(move |sender| {
+        let sender = Rc::new(RefCell::new(sender));
+        let forwards = inputs.into_iter().map(|input| forward(input, sender.clone())).collect();
+        return join_promises(forwards).map(|_| Ok(()));
+    });
+}
+
+/// Splits a stream into several, sending each value to the stream for the key that `key_fn`
+/// returns for it. Values whose keys are not in `keys` are discarded. After sending a value to a
+/// sub-stream, no further values are read from `input` until it has been taken from that
+/// sub-stream, so a slow reader holds back all of the others. The sub-streams end when `input`
+/// does. If `input` fails, the error is delivered to the next reader of each sub-stream that
+/// isn't already waiting.
+pub fn demux<K, T, F>(input: PromiseStream<T>, keys: Vec<K>, key_fn: F) -> HashMap<K, PromiseStream<T>>
+    where K: 'static + Clone + Eq + Hash,
+          T: 'static,
+          F: 'static + Fn(&T) -> K
+{
+    let mut streams = HashMap::new();
+    let mut senders = HashMap::new();
+    for key in keys.into_iter() {
+        let (stream, sender) = new_stream();
+        streams.insert(key.clone(), stream);
+        senders.insert(key, sender);
+    }
+
+    let states = streams.values().map(|stream: &PromiseStream<T>| stream.state.clone()).collect();
+    let mut tasks = TaskSet::new(Box::new(DemuxErrorHandler { states: states }));
+    tasks.add(demux_loop(input, senders, key_fn));
+    let driver = Rc::new(tasks);
+    for stream in streams.values_mut() {
+        stream.driver = Some(driver.clone());
+    }
+    return streams;
+}
+
+fn demux_loop<K, T, F>(mut input: PromiseStream<T>, mut senders: HashMap<K, StreamSender<T>>,
+                       key_fn: F) -> Promise<()>
+    where K: 'static + Eq + Hash,
+          T: 'static,
+          F: 'static + Fn(&T) -> K
+{
+    return input.next().then(move |value| {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Promise::fulfilled(())),
+        };
+        let drained = match senders.get_mut(&key_fn(&value)) {
+            Some(sender) => {
+                sender.send(value);
+                sender.when_drained()
+            }
+            None => Promise::fulfilled(()),
+        };
+        return Ok(drained.then(move |()| Ok(demux_loop(input, senders, key_fn))));
+    });
+}
+
+struct DemuxErrorHandler<T> where T: 'static {
+    states: Vec<Rc<RefCell<StreamState<T>>>>,
+}
+
+impl <T> ErrorHandler for DemuxErrorHandler<T> {
+    fn task_failed(&mut self, error: Error) {
+        // Errors can't be cloned, so the other sub-streams get a copy of the message.
+        let message = format!("{}", error);
+        let mut error = Some(error);
+        for state in &self.states {
+            let error = match error.take() {
+                Some(error) => error,
+                None => Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, message.clone())),
+            };
+            state.borrow_mut().fail(error);
+        }
+    }
+}
+
+/// A value waiting in a `Reorderer` for its turn. Ordered so that the lowest sequence number is
+/// at the top of the heap.
+struct Pending<T> {
+    seq: u64,
+    value: T,
+}
+
+impl <T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Pending<T>) -> bool { self.seq == other.seq }
+}
+
+impl <T> Eq for Pending<T> {}
+
+impl <T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Pending<T>) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl <T> Ord for Pending<T> {
+    fn cmp(&self, other: &Pending<T>) -> Ordering { other.seq.cmp(&self.seq) }
+}
+
+struct ReordererState<T> where T: 'static {
+    sender: Option<StreamSender<T>>,
+    next_seq: u64,
+    outstanding: HashSet<u64>,
+    buffer: BinaryHeap<Pending<T>>,
+}
+
+impl <T> ReordererState<T> {
+    fn deliver(&mut self, seq: u64, value: T) {
+        self.buffer.push(Pending { seq: seq, value: value });
+        while self.buffer.peek().map_or(false, |pending| pending.seq == self.next_seq) {
+            let pending = self.buffer.pop().unwrap();
+            self.outstanding.remove(&pending.seq);
+            self.next_seq += 1;
+            if let Some(ref mut sender) = self.sender {
+                sender.send(pending.value);
+            }
+        }
+    }
+}
+
+struct ReordererErrorHandler<T> where T: 'static {
+    state: Rc<RefCell<ReordererState<T>>>,
+}
+
+impl <T> ErrorHandler for ReordererErrorHandler<T> {
+    fn task_failed(&mut self, error: Error) {
+        let state = &mut *self.state.borrow_mut();
+        state.buffer.clear();
+        if let Some(sender) = state.sender.take() {
+            sender.fail(error);
+        }
+    }
+}
+
+/// Accepts promises tagged with sequence numbers and delivers their values on a `PromiseStream`
+/// in sequence order, regardless of the order in which the promises resolve.
+///
+/// Values that resolve early are buffered until all of their predecessors have been delivered.
+/// To bound that buffer, only sequence numbers within `capacity` of the next one to be delivered
+/// are accepted. If any of the promises is rejected, the stream fails with its error.
+///
+/// Dropping the `Reorderer` cancels the outstanding promises and ends the stream.
+pub struct Reorderer<T> where T: 'static {
+    state: Rc<RefCell<ReordererState<T>>>,
+    capacity: u64,
+    tasks: TaskSet,
+}
+
+impl <T> Reorderer<T> {
+    /// Creates a reorderer whose stream begins at sequence number `first_seq`.
+    pub fn new(first_seq: u64, capacity: u64) -> (Reorderer<T>, PromiseStream<T>) {
+        let (stream, sender) = new_stream();
+        let state = Rc::new(RefCell::new(ReordererState {
+            sender: Some(sender),
+            next_seq: first_seq,
+            outstanding: HashSet::new(),
+            buffer: BinaryHeap::new(),
+        }));
+        let tasks = TaskSet::new(Box::new(ReordererErrorHandler { state: state.clone() }));
+        return (Reorderer { state: state, capacity: capacity, tasks: tasks }, stream);
+    }
+
+    /// Adds the promise for the value with sequence number `seq`. Fails if `seq` has already been
+    /// added, or if it lies outside the window of `capacity` sequence numbers starting at the next
+    /// one to be delivered.
+    pub fn push(&mut self, seq: u64, promise: Promise<T>) -> Result<()> {
+        {
+            let state = &mut *self.state.borrow_mut();
+            if seq < state.next_seq || seq - state.next_seq >= self.capacity {
+                return Err(Box::new(::std::io::Error::new(
+                    ::std::io::ErrorKind::InvalidInput,
+                    format!("sequence number {} is outside the window [{}, {})",
+                            seq, state.next_seq, state.next_seq + self.capacity))));
+            }
+            if !state.outstanding.insert(seq) {
+                return Err(Box::new(::std::io::Error::new(
+                    ::std::io::ErrorKind::InvalidInput,
+                    format!("sequence number {} was already pushed", seq))));
+            }
+        }
+        let state = self.state.clone();
+        self.tasks.add(promise.map(move |value| {
+            state.borrow_mut().deliver(seq, value);
+            return Ok(());
+        }));
+        return Ok(());
+    }
+
+    /// Returns the sequence number of the next value to be delivered.
+    pub fn next_seq(&self) -> u64 {
+        return self.state.borrow().next_seq;
+    }
+}
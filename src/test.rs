@@ -0,0 +1,138 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Helpers for testing code that uses gj.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use {EventLoop, Result, WaitScope};
+use io::{Clock, MioEventPort, Timer};
+use private::with_current_event_loop;
+
+/// Runs `main` on a fresh event loop, like `EventLoop::top_level()`, and then checks that it
+/// cleaned up after itself. Returns whatever `main` returned.
+///
+/// Panics if `main` returns an error or if, once it has returned, the loop still has any queued
+/// events, live event nodes, registered fd observers, or unfired promise fulfillers. Such
+/// leftovers usually indicate a leaked promise, task, or stream.
+pub fn run<T, F>(main: F) -> T where F: FnOnce(&WaitScope) -> Result<T> {
+    let result = EventLoop::top_level(move |wait_scope| {
+        let value = try!(main(wait_scope));
+
+        let report = with_current_event_loop(|event_loop| {
+            LeakReport {
+                queued_events: event_loop.queued_event_count(),
+
+                event_nodes: event_loop.all_events.len(),
+                observers: event_loop.event_port.borrow().observer_count(),
+                fulfillers: event_loop.live_fulfillers.get(),
+            }
+        });
+
+        if !report.is_clean() {
+            panic!("gj::test::run() found leftovers after the test finished:\n{}", report);
+        }
+        Ok(value)
+    });
+
+    match result {
+        Ok(value) => value,
+        Err(e) => panic!("gj::test::run() failed: {}", e),
+    }
+}
+
+struct LeakReport {
+    queued_events: usize,
+    event_nodes: usize,
+    observers: usize,
+    fulfillers: usize,
+}
+
+impl LeakReport {
+    fn is_clean(&self) -> bool {
+        self.queued_events == 0 && self.event_nodes == 0 && self.observers == 0 && self.fulfillers == 0
+    }
+}
+
+impl ::std::fmt::Display for LeakReport {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        try!(writeln!(fmt, "  queued events:      {}", self.queued_events));
+        try!(writeln!(fmt, "  live event nodes:   {}", self.event_nodes));
+        try!(writeln!(fmt, "  fd observers:       {}", self.observers));
+        write!(fmt, "  unfired fulfillers: {}", self.fulfillers)
+    }
+}
+
+/// A clock that only moves when told to, so that tests involving timers run deterministically
+/// and without sleeping. Clones share the same time.
+///
+/// ```
+/// # extern crate gj;
+/// # fn main() {
+/// gj::EventLoop::top_level(|wait_scope| {
+///     let clock = gj::test::TestClock::install();
+///     let timer = gj::io::Timer.after_delay_ms(500);
+///     clock.advance_ms(500);
+///     try!(timer.wait(wait_scope));
+///     Ok(())
+/// }).unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TestClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl TestClock {
+    /// Creates a clock that starts at the current time.
+    pub fn new() -> TestClock {
+        TestClock { now: Rc::new(Cell::new(Instant::now())) }
+    }
+
+    /// Creates a clock and makes the current thread's timers run on it. See
+    /// `Timer::set_clock()`. Panics if the current thread has no event loop.
+    pub fn install() -> TestClock {
+        let clock = TestClock::new();
+        Timer.set_clock(clock.clone());
+        return clock;
+    }
+
+    /// Moves the clock forward by `duration`. If the clock is installed, the timers whose
+    /// deadlines it passes are fulfilled, so their callbacks run on the next turns of the loop.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+        if EventLoop::exists() {
+            MioEventPort::poll_current();
+        }
+    }
+
+    /// Like `advance()`, with the duration in milliseconds.
+    pub fn advance_ms(&self, ms: u64) {
+        self.advance(Duration::from_millis(ms))
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
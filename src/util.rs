@@ -0,0 +1,504 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Higher-level helpers built out of promises.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+use {new_promise_and_fulfiller, race_ok, Error, ErrorHandler, Promise, PromiseFulfiller, Result, TaskHandle,
+     TaskSet};
+use io::Timer;
+
+/// An `ErrorHandler` for task sets whose tasks never fail because they handle their own errors.
+struct Unreachable;
+
+impl ErrorHandler for Unreachable {
+    fn task_failed(&mut self, error: Error) {
+        panic!("unexpected task failure: {}", error);
+    }
+}
+
+enum CellState<T> where T: 'static {
+    Idle,
+    Pending(Vec<Box<PromiseFulfiller<T>>>),
+    Ready(T),
+}
+
+struct CellInner<T> where T: 'static {
+    factory: Box<FnMut() -> Promise<T>>,
+    state: CellState<T>,
+    tasks: TaskSet,
+}
+
+/// A lazily computed, shared value. The first call to `get()` invokes the factory and
+/// callers get promises for its result, which is then cached for later calls.
+///
+/// If the factory's promise is rejected, every caller waiting on it is rejected and the
+/// cell goes back to its initial state, so the next call to `get()` tries again.
+///
+/// Clones of a `PromiseCell` share the same value. Dropping the last clone cancels a computation
+/// that is still in progress.
+pub struct PromiseCell<T> where T: 'static {
+    inner: Rc<RefCell<CellInner<T>>>,
+}
+
+impl <T> Clone for PromiseCell<T> {
+    fn clone(&self) -> PromiseCell<T> {
+        PromiseCell { inner: self.inner.clone() }
+    }
+}
+
+impl <T> PromiseCell<T> where T: Clone {
+    pub fn new<F>(factory: F) -> PromiseCell<T>
+        where F: 'static,
+              F: FnMut() -> Promise<T>
+    {
+        PromiseCell {
+            inner: Rc::new(RefCell::new(CellInner {
+                factory: Box::new(factory),
+                state: CellState::Idle,
+                tasks: TaskSet::new(Box::new(Unreachable)),
+            }))
+        }
+    }
+
+    /// Returns a promise for the value, starting its computation if necessary.
+    pub fn get(&self) -> Promise<T> {
+        let inner = &mut *self.inner.borrow_mut();
+        match inner.state {
+            CellState::Ready(ref value) => return Promise::fulfilled(value.clone()),
+            CellState::Pending(ref mut waiters) => {
+                let (promise, fulfiller) = new_promise_and_fulfiller();
+                waiters.push(fulfiller);
+                return promise;
+            }
+            CellState::Idle => (),
+        }
+
+        let (promise, fulfiller) = new_promise_and_fulfiller();
+        inner.state = CellState::Pending(vec![fulfiller]);
+        let weak1 = Rc::downgrade(&self.inner);
+        let weak2 = weak1.clone();
+        let computation = (inner.factory)();
+        inner.tasks.add(computation.map_else(
+            move |value| {
+                PromiseCell::resolve(weak1, Ok(value));
+                return Ok(());
+            },
+            move |error| {
+                PromiseCell::resolve(weak2, Err(error));
+                return Ok(());
+            }));
+        return promise;
+    }
+
+    /// Returns the cached value, if the computation has finished.
+    pub fn peek(&self) -> Option<T> {
+        match self.inner.borrow().state {
+            CellState::Ready(ref value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forgets the cached value, so that the next call to `get()` invokes the factory again.
+    /// Has no effect on a computation that is still in progress.
+    pub fn reset(&self) {
+        let inner = &mut *self.inner.borrow_mut();
+        if let CellState::Ready(_) = inner.state {
+            inner.state = CellState::Idle;
+        }
+    }
+
+    fn resolve(inner: Weak<RefCell<CellInner<T>>>, result: Result<T>) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let new_state = match result {
+            Ok(ref value) => CellState::Ready(value.clone()),
+            Err(_) => CellState::Idle,
+        };
+        let waiters = match ::std::mem::replace(&mut inner.borrow_mut().state, new_state) {
+            CellState::Pending(waiters) => waiters,
+            _ => unreachable!(),
+        };
+        match result {
+            Ok(value) => {
+                for waiter in waiters.into_iter() {
+                    waiter.fulfill(value.clone());
+                }
+            }
+            Err(error) => {
+                // Errors can't be cloned, so the other waiters get a copy of the message.
+                let message = format!("{}", error);
+                let mut waiters = waiters.into_iter();
+                if let Some(first) = waiters.next() {
+                    first.reject(error);
+                }
+                for waiter in waiters {
+                    waiter.reject(Box::new(
+                        ::std::io::Error::new(::std::io::ErrorKind::Other, message.clone())));
+                }
+            }
+        }
+    }
+}
+
+/// Starts an attempt by calling `factory`, and starts a second, hedging attempt if the first
+/// hasn't succeeded after `delay` milliseconds (or as soon as the first fails). The result is
+/// that of whichever attempt succeeds first; the other attempt is then cancelled. If both
+/// attempts fail, the result is rejected with an `AggregateError` holding both errors.
+///
+/// This trades some extra load for lower tail latency when requests occasionally stall.
+pub fn hedge<T, F>(mut factory: F, delay: u64) -> Promise<T>
+    where T: 'static,
+          F: 'static,
+          F: FnMut() -> Promise<T>
+{
+    let (failed, failed_fulfiller) = new_promise_and_fulfiller::<()>();
+    let first = factory().map_else(
+        |value| Ok(value),
+        move |error| {
+            failed_fulfiller.fulfill(());
+            return Err(error);
+        });
+    let second = Timer.after_delay_ms(delay).exclusive_join(failed).then(move |()| {
+        return Ok(factory());
+    });
+    return race_ok(vec![first, second]);
+}
+
+/// The error with which a `CircuitBreaker` rejects calls while it is open.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl ::std::fmt::Display for Overloaded {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "circuit breaker is open")
+    }
+}
+
+impl ::std::error::Error for Overloaded {
+    fn description(&self) -> &str {
+        "circuit breaker is open"
+    }
+}
+
+/// The state of a `CircuitBreaker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through, and their outcomes are tracked.
+    Closed,
+
+    /// Too many recent calls have failed. Calls are rejected without being attempted.
+    Open,
+
+    /// The open period has elapsed. A single trial call is let through; if it succeeds the
+    /// breaker closes, and if it fails the breaker opens again.
+    HalfOpen,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    recent: VecDeque<bool>,
+    probe_in_flight: bool,
+    window: usize,
+    max_failures: usize,
+    open_ms: u64,
+    timers: TaskSet,
+}
+
+impl BreakerState {
+    fn open(state: &Rc<RefCell<BreakerState>>) {
+        let weak = Rc::downgrade(state);
+        let inner = &mut *state.borrow_mut();
+        inner.state = CircuitState::Open;
+        inner.recent.clear();
+        inner.timers.add(Timer.after_delay_ms(inner.open_ms).map(move |()| {
+            if let Some(state) = weak.upgrade() {
+                state.borrow_mut().state = CircuitState::HalfOpen;
+            }
+            return Ok(());
+        }));
+    }
+
+    fn record(state: &Rc<RefCell<BreakerState>>, probe: bool, success: bool) {
+        let trip = {
+            let inner = &mut *state.borrow_mut();
+            if probe {
+                inner.probe_in_flight = false;
+                if success {
+                    inner.state = CircuitState::Closed;
+                }
+                !success
+            } else if inner.state == CircuitState::Closed {
+                inner.recent.push_back(success);
+                if inner.recent.len() > inner.window {
+                    inner.recent.pop_front();
+                }
+                inner.recent.iter().filter(|&&ok| !ok).count() >= inner.max_failures
+            } else {
+                false
+            }
+        };
+        if trip {
+            BreakerState::open(state);
+        }
+    }
+}
+
+/// Reports the outcome of a call to its breaker. Dropped without reporting if the call is
+/// cancelled, in which case a trial call is forgotten so that another can take its place.
+struct CallGuard {
+    state: Weak<RefCell<BreakerState>>,
+    probe: bool,
+    done: Cell<bool>,
+}
+
+impl CallGuard {
+    fn report(&self, success: bool) {
+        self.done.set(true);
+        if let Some(state) = self.state.upgrade() {
+            BreakerState::record(&state, self.probe, success);
+        }
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if self.probe && !self.done.get() {
+            if let Some(state) = self.state.upgrade() {
+                state.borrow_mut().probe_in_flight = false;
+            }
+        }
+    }
+}
+
+/// Protects a downstream service from being hammered while it is failing. Wrap each operation on
+/// the service in a call to `call()`; once `max_failures` of the last `window` operations have
+/// failed, the breaker opens and rejects operations with `Overloaded` without attempting them.
+/// After `open_ms` milliseconds, a single trial operation is allowed through to decide whether
+/// to close the breaker again.
+pub struct CircuitBreaker {
+    state: Rc<RefCell<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(window: usize, max_failures: usize, open_ms: u64) -> CircuitBreaker {
+        CircuitBreaker {
+            state: Rc::new(RefCell::new(BreakerState {
+                state: CircuitState::Closed,
+                recent: VecDeque::new(),
+                probe_in_flight: false,
+                window: window,
+                max_failures: max_failures,
+                open_ms: open_ms,
+                timers: TaskSet::new(Box::new(Unreachable)),
+            }))
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        return self.state.borrow().state;
+    }
+
+    /// Starts the operation returned by `operation`, unless the breaker is open.
+    pub fn call<T, F>(&self, operation: F) -> Promise<T>
+        where T: 'static,
+              F: FnOnce() -> Promise<T>
+    {
+        let probe = {
+            let inner = &mut *self.state.borrow_mut();
+            match inner.state {
+                CircuitState::Closed => false,
+                CircuitState::Open => return Promise::rejected(Box::new(Overloaded)),
+                CircuitState::HalfOpen => {
+                    if inner.probe_in_flight {
+                        return Promise::rejected(Box::new(Overloaded));
+                    }
+                    inner.probe_in_flight = true;
+                    true
+                }
+            }
+        };
+        let guard1 = Rc::new(CallGuard { state: Rc::downgrade(&self.state), probe: probe,
+                                         done: Cell::new(false) });
+        let guard2 = guard1.clone();
+        return operation().map_else(
+            move |value| {
+                guard1.report(true);
+                return Ok(value);
+            },
+            move |error| {
+                guard2.report(false);
+                return Err(error);
+            });
+    }
+}
+
+/// Like a `TaskSet`, but for promises that produce values. The outcome of each task, whether a
+/// value or an error, is kept until it is collected with `take_completed()` or `drain()`, in the
+/// order in which the tasks completed. Dropping the reaper cancels its unfinished tasks.
+pub struct TaskReaper<T> where T: 'static {
+    tasks: TaskSet,
+    completed: Rc<RefCell<Vec<Result<T>>>>,
+}
+
+impl <T> TaskReaper<T> {
+    pub fn new() -> TaskReaper<T> {
+        TaskReaper {
+            tasks: TaskSet::new(Box::new(Unreachable)),
+            completed: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Adds a task whose outcome is to be collected.
+    pub fn add(&mut self, promise: Promise<T>) -> TaskHandle {
+        let completed = Rc::downgrade(&self.completed);
+        self.tasks.add(promise.map_else(|value| Ok(Ok(value)), |error| Ok(Err(error))).map(move |outcome| {
+            if let Some(completed) = completed.upgrade() {
+                completed.borrow_mut().push(outcome);
+            }
+            Ok(())
+        }))
+    }
+
+    /// The number of tasks that have not yet completed.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Removes and returns the outcomes of the tasks that have completed so far.
+    pub fn take_completed(&mut self) -> Vec<Result<T>> {
+        ::std::mem::replace(&mut *self.completed.borrow_mut(), Vec::new())
+    }
+
+    /// Returns a promise for the outcomes of all of the tasks that have not yet been collected,
+    /// which resolves once no tasks are outstanding.
+    pub fn drain(&mut self) -> Promise<Vec<Result<T>>> {
+        let completed = self.completed.clone();
+        self.tasks.on_empty().map(move |()| {
+            Ok(::std::mem::replace(&mut *completed.borrow_mut(), Vec::new()))
+        })
+    }
+}
+
+/// How a `Supervisor` restarts a failed task.
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    /// How many times the task may be restarted before the supervisor gives up on it. `None`
+    /// means there is no limit.
+    pub max_restarts: Option<u32>,
+
+    /// The delay before the first restart. Each further restart waits twice as long as the one
+    /// before, up to `max_backoff_ms`.
+    pub initial_backoff_ms: u64,
+
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy {
+        RestartPolicy { max_restarts: Some(5), initial_backoff_ms: 100, max_backoff_ms: 30000 }
+    }
+}
+
+/// Runs tasks that are restarted when they fail, according to a `RestartPolicy`. A task whose
+/// promise resolves successfully is done and is not restarted. When a task runs out of restarts,
+/// the supervisor's give-up callback is called with the task's name and its last error.
+///
+/// Dropping the supervisor cancels its tasks, including any waiting to be restarted.
+pub struct Supervisor {
+    tasks: TaskSet,
+    give_up: Rc<RefCell<Box<FnMut(&str, Error)>>>,
+}
+
+impl Supervisor {
+    pub fn new<G>(give_up: G) -> Supervisor
+        where G: 'static,
+              G: FnMut(&str, Error)
+    {
+        Supervisor {
+            tasks: TaskSet::new(Box::new(Unreachable)),
+            give_up: Rc::new(RefCell::new(Box::new(give_up))),
+        }
+    }
+
+    /// Starts the task returned by `factory`, calling `factory` again whenever the task needs to
+    /// be restarted.
+    pub fn supervise<F>(&mut self, name: &str, policy: RestartPolicy, factory: F) -> TaskHandle
+        where F: 'static,
+              F: FnMut() -> Promise<()>
+    {
+        let attempt = Attempt {
+            name: name.to_string(),
+            policy: policy,
+            restarts: 0,
+            backoff_ms: policy.initial_backoff_ms,
+            factory: Box::new(factory),
+            give_up: Rc::downgrade(&self.give_up),
+        };
+        self.tasks.add_named(attempt.run(), name)
+    }
+
+    /// The number of tasks that are running or waiting to be restarted.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Returns a promise that resolves once every task has either finished or been given up on.
+    pub fn on_empty(&mut self) -> Promise<()> {
+        self.tasks.on_empty()
+    }
+}
+
+struct Attempt {
+    name: String,
+    policy: RestartPolicy,
+    restarts: u32,
+    backoff_ms: u64,
+    factory: Box<FnMut() -> Promise<()>>,
+    give_up: Weak<RefCell<Box<FnMut(&str, Error)>>>,
+}
+
+impl Attempt {
+    fn run(mut self) -> Promise<()> {
+        let task = (self.factory)();
+        return task.then_else(|()| Ok(Promise::fulfilled(())), move |error| {
+            let exhausted = match self.policy.max_restarts {
+                Some(max) => self.restarts >= max,
+                None => false,
+            };
+            if exhausted {
+                if let Some(give_up) = self.give_up.upgrade() {
+                    (&mut *give_up.borrow_mut())(&self.name, error);
+                }
+                return Ok(Promise::fulfilled(()));
+            }
+            let delay = self.backoff_ms;
+            self.restarts += 1;
+            self.backoff_ms = ::std::cmp::min(self.backoff_ms.saturating_mul(2),
+                                              self.policy.max_backoff_ms);
+            return Ok(Timer.after_delay_ms(delay).then(move |()| Ok(self.run())));
+        });
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Runs on its own, since it exhausts the process's file descriptors.
+
+#![cfg(feature = "debug-server")]
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
+extern crate gj;
+extern crate libc;
+
+use std::io::{Read, Write};
+
+fn set_fd_limit(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlimit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) }, 0);
+    let old = rlimit.rlim_cur;
+    rlimit.rlim_cur = limit;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) }, 0);
+    old
+}
+
+#[test]
+fn keeps_accepting_after_running_out_of_fds() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-debug-server-fds-test.sock");
+        let _ = ::std::fs::remove_file(&path);
+        let mut tasks = gj::TaskSet::new_with(|_error| {});
+        tasks.add(try!(gj::debug::serve(&path)));
+
+        let old_limit = set_fd_limit(256);
+        let mut files = Vec::new();
+        loop {
+            match ::std::fs::File::open("/dev/null") {
+                Ok(file) => files.push(file),
+                Err(ref e) if e.raw_os_error() == Some(libc::EMFILE) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        // The client takes the last free descriptor, so the server can't accept it yet.
+        files.pop();
+        let mut client = try!(::std::os::unix::net::UnixStream::connect(&path));
+        try!(gj::io::Timer.after_delay_ms(20).wait(wait_scope));
+
+        drop(files);
+        set_fd_limit(old_limit);
+        try!(client.write_all(b"help\nquit\n"));
+        try!(client.set_nonblocking(true));
+        let mut output = Vec::new();
+        for _ in 0..100 {
+            try!(gj::io::Timer.after_delay_ms(10).wait(wait_scope));
+            match client.read_to_end(&mut output) {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        assert!(String::from_utf8_lossy(&output).contains("stats"));
+        Ok(())
+    }).unwrap();
+}
@@ -19,7 +19,13 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
 extern crate gj;
+extern crate mio;
+extern crate net2;
+#[cfg(feature = "tls")] extern crate rustls;
 
 #[test]
 fn hello() {
@@ -75,3 +81,1520 @@ fn echo() {
         Ok(())
     }).unwrap();
 }
+
+#[test]
+fn set_interest() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10002").unwrap();
+        let receiver = addr.listen().unwrap();
+
+        let _write_promise = receiver.accept().then(move |(_, stream)| {
+            return Ok(stream.write(vec![9,8,7]));
+        });
+
+        let mut stream = addr.connect().wait(wait_scope).unwrap();
+        assert!(stream.interest().is_readable());
+
+        stream.set_interest(::mio::Ready::writable()).unwrap();
+        assert!(!stream.interest().is_readable());
+        assert!(stream.interest().is_writable());
+
+        stream.set_interest(::mio::Ready::readable() | ::mio::Ready::writable()).unwrap();
+        let (_, buf, _) = stream.read(vec![0u8; 3], 3).wait(wait_scope).unwrap();
+        assert_eq!(&buf[..], [9,8,7]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn deadline() {
+    use gj::io::AsyncRead;
+    gj::EventLoop::top_level(|wait_scope| {
+
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10003").unwrap();
+        let receiver = addr.listen().unwrap();
+
+        // Accept the connection but never write to it.
+        let _accept_promise = receiver.accept();
+
+        let read_promise = gj::with_deadline(::std::time::Duration::from_millis(50), || {
+            assert!(gj::current_deadline().is_some());
+            addr.connect().then(move |stream| {
+                return Ok(stream.read(vec![0u8; 3], 3));
+            })
+        });
+        assert!(gj::current_deadline().is_none());
+
+        let error = read_promise.wait(wait_scope).err().unwrap();
+        assert_eq!(error.downcast_ref::<::std::io::Error>().unwrap().kind(),
+                   ::std::io::ErrorKind::TimedOut);
+        Ok(())
+    }).unwrap();
+}
+
+struct IgnoreErrors;
+
+impl gj::ErrorHandler for IgnoreErrors {
+    fn task_failed(&mut self, _error: gj::Error) {}
+}
+
+#[test]
+fn router() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let first = gj::io::NetworkAddress::new("127.0.0.1:10004").unwrap();
+        let second = gj::io::NetworkAddress::new("127.0.0.1:10005").unwrap();
+
+        let mut router = gj::io::router::Router::new(Box::new(IgnoreErrors));
+        router.route(first.clone(), |stream| stream.write(vec![4]).map(|_| Ok(()))).unwrap();
+        router.route(second.clone(), |stream| stream.write(vec![5]).map(|_| Ok(()))).unwrap();
+
+        let (_, buf, _) = first.connect().then(|stream| Ok(stream.read(vec![0u8; 1], 1)))
+            .wait(wait_scope).unwrap();
+        assert_eq!(buf[0], 4);
+        let (_, buf, _) = second.connect().then(|stream| Ok(stream.read(vec![0u8; 1], 1)))
+            .wait(wait_scope).unwrap();
+        assert_eq!(buf[0], 5);
+
+        router.shutdown().wait(wait_scope).unwrap();
+        assert_eq!(router.connection_count(), 0);
+        assert!(router.route(first, |_| gj::Promise::fulfilled(())).is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "debug-server")]
+#[test]
+fn debug_server() {
+    use gj::io::AsyncRead;
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-debug-server-test.sock");
+        let _ = ::std::fs::remove_file(&path);
+
+        let mut tasks = gj::TaskSet::new(Box::new(IgnoreErrors));
+        tasks.add(gj::debug::serve(&path).unwrap());
+
+        let (_, stream) = gj::io::spawn(move |stream, wait_scope| {
+            use std::io::{Read, Write};
+            use gj::io::AsyncWrite;
+            let mut client = ::std::os::unix::net::UnixStream::connect(&path).unwrap();
+            client.write_all(b"stats\ntasks\ntraces\nlog\nquit\n").unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            assert!(output.contains("queued events: "));
+            assert!(output.contains("task sets: 2\n"));
+            assert!(output.contains("task set 0: outstanding=1 "));
+            assert!(output.contains("nodes: live=") || output.contains("node-counters feature"));
+            assert!(output.contains("log level: ") || output.contains("log feature"));
+
+            // The server hangs up rather than buffer an endless line.
+            let mut client = ::std::os::unix::net::UnixStream::connect(&path).unwrap();
+            client.write_all(&vec![b'x'; 4097]).unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            assert_eq!(output, "line too long\n");
+            try!(stream.write(vec![1]).wait(wait_scope));
+            Ok(())
+        }).unwrap();
+
+        // The other thread sends one byte once it has checked the server's replies.
+        let (_, buf, _) = stream.read(vec![0u8; 1], 1).wait(wait_scope).unwrap();
+        assert_eq!(buf[0], 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn post_mortem() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-post-mortem-test.txt");
+        let _ = ::std::fs::remove_file(&path);
+        gj::io::post_mortem::set_post_mortem(gj::io::post_mortem::PostMortem::File(path.clone()));
+
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10006").unwrap();
+        let receiver = addr.listen().unwrap();
+        let mut tasks = gj::TaskSet::new(Box::new(IgnoreErrors));
+        tasks.add(receiver.accept().map(|_| Ok(())));
+        gj::io::Timer.after_delay_ms(1).wait(wait_scope).unwrap();
+
+        let snapshot = gj::io::post_mortem::snapshot();
+        assert_eq!(snapshot.observers.len(), 1);
+        assert_eq!(snapshot.observers[0].kind, "ConnectionReceiver");
+        assert!(snapshot.observers[0].waiting_to_read);
+
+        gj::io::post_mortem::dump("testing").unwrap();
+        let mut contents = String::new();
+        {
+            use std::io::Read;
+            ::std::fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        }
+        assert!(contents.starts_with("gj post-mortem: testing\n1 fd observers"));
+        assert!(contents.contains("ConnectionReceiver interest=readable waiting=read\n"));
+        let _ = ::std::fs::remove_file(&path);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn parse_addr() {
+    use gj::net::{parse_addr, Endpoint};
+    match parse_addr("tcp://127.0.0.1:10007").unwrap() {
+        Endpoint::Tcp(_) => {}
+        _ => panic!("expected a tcp endpoint"),
+    }
+    match parse_addr("127.0.0.1:10007").unwrap() {
+        Endpoint::Tcp(_) => {}
+        _ => panic!("expected a tcp endpoint"),
+    }
+    match parse_addr("unix:/tmp/gj.sock").unwrap() {
+        Endpoint::Unix(path) => assert_eq!(path, ::std::path::PathBuf::from("/tmp/gj.sock")),
+        _ => panic!("expected a unix endpoint"),
+    }
+    match parse_addr("tls://[::1]:443").unwrap() {
+        Endpoint::Tls { host, .. } => assert_eq!(host, "::1"),
+        _ => panic!("expected a tls endpoint"),
+    }
+    match parse_addr("tcp://example.com:80").unwrap() {
+        // The name is left for `connect()` to look up.
+        Endpoint::Tcp(address) => {
+            assert!(address.socket_addrs().is_empty());
+            assert_eq!(address.to_string(), "example.com:80");
+        }
+        _ => panic!("expected a tcp endpoint"),
+    }
+    assert!(parse_addr("udp://127.0.0.1:53").is_err());
+    assert!(parse_addr("unix:").is_err());
+    assert!(parse_addr("example.com:http").is_err());
+}
+
+#[test]
+fn unix_connector() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    use gj::net::{parse_addr, Connector};
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-unix-connector-test.sock");
+        let _ = ::std::fs::remove_file(&path);
+        let endpoint = try!(parse_addr(&format!("unix:{}", path.display())));
+        assert!(endpoint.tcp_connector().is_none());
+        let connector = endpoint.unix_connector().unwrap();
+
+        let receiver = try!(connector.clone().listen());
+        let accept_promise = receiver.accept().then(|(_, stream)| Ok(stream.write(vec![9])));
+        let stream = try!(Connector::connect(&connector).wait(wait_scope));
+        let (_, buf, _) = try!(stream.read(vec![0u8; 1], 1).wait(wait_scope));
+        assert_eq!(buf[0], 9);
+        let _ = try!(accept_promise.wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn connect_timings() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let addrs = gj::io::NetworkAddress::resolve_all("localhost:10008").unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs[0].resolution_time().is_some());
+
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10008").unwrap();
+        let receiver = addr.listen().unwrap();
+        let accept_promise = receiver.accept();
+        let client = addr.connect().wait(wait_scope).unwrap();
+        let (_, server) = accept_promise.wait(wait_scope).unwrap();
+
+        let timings = client.connect_timings().unwrap();
+        assert!(timings.resolution.is_some());
+        assert!(server.connect_timings().is_none());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn remote_call() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (join_handle, stream) = gj::io::spawn(|stream, wait_scope| {
+            let server = try!(gj::io::rpc::serve(stream, |mut request: Vec<u8>| {
+                if request.is_empty() {
+                    return gj::Promise::rejected(Box::new(
+                        ::std::io::Error::new(::std::io::ErrorKind::Other, "empty request")));
+                }
+                request.reverse();
+                gj::Promise::fulfilled(request)
+            }));
+            server.wait(wait_scope)
+        }).unwrap();
+
+        {
+            let channel = gj::io::rpc::RpcChannel::new(stream).unwrap();
+            let first = gj::io::rpc::remote_call(&channel, &vec![1u8, 2, 3]);
+            let second = channel.call(&vec![4u8, 5]);
+            let failed = channel.call::<Vec<u8>, Vec<u8>>(&Vec::new());
+            let first: Vec<u8> = first.wait(wait_scope).unwrap();
+            let second: Vec<u8> = second.wait(wait_scope).unwrap();
+            assert_eq!(first, vec![3, 2, 1]);
+            assert_eq!(second, vec![5, 4]);
+            let error = failed.wait(wait_scope).err().unwrap();
+            assert_eq!(format!("{}", error), "empty request");
+        }
+        join_handle.join().unwrap();
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn wire() {
+    use gj::io::wire::Wire;
+    let mut bytes = Vec::new();
+    0x0102030405060708u64.encode(&mut bytes);
+    assert_eq!(bytes, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(u64::decode(&bytes).unwrap(), 0x0102030405060708);
+    assert!(u64::decode(&bytes[1..]).is_err());
+
+    let mut bytes = Vec::new();
+    "hello".to_string().encode(&mut bytes);
+    assert_eq!(String::decode(&bytes).unwrap(), "hello");
+    assert!(String::decode(&[0xff]).is_err());
+}
+
+#[test]
+fn write_yields() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        gj::io::set_max_write_bytes_per_turn(10);
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10009").unwrap();
+        let receiver = addr.listen().unwrap();
+        let accept_promise = receiver.accept();
+        let client = addr.connect().wait(wait_scope).unwrap();
+        let (_, server) = accept_promise.wait(wait_scope).unwrap();
+
+        let other_ran = Rc::new(Cell::new(false));
+        let other_ran1 = other_ran.clone();
+        let other_ran2 = other_ran.clone();
+        let data: Vec<u8> = (0..100).collect();
+        let mut tasks = gj::TaskSet::new_with(|error: gj::Error| panic!("{}", error));
+        tasks.add(client.write(data.clone()).map(move |_| {
+            assert!(other_ran1.get(), "the write should have let the other task run");
+            Ok(())
+        }));
+        tasks.add(gj::Promise::fulfilled(()).map(move |()| {
+            other_ran2.set(true);
+            Ok(())
+        }));
+        let (_, buf, _) = server.read(vec![0u8; 100], 100).wait(wait_scope).unwrap();
+        assert_eq!(buf, data);
+        tasks.on_empty().wait(wait_scope).unwrap();
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn read_cap() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = gj::io::NetworkAddress::new("127.0.0.1:10010").unwrap();
+        let receiver = addr.listen().unwrap();
+        let accept_promise = receiver.accept();
+        let client = addr.connect().wait(wait_scope).unwrap();
+        let (_, mut server) = accept_promise.wait(wait_scope).unwrap();
+        server.set_max_read_bytes_per_turn(10);
+
+        let data: Vec<u8> = (0..100).collect();
+        client.write(data.clone()).wait(wait_scope).unwrap();
+        let (_, buf, _) = server.read(vec![0u8; 100], 100).wait(wait_scope).unwrap();
+        assert_eq!(buf, data);
+        assert!(gj::io::stats().read_cap_hits >= 9);
+        assert_eq!(gj::io::stats().write_cap_hits, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn loop_waker() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    gj::EventLoop::top_level(|wait_scope| {
+        let flag = Arc::new(AtomicBool::new(false));
+        let waker = gj::io::LoopWaker::current();
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let mut fulfiller = Some(fulfiller);
+        let flag1 = flag.clone();
+        gj::EventLoop::on_idle(move || {
+            if flag1.load(Ordering::SeqCst) {
+                fulfiller.take().unwrap().fulfill(());
+                return false;
+            }
+            true
+        });
+
+        let thread = ::std::thread::spawn(move || {
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+            flag.store(true, Ordering::SeqCst);
+            waker.clone().wake().unwrap();
+        });
+        try!(promise.wait(wait_scope));
+        thread.join().unwrap();
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn on_wake() {
+    use std::sync::mpsc::channel;
+    gj::EventLoop::top_level(|wait_scope| {
+        let waker = gj::io::LoopWaker::current();
+        let (sender, receiver) = channel::<u32>();
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let mut fulfiller = Some(fulfiller);
+        gj::EventLoop::on_wake(move || {
+            match receiver.try_recv() {
+                Ok(n) => {
+                    fulfiller.take().unwrap().fulfill(n);
+                    false
+                }
+                Err(_) => true,
+            }
+        });
+
+        let thread = ::std::thread::spawn(move || {
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+            sender.send(7).unwrap();
+            waker.wake().unwrap();
+        });
+        assert_eq!(try!(promise.wait(wait_scope)), 7);
+        thread.join().unwrap();
+
+        // The callback is gone, so nothing is left that could fulfill this.
+        let (never, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        assert!(never.wait(wait_scope).is_err());
+        drop(fulfiller);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn duration_timers() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let start = ::std::time::Instant::now();
+        try!(gj::io::Timer.after_delay(::std::time::Duration::new(0, 1000)).wait(wait_scope));
+        assert!(start.elapsed() >= ::std::time::Duration::new(0, 1000));
+
+        let never = gj::io::Timer.after_delay(::std::time::Duration::from_secs(10));
+        let result = gj::io::Timer.timeout_after(::std::time::Duration::from_millis(1), never)
+            .wait(wait_scope);
+        assert!(result.is_err());
+
+        let value = try!(gj::io::Timer.timeout_after(::std::time::Duration::from_secs(10),
+                                                     gj::Promise::fulfilled(5)).wait(wait_scope));
+        assert_eq!(value, 5);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn timer_at() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let start = gj::io::Timer.now();
+        for n in 1..4 {
+            let time = start + ::std::time::Duration::from_millis(5 * n);
+            try!(gj::io::Timer.at(time).wait(wait_scope));
+            assert!(gj::io::Timer.now() >= time);
+        }
+
+        // A time in the past resolves right away.
+        try!(gj::io::Timer.at(start).wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn timer_every() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let start = gj::io::Timer.now();
+        let mut ticks = gj::io::Timer.every_ms(5);
+        let mut previous = start;
+        for _ in 0..3 {
+            let tick = try!(ticks.next().wait(wait_scope)).unwrap();
+            assert!(tick > previous);
+            assert!(gj::io::Timer.now() >= tick);
+            previous = tick;
+        }
+
+        // A slow consumer skips ticks instead of getting them all at once.
+        try!(gj::io::Timer.after_delay_ms(30).wait(wait_scope));
+        let tick = try!(ticks.next().wait(wait_scope)).unwrap();
+        let after = try!(ticks.next().wait(wait_scope)).unwrap();
+        assert!(after - tick > ::std::time::Duration::from_millis(5));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn timer_handle() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (promise, handle) = gj::io::Timer.after_delay_with_handle(::std::time::Duration::from_secs(10));
+        assert!(handle.remaining().unwrap() > ::std::time::Duration::from_secs(9));
+        assert!(handle.reset(::std::time::Duration::from_millis(5)));
+        assert!(handle.remaining().unwrap() <= ::std::time::Duration::from_millis(5));
+        try!(promise.wait(wait_scope));
+        assert_eq!(handle.remaining(), None);
+        assert!(!handle.reset(::std::time::Duration::from_millis(5)));
+        assert!(!handle.cancel());
+
+        let (promise, handle) = gj::io::Timer.after_delay_with_handle(::std::time::Duration::from_secs(10));
+        assert!(handle.cancel());
+        assert!(promise.wait(wait_scope).is_err());
+        assert_eq!(handle.remaining(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn many_timers() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let fired = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut kept = Vec::new();
+        let mut cancelled = Vec::new();
+        for n in 0..10000u64 {
+            let fired = fired.clone();
+            // Spread the delays across several levels of the timer wheel.
+            let delay = ::std::time::Duration::from_millis(1 + n * 13 % 250);
+            let timer = gj::io::Timer.after_delay(delay).map(move |()| {
+                fired.borrow_mut().push(n);
+                Ok(())
+            });
+            if n % 1001 == 0 { kept.push(timer) } else { cancelled.push(timer) }
+        }
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 10000);
+        drop(cancelled);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 10);
+
+        try!(gj::join_promises(kept).wait(wait_scope));
+        let expected: Vec<u64> = (0..10).map(|n| n * 1001).collect();
+        assert_eq!(*fired.borrow(), expected);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_clock() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let clock = gj::test::TestClock::install();
+        let start = gj::io::Timer.now();
+        let short = gj::io::Timer.after_delay_ms(500);
+        let long = gj::io::Timer.after_delay(::std::time::Duration::from_secs(3600));
+
+        clock.advance_ms(499);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 2);
+        clock.advance_ms(1);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 1);
+        try!(short.wait(wait_scope));
+        assert_eq!(gj::io::Timer.now() - start, ::std::time::Duration::from_millis(500));
+
+        clock.advance(::std::time::Duration::from_secs(3600));
+        try!(long.wait(wait_scope));
+
+        // Only the test can move the clock on, so waiting on a timer would never finish.
+        let error = gj::io::Timer.after_delay_ms(1).wait(wait_scope).unwrap_err();
+        assert!(format!("{}", error).starts_with("deadlock"));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn timer_now() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let timer = wait_scope.timer();
+        let before = timer.now();
+
+        // The loop's time only moves when it checks for I/O.
+        ::std::thread::sleep(::std::time::Duration::from_millis(2));
+        assert_eq!(gj::io::Timer.now(), before);
+
+        try!(timer.after_delay_ms(5).wait(wait_scope));
+        assert!(timer.now() - before >= ::std::time::Duration::from_millis(5));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn timeout_after_keep() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let promise = match try!(gj::io::Timer.timeout_after_keep_ms(1, promise).wait(wait_scope)) {
+            gj::io::TimeoutResult::TimedOut(promise) => promise,
+            gj::io::TimeoutResult::Completed(_) => panic!("should have timed out"),
+        };
+        fulfiller.fulfill(7);
+        assert_eq!(try!(promise.wait(wait_scope)), 7);
+
+        let promise = gj::io::Timer.timeout_after_keep_ms(10000, gj::Promise::fulfilled(8));
+        match try!(promise.wait(wait_scope)) {
+            gj::io::TimeoutResult::Completed(value) => assert_eq!(value, 8),
+            gj::io::TimeoutResult::TimedOut(_) => panic!("should have completed"),
+        }
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn precise_timers() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let resolution = gj::io::Timer.precise_resolution();
+        assert!(resolution <= ::std::time::Duration::from_millis(1));
+
+        let delay = ::std::time::Duration::new(0, 200_000);
+        let start = ::std::time::Instant::now();
+        try!(gj::io::Timer.after_delay_precise(delay).wait(wait_scope));
+        assert!(start.elapsed() >= delay);
+
+        // Precise timers fire in deadline order, and can be cancelled.
+        let log = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut timers = Vec::new();
+        for &(n, micros) in [(1, 3000), (2, 1000), (3, 2000)].iter() {
+            let log = log.clone();
+            // Unlike a `map()` callback, which would wait for the join to collect the results, a
+            // `then()` callback runs as soon as its timer fires.
+            timers.push(gj::io::Timer.after_delay_precise(::std::time::Duration::new(0, micros * 1000))
+                        .then(move |()| { log.borrow_mut().push(n); Ok(gj::Promise::fulfilled(())) }));
+        }
+        let cancelled = gj::io::Timer.after_delay_precise(::std::time::Duration::new(0, 50_000));
+        drop(cancelled);
+        try!(gj::join_promises(timers).wait(wait_scope));
+        assert_eq!(*log.borrow(), vec![2, 3, 1]);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn udp() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let first = try!(gj::io::UdpSocket::bind("127.0.0.1:0"));
+        let second = try!(gj::io::UdpSocket::bind("127.0.0.1:0"));
+        let first_addr = try!(first.local_addr());
+        let second_addr = try!(second.local_addr());
+
+        // Receive before anything has been sent, so that the read has to wait for the reactor.
+        let received = second.recv_from(vec![0u8; 16]);
+        let (first, _, n) = try!(first.send_to(vec![1u8, 2, 3], second_addr).wait(wait_scope));
+        assert_eq!(n, 3);
+        let (second, buf, n, from) = try!(received.wait(wait_scope));
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+        assert_eq!(from, first_addr);
+
+        try!(first.connect(second_addr));
+        try!(second.connect(first_addr));
+        let (_second, _, _) = try!(second.send(vec![4u8, 5]).wait(wait_scope));
+        let (_first, buf, n) = try!(first.recv(vec![0u8; 16]).wait(wait_scope));
+        assert_eq!(&buf[..n], &[4, 5]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn unix_hello() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-unix-hello-test.sock");
+        let _ = ::std::fs::remove_file(&path);
+        let addr = gj::io::UnixAddress::new(&path);
+
+        let receiver = try!(addr.clone().listen());
+
+        let _write_promise = receiver.accept().then(move |(_, stream)| {
+            return Ok(stream.write(vec![0,1,2,3,4,5]));
+        });
+
+        let read_promise = addr.connect().then(move |stream| {
+            return Ok(stream.read(vec![0u8; 6], 6));
+        });
+
+        let (_, buf, _) = try!(read_promise.wait(wait_scope));
+        assert_eq!(&buf[..], [0,1,2,3,4,5]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn ipv6() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = try!(gj::io::NetworkAddress::new("[::1]:10011"));
+        assert!(addr.socket_addr().is_ipv6());
+
+        let receiver = try!(addr.listen());
+        let _write_promise = receiver.accept().then(move |(_, stream)| {
+            return Ok(stream.write(vec![6u8, 1]));
+        });
+        let (_, buf, _) = try!(addr.connect().then(move |stream| {
+            return Ok(stream.read(vec![0u8; 2], 2));
+        }).wait(wait_scope));
+        assert_eq!(&buf[..], [6, 1]);
+
+        // A dual-stack listener also accepts IPv4 connections.
+        let any = try!(gj::io::NetworkAddress::new("[::]:10012")).with_v6_only(false);
+        let receiver = try!(any.listen());
+        let accept_promise = receiver.accept();
+        let _client = try!(try!(gj::io::NetworkAddress::new("127.0.0.1:10012")).connect()
+                           .wait(wait_scope));
+        let _ = try!(accept_promise.wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn async_resolve() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let addrs = try!(gj::io::NetworkAddress::resolve("127.0.0.1:10013").wait(wait_scope));
+        assert_eq!(addrs.len(), 1);
+        assert!(addrs[0].resolution_time().is_some());
+
+        let receiver = try!(addrs[0].listen());
+        let accept_promise = receiver.accept();
+        let _client = try!(gj::io::NetworkAddress::connect_host("127.0.0.1:10013")
+                           .wait(wait_scope));
+        let (receiver, _) = try!(accept_promise.wait(wait_scope));
+
+        // An address made from a name looks it up each time it connects.
+        let address = gj::io::NetworkAddress::from_host("127.0.0.1:10013");
+        assert!(address.listen().is_err());
+        let accept_promise = receiver.accept();
+        let client = try!(address.connect().wait(wait_scope));
+        assert!(client.connect_timings().unwrap().resolution.is_some());
+        let _ = try!(accept_promise.wait(wait_scope));
+
+        assert!(gj::io::NetworkAddress::resolve("no-such-host.invalid:80").wait(wait_scope).is_err());
+
+        // There are more lookups than worker threads, so some of them wait their turn.
+        let lookups: Vec<_> = (0..10).map(|_| gj::io::NetworkAddress::resolve("127.0.0.1:10013"))
+            .collect();
+        for lookup in lookups {
+            assert_eq!(try!(lookup.wait(wait_scope)).len(), 1);
+        }
+        Ok(())
+    }).unwrap();
+}
+
+/// Returns whether `fd` is close-on-exec, going by the flags that Linux reports for it.
+#[cfg(target_os = "linux")]
+fn is_cloexec(fd: ::std::os::unix::io::RawFd) -> bool {
+    let mut info = String::new();
+    ::std::io::Read::read_to_string(
+        &mut ::std::fs::File::open(format!("/proc/self/fdinfo/{}", fd)).unwrap(), &mut info).unwrap();
+    let flags = info.lines().find(|line| line.starts_with("flags:")).unwrap();
+    let flags = u32::from_str_radix(flags["flags:".len()..].trim(), 8).unwrap();
+    flags & 0o2000000 != 0
+}
+
+#[test]
+fn pipe() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            assert!(is_cloexec(reader.as_raw_fd()) && is_cloexec(writer.as_raw_fd()));
+        }
+        let read_promise = reader.read(vec![0u8; 3], 3);
+        let (writer, _) = try!(writer.write(vec![7u8, 8, 9]).wait(wait_scope));
+        let (reader, buf, _) = try!(read_promise.wait(wait_scope));
+        assert_eq!(&buf[..], [7, 8, 9]);
+
+        drop(writer);
+        let (_, _, n) = try!(reader.try_read(vec![0u8; 3], 1).wait(wait_scope));
+        assert_eq!(n, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn vectored() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10014"));
+        let receiver = try!(addr.listen());
+        let accept_promise = receiver.accept();
+        let client = try!(addr.connect().wait(wait_scope));
+        let (_, server) = try!(accept_promise.wait(wait_scope));
+
+        let write_promise = client.write_vectored(vec![vec![1u8, 2], vec![], vec![3, 4, 5]]);
+        let (_client, bufs) = try!(write_promise.wait(wait_scope));
+        assert_eq!(bufs.len(), 3);
+
+        let read_promise = server.try_read_vectored(vec![vec![0u8; 3], vec![0u8; 4]], 5);
+        let (_server, bufs, n) = try!(read_promise.wait(wait_scope));
+        assert_eq!(n, 5);
+        assert_eq!(&bufs[0][..], [1, 2, 3]);
+        assert_eq!(&bufs[1][..2], [4, 5]);
+
+        let (reader, writer) = try!(gj::io::pipe());
+        let (_writer, _) = try!(writer.write(vec![6u8, 7, 8]).wait(wait_scope));
+        let read_promise = reader.try_read_vectored(vec![vec![0u8; 1], vec![0u8; 4]], 3);
+        let (_, bufs, n) = try!(read_promise.wait(wait_scope));
+        assert_eq!(n, 3);
+        assert_eq!(&bufs[1][..2], [7, 8]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn transfer() {
+    use std::io::Write;
+    use gj::io::AsyncRead;
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-transfer-test.txt");
+        {
+            let mut file = try!(::std::fs::File::create(&path));
+            try!(file.write_all(b"hello, transfer"));
+        }
+        let file = try!(::std::fs::File::open(&path));
+
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10015"));
+        let receiver = try!(addr.listen());
+        let accept_promise = receiver.accept();
+        let client = try!(addr.connect().wait(wait_scope));
+        let (_, server) = try!(accept_promise.wait(wait_scope));
+
+        // Asking for more than the file holds sends what there is.
+        let (_file, _client, sent) = try!(gj::io::transfer(file, client, 1000).wait(wait_scope));
+        assert_eq!(sent, 15);
+        let (_, buf, _) = try!(server.read(vec![0u8; 15], 15).wait(wait_scope));
+        assert_eq!(&buf[..], b"hello, transfer");
+
+        let _ = ::std::fs::remove_file(&path);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn socket_options() {
+    use std::time::Duration;
+    gj::EventLoop::top_level(|wait_scope| {
+        let options = gj::io::SocketOptions::new().nodelay(true).recv_buffer_size(64 * 1024);
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10016")).with_socket_options(options);
+        let receiver = try!(addr.listen());
+        let accept_promise = receiver.accept();
+        let client = try!(addr.connect().wait(wait_scope));
+        let (_, server) = try!(accept_promise.wait(wait_scope));
+
+        assert!(try!(client.nodelay()));
+        assert!(try!(server.nodelay()));
+        assert!(try!(server.recv_buffer_size()) >= 64 * 1024);
+
+        try!(client.set_nodelay(false));
+        assert!(!try!(client.nodelay()));
+
+        let keepalive = gj::io::Keepalive { idle: Duration::from_secs(60),
+                                            interval: Duration::from_secs(10),
+                                            retries: 3 };
+        try!(client.set_keepalive(Some(keepalive)));
+        assert_eq!(try!(client.keepalive()), Some(keepalive));
+        try!(client.set_keepalive(None));
+        assert_eq!(try!(client.keepalive()), None);
+
+        try!(client.set_linger(Some(Duration::from_secs(0))));
+        assert_eq!(try!(client.linger()), Some(Duration::from_secs(0)));
+
+        try!(client.set_send_buffer_size(32 * 1024));
+        assert!(try!(client.send_buffer_size()) >= 32 * 1024);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn shutdown() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10017"));
+        let receiver = try!(addr.listen());
+        let accept_promise = receiver.accept();
+        let client = try!(addr.connect().wait(wait_scope));
+        let (_, server) = try!(accept_promise.wait(wait_scope));
+
+        // The client sends its request and half-closes, and the server answers once it sees EOF.
+        let client = try!(client.write(vec![1u8, 2]).then(|(client, _)| {
+            return Ok(client.shutdown(::std::net::Shutdown::Write));
+        }).wait(wait_scope));
+        let (server, buf, n) = try!(server.try_read(vec![0u8; 8], 8).wait(wait_scope));
+        assert_eq!(&buf[..n], [1, 2]);
+        let (_server, _) = try!(server.write(vec![3u8]).wait(wait_scope));
+        let (_, buf, _) = try!(client.read(vec![0u8; 1], 1).wait(wait_scope));
+        assert_eq!(&buf[..], [3]);
+        Ok(())
+    }).unwrap();
+}
+
+/// Returns a listener that never answers new connections, along with a connection that keeps it
+/// that way and the listener's address. With a backlog of zero, the one connection fills its
+/// accept queue, and the SYNs of later ones are dropped, much as they would be by a host that
+/// has gone away.
+fn stalled_listener() -> (::std::net::TcpListener, ::std::net::TcpStream, gj::io::NetworkAddress) {
+    let listener = net2::TcpBuilder::new_v4().unwrap()
+        .bind("127.0.0.1:0").unwrap()
+        .listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let filler = ::std::net::TcpStream::connect(addr).unwrap();
+    return (listener, filler, gj::io::NetworkAddress::new(addr).unwrap());
+}
+
+#[test]
+fn connect_errors() {
+    gj::EventLoop::top_level(|wait_scope| {
+        // Nothing listens on this port, so the connection is refused.
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10018"));
+        match addr.connect().wait(wait_scope) {
+            Ok(_) => panic!("expected the connection to be refused"),
+            Err(e) => assert!(format!("{}", e).starts_with("failed to connect to 127.0.0.1:10018")),
+        }
+
+        // A listener whose accept queue is full never answers.
+        let (_listener, _filler, addr) = stalled_listener();
+        let result = addr.connect_with_timeout(::std::time::Duration::from_millis(50)).wait(wait_scope);
+        assert!(result.is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn buffered_reader() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let (writer, _) = try!(writer.write(b"hello, buffered world".to_vec()).wait(wait_scope));
+        drop(writer);
+
+        let reader = gj::io::BufferedReader::with_capacity(4, reader);
+        let mut reader = try!(reader.fill_buf().wait(wait_scope));
+        assert_eq!(reader.buffer(), b"hell");
+        reader.consume(2);
+        assert_eq!(reader.buffer(), b"ll");
+
+        // Partly from the buffer and partly through it.
+        let (reader, buf, n) = try!(reader.read(vec![0u8; 5], 5).wait(wait_scope));
+        assert_eq!(n, 5);
+        assert_eq!(&buf[..], b"llo, ");
+
+        // Bigger than the buffer, so it goes straight to the pipe.
+        let (reader, buf, n) = try!(reader.try_read(vec![0u8; 20], 20).wait(wait_scope));
+        assert_eq!(&buf[..n], b"buffered world");
+
+        let reader = try!(reader.fill_buf().wait(wait_scope));
+        assert!(reader.buffer().is_empty());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn buffered_writer() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let writer = gj::io::BufferedWriter::with_capacity(8, writer);
+
+        let (writer, _) = try!(writer.write(vec![1u8, 2, 3]).wait(wait_scope));
+        let (writer, _) = try!(writer.write(vec![4u8, 5]).wait(wait_scope));
+        assert_eq!(writer.buffer(), [1, 2, 3, 4, 5]);
+
+        // Doesn't fit, so the buffer goes out first.
+        let (writer, _) = try!(writer.write(vec![6u8, 7, 8, 9]).wait(wait_scope));
+        assert_eq!(writer.buffer(), [6, 7, 8, 9]);
+        let (reader, buf, _) = try!(reader.read(vec![0u8; 5], 5).wait(wait_scope));
+        assert_eq!(&buf[..], [1, 2, 3, 4, 5]);
+
+        let _writer = try!(writer.into_inner().wait(wait_scope));
+        let (_, buf, _) = try!(reader.read(vec![0u8; 4], 4).wait(wait_scope));
+        assert_eq!(&buf[..], [6, 7, 8, 9]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn read_to_end() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        // The pipe has room for all of it, and closing the writer marks the end.
+        let (writer, _) = try!(writer.write(data.clone()).wait(wait_scope));
+        drop(writer);
+
+        let (reader, head) = try!(reader.read_exact(vec![0u8; 10]).wait(wait_scope));
+        assert_eq!(&head[..], &data[..10]);
+        let (reader, rest) = try!(reader.read_to_end(vec![42]).wait(wait_scope));
+        assert_eq!(rest.len(), 9991);
+        assert_eq!(rest[0], 42);
+        assert_eq!(&rest[1..], &data[10..]);
+
+        assert!(reader.read_exact(vec![0u8; 1]).wait(wait_scope).is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn read_lines() {
+    use gj::io::AsyncWrite;
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let (writer, _) = try!(writer.write(b"HELO a\r\nMAIL b\nlast".to_vec()).wait(wait_scope));
+        drop(writer);
+
+        let reader = gj::io::BufferedReader::with_capacity(3, reader);
+        let (reader, buf) = try!(reader.read_until(b' ', Vec::new()).wait(wait_scope));
+        assert_eq!(&buf[..], b"HELO ");
+        let (reader, line) = try!(reader.read_line().wait(wait_scope));
+        assert_eq!(line, Some("a".to_string()));
+
+        let mut lines = reader.lines();
+        assert_eq!(try!(lines.next().wait(wait_scope)), Some("MAIL b".to_string()));
+        assert_eq!(try!(lines.next().wait(wait_scope)), Some("last".to_string()));
+        assert_eq!(try!(lines.next().wait(wait_scope)), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn peek() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10019"));
+        let receiver = try!(addr.listen());
+        let accept_promise = receiver.accept();
+        let client = try!(addr.connect().wait(wait_scope));
+        let (_, server) = try!(accept_promise.wait(wait_scope));
+
+        let peek_promise = server.peek(vec![0u8; 1]);
+        let (_client, _) = try!(client.write(vec![0x16u8, 3, 1]).wait(wait_scope));
+        let (server, buf, n) = try!(peek_promise.wait(wait_scope));
+        assert_eq!(n, 1);
+        assert_eq!(buf[0], 0x16);
+
+        let (_, buf, _) = try!(server.read(vec![0u8; 3], 3).wait(wait_scope));
+        assert_eq!(&buf[..], [0x16, 3, 1]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn reuse_port() {
+    gj::EventLoop::top_level(|_wait_scope| {
+        let options = gj::io::SocketOptions::new().reuse_port(true);
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10020")).with_socket_options(options);
+        let _first = try!(addr.listen());
+        let _second = try!(addr.listen());
+        Ok(())
+    }).unwrap();
+
+    let addr = gj::io::NetworkAddress::new("127.0.0.1:10021").unwrap();
+    let threads = gj::io::listen_sharded(addr, 3, |_receiver| gj::Promise::fulfilled(()));
+    assert_eq!(threads.len(), 3);
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), Ok(()));
+    }
+}
+
+#[test]
+fn connect_any() {
+    gj::EventLoop::top_level(|wait_scope| {
+        assert!(gj::io::NetworkAddress::connect_any(Vec::new()).wait(wait_scope).is_err());
+
+        // Nothing listens on the first address, so the refusal moves straight on to the second.
+        let refused = try!(gj::io::NetworkAddress::new("127.0.0.1:10022"));
+        let listening = try!(gj::io::NetworkAddress::new("127.0.0.1:10023"));
+        let receiver = try!(listening.listen());
+        let accept_promise = receiver.accept();
+        let start = ::std::time::Instant::now();
+        let addresses = vec![refused.clone(), listening.clone()];
+        let _client = try!(gj::io::NetworkAddress::connect_any(addresses).wait(wait_scope));
+        let (receiver, _) = try!(accept_promise.wait(wait_scope));
+        assert!(start.elapsed() < ::std::time::Duration::from_millis(gj::io::CONNECT_ANY_STAGGER_MS));
+
+        // An address that stands for both keeps them, and `connect()` falls back the same way.
+        let both = [refused.socket_addr(), listening.socket_addr()];
+        let address = try!(gj::io::NetworkAddress::new(&both[..]));
+        assert_eq!(address.socket_addrs(), &both[..]);
+        let accept_promise = receiver.accept();
+        let _client = try!(address.connect().wait(wait_scope));
+        let (receiver, _) = try!(accept_promise.wait(wait_scope));
+
+        // A stalled first attempt only holds up the second by the stagger.
+        let (_stalled_listener, _filler, stalled) = stalled_listener();
+        let accept_promise = receiver.accept();
+        let stagger = ::std::time::Duration::from_millis(20);
+        let _client = try!(gj::io::NetworkAddress::connect_any_staggered(vec![stalled, listening],
+                                                                         stagger)
+                           .wait(wait_scope));
+        let _ = try!(accept_promise.wait(wait_scope));
+
+        match gj::io::NetworkAddress::connect_any(vec![refused]).wait(wait_scope) {
+            Ok(_) => panic!("expected the connection to be refused"),
+            Err(e) => assert!(format!("{}", e).starts_with("failed to connect")),
+        }
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn stream_from_raw_fd() {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut theirs, ours) = try!(::std::os::unix::net::UnixStream::pair());
+        let stream = try!(unsafe { gj::io::stream_from_raw_fd(ours.into_raw_fd()) });
+
+        try!(theirs.write_all(b"ping"));
+        let (stream, buf, _) = try!(stream.read(vec![0u8; 4], 4).wait(wait_scope));
+        assert_eq!(&buf[..], b"ping");
+        let (_stream, _) = try!(stream.write(b"pong".to_vec()).wait(wait_scope));
+
+        let mut reply = [0u8; 4];
+        try!(::std::io::Read::read_exact(&mut theirs, &mut reply));
+        assert_eq!(&reply, b"pong");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn max_events_per_poll() {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use gj::io::AsyncRead;
+    gj::EventLoopBuilder::new().max_events_per_poll(1).top_level(|wait_scope| {
+        let done = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut theirs = Vec::new();
+        let mut reads = Vec::new();
+        for _ in 0..3 {
+            let (their_end, ours) = try!(::std::os::unix::net::UnixStream::pair());
+            let stream = try!(unsafe { gj::io::stream_from_raw_fd(ours.into_raw_fd()) });
+            let done = done.clone();
+            reads.push(stream.read(vec![0u8; 1], 1).then(move |_| {
+                done.set(done.get() + 1);
+                Ok(gj::Promise::fulfilled(()))
+            }));
+            theirs.push(their_end);
+        }
+
+        // Work through the readiness of the fresh sockets, so that the reads are waiting.
+        for _ in 0..6 {
+            gj::EventLoop::poll(wait_scope);
+        }
+        assert_eq!(done.get(), 0);
+
+        for their_end in theirs.iter_mut() {
+            try!(their_end.write_all(b"x"));
+        }
+        for n in 1..4 {
+            gj::EventLoop::poll(wait_scope);
+            assert_eq!(done.get(), n);
+        }
+        for read in reads {
+            try!(read.wait(wait_scope));
+        }
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn unix_datagram_fd_passing() {
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (supervisor, worker) = try!(gj::io::UnixDatagram::pair());
+        let (mut theirs, ours) = try!(::std::os::unix::net::UnixStream::pair());
+
+        let fds = vec![ours.as_raw_fd()];
+        let (_supervisor, _, n) = try!(supervisor.send_with_fds(b"conn".to_vec(), fds)
+                                       .wait(wait_scope));
+        assert_eq!(n, 4);
+        drop(ours);
+
+        let (_worker, buf, n, fds) = try!(worker.recv_with_fds(vec![0u8; 16]).wait(wait_scope));
+        assert_eq!(&buf[..n], b"conn");
+        assert_eq!(fds.len(), 1);
+        #[cfg(target_os = "linux")]
+        assert!(is_cloexec(fds[0]));
+
+        // The received descriptor is a working copy of the one that was sent.
+        let mut received = unsafe { ::std::os::unix::net::UnixStream::from_raw_fd(fds[0]) };
+        try!(received.write_all(b"hi"));
+        let mut reply = [0u8; 2];
+        try!(theirs.read_exact(&mut reply));
+        assert_eq!(&reply, b"hi");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn throttled() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let reader = gj::io::Throttled::new(reader).read_limit(1000);
+        let writer = gj::io::Throttled::new(writer).write_limit(1000);
+
+        // 300 bytes at 1000 bytes per second go as three chunks, 100ms apart.
+        let start = ::std::time::Instant::now();
+        let read_promise = reader.read(vec![0u8; 300], 300);
+        let (_writer, _) = try!(writer.write(vec![5u8; 300]).wait(wait_scope));
+        assert!(start.elapsed() >= ::std::time::Duration::from_millis(190));
+        let (_reader, buf, n) = try!(read_promise.wait(wait_scope));
+        assert_eq!(n, 300);
+        assert!(buf.iter().all(|&b| b == 5));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn write_queue() {
+    use gj::io::AsyncRead;
+    gj::EventLoop::top_level(|wait_scope| {
+        let (reader, writer) = try!(gj::io::pipe());
+        let mut queue = gj::io::WriteQueue::new(writer, 4);
+
+        let first = queue.push(b"abc".to_vec());
+        let _second = queue.push(b"def".to_vec());
+        assert_eq!(queue.queued_bytes(), 6);
+        try!(queue.ready().wait(wait_scope));
+        assert!(queue.queued_bytes() <= 4);
+        try!(first.wait(wait_scope));
+        try!(queue.flush().wait(wait_scope));
+        assert_eq!(queue.queued_bytes(), 0);
+
+        let (reader, buf, _) = try!(reader.read(vec![0u8; 6], 6).wait(wait_scope));
+        assert_eq!(&buf[..], b"abcdef");
+
+        // Once a write fails, so does everything after it.
+        drop(reader);
+        assert!(queue.push(b"lost".to_vec()).wait(wait_scope).is_err());
+        assert!(queue.push(b"later".to_vec()).wait(wait_scope).is_err());
+        assert!(queue.ready().wait(wait_scope).is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn pump() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (source, source_writer) = try!(gj::io::pipe());
+        let (sink_reader, sink) = try!(gj::io::pipe());
+        let (source_writer, _) = try!(source_writer.write(b"hello world".to_vec()).wait(wait_scope));
+        drop(source_writer);
+
+        // The limit stops the copy partway, and a second pump picks up where it left off.
+        let (source, sink, n) = try!(gj::io::pump_limited(source, sink, 5).wait(wait_scope));
+        assert_eq!(n, 5);
+        let (sink_reader, buf, _) = try!(sink_reader.read(vec![0u8; 5], 5).wait(wait_scope));
+        assert_eq!(&buf[..], b"hello");
+
+        let (_source, sink, n) = try!(gj::io::pump(source, sink).wait(wait_scope));
+        assert_eq!(n, 6);
+        drop(sink);
+        let (_, buf) = try!(sink_reader.read_to_end(Vec::new()).wait(wait_scope));
+        assert_eq!(&buf[..], b" world");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn serve() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let addr = try!(gj::io::NetworkAddress::new("127.0.0.1:10024"));
+        let receiver = try!(addr.listen());
+        let connections = gj::TaskSet::new_with(|error: gj::Error| panic!("{}", error));
+        let mut served = 0u8;
+        let mut tasks = gj::TaskSet::new(Box::new(IgnoreErrors));
+        tasks.add(receiver.serve(connections, move |stream| {
+            served += 1;
+            return stream.write(vec![served]).map(|_| Ok(()));
+        }));
+
+        for expected in 1..3 {
+            let stream = try!(addr.connect().wait(wait_scope));
+            let (_, buf, _) = try!(stream.read(vec![0u8; 1], 1).wait(wait_scope));
+            assert_eq!(buf[0], expected);
+        }
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn proxy_connector() {
+    use std::io::{Read, Write};
+    use gj::io::AsyncRead;
+
+    let socks = ::std::net::TcpListener::bind("127.0.0.1:10025").unwrap();
+    let http = ::std::net::TcpListener::bind("127.0.0.1:10026").unwrap();
+    let proxies = ::std::thread::spawn(move || {
+        let (mut client, _) = socks.accept().unwrap();
+        let mut greeting = [0u8; 4];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 2, 0, 2]);
+        client.write_all(&[5, 2]).unwrap();
+        let mut auth = [0u8; 9];
+        client.read_exact(&mut auth).unwrap();
+        assert_eq!(&auth, b"\x01\x03bob\x03pwd");
+        client.write_all(&[1, 0]).unwrap();
+        let mut request = [0u8; 18];
+        client.read_exact(&mut request).unwrap();
+        assert_eq!(&request[..], b"\x05\x01\x00\x03\x0bexample.com\x00\x50");
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+        client.write_all(b"socks").unwrap();
+
+        let (mut client, _) = http.accept().unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            client.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        assert!(request.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhttp").unwrap();
+    });
+
+    gj::EventLoop::top_level(|wait_scope| {
+        let socks = gj::io::ProxyConnector::new(try!(gj::io::NetworkAddress::new("127.0.0.1:10025")),
+                                                gj::io::ProxyKind::Socks5)
+            .credentials("bob", "pwd");
+        let stream = try!(socks.connect("example.com", 80).wait(wait_scope));
+        let (_, buf, _) = try!(stream.read(vec![0u8; 5], 5).wait(wait_scope));
+        assert_eq!(&buf[..], b"socks");
+
+        let http = gj::io::ProxyConnector::new(try!(gj::io::NetworkAddress::new("127.0.0.1:10026")),
+                                               gj::io::ProxyKind::HttpConnect);
+        let stream = try!(http.connect("example.com", 443).wait(wait_scope));
+        let (_, buf, _) = try!(stream.read(vec![0u8; 4], 4).wait(wait_scope));
+        assert_eq!(&buf[..], b"http");
+        Ok(())
+    }).unwrap();
+    proxies.join().unwrap();
+}
+
+#[test]
+fn command() {
+    use std::process::Stdio;
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let mut child = try!(gj::io::Command::new("sh")
+                             .args(&["-c", "read line; echo \"got $line\"; exit 3"])
+                             .stdin(Stdio::piped())
+                             .stdout(Stdio::piped())
+                             .spawn());
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        let (stdin, _) = try!(stdin.write(b"ping\n".to_vec()).wait(wait_scope));
+        drop(stdin);
+        let (_, output) = try!(stdout.read_to_end(Vec::new()).wait(wait_scope));
+        assert_eq!(&output[..], b"got ping\n");
+
+        let status = try!(child.wait().wait(wait_scope));
+        assert_eq!(status.code(), Some(3));
+
+        // Several children, some of which exit before they are handed to the reaper, share it.
+        let mut children = Vec::new();
+        for code in 0..5 {
+            children.push(try!(gj::io::Command::new("sh").args(&["-c", &format!("exit {}", code)])
+                               .spawn()));
+        }
+        for (code, child) in children.into_iter().enumerate() {
+            assert_eq!(try!(child.wait().wait(wait_scope)).code(), Some(code as i32));
+        }
+
+        // Once every child has been collected, nothing is left that could wake the loop.
+        let (never, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        assert!(never.wait(wait_scope).is_err());
+        drop(fulfiller);
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watcher() {
+    use gj::io::ChangeKind;
+    gj::EventLoop::top_level(|wait_scope| {
+        let dir = ::std::env::temp_dir().join("gj-watcher-test");
+        let _ = ::std::fs::remove_dir_all(&dir);
+        try!(::std::fs::create_dir(&dir));
+
+        let mut watcher = try!(gj::io::Watcher::new());
+        try!(watcher.watch(&dir));
+        let file = dir.join("config");
+        try!(::std::fs::File::create(&file));
+        let (watcher, change) = try!(watcher.next().wait(wait_scope));
+        assert_eq!(change.kind, ChangeKind::Created);
+        assert_eq!(change.path, file);
+
+        try!(::std::fs::remove_file(&file));
+        let (mut watcher, change) = try!(watcher.next().wait(wait_scope));
+        assert_eq!(change.kind, ChangeKind::Removed);
+        assert_eq!(change.path, file);
+
+        try!(watcher.unwatch(&dir));
+        try!(::std::fs::remove_dir(&dir));
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watcher_shared_watch() {
+    use gj::io::ChangeKind;
+    gj::EventLoop::top_level(|wait_scope| {
+        let dir = ::std::env::temp_dir().join("gj-watcher-shared-test");
+        let link = ::std::env::temp_dir().join("gj-watcher-shared-link");
+        let _ = ::std::fs::remove_dir_all(&dir);
+        let _ = ::std::fs::remove_file(&link);
+        try!(::std::fs::create_dir(&dir));
+        try!(::std::os::unix::fs::symlink(&dir, &link));
+
+        // Both paths name the same directory, so the system hands back a single watch.
+        let mut watcher = try!(gj::io::Watcher::new());
+        try!(watcher.watch(&dir));
+        try!(watcher.watch(&link));
+        try!(::std::fs::File::create(dir.join("a")));
+        let (watcher, first) = try!(watcher.next().wait(wait_scope));
+        let (mut watcher, second) = try!(watcher.next().wait(wait_scope));
+        assert_eq!(first.kind, ChangeKind::Created);
+        assert_eq!(second.kind, ChangeKind::Created);
+        assert_eq!(first.path, dir.join("a"));
+        assert_eq!(second.path, link.join("a"));
+
+        // Unwatching one path leaves the other one watched.
+        try!(watcher.unwatch(&link));
+        try!(::std::fs::File::create(dir.join("b")));
+        let (mut watcher, change) = try!(watcher.next().wait(wait_scope));
+        assert_eq!(change.path, dir.join("b"));
+        assert!(watcher.unwatch(&link).is_err());
+
+        try!(watcher.unwatch(&dir));
+        try!(::std::fs::remove_file(&link));
+        try!(::std::fs::remove_dir_all(&dir));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn fs() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let path = ::std::env::temp_dir().join("gj-fs-test.txt");
+        try!(gj::io::fs::write_file(&path, b"hello disk".to_vec()).wait(wait_scope));
+        assert_eq!(try!(gj::io::fs::metadata(&path).wait(wait_scope)).len(), 10);
+        assert_eq!(&try!(gj::io::fs::read_file(&path).wait(wait_scope))[..], b"hello disk");
+
+        let file = try!(gj::io::fs::open(&path).wait(wait_scope));
+        let (file, buf, n) = try!(gj::io::fs::read(file, vec![0u8; 5]).wait(wait_scope));
+        assert_eq!(&buf[..n], b"hello");
+        let (_, _, n) = try!(gj::io::fs::read(file, vec![0u8; 16]).wait(wait_scope));
+        assert_eq!(n, 5);
+
+        try!(::std::fs::remove_file(&path));
+        assert!(gj::io::fs::open(&path).wait(wait_scope).is_err());
+
+        // With no calls outstanding, the pool doesn't keep the loop from noticing a deadlock.
+        let (never, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let error = never.wait(wait_scope).err().expect("wait() should fail");
+        assert!(format!("{}", error).starts_with("deadlock"));
+        drop(fulfiller);
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "tls")]
+fn tls_socket_pair() -> gj::Result<(gj::io::SocketStream, gj::io::SocketStream)> {
+    use std::os::unix::io::IntoRawFd;
+    let (client, server) = try!(::std::os::unix::net::UnixStream::pair());
+    let client = try!(unsafe { gj::io::stream_from_raw_fd(client.into_raw_fd()) });
+    let server = try!(unsafe { gj::io::stream_from_raw_fd(server.into_raw_fd()) });
+    return Ok((client, server));
+}
+
+#[cfg(feature = "tls")]
+fn tls_configs() -> (gj::io::tls::TlsConnector, gj::io::tls::TlsAcceptor) {
+    use std::sync::Arc;
+    use rustls::internal::pemfile::{certs, rsa_private_keys};
+
+    // tests/tls holds a throwaway CA and a `localhost` certificate signed by it.
+    let ca = certs(&mut &include_bytes!("tls/ca.pem")[..]).unwrap();
+    let chain = certs(&mut &include_bytes!("tls/server.pem")[..]).unwrap();
+    let mut keys = rsa_private_keys(&mut &include_bytes!("tls/server.key")[..]).unwrap();
+
+    let mut client = rustls::ClientConfig::new();
+    client.root_store.add(&ca[0]).unwrap();
+    let mut server = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    server.set_single_cert(chain, keys.remove(0)).unwrap();
+
+    return (gj::io::tls::TlsConnector::new(Arc::new(client)),
+            gj::io::tls::TlsAcceptor::new(Arc::new(server)));
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_loopback() {
+    use gj::io::{AsyncRead, AsyncWrite};
+    gj::EventLoop::top_level(|wait_scope| {
+        let (connector, acceptor) = tls_configs();
+        let (client, server) = try!(tls_socket_pair());
+
+        let server = acceptor.accept(server);
+        let client = try!(connector.connect("localhost", client).wait(wait_scope));
+        let server = try!(server.wait(wait_scope));
+
+        let (client, _) = try!(client.write(b"hello server".to_vec()).wait(wait_scope));
+        let (server, buf, _) = try!(server.read(vec![0u8; 12], 12).wait(wait_scope));
+        assert_eq!(&buf[..], b"hello server");
+
+        let (server, _) = try!(server.write(b"hello client".to_vec()).wait(wait_scope));
+        let (client, buf, _) = try!(client.read(vec![0u8; 12], 12).wait(wait_scope));
+        assert_eq!(&buf[..], b"hello client");
+
+        // After close_notify and the socket going away, the client sees a clean EOF.
+        let raw = try!(server.close_notify().wait(wait_scope));
+        drop(raw);
+        let (_client, _, n) = try!(client.try_read(vec![0u8; 16], 1).wait(wait_scope));
+        assert_eq!(n, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_wrong_host() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (connector, acceptor) = tls_configs();
+        let (client, server) = try!(tls_socket_pair());
+
+        let server = acceptor.accept(server);
+        assert!(connector.connect("example.com", client).wait(wait_scope).is_err());
+        drop(server);
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_eof_during_handshake() {
+    use gj::io::AsyncRead;
+    gj::EventLoop::top_level(|wait_scope| {
+        let (connector, _) = tls_configs();
+        let (client, server) = try!(tls_socket_pair());
+
+        // The server reads the ClientHello and hangs up. A `then()` callback drops the stream
+        // right away, rather than when the result is collected. Leaving part of the ClientHello
+        // unread would make the hangup a reset instead.
+        let server = server.try_read(vec![0u8; 4096], 1).then(|_| Ok(gj::Promise::fulfilled(())));
+
+        let error = connector.connect("localhost", client).wait(wait_scope).err().unwrap();
+        assert_eq!(error.to_string(), "connection closed during TLS handshake");
+        try!(server.wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
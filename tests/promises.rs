@@ -19,6 +19,10 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
+#[macro_use]
 extern crate gj;
 
 #[test]
@@ -274,6 +278,168 @@ fn task_set() {
     }).unwrap();
 }
 
+#[test]
+fn task_set_stats() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::new(Box::new(ErrorHandlerImpl {error_count: error_count.clone()}));
+        tasks.add(gj::Promise::fulfilled(()));
+        tasks.add(gj::Promise::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "Fake IO Error"))));
+        let (never, _fulfiller) = gj::new_promise_and_fulfiller();
+        tasks.add(never);
+
+        gj::Promise::fulfilled(()).map(|()| { return Ok(()) } ).wait(wait_scope).unwrap();
+        let stats = tasks.stats();
+        assert_eq!((stats.added, stats.fulfilled, stats.rejected, stats.cancelled), (3, 1, 1, 0));
+        assert_eq!(stats.outstanding(), 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_set_on_empty() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::new(Box::new(ErrorHandlerImpl {error_count: error_count.clone()}));
+        tasks.on_empty().wait(wait_scope).unwrap();
+
+        let (promise1, fulfiller1) = gj::new_promise_and_fulfiller();
+        let (promise2, fulfiller2) = gj::new_promise_and_fulfiller();
+        tasks.add(promise1);
+        tasks.add(promise2);
+        let empty = tasks.on_empty();
+        fulfiller1.fulfill(());
+        gj::Promise::fulfilled(()).wait(wait_scope).unwrap();
+        assert_eq!(tasks.stats().outstanding(), 1);
+        fulfiller2.reject(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "failed")));
+        empty.wait(wait_scope).unwrap();
+        assert_eq!(error_count.get(), 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_set_with_limit() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::with_limit(
+            Box::new(ErrorHandlerImpl {error_count: error_count.clone()}), 2);
+        let fulfillers = Rc::new(RefCell::new(Vec::new()));
+        for _ in 0..5 {
+            let fulfillers = fulfillers.clone();
+            tasks.add_factory(move || {
+                let (promise, fulfiller) = gj::new_promise_and_fulfiller();
+                fulfillers.borrow_mut().push(fulfiller);
+                return promise;
+            });
+        }
+        assert_eq!(fulfillers.borrow().len(), 2);
+
+        for started in 3..6 {
+            let fulfiller = fulfillers.borrow_mut().remove(0);
+            fulfiller.fulfill(());
+            gj::Promise::fulfilled(()).wait(wait_scope).unwrap();
+            // The finished task's place has gone to the next one in the queue.
+            assert_eq!(fulfillers.borrow().len(), 2);
+            assert_eq!(tasks.stats().fulfilled as usize, started - 2);
+        }
+
+        let empty = tasks.on_empty();
+        for fulfiller in ::std::mem::replace(&mut *fulfillers.borrow_mut(), Vec::new()) {
+            fulfiller.fulfill(());
+        }
+        empty.wait(wait_scope).unwrap();
+        assert_eq!(tasks.stats().fulfilled, 5);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_handle() {
+    gj::test::run(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::with_limit(
+            Box::new(ErrorHandlerImpl {error_count: error_count.clone()}), 1);
+        let (promise1, _fulfiller1) = gj::new_promise_and_fulfiller();
+        let (promise2, fulfiller2) = gj::new_promise_and_fulfiller();
+        let (promise3, _fulfiller3) = gj::new_promise_and_fulfiller();
+        let running = tasks.add(promise1);
+        let next = tasks.add(promise2);
+        let queued = tasks.add(promise3);
+        assert!(running.is_pending() && !running.is_queued());
+        assert!(next.is_queued());
+
+        assert!(queued.cancel());
+        assert!(!queued.is_pending());
+        assert!(!queued.cancel());
+
+        // Cancelling the running task frees its slot for the next one.
+        assert!(running.cancel());
+        assert!(!next.is_queued() && next.is_pending());
+        fulfiller2.fulfill(());
+        tasks.on_empty().wait(wait_scope).unwrap();
+        assert!(!next.is_pending());
+
+        let stats = tasks.stats();
+        assert_eq!((stats.fulfilled, stats.cancelled), (1, 2));
+        Ok(())
+    });
+}
+
+#[test]
+fn task_drops_own_set() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    gj::EventLoop::top_level(|wait_scope| {
+        // Dropping the set from inside the task drops the node of the event that is firing.
+        let tasks = Rc::new(RefCell::new(Some(gj::TaskSet::new_with(|error| panic!("{}", error)))));
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let tasks1 = tasks.clone();
+        tasks.borrow_mut().as_mut().unwrap().add(promise.map(move |()| {
+            drop(tasks1.borrow_mut().take());
+            Ok(())
+        }));
+        fulfiller.fulfill(());
+        try!(gj::Promise::yield_now().wait(wait_scope));
+        assert!(tasks.borrow().is_none());
+
+        // The loop carries on as before.
+        assert_eq!(try!(gj::Promise::fulfilled(()).then(|()| Ok(gj::Promise::fulfilled(7)))
+                        .wait(wait_scope)), 7);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_set_introspection() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::with_limit(
+            Box::new(ErrorHandlerImpl {error_count: error_count.clone()}), 1);
+        assert!(tasks.is_empty());
+        let (promise1, fulfiller1) = gj::new_promise_and_fulfiller();
+        let (promise2, fulfiller2) = gj::new_promise_and_fulfiller();
+        let first = tasks.add(promise1);
+        let second = tasks.add(promise2);
+        assert_eq!(tasks.len(), 2);
+
+        let infos = tasks.tasks();
+        assert_eq!(infos.len(), 2);
+        assert_eq!((infos[0].id, infos[0].queued), (first.id(), false));
+        assert_eq!((infos[1].id, infos[1].queued), (second.id(), true));
+
+        fulfiller1.fulfill(());
+        fulfiller2.fulfill(());
+        tasks.on_empty().wait(wait_scope).unwrap();
+        assert!(tasks.is_empty());
+        assert!(tasks.tasks().is_empty());
+        Ok(())
+    }).unwrap();
+}
+
 #[test]
 fn array_join() {
     gj::EventLoop::top_level(|wait_scope| {
@@ -331,3 +497,840 @@ fn exclusive_join() {
         Ok(())
     }).unwrap();
 }
+
+fn parse_and_add(a: &'static str, b: &'static str) -> gj::Promise<i32> {
+    let i: i32 = pry!(a.parse());
+    let j: i32 = pry!(b.parse());
+    return gj::Promise::fulfilled(i + j);
+}
+
+#[test]
+fn pry() {
+    gj::EventLoop::top_level(|wait_scope| {
+        assert_eq!(parse_and_add("12", "30").wait(wait_scope).unwrap(), 42);
+        assert!(parse_and_add("12", "XXX").wait(wait_scope).is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn top_level_panic() {
+    let result = ::std::panic::catch_unwind(|| {
+        gj::EventLoop::top_level(|wait_scope| {
+            let (promise, _fulfiller) = gj::new_promise_and_fulfiller::<()>();
+            let _pending = promise.map(|()| { return Ok(()); });
+            gj::Promise::fulfilled(()).map(|()| -> gj::Result<()> {
+                panic!("callback panicked");
+            }).wait(wait_scope)
+        })
+    });
+    assert!(result.is_err());
+
+    // The panic should not have left the event loop installed on this thread.
+    gj::EventLoop::top_level(|wait_scope| {
+        gj::Promise::fulfilled(()).wait(wait_scope)
+    }).unwrap();
+}
+
+fn count_down(n: u32) -> gj::Promise<u32> {
+    if n == 0 {
+        return gj::Promise::fulfilled(0);
+    } else {
+        return gj::Promise::fulfilled(()).then(move |()| {
+            return Ok(count_down(n - 1));
+        });
+    }
+}
+
+#[test]
+fn recursive_chain() {
+    // Each continuation returns a fresh chain. These should get flattened rather than nested,
+    // so that the loop runs in constant stack.
+    gj::EventLoop::top_level(|wait_scope| {
+        let value = count_down(100000).wait(wait_scope).unwrap();
+        assert_eq!(value, 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_fixture() {
+    gj::test::run(|wait_scope| {
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        fulfiller.fulfill(5);
+        assert_eq!(promise.map(|x| { return Ok(x + 1); }).wait(wait_scope).unwrap(), 6);
+        Ok(())
+    });
+}
+
+#[test]
+#[should_panic]
+fn test_fixture_detects_leak() {
+    gj::test::run(|_wait_scope| {
+        let (_promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        ::std::mem::forget(fulfiller);
+        Ok(())
+    });
+}
+
+#[test]
+fn top_level_result() {
+    let value = gj::EventLoop::top_level(|wait_scope| {
+        gj::Promise::fulfilled(20).map(|x| Ok(x + 1)).wait(wait_scope)
+    }).unwrap();
+    assert_eq!(value, 21);
+
+    assert_eq!(gj::test::run(|_wait_scope| Ok("done")), "done");
+}
+
+#[test]
+fn prelude() {
+    use gj::prelude::*;
+    EventLoop::top_level(|wait_scope| {
+        let (promise, fulfiller) = new_promise_and_fulfiller::<u32>();
+        let (mut stream, mut sender) = new_stream::<u32>();
+        sender.send(1);
+        fulfiller.fulfill(2);
+        let promise: Promise<u32> = promise.map(|x| Ok(x * 10));
+        assert_eq!(try!(promise.wait(wait_scope)), 20);
+        assert_eq!(try!(stream.next().wait(wait_scope)), Some(1));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn loop_stats() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let stats = gj::EventLoop::loop_stats();
+        assert_eq!((stats.turns, stats.queued_events, stats.pending_timers), (0, 0, 0));
+
+        let cancelled = gj::io::Timer.after_delay_ms(10000);
+        let timer = gj::io::Timer.after_delay_ms(1);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 2);
+
+        drop(cancelled);
+        assert_eq!(gj::EventLoop::loop_stats().pending_timers, 1);
+        try!(timer.wait(wait_scope));
+        let stats = gj::EventLoop::loop_stats();
+        assert_eq!((stats.queued_events, stats.pending_timers), (0, 0));
+        assert!(stats.turns > 0);
+        assert!(stats.sleeping > ::std::time::Duration::new(0, 0));
+        Ok(())
+    }).unwrap();
+}
+
+struct RecordingObserver {
+    log: ::std::rc::Rc<::std::cell::RefCell<Vec<(bool, gj::EventId)>>>,
+}
+
+impl gj::LoopObserver for RecordingObserver {
+    fn before_fire(&mut self, event: gj::EventId) {
+        self.log.borrow_mut().push((true, event));
+    }
+
+    fn after_fire(&mut self, event: gj::EventId, _elapsed: ::std::time::Duration) {
+        self.log.borrow_mut().push((false, event));
+    }
+}
+
+#[test]
+fn loop_observer() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let log = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        gj::EventLoop::set_loop_observer(RecordingObserver { log: log.clone() });
+
+        try!(gj::Promise::fulfilled(()).then(|()| Ok(gj::Promise::fulfilled(()))).wait(wait_scope));
+        let events = log.borrow().clone();
+        assert!(!events.is_empty());
+        for pair in events.chunks(2) {
+            assert_eq!(pair.len(), 2);
+            assert!(pair[0].0 && !pair[1].0);
+            assert_eq!(pair[0].1, pair[1].1);
+        }
+
+        gj::EventLoop::clear_loop_observer();
+        log.borrow_mut().clear();
+        try!(gj::Promise::fulfilled(()).then(|()| Ok(gj::Promise::fulfilled(()))).wait(wait_scope));
+        assert!(log.borrow().is_empty());
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn on_idle() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let runs = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let mut fulfiller = Some(fulfiller);
+        let runs1 = runs.clone();
+        gj::EventLoop::on_idle(move || {
+            runs1.set(runs1.get() + 1);
+            if let Some(fulfiller) = fulfiller.take() {
+                fulfiller.fulfill(5);
+            }
+            runs1.get() < 2
+        });
+
+        // The queue empties right away, so the callback runs before the loop would sleep.
+        assert_eq!(try!(promise.wait(wait_scope)), 5);
+        assert_eq!(runs.get(), 1);
+
+        // The second run returns false, which unregisters the callback.
+        try!(gj::io::Timer.after_delay_ms(1).wait(wait_scope));
+        assert_eq!(runs.get(), 2);
+        try!(gj::io::Timer.after_delay_ms(1).wait(wait_scope));
+        assert_eq!(runs.get(), 2);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn priorities() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let order = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        for &(priority, name) in [(gj::Priority::Low, "low"), (gj::Priority::Normal, "normal"),
+                                  (gj::Priority::High, "high")].iter() {
+            let order = order.clone();
+            let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+            tasks.add(promise.map(move |()| { order.borrow_mut().push(name); Ok(()) }));
+            gj::EventLoop::with_priority(priority, move || fulfiller.fulfill(()));
+        }
+        try!(tasks.on_empty().wait(wait_scope));
+        assert_eq!(*order.borrow(), vec!["high", "normal", "low"]);
+
+        // A steady stream of high-priority work does not starve the low-priority queue.
+        fn spin(count: ::std::rc::Rc<::std::cell::Cell<u32>>,
+                low_ran: ::std::rc::Rc<::std::cell::Cell<bool>>) -> gj::Promise<()> {
+            if low_ran.get() {
+                return gj::Promise::fulfilled(());
+            }
+            count.set(count.get() + 1);
+            gj::Promise::fulfilled(()).then(move |()| Ok(spin(count, low_ran)))
+        }
+        let spins = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let low_ran = ::std::rc::Rc::new(::std::cell::Cell::new(false));
+        let low_ran1 = low_ran.clone();
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        tasks.add(promise.map(move |()| { low_ran1.set(true); Ok(()) }));
+        gj::EventLoop::with_priority(gj::Priority::Low, move || fulfiller.fulfill(()));
+        gj::EventLoop::with_priority(gj::Priority::High, || {
+            tasks.add(spin(spins.clone(), low_ran.clone()));
+        });
+        try!(tasks.on_empty().wait(wait_scope));
+        assert!(low_ran.get());
+        assert!(spins.get() < 100);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn loop_catch_panics() {
+    gj::EventLoop::top_level(|wait_scope| {
+        gj::EventLoop::set_catch_panics(true);
+        let promise = gj::Promise::fulfilled(()).map(|()| -> gj::Result<u32> { panic!("boom") });
+        let recovered = promise.map_else(|_| Ok(false), |e| {
+            assert_eq!(format!("{}", e), "task panicked: boom");
+            Ok(true)
+        });
+        assert!(try!(recovered.wait(wait_scope)));
+
+        // The loop is still usable afterwards.
+        assert_eq!(try!(gj::Promise::fulfilled(3).map(|x| Ok(x + 1)).wait(wait_scope)), 4);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn wait_scope_poll() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        fn step(count: ::std::rc::Rc<::std::cell::Cell<u32>>, left: u32) -> gj::Promise<()> {
+            count.set(count.get() + 1);
+            if left == 0 {
+                return gj::Promise::fulfilled(());
+            }
+            gj::Promise::fulfilled(()).then(move |()| Ok(step(count, left - 1)))
+        }
+        tasks.add(gj::Promise::fulfilled(()).then({
+            let count = count.clone();
+            move |()| Ok(step(count, 3))
+        }));
+
+        // Each poll runs only the events that were ready when it was called.
+        assert!(wait_scope.poll());
+        assert_eq!(count.get(), 1);
+        assert!(!wait_scope.poll_n(100));
+        assert_eq!(count.get(), 4);
+        assert!(!wait_scope.poll());
+        Ok(())
+    }).unwrap();
+}
+
+struct DropFlag(::std::rc::Rc<::std::cell::Cell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+#[test]
+fn daemonize() {
+    let dropped = ::std::rc::Rc::new(::std::cell::Cell::new(false));
+    let errors = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+    let dropped1 = dropped.clone();
+    let errors1 = errors.clone();
+    gj::EventLoop::top_level(move |wait_scope| {
+        let dropped2 = dropped1.clone();
+        let errors2 = errors1.clone();
+        gj::EventLoop::daemonize(gj::Promise::rejected(Box::new(gj::Interrupted)),
+                                 move |_error| errors2.set(errors2.get() + 1));
+
+        // Never finishes, so it is cancelled when the loop exits.
+        let (never, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let flag = DropFlag(dropped1);
+        gj::EventLoop::daemonize(never.map(move |()| { drop(flag); Ok(()) }), |_error| {});
+        ::std::mem::forget(fulfiller);
+
+        try!(gj::Promise::fulfilled(()).then(|()| Ok(gj::Promise::fulfilled(()))).wait(wait_scope));
+        assert_eq!(errors1.get(), 1);
+        assert!(!dropped2.get());
+        Ok(())
+    }).unwrap();
+    assert!(dropped.get());
+    assert_eq!(errors.get(), 1);
+}
+
+#[test]
+fn deadlock() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let error = promise.map(|()| Ok(())).wait(wait_scope).err().expect("wait() should fail");
+        assert!(format!("{}", error).starts_with("deadlock"));
+        drop(fulfiller);
+
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        drop(fulfiller);
+        assert!(promise.wait(wait_scope).is_err());
+
+        // A pending timer is enough to keep the wait going.
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<()>();
+        let timer = gj::io::Timer.after_delay_ms(1).map(move |()| {
+            fulfiller.fulfill(());
+            Ok(())
+        });
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        tasks.add(timer);
+        try!(promise.wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn yield_now() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let order = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        let order1 = order.clone();
+        tasks.add(gj::Promise::fulfilled(()).then(move |()| {
+            order1.borrow_mut().push(1);
+            Ok(gj::Promise::yield_now().map(move |()| { order1.borrow_mut().push(3); Ok(()) }))
+        }));
+        let order2 = order.clone();
+        tasks.add(gj::Promise::fulfilled(()).map(move |()| { order2.borrow_mut().push(2); Ok(()) }));
+
+        // The second task was already queued when the first one yielded.
+        try!(tasks.on_empty().wait(wait_scope));
+        assert_eq!(*order.borrow(), vec![1, 2, 3]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn turn_budget() {
+    fn spin(done: ::std::rc::Rc<::std::cell::Cell<bool>>) -> gj::Promise<()> {
+        if done.get() {
+            return gj::Promise::fulfilled(());
+        }
+        gj::Promise::yield_now().then(move |()| Ok(spin(done)))
+    }
+
+    gj::EventLoop::top_level(|wait_scope| {
+        // The spinning chain never lets the queue empty, so the timer only fires because the
+        // budget makes the loop check for it.
+        gj::EventLoop::set_poll_interval(None);
+        gj::EventLoop::set_turn_budget(Some(::std::time::Duration::from_millis(1)));
+        let done = ::std::rc::Rc::new(::std::cell::Cell::new(false));
+        let done1 = done.clone();
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        tasks.add(gj::io::Timer.after_delay_ms(5).map(move |()| { done1.set(true); Ok(()) }));
+        try!(spin(done).wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn poll_interval() {
+    fn spin(done: ::std::rc::Rc<::std::cell::Cell<bool>>) -> gj::Promise<()> {
+        if done.get() {
+            return gj::Promise::fulfilled(());
+        }
+        gj::Promise::yield_now().then(move |()| Ok(spin(done)))
+    }
+
+    gj::EventLoop::top_level(|wait_scope| {
+        // As in `turn_budget`, the timer fires even though the queue never empties.
+        gj::EventLoop::set_poll_interval(Some(10));
+        let done = ::std::rc::Rc::new(::std::cell::Cell::new(false));
+        let done1 = done.clone();
+        let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+        tasks.add(gj::io::Timer.after_delay_ms(5).map(move |()| { done1.set(true); Ok(()) }));
+        try!(spin(done).wait(wait_scope));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn event_loop_builder() {
+    let log = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let value = gj::EventLoopBuilder::new()
+        .max_turns_per_poll(1)
+        .loop_observer(RecordingObserver { log: log.clone() })
+        .top_level(|wait_scope| {
+            let mut tasks = gj::TaskSet::new_with(|error| panic!("{}", error));
+            tasks.add(gj::Promise::fulfilled(()));
+            tasks.add(gj::Promise::fulfilled(()));
+            assert_eq!(gj::EventLoop::poll(wait_scope), 1);
+            assert_eq!(gj::EventLoop::poll(wait_scope), 1);
+            assert_eq!(gj::EventLoop::poll(wait_scope), 0);
+            Ok(7)
+        }).unwrap();
+    assert_eq!(value, 7);
+    assert_eq!(log.borrow().len(), 4);
+}
+
+#[test]
+fn event_loop_builder_spin() {
+    let value = gj::EventLoopBuilder::new()
+        .poll_strategy(gj::PollStrategy::Spin)
+        .observer_capacity(64)
+        .top_level(|wait_scope| {
+            // Spinning still sees the timer expire.
+            let start = ::std::time::Instant::now();
+            try!(gj::io::Timer.after_delay(::std::time::Duration::from_millis(20)).wait(wait_scope));
+            assert!(start.elapsed() >= ::std::time::Duration::from_millis(20));
+            Ok(7)
+        }).unwrap();
+    assert_eq!(value, 7);
+}
+
+#[test]
+fn try_top_level() {
+    gj::EventLoop::top_level(|_wait_scope| {
+        let error = gj::EventLoop::try_top_level(|_| Ok(())).unwrap_err();
+        assert_eq!(format!("{}", error), "this thread already has an event loop");
+        Ok(())
+    }).unwrap();
+
+    assert!(!gj::EventLoop::exists());
+    assert_eq!(gj::EventLoop::try_top_level(|_| Ok(7)).unwrap(), 7);
+}
+
+#[test]
+fn scoped() {
+    fn blocking_double(n: u32) -> gj::Result<u32> {
+        gj::EventLoop::scoped(|wait_scope| {
+            gj::Promise::yield_now().map(move |()| Ok(n * 2)).wait(wait_scope)
+        })
+    }
+
+    // Without a loop, one is created and torn down.
+    assert_eq!(blocking_double(2).unwrap(), 4);
+    assert!(!gj::EventLoop::exists());
+
+    gj::EventLoop::top_level(|wait_scope| {
+        // From synchronous code, the existing loop is used.
+        assert_eq!(try!(blocking_double(3)), 6);
+        assert!(gj::EventLoop::exists());
+
+        // From a callback, waiting would block the loop.
+        let error = gj::Promise::fulfilled(()).map(|()| {
+            Ok(blocking_double(4).unwrap_err())
+        }).wait(wait_scope).unwrap();
+        assert_eq!(format!("{}", error), "cannot wait on the event loop from inside one of its callbacks");
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn on_teardown() {
+    let log = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let log1 = log.clone();
+    gj::EventLoop::top_level(move |_wait_scope| {
+        let log2 = log1.clone();
+        gj::EventLoop::on_teardown(move || log2.borrow_mut().push("first"));
+        let log3 = log1.clone();
+        gj::EventLoop::on_teardown(move || {
+            log3.borrow_mut().push("second");
+            let log4 = log3.clone();
+            gj::EventLoop::on_teardown(move || log4.borrow_mut().push("registered by second"));
+        });
+        assert!(log1.borrow().is_empty());
+        Ok(())
+    }).unwrap();
+    assert_eq!(*log.borrow(), vec!["second", "registered by second", "first"]);
+}
+
+#[test]
+fn race_ok() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (never, _never_fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let (rejected, rejecter) = gj::new_promise_and_fulfiller::<u32>();
+        rejecter.reject(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "failed")));
+        let succeeded = gj::Promise::fulfilled(()).map(|()| { return Ok(7); });
+
+        let value = gj::race_ok(vec![never, rejected, succeeded]).wait(wait_scope).unwrap();
+        assert_eq!(value, 7);
+        Ok(())
+    }).unwrap();
+
+    gj::EventLoop::top_level(|wait_scope| {
+        let promises: Vec<gj::Promise<u32>> = vec![
+            gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "a"))),
+            gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "b")))];
+
+        let error = gj::race_ok(promises).wait(wait_scope).err().unwrap();
+        let aggregate = error.downcast_ref::<gj::AggregateError>().unwrap();
+        assert_eq!(aggregate.errors.len(), 2);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn array_join_fail_fast() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let promises = vec![gj::Promise::fulfilled(123),
+                            gj::Promise::fulfilled(456)];
+        let result = gj::join_promises_fail_fast(promises).wait(wait_scope).unwrap();
+        assert_eq!(result, vec![123, 456]);
+        Ok(())
+    }).unwrap();
+
+    gj::EventLoop::top_level(|wait_scope| {
+        // This never resolves, so the join only completes if the rejection cancels it.
+        let (never, _never_fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let rejected = gj::Promise::fulfilled(()).map(|()| -> gj::Result<u32> {
+            return Err(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "failed")));
+        });
+        assert!(gj::join_promises_fail_fast(vec![never, rejected]).wait(wait_scope).is_err());
+        Ok(())
+    }).unwrap();
+}
+
+#[cfg(feature = "node-counters")]
+#[test]
+fn node_counts() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let before = gj::EventLoop::node_counts();
+        let promise = gj::Promise::fulfilled(1).then(|x| {
+            return Ok(gj::Promise::fulfilled(x + 1));
+        });
+        let during = gj::EventLoop::node_counts();
+        assert_eq!(during.chain.live, before.chain.live + 1);
+        assert_eq!(during.transform.created, before.transform.created + 1);
+
+        assert_eq!(promise.wait(wait_scope).unwrap(), 2);
+        let after = gj::EventLoop::node_counts();
+        assert_eq!(after.chain.live, before.chain.live);
+        assert_eq!(after.transform.live, before.transform.live);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn progress() {
+    use gj::PromiseFulfiller;
+    gj::EventLoop::top_level(|wait_scope| {
+        let (promise, mut progress, mut fulfiller) =
+            gj::new_promise_fulfiller_with_progress::<&'static str, u32>();
+        fulfiller.progress(10);
+        fulfiller.progress(50);
+        assert_eq!(progress.next().wait(wait_scope).unwrap(), Some(10));
+        assert_eq!(progress.next().wait(wait_scope).unwrap(), Some(50));
+        let last = progress.next();
+        fulfiller.fulfill("done");
+        assert_eq!(promise.wait(wait_scope).unwrap(), "done");
+        assert_eq!(last.wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn inspect() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let seen = Rc::new(Cell::new(0));
+        let seen1 = seen.clone();
+        let seen2 = seen.clone();
+        let value = gj::Promise::fulfilled(5u32)
+            .inspect(move |&value| seen1.set(value))
+            .inspect_err(|_| panic!("not rejected"))
+            .wait(wait_scope).unwrap();
+        assert_eq!(value, 5);
+        assert_eq!(seen.get(), 5);
+
+        let error = gj::Promise::<u32>::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "failed")))
+            .inspect(|_| panic!("not fulfilled"))
+            .inspect_err(move |error| {
+                assert_eq!(format!("{}", error), "failed");
+                seen2.set(0);
+            })
+            .wait(wait_scope).unwrap_err();
+        assert_eq!(format!("{}", error), "failed");
+        assert_eq!(seen.get(), 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn until_latch() {
+    gj::test::run(|wait_scope| {
+        let latch = gj::Latch::new();
+        assert_eq!(gj::Promise::fulfilled(3u32).until(&latch).wait(wait_scope).unwrap(), 3);
+
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let interrupted = promise.until(&latch);
+        let triggered = latch.clone().when_triggered();
+        latch.trigger();
+        assert!(latch.is_triggered());
+
+        let error = interrupted.wait(wait_scope).unwrap_err();
+        assert!(error.downcast_ref::<gj::Interrupted>().is_some());
+        triggered.wait(wait_scope).unwrap();
+
+        // The promise is gone, so this has no effect.
+        fulfiller.fulfill(1);
+
+        assert!(gj::Promise::fulfilled(4u32).until(&latch).wait(wait_scope).is_err());
+        Ok(())
+    });
+}
+
+#[test]
+fn exclusive_join_cancel() {
+    gj::test::run(|wait_scope| {
+        let (left, left_fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let (right, right_fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        drop(left.exclusive_join(right));
+        left_fulfiller.fulfill(1);
+        right_fulfiller.fulfill(2);
+        gj::Promise::fulfilled(()).wait(wait_scope).unwrap();
+        Ok(())
+    });
+}
+
+struct NamingErrorHandler {
+    failed: ::std::rc::Rc<::std::cell::RefCell<Vec<String>>>,
+}
+
+impl gj::ErrorHandler for NamingErrorHandler {
+    fn task_failed(&mut self, _error: gj::Error) {
+        unreachable!()
+    }
+
+    fn task_failed_with_info(&mut self, task: &gj::TaskInfo, error: gj::Error) {
+        let name = task.name.clone().unwrap_or("unnamed".to_string());
+        self.failed.borrow_mut().push(format!("{}: {}", name, error));
+    }
+}
+
+#[test]
+fn named_tasks() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let failed = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut tasks = gj::TaskSet::new(Box::new(NamingErrorHandler { failed: failed.clone() }));
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller();
+        tasks.add_named(promise, "connection 7");
+        tasks.add(gj::Promise::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "bad request"))));
+        assert_eq!(tasks.tasks()[0].name, Some("connection 7".to_string()));
+
+        fulfiller.reject(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "reset")));
+        tasks.on_empty().wait(wait_scope).unwrap();
+        let mut failed = failed.borrow().clone();
+        failed.sort();
+        assert_eq!(failed, vec!["connection 7: reset".to_string(), "unnamed: bad request".to_string()]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn closure_error_handler() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let counter = error_count.clone();
+        let mut tasks = gj::TaskSet::new_with(move |_error| counter.set(counter.get() + 1));
+        tasks.add(gj::Promise::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "failed"))));
+        tasks.add(gj::Promise::fulfilled(()));
+        tasks.on_empty().wait(wait_scope).unwrap();
+        assert_eq!(error_count.get(), 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn defer() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let log = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let (log1, log2) = (log.clone(), log.clone());
+        // `then()` callbacks run in events of their own, rather than when the join is collected.
+        let first = gj::Promise::fulfilled(()).then(move |()| {
+            log1.borrow_mut().push("first");
+            let log = log1.clone();
+            gj::EventLoop::defer(move || log.borrow_mut().push("deferred"));
+            Ok(gj::Promise::fulfilled(()))
+        });
+        let second = gj::Promise::fulfilled(()).then(move |()| {
+            log2.borrow_mut().push("second");
+            Ok(gj::Promise::fulfilled(()))
+        });
+        gj::join_promises(vec![first, second]).wait(wait_scope).unwrap();
+        assert_eq!(*log.borrow(), vec!["first", "deferred", "second"]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_set_drain() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let error_count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut tasks = gj::TaskSet::new(Box::new(ErrorHandlerImpl {error_count: error_count.clone()}));
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller();
+        tasks.add(promise);
+        let drained = tasks.drain(None);
+        assert!(tasks.is_draining());
+        let late = tasks.add(gj::Promise::fulfilled(()));
+        assert!(!late.is_pending());
+        fulfiller.fulfill(());
+        drained.wait(wait_scope).unwrap();
+        assert_eq!(tasks.stats().fulfilled, 1);
+        assert_eq!(tasks.stats().cancelled, 1);
+
+        let mut tasks = gj::TaskSet::new(Box::new(ErrorHandlerImpl {error_count: error_count.clone()}));
+        let (promise, _fulfiller) = gj::new_promise_and_fulfiller();
+        tasks.add(promise);
+        tasks.drain(Some(10)).wait(wait_scope).unwrap();
+        assert!(tasks.is_empty());
+        assert_eq!(tasks.stats().cancelled, 1);
+        assert_eq!(error_count.get(), 0);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_set_catch_panics() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let messages = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let messages1 = messages.clone();
+        let mut tasks = gj::TaskSet::new_with(move |error: gj::Error| {
+            messages1.borrow_mut().push(format!("{}", error));
+        });
+        tasks.set_catch_panics(true);
+        tasks.add(gj::Promise::fulfilled(()).map(|()| -> gj::Result<()> { panic!("oops") }));
+        tasks.add(gj::Promise::fulfilled(()));
+        tasks.on_empty().wait(wait_scope).unwrap();
+        assert_eq!(*messages.borrow(), vec!["task panicked: oops".to_string()]);
+        assert_eq!(tasks.stats().fulfilled, 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn run_and_poll() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let count = Rc::new(Cell::new(0));
+        let mut tasks = gj::TaskSet::new_with(|_error: gj::Error| {});
+        for _ in 0..3 {
+            let count = count.clone();
+            tasks.add(gj::Promise::fulfilled(()).map(move |()| {
+                count.set(count.get() + 1);
+                Ok(())
+            }));
+        }
+        assert_eq!(gj::EventLoop::run(wait_scope, 1), 1);
+        assert_eq!(count.get(), 1);
+        assert_eq!(gj::EventLoop::run_until_idle(wait_scope), 2);
+        assert_eq!(count.get(), 3);
+        assert_eq!(gj::EventLoop::run_until_idle(wait_scope), 0);
+
+        let fired = Rc::new(Cell::new(false));
+        let fired1 = fired.clone();
+        tasks.add(gj::io::Timer.after_delay_ms(0).map(move |()| {
+            fired1.set(true);
+            Ok(())
+        }));
+        ::std::thread::sleep(::std::time::Duration::from_millis(20));
+        while !fired.get() {
+            gj::EventLoop::poll(wait_scope);
+        }
+        Ok(())
+    }).unwrap();
+}
+
+struct SimulatedPort {
+    waits: ::std::rc::Rc<::std::cell::Cell<u32>>,
+    pending: ::std::rc::Rc<::std::cell::RefCell<Vec<Box<gj::PromiseFulfiller<u32>>>>>,
+}
+
+impl gj::EventPort for SimulatedPort {
+    fn wait(&mut self) -> bool {
+        self.waits.set(self.waits.get() + 1);
+        let fulfiller = self.pending.borrow_mut().pop().expect("waited with nothing pending");
+        fulfiller.fulfill(self.waits.get());
+        false
+    }
+
+    fn poll(&mut self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn custom_event_port() {
+    let waits = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+    let pending = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let port = SimulatedPort { waits: waits.clone(), pending: pending.clone() };
+    gj::EventLoop::top_level_with_port(port, |wait_scope| {
+        let (promise, fulfiller) = gj::new_promise_and_fulfiller();
+        pending.borrow_mut().push(fulfiller);
+        assert_eq!(promise.wait(wait_scope).unwrap(), 1);
+        Ok(())
+    }).unwrap();
+    assert_eq!(waits.get(), 1);
+}
+
+#[test]
+fn runnable_callback() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let transitions = Rc::new(RefCell::new(Vec::new()));
+        let transitions1 = transitions.clone();
+        gj::EventLoop::set_runnable_callback(move |runnable| transitions1.borrow_mut().push(runnable));
+
+        let mut tasks = gj::TaskSet::new_with(|_error: gj::Error| {});
+        tasks.add(gj::Promise::fulfilled(()));
+        tasks.add(gj::Promise::fulfilled(()));
+        assert_eq!(*transitions.borrow(), vec![true]);
+        gj::EventLoop::run_until_idle(wait_scope);
+        assert_eq!(*transitions.borrow(), vec![true, false]);
+        Ok(())
+    }).unwrap();
+}
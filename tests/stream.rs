@@ -0,0 +1,206 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
+extern crate gj;
+
+#[test]
+fn send_and_close() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut stream, mut sender) = gj::stream::new_stream::<u32>();
+        let first = stream.next();
+        sender.send(1);
+        sender.send(2);
+        drop(sender);
+        assert_eq!(first.wait(wait_scope).unwrap(), Some(1));
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(2));
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), None);
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn cancelled_next_does_not_lose_values() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut stream, mut sender) = gj::stream::new_stream::<u32>();
+        drop(stream.next());
+        sender.send(7);
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(7));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn reorderer() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut reorderer, mut stream) = gj::stream::Reorderer::<u32>::new(0, 4);
+        let mut fulfillers = Vec::new();
+        for seq in 0..3 {
+            let (promise, fulfiller) = gj::new_promise_and_fulfiller();
+            reorderer.push(seq, promise).unwrap();
+            fulfillers.push(fulfiller);
+        }
+        assert!(reorderer.push(1, gj::Promise::fulfilled(10)).is_err());
+        assert!(reorderer.push(4, gj::Promise::fulfilled(40)).is_err());
+
+        let mut fulfillers = fulfillers.into_iter().rev();
+        fulfillers.next().unwrap().fulfill(200);
+        fulfillers.next().unwrap().fulfill(100);
+        let first = stream.next();
+        fulfillers.next().unwrap().fulfill(0);
+
+        assert_eq!(first.wait(wait_scope).unwrap(), Some(0));
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(100));
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(200));
+        assert_eq!(reorderer.next_seq(), 3);
+
+        drop(reorderer);
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn reorderer_failure() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut reorderer, mut stream) = gj::stream::Reorderer::<u32>::new(5, 2);
+        reorderer.push(6, gj::Promise::fulfilled(6)).unwrap();
+        reorderer.push(5, gj::Promise::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "failed")))).unwrap();
+        assert!(stream.next().wait(wait_scope).is_err());
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn debounce() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (input, mut sender) = gj::stream::new_stream::<u32>();
+        let mut output = input.debounce_ms(20);
+        sender.send(1);
+        sender.send(2);
+        sender.send(3);
+        assert_eq!(output.next().wait(wait_scope).unwrap(), Some(3));
+        sender.send(4);
+        sender.send(5);
+        drop(sender);
+        assert_eq!(output.next().wait(wait_scope).unwrap(), Some(5));
+        assert_eq!(output.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn throttle() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (input, mut sender) = gj::stream::new_stream::<u32>();
+        let mut output = input.throttle_ms(20);
+        sender.send(1);
+        sender.send(2);
+        sender.send(3);
+        assert_eq!(output.next().wait(wait_scope).unwrap(), Some(1));
+        assert_eq!(output.next().wait(wait_scope).unwrap(), Some(3));
+        sender.send(4);
+        drop(sender);
+        assert_eq!(output.next().wait(wait_scope).unwrap(), Some(4));
+        assert_eq!(output.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn spawn_stream_failure() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let mut stream = gj::stream::spawn_stream::<u32, _>(|mut sender| {
+            sender.send(1);
+            return gj::Promise::rejected(Box::new(
+                ::std::io::Error::new(::std::io::ErrorKind::Other, "failed")));
+        });
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(1));
+        assert!(stream.next().wait(wait_scope).is_err());
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn when_drained() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (mut stream, mut sender) = gj::stream::new_stream::<u32>();
+        sender.when_drained().wait(wait_scope).unwrap();
+        sender.send(1);
+        sender.send(2);
+        let drained = sender.when_drained();
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(1));
+        assert_eq!(stream.next().wait(wait_scope).unwrap(), Some(2));
+        drained.wait(wait_scope).unwrap();
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn merge() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (input1, mut sender1) = gj::stream::new_stream::<u32>();
+        let (input2, mut sender2) = gj::stream::new_stream::<u32>();
+        let mut merged = gj::stream::merge(vec![input1, input2]);
+        sender1.send(1);
+        sender2.send(2);
+        sender1.send(3);
+        drop(sender1);
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            values.push(merged.next().wait(wait_scope).unwrap().unwrap());
+        }
+        values.sort();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        let last = merged.next();
+        drop(sender2);
+        assert_eq!(last.wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn demux() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let (input, mut sender) = gj::stream::new_stream::<u32>();
+        let mut streams = gj::stream::demux(input, vec![0, 1], |value| value % 3);
+        for value in 0..6 {
+            sender.send(value);
+        }
+        drop(sender);
+        let mut zeros = streams.remove(&0).unwrap();
+        let mut ones = streams.remove(&1).unwrap();
+        assert_eq!(zeros.next().wait(wait_scope).unwrap(), Some(0));
+        assert_eq!(ones.next().wait(wait_scope).unwrap(), Some(1));
+        assert_eq!(zeros.next().wait(wait_scope).unwrap(), Some(3));
+        assert_eq!(ones.next().wait(wait_scope).unwrap(), Some(4));
+        assert_eq!(zeros.next().wait(wait_scope).unwrap(), None);
+        assert_eq!(ones.next().wait(wait_scope).unwrap(), None);
+        Ok(())
+    }).unwrap();
+}
@@ -0,0 +1,304 @@
+// Copyright (c) 2013-2015 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+#![allow(deprecated, bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::style, clippy::complexity)]
+
+extern crate gj;
+
+#[test]
+fn promise_cell() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let calls = Rc::new(Cell::new(0));
+        let calls1 = calls.clone();
+        let cell = gj::util::PromiseCell::new(move || {
+            calls1.set(calls1.get() + 1);
+            return gj::Promise::fulfilled(()).map(|()| Ok(42u32));
+        });
+        assert_eq!(cell.peek(), None);
+        let first = cell.get();
+        let second = cell.clone().get();
+        assert_eq!(first.wait(wait_scope).unwrap(), 42);
+        assert_eq!(second.wait(wait_scope).unwrap(), 42);
+        assert_eq!(cell.get().wait(wait_scope).unwrap(), 42);
+        assert_eq!(cell.peek(), Some(42));
+        assert_eq!(calls.get(), 1);
+
+        cell.reset();
+        assert_eq!(cell.get().wait(wait_scope).unwrap(), 42);
+        assert_eq!(calls.get(), 2);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn promise_cell_retries_after_failure() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let calls = Rc::new(Cell::new(0));
+        let calls1 = calls.clone();
+        let cell = gj::util::PromiseCell::new(move || {
+            calls1.set(calls1.get() + 1);
+            if calls1.get() == 1 {
+                return gj::Promise::rejected(Box::new(
+                    ::std::io::Error::new(::std::io::ErrorKind::Other, "first attempt fails")));
+            } else {
+                return gj::Promise::fulfilled(7u32);
+            }
+        });
+        let first = cell.get();
+        let second = cell.get();
+        assert!(first.wait(wait_scope).is_err());
+        assert!(second.wait(wait_scope).is_err());
+        assert_eq!(cell.get().wait(wait_scope).unwrap(), 7);
+        assert_eq!(calls.get(), 2);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn hedge() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        // The first attempt stalls, so the hedging attempt wins.
+        let calls = Rc::new(Cell::new(0));
+        let calls1 = calls.clone();
+        let promise = gj::util::hedge(move || {
+            calls1.set(calls1.get() + 1);
+            if calls1.get() == 1 {
+                return gj::io::Timer.after_delay_ms(10000).map(|()| Ok(1u32));
+            } else {
+                return gj::Promise::fulfilled(2u32);
+            }
+        }, 10);
+        assert_eq!(promise.wait(wait_scope).unwrap(), 2);
+        assert_eq!(calls.get(), 2);
+
+        // The first attempt succeeds promptly, so no second attempt is made.
+        let calls = Rc::new(Cell::new(0));
+        let calls1 = calls.clone();
+        let promise = gj::util::hedge(move || {
+            calls1.set(calls1.get() + 1);
+            return gj::Promise::fulfilled(calls1.get());
+        }, 10000);
+        assert_eq!(promise.wait(wait_scope).unwrap(), 1);
+        assert_eq!(calls.get(), 1);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn hedge_after_failure() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    gj::EventLoop::top_level(|wait_scope| {
+        let start = ::std::time::Instant::now();
+        let calls = Rc::new(Cell::new(0));
+        let calls1 = calls.clone();
+        let promise = gj::util::hedge(move || {
+            calls1.set(calls1.get() + 1);
+            if calls1.get() == 1 {
+                return gj::Promise::rejected(Box::new(
+                    ::std::io::Error::new(::std::io::ErrorKind::Other, "first attempt fails")));
+            } else {
+                return gj::Promise::fulfilled(2u32);
+            }
+        }, 10000);
+        assert_eq!(promise.wait(wait_scope).unwrap(), 2);
+        assert!(start.elapsed() < ::std::time::Duration::from_secs(5));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn circuit_breaker() {
+    use gj::util::{CircuitBreaker, CircuitState, Overloaded};
+    gj::EventLoop::top_level(|wait_scope| {
+        let breaker = CircuitBreaker::new(4, 2, 20);
+        let fail = || -> gj::Promise<u32> {
+            gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "down")))
+        };
+        assert_eq!(breaker.call(|| gj::Promise::fulfilled(1)).wait(wait_scope).unwrap(), 1);
+        assert!(breaker.call(&fail).wait(wait_scope).is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.call(&fail).wait(wait_scope).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let error = breaker.call(|| -> gj::Promise<u32> { panic!("should not be called") })
+                           .wait(wait_scope).unwrap_err();
+        assert!(error.downcast_ref::<Overloaded>().is_some());
+
+        gj::io::Timer.after_delay_ms(40).wait(wait_scope).unwrap();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        let (probe, fulfiller) = gj::new_promise_and_fulfiller::<u32>();
+        let probe = breaker.call(move || probe);
+        assert!(breaker.call(|| gj::Promise::fulfilled(2)).wait(wait_scope).is_err());
+        fulfiller.fulfill(3);
+        assert_eq!(probe.wait(wait_scope).unwrap(), 3);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn task_reaper() {
+    gj::EventLoop::top_level(|wait_scope| {
+        let mut reaper = gj::util::TaskReaper::new();
+        let (promise1, fulfiller1) = gj::new_promise_and_fulfiller::<u32>();
+        let (promise2, fulfiller2) = gj::new_promise_and_fulfiller::<u32>();
+        reaper.add(promise1);
+        reaper.add(promise2);
+        reaper.add(gj::Promise::rejected(Box::new(
+            ::std::io::Error::new(::std::io::ErrorKind::Other, "failed"))));
+        assert_eq!(reaper.len(), 3);
+
+        fulfiller1.fulfill(1);
+        gj::io::Timer.after_delay_ms(1).wait(wait_scope).unwrap();
+        let completed = reaper.take_completed();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(reaper.len(), 1);
+
+        fulfiller2.fulfill(2);
+        let rest = reaper.drain().wait(wait_scope).unwrap();
+        let mut values: Vec<u32> = completed.into_iter().chain(rest.into_iter())
+            .filter_map(|outcome| outcome.ok()).collect();
+        values.sort();
+        assert_eq!(values, vec![1, 2]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn supervisor() {
+    use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
+    gj::EventLoop::top_level(|wait_scope| {
+        let given_up = Rc::new(RefCell::new(Vec::new()));
+        let given_up1 = given_up.clone();
+        let mut supervisor = gj::util::Supervisor::new(move |name: &str, error: gj::Error| {
+            given_up1.borrow_mut().push(format!("{}: {}", name, error));
+        });
+        let policy = gj::util::RestartPolicy { max_restarts: Some(3), initial_backoff_ms: 1,
+                                               max_backoff_ms: 4 };
+
+        let flaky_calls = Rc::new(Cell::new(0));
+        let flaky_calls1 = flaky_calls.clone();
+        supervisor.supervise("flaky", policy, move || {
+            flaky_calls1.set(flaky_calls1.get() + 1);
+            if flaky_calls1.get() < 3 {
+                gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "flaked")))
+            } else {
+                gj::Promise::fulfilled(())
+            }
+        });
+
+        let broken_calls = Rc::new(Cell::new(0));
+        let broken_calls1 = broken_calls.clone();
+        supervisor.supervise("broken", policy, move || {
+            broken_calls1.set(broken_calls1.get() + 1);
+            gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "broke")))
+        });
+
+        supervisor.on_empty().wait(wait_scope).unwrap();
+        assert_eq!(flaky_calls.get(), 3);
+        assert_eq!(broken_calls.get(), 4);
+        assert_eq!(*given_up.borrow(), vec!["broken: broke".to_string()]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn scheduler() {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use gj::scheduler::{Overlap, Schedule, Scheduler};
+    gj::EventLoop::top_level(|wait_scope| {
+        let clock = gj::test::TestClock::install();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let errors1 = errors.clone();
+        let mut scheduler = Scheduler::new(Box::new(move |error: gj::Error| {
+            errors1.borrow_mut().push(format!("{}", error));
+        }));
+
+        let skipped = Rc::new(Cell::new(0));
+        let queued = Rc::new(Cell::new(0));
+        let failed = Rc::new(Cell::new(0));
+        let (skipped1, queued1, failed1) = (skipped.clone(), queued.clone(), failed.clone());
+        let period = ::std::time::Duration::from_millis(100);
+        scheduler.schedule("skip", Schedule::FixedRate(period), Overlap::Skip, move || {
+            skipped1.set(skipped1.get() + 1);
+            gj::Promise::fulfilled(())
+        });
+        scheduler.schedule("queue", Schedule::FixedRate(period), Overlap::Queue, move || {
+            queued1.set(queued1.get() + 1);
+            gj::Promise::fulfilled(())
+        });
+        let handle = scheduler.schedule("fail", Schedule::FixedDelay(period), Overlap::Skip, move || {
+            failed1.set(failed1.get() + 1);
+            gj::Promise::rejected(Box::new(::std::io::Error::new(::std::io::ErrorKind::Other, "oops")))
+        });
+        assert_eq!(scheduler.len(), 3);
+
+        clock.advance_ms(100);
+        gj::EventLoop::run_until_idle(wait_scope);
+        assert_eq!((skipped.get(), queued.get(), failed.get()), (1, 1, 1));
+
+        // Two more periods pass at once.
+        clock.advance_ms(250);
+        gj::EventLoop::run_until_idle(wait_scope);
+        assert_eq!((skipped.get(), queued.get(), failed.get()), (2, 3, 2));
+        assert_eq!(*errors.borrow(), vec!["oops".to_string(), "oops".to_string()]);
+
+        handle.cancel();
+        assert_eq!(scheduler.len(), 2);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn calendar_spec() {
+    use gj::scheduler::CalendarSpec;
+    let at = |secs: u64| ::std::time::UNIX_EPOCH + ::std::time::Duration::from_secs(secs);
+
+    // Friday, 2021-01-01 00:00 UTC.
+    let new_year = 1609459200;
+    let weekdays = CalendarSpec::parse("30 9 * * 1-5").unwrap();
+    let friday = weekdays.next_after(at(new_year)).unwrap();
+    assert_eq!(friday, at(new_year + 9 * 3600 + 1800));
+    assert_eq!(weekdays.next_after(friday).unwrap(), at(new_year + 3 * 86400 + 9 * 3600 + 1800));
+
+    let quarter_hours = CalendarSpec::parse("*/15 * * * *").unwrap();
+    assert_eq!(quarter_hours.next_after(at(new_year + 60)).unwrap(), at(new_year + 900));
+
+    // 2024-02-29 is the next leap day.
+    let leap_day = CalendarSpec::parse("0 0 29 2 *").unwrap();
+    assert_eq!(leap_day.next_after(at(new_year)).unwrap(), at(1709164800));
+
+    assert!(CalendarSpec::parse("60 * * * *").is_err());
+    assert!(CalendarSpec::parse("* * *").is_err());
+    assert!(CalendarSpec::parse("59/4294967295 * * * *").is_ok());
+    assert_eq!(CalendarSpec::parse("0 0 31 2 *").unwrap().next_after(at(new_year)), None);
+}